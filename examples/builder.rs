@@ -11,7 +11,6 @@
  *  You should have received a copy of the GNU General Public License
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use rspawn::RSpawn;
 use std::io;
 
 fn init_logger() {
@@ -36,10 +35,12 @@ fn main() {
 
     #[allow(non_snake_case)]
     let check_if_executed_from_PATH = false; // Only ask for update when called from PATH
-    if let Err(e) = RSpawn::new()
+    match rspawn::self_update!()
         .check_if_executed_from_PATH(check_if_executed_from_PATH)
         .user_confirm(custom_confirm)
         .relaunch_program() {
-        eprintln!("Error: {}", e);
+        Ok(rspawn::UpdateOutcome::Relaunched { .. }) => std::process::exit(0),
+        Ok(outcome) => println!("Update check: {}", outcome),
+        Err(e) => eprintln!("Error: {}", e),
     }
 }
@@ -11,7 +11,7 @@
  *  You should have received a copy of the GNU General Public License
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use rspawn::relaunch_program;
+use rspawn::{relaunch_program, UpdateOutcome};
 use std::io;
 
 fn init_logger() {
@@ -37,7 +37,10 @@ fn main() {
     #[allow(non_snake_case)]
     let check_if_executed_from_PATH = true; // Only ask for update when called from PATH
 
-    if let Err(e) = relaunch_program(None, Some(custom_confirm), check_if_executed_from_PATH) {
-        eprintln!("Error: {}", e);
+    match relaunch_program(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), None, Some(custom_confirm), check_if_executed_from_PATH) {
+        // The new version is running: leave it the terminal
+        Ok(UpdateOutcome::Relaunched { .. }) => std::process::exit(0),
+        Ok(_) => {}
+        Err(e) => eprintln!("Error: {}", e),
     }
 }
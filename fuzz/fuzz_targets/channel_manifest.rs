@@ -0,0 +1,38 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![no_main]
+
+use std::collections::BTreeMap;
+use libfuzzer_sys::fuzz_target;
+use rspawn::{ChannelManifest, HostInfo};
+
+// Any JSON: a manifest that parses survives a round trip and resolves a version without panicking
+fuzz_target!(|data: &[u8]| {
+    let Ok(json) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(manifest) = ChannelManifest::parse(json) else {
+        return;
+    };
+    let serialized = serde_json::to_string(&manifest).expect("a parsed manifest serializes");
+    assert_eq!(ChannelManifest::parse(&serialized).ok(), Some(manifest.clone()));
+
+    let host = HostInfo {
+        hostname: Some("web-01".to_string()),
+        labels: BTreeMap::from([("region".to_string(), "eu".to_string())]),
+    };
+    for channel in manifest.channels.keys().map(|name| Some(name.as_str())).chain([None]) {
+        let _ = manifest.target_version("mytool", channel, "install-0123456789abcdef", &host);
+    }
+});
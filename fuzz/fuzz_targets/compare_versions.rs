@@ -0,0 +1,38 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![no_main]
+
+use std::cmp::Ordering;
+use libfuzzer_sys::fuzz_target;
+use rspawn::{compare_versions, is_prerelease, is_update, UpdatePolicy};
+
+// Three versions, one per line: the ordering must be total and `is_update` agree with it
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let mut lines = input.splitn(3, '\n');
+    let (a, b, c) = (lines.next().unwrap_or(""), lines.next().unwrap_or(""), lines.next().unwrap_or(""));
+
+    assert_eq!(compare_versions(a, a), Ordering::Equal, "{a:?} is not equal to itself");
+    assert_eq!(compare_versions(a, b), compare_versions(b, a).reverse(), "{a:?} and {b:?} compare both ways");
+    if compare_versions(a, b) != Ordering::Greater && compare_versions(b, c) != Ordering::Greater {
+        assert_ne!(compare_versions(a, c), Ordering::Greater, "{a:?} <= {b:?} <= {c:?}, but {a:?} > {c:?}");
+    }
+
+    let newer = is_update(a, b, UpdatePolicy::Newer);
+    assert_eq!(newer, compare_versions(b, a) == Ordering::Greater);
+    assert_eq!(is_update(a, b, UpdatePolicy::NewerStable), newer && !is_prerelease(b));
+    assert!(!newer || is_update(a, b, UpdatePolicy::AnyChange), "{b:?} is newer than {a:?}, but not a change");
+});
@@ -0,0 +1,30 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rspawn::fuzzing::{is_on_channel, parse_latest_version};
+
+// The first line picks the channel (empty for none), the rest is the response body
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let (channel, body) = input.split_once('\n').unwrap_or(("", input));
+    let channel = Some(channel).filter(|channel| !channel.is_empty());
+
+    if let (Ok(num), Some(channel)) = (parse_latest_version(body, channel), channel) {
+        assert!(is_on_channel(&num, channel), "{num} returned for channel {channel}");
+    }
+});
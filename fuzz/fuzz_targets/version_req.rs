@@ -0,0 +1,33 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rspawn::VersionReq;
+
+// The first line is a requirement, the second a version to match against it
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let (requirement, version) = input.split_once('\n').unwrap_or((input, ""));
+    let Ok(parsed) = VersionReq::parse(requirement) else {
+        return;
+    };
+    let matches = parsed.matches(version);
+
+    // The displayed requirement means the same thing
+    let reparsed = VersionReq::parse(&parsed.to_string()).expect("a displayed requirement parses");
+    assert_eq!(reparsed.matches(version), matches, "{requirement:?} and {parsed} disagree on {version:?}");
+});
@@ -0,0 +1,104 @@
+#ifndef RSPAWN_H
+#define RSPAWN_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The call succeeded.
+ */
+#define RSPAWN_OK 0
+
+/**
+ * A required argument was NULL or not valid UTF-8.
+ */
+#define RSPAWN_ERR_INVALID_ARGUMENT -1
+
+/**
+ * The latest version could not be fetched from the registry.
+ */
+#define RSPAWN_ERR_CHECK_FAILED -2
+
+/**
+ * `cargo install` failed.
+ */
+#define RSPAWN_ERR_INSTALL_FAILED -3
+
+/**
+ * Updates are frozen by the operator's hold file.
+ */
+#define RSPAWN_ERR_HELD -4
+
+/**
+ * Result of `rspawn_check`, released with `rspawn_free_info`.
+ */
+typedef struct RspawnInfo {
+  /**
+   * Name of the checked crate.
+   */
+  char *crate_name;
+  /**
+   * The version passed to `rspawn_check`.
+   */
+  char *current_version;
+  /**
+   * The latest version on the registry.
+   */
+  char *latest_version;
+  /**
+   * Whether `latest_version` is an update over `current_version`.
+   */
+  bool update_available;
+} RspawnInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns a description of the last error on the calling thread, or NULL.
+ *
+ * The string stays valid until the next rspawn call on the same thread.
+ */
+const char *rspawn_last_error(void);
+
+/**
+ * Checks the registry for a newer version of `crate_name`.
+ *
+ * On success `*out` points to a newly allocated `RspawnInfo`, to be released
+ * with `rspawn_free_info`.
+ *
+ * # Safety
+ * `crate_name` and `current_version` must be NULL or valid NUL-terminated strings,
+ * and `out` must be NULL or valid for writes.
+ */
+int rspawn_check(const char *crate_name, const char *current_version, struct RspawnInfo **out);
+
+/**
+ * Installs the latest version of `crate_name` with `cargo install`.
+ *
+ * `features` is an optional comma separated list of features to enable.
+ * The calling program is not relaunched: that is left to the caller.
+ *
+ * # Safety
+ * `crate_name` and `features` must be NULL or valid NUL-terminated strings.
+ */
+int rspawn_update(const char *crate_name, const char *features);
+
+/**
+ * Releases an `RspawnInfo` returned by `rspawn_check`. Passing NULL is a no-op.
+ *
+ * # Safety
+ * `info` must be NULL or a pointer returned by `rspawn_check` that was not freed yet.
+ */
+void rspawn_free_info(struct RspawnInfo *info);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RSPAWN_H */
@@ -0,0 +1,231 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Update approval resolved by a remote endpoint, for fleets where operators
+//! centrally decide which versions their agents roll out.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::{info, debug, warn};
+use crate::log_target::CHECK;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::host::hostname;
+use crate::http::{self, ClientConfig};
+use crate::json;
+use crate::state::{state_dir_in, with_file_lock, write_atomic};
+
+// File caching the endpoint's answers, inside `state_dir()`
+const APPROVALS_FILE_NAME: &str = "approvals.json";
+
+/// Settings for asking a remote endpoint whether an update may be installed.
+///
+/// rspawn sends `GET <url>?crate=<name>&version=<version>&current=<version>&host=<hostname>`
+/// and installs only if the response is `{"approved": true}`. Anything else,
+/// including a timeout or an unreachable endpoint, counts as not approved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteApproval {
+    /// The approval endpoint.
+    pub url: String,
+    /// How long to wait for an answer. Defaults to 10 seconds.
+    pub timeout: Duration,
+    /// How long an answer is reused before asking again. Defaults to 5 minutes;
+    /// `Duration::ZERO` always asks.
+    pub cache_ttl: Duration,
+}
+
+impl RemoteApproval {
+    /// Asks the endpoint at `url`, with the default timeout and cache TTL.
+    pub fn new(url: &str) -> Self {
+        RemoteApproval {
+            url: url.to_string(),
+            timeout: Duration::from_secs(10),
+            cache_ttl: Duration::from_secs(5 * 60),
+        }
+    }
+
+    /// Sets how long to wait for an answer.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long an answer is reused before asking again.
+    pub fn cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    fn request_url(&self, crate_name: &str, version: &str, current_version: &str) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut url = format!(
+            "{}{}crate={}&version={}&current={}",
+            self.url,
+            separator,
+            encode_query_value(crate_name),
+            encode_query_value(version),
+            encode_query_value(current_version)
+        );
+        if let Some(host) = hostname() {
+            url.push_str(&format!("&host={}", encode_query_value(&host)));
+        }
+        url
+    }
+}
+
+// Percent-encodes a query parameter value, e.g. the `+` of build metadata
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedApproval {
+    approved: bool,
+    // Answer time, in seconds since the Unix epoch
+    answered_at: u64,
+}
+
+// What the endpoint answers
+#[derive(Debug, Deserialize)]
+struct ApprovalAnswer {
+    #[serde(default)]
+    approved: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ApprovalCache {
+    // By `cache_key`
+    #[serde(default)]
+    versions: BTreeMap<String, CachedApproval>,
+}
+
+// Answers are per crate: several programs (or target binaries) share the state dir
+fn cache_key(crate_name: &str, version: &str) -> String {
+    format!("{}@{}", crate_name, version)
+}
+
+fn cache_path(fs: &dyn Fs) -> Result<PathBuf> {
+    Ok(state_dir_in(fs)?.join(APPROVALS_FILE_NAME))
+}
+
+fn load_cache(path: &Path, fs: &dyn Fs) -> ApprovalCache {
+    fs.read_to_string(path)
+        .ok()
+        .and_then(|contents| json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn ask(approval: &RemoteApproval, crate_name: &str, version: &str, current_version: &str) -> Result<bool> {
+    let url = approval.request_url(crate_name, version, current_version);
+    debug!(target: CHECK, "Asking for approval at {}", url);
+    let client = http::client(ClientConfig {
+        timeout: Some(approval.timeout),
+        ..ClientConfig::default()
+    })?;
+    let response = client
+        .get(&url)
+        .send()
+        .with_context(|| format!("Failed to reach approval endpoint {}", approval.url))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("Approval endpoint {} answered HTTP {}", approval.url, status));
+    }
+    let answer: ApprovalAnswer = json::from_str(&response.text().context("Failed to read approval response")?)
+        .context("Failed to parse approval response")?;
+    Ok(answer.approved)
+}
+
+// Whether installing `version` is approved. Fails closed: errors count as "no".
+pub(crate) fn is_approved(
+    approval: &RemoteApproval,
+    crate_name: &str,
+    version: &str,
+    current_version: &str,
+    clock: &dyn Clock,
+    fs: &dyn Fs,
+) -> bool {
+    let cache_path = cache_path(fs).ok();
+    if let Some(path) = &cache_path {
+        if let Some(cached) = load_cache(path, fs).versions.get(&cache_key(crate_name, version)) {
+            if clock.unix_now().saturating_sub(cached.answered_at) < approval.cache_ttl.as_secs() {
+                debug!(target: CHECK, "Using cached approval answer for {} {}: {}", crate_name, version, cached.approved);
+                return cached.approved;
+            }
+        }
+    }
+
+    let approved = match ask(approval, crate_name, version, current_version) {
+        Ok(approved) => approved,
+        Err(e) => {
+            warn!(target: CHECK, "Treating {} {} as not approved: {:#}", crate_name, version, e);
+            return false;
+        }
+    };
+    info!(target: CHECK, "Approval endpoint answered {} for {} {}", approved, crate_name, version);
+
+    if let Some(path) = cache_path.filter(|_| json::AVAILABLE) {
+        let stored = with_file_lock(fs, &path, || {
+            let mut cache = load_cache(&path, fs);
+            cache.versions.insert(
+                cache_key(crate_name, version),
+                CachedApproval { approved, answered_at: clock.unix_now() },
+            );
+            let contents = json::to_string_pretty(&cache).context("Failed to serialize approvals")?;
+            write_atomic(fs, &path, contents.as_bytes())
+        });
+        if let Err(e) = stored {
+            warn!(target: CHECK, "Failed to cache the approval answer: {:#}", e);
+        }
+    }
+    approved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    // Nothing listens on the discard port, so asking fails right away
+    const UNREACHABLE: &str = "http://127.0.0.1:9/approve";
+
+    #[test]
+    fn request_url_encodes_each_value() {
+        let approval = RemoteApproval::new("https://example.com/approve?fleet=a");
+        let url = approval.request_url("my app", "1.2.0+build.5", "1.1.0&x=y");
+        assert!(url.starts_with("https://example.com/approve?fleet=a&crate=my%20app&version=1.2.0%2Bbuild.5&current=1.1.0%26x%3Dy"), "{url}");
+    }
+
+    #[test]
+    fn cached_answers_are_per_crate() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = cache_path(&fs).unwrap();
+        let mut cache = ApprovalCache::default();
+        cache.versions.insert(cache_key("foo", "1.2.0"), CachedApproval { approved: true, answered_at: clock.unix_now() });
+        fs.write(&path, json::to_string(&cache).unwrap().as_bytes()).unwrap();
+
+        let approval = RemoteApproval::new(UNREACHABLE);
+        assert!(is_approved(&approval, "foo", "1.2.0", "1.1.0", &clock, &fs));
+        assert!(!is_approved(&approval, "bar", "1.2.0", "1.1.0", &clock, &fs));
+    }
+}
@@ -0,0 +1,91 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Moving a program's update state between machines, for `rspawn state`.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::error::RspawnError;
+use crate::json;
+use crate::clock::{Clock, SystemClock};
+use crate::state::State;
+
+// Bumped when an archive stops being readable by older versions
+const ARCHIVE_FORMAT: u32 = 1;
+
+/// A program's update state, exported with `export_state` to be imported
+/// elsewhere with `import_state`.
+///
+/// It holds the whole `State`: the user's settings and channel, the versions
+/// being backed off from, the update history and the last run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateArchive {
+    /// Version of the archive format.
+    pub format: u32,
+    /// The program the state was exported from, by executable name.
+    pub program: String,
+    /// Export time, in seconds since the Unix epoch.
+    pub exported_at: u64,
+    /// The exported state.
+    pub state: State,
+}
+
+impl StateArchive {
+    /// Serializes the archive as JSON, the format `rspawn state export` writes.
+    pub fn to_json(&self) -> Result<String, RspawnError> {
+        json::to_string_pretty(self).context("Failed to serialize the state archive").map_err(RspawnError::from)
+    }
+
+    /// Reads an archive written by `to_json`, rejecting formats this version doesn't know.
+    pub fn from_json(json: &str) -> Result<StateArchive, RspawnError> {
+        let archive: StateArchive = json::from_str(json)
+            .context("Failed to parse the state archive")
+            .map_err(RspawnError::ParseError)?;
+        if archive.format > ARCHIVE_FORMAT {
+            return Err(RspawnError::ParseError(anyhow::anyhow!(
+                "The state archive has format {}, this version of rspawn only reads up to {}",
+                archive.format,
+                ARCHIVE_FORMAT
+            )));
+        }
+        Ok(archive)
+    }
+}
+
+/// Exports the update state of `program` (by executable name).
+pub fn export_state(program: &str) -> Result<StateArchive, RspawnError> {
+    Ok(StateArchive {
+        format: ARCHIVE_FORMAT,
+        program: program.to_string(),
+        exported_at: SystemClock.unix_now(),
+        state: State::load_program(program)?,
+    })
+}
+
+/// Replaces the update state of `program` (by executable name) with the one in
+/// `archive`, which may come from another program name or machine.
+///
+/// What describes binaries on this machine is kept: the installed binary and
+/// its hash, the target binaries and the pending restart. The install id comes
+/// along, so the program lands in the same stage of staged rollouts.
+pub fn import_state(program: &str, archive: &StateArchive) -> Result<(), RspawnError> {
+    State::update_program(program, |state| {
+        let imported = State {
+            installed: state.installed.take(),
+            targets: std::mem::take(&mut state.targets),
+            pending_restart: state.pending_restart.take(),
+            ..archive.state.clone()
+        };
+        *state = imported;
+    })
+    .map_err(RspawnError::from)
+}
@@ -0,0 +1,168 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Data files published alongside a release (completions, grammars, models),
+//! listed in the channel manifest and placed in the program's data directory
+//! as part of the update.
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use log::{debug, info, warn};
+use crate::error::RspawnError;
+use crate::log_target::INSTALL;
+use crate::download::{self, DownloadSettings};
+use crate::events::Events;
+use crate::filesystem::Fs;
+use crate::integrity::IntegrityError;
+use crate::state::current_exe_name;
+use crate::unique::unique_token;
+#[cfg(feature = "p2p")]
+use crate::p2p;
+
+/// Environment variable overriding the root of the data directories.
+pub const DATA_DIR_ENV: &str = "RSPAWN_DATA_DIR";
+
+/// A data file published with a release, see `ChannelManifest::assets`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Asset {
+    /// Where to download it from.
+    pub url: String,
+    /// Hex-encoded SHA-256 of the file.
+    pub sha256: String,
+    /// Where to put it, relative to the data directory (e.g. `completions/mytool.bash`).
+    pub path: PathBuf,
+}
+
+/// Returns the data directory of the running program, where assets are placed.
+///
+/// This is `<root>/<program name>`, where `<root>` is `$RSPAWN_DATA_DIR` if set,
+/// otherwise `$XDG_DATA_HOME` (falling back to `~/.local/share`), or
+/// `%APPDATA%` on Windows.
+pub fn data_dir() -> Result<PathBuf, RspawnError> {
+    let root = if let Some(dir) = env::var_os(DATA_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        PathBuf::from(dir)
+    } else if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA").context("Neither RSPAWN_DATA_DIR nor APPDATA are set")?)
+    } else if let Some(xdg_data_home) = env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        PathBuf::from(xdg_data_home)
+    } else {
+        let home = env::var_os("HOME").context("Neither RSPAWN_DATA_DIR nor HOME are set")?;
+        PathBuf::from(home).join(".local").join("share")
+    };
+    Ok(root.join(current_exe_name()?))
+}
+
+// Fails unless `path` stays inside the data directory
+fn check_relative(path: &Path) -> Result<()> {
+    let escapes = path.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes || path.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("Asset path {} must be relative to the data directory", path.display()));
+    }
+    Ok(())
+}
+
+// Assets downloaded and verified, waiting for the install to go through
+pub(crate) struct StagedAssets {
+    dir: PathBuf,
+    // Each staged file, and where it goes
+    files: Vec<(PathBuf, PathBuf)>,
+}
+
+// Downloads every asset next to `data_dir`, failing on the first one that
+// can't be fetched or doesn't match its hash; nothing is placed yet
+pub(crate) fn stage(assets: &[Asset], data_dir: &Path, settings: &DownloadSettings, events: &mut Events, fs: &dyn Fs) -> Result<StagedAssets> {
+    let dir = data_dir.join(format!(".{}", unique_token("assets")));
+    fs.create_dir_all(&dir)
+        .with_context(|| format!("Failed to create staging directory {}", dir.display()))?;
+    let mut staged = StagedAssets { dir, files: Vec::new() };
+
+    for (index, asset) in assets.iter().enumerate() {
+        let result = check_relative(&asset.path).and_then(|()| {
+            #[cfg(feature = "p2p")]
+            let from_peers = if settings.peers { p2p::fetch(&asset.url, &asset.sha256, settings, events) } else { None };
+            #[cfg(not(feature = "p2p"))]
+            let from_peers = None;
+            let contents = match from_peers {
+                Some(contents) => contents,
+                None => download::download_checked(&asset.url, settings, events, |contents| {
+                    let actual: String = Sha256::digest(contents).iter().map(|byte| format!("{:02x}", byte)).collect();
+                    if !actual.eq_ignore_ascii_case(asset.sha256.trim()) {
+                        return Err(IntegrityError {
+                            path: asset.path.clone(),
+                            expected: asset.sha256.trim().to_lowercase(),
+                            actual,
+                        }
+                        .into());
+                    }
+                    Ok(())
+                })?,
+            };
+            #[cfg(feature = "p2p")]
+            if settings.peers {
+                p2p::keep(&asset.sha256, &contents);
+            }
+            let file = staged.dir.join(index.to_string());
+            fs.write(&file, &contents)
+                .with_context(|| format!("Failed to stage {}", asset.path.display()))?;
+            Ok(file)
+        });
+        match result {
+            Ok(file) => staged.files.push((file, data_dir.join(&asset.path))),
+            Err(e) => {
+                staged.discard(fs);
+                return Err(e.context(format!("Failed to fetch asset {}", asset.url)));
+            }
+        }
+    }
+    Ok(staged)
+}
+
+impl StagedAssets {
+    // Where each asset goes once placed
+    pub(crate) fn targets(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(_, target)| target.as_path())
+    }
+
+    // Moves every asset to its place in the data directory, cleaning up the staging directory either way
+    pub(crate) fn place(self, fs: &dyn Fs) -> Result<()> {
+        let result = self.files.iter().try_for_each(|(file, target)| {
+            if let Some(parent) = target.parent() {
+                fs.create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            fs.rename(file, target)
+                .with_context(|| format!("Failed to place {}", target.display()))?;
+            info!(target: INSTALL, "Placed {}", target.display());
+            Ok(())
+        });
+        self.discard(fs);
+        result
+    }
+
+    // Removes whatever is left of the staging directory
+    pub(crate) fn discard(&self, fs: &dyn Fs) {
+        for (file, _) in &self.files {
+            if fs.exists(file) {
+                if let Err(e) = fs.remove_file(file) {
+                    warn!(target: INSTALL, "Failed to remove staged asset {}: {}", file.display(), e);
+                }
+            }
+        }
+        debug!(target: INSTALL, "Removing staging directory {}", self.dir.display());
+        if let Err(e) = fs.remove_dir(&self.dir) {
+            debug!(target: INSTALL, "Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The update flow on tokio, behind `RSpawn::relaunch_async` and `relaunch_program_async`.
+//!
+//! It runs the same steps as `relaunch_program`, awaiting the two that take
+//! long instead of blocking on them: the version lookup on crates.io, through
+//! reqwest's async client, and `cargo install`, through `tokio::process`. The
+//! other steps still block (on files, locks, the terminal prompt, other version
+//! sources), so they run on tokio's blocking pool, and the future is `Send`.
+use std::ops::ControlFlow;
+use std::panic;
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use crate::credentials::REGISTRY_TOKEN;
+use crate::registry;
+use crate::{
+    cached_latest, confirm_update, finish_install, finish_plan, prepare_install, record_run, share_latest, start_check, Check,
+    FoundVersion, InstallReporter, RspawnError, UpdateConfirm, UpdateOptions, UpdateOutcome, VersionSource,
+};
+
+// The options are lent to one blocking step at a time, and always given back
+const LENT_OUT: &str = "the update options are lent to a single step at a time";
+
+// `run_update`, on tokio
+pub(crate) async fn run_update(options: UpdateOptions, confirm_fn: UpdateConfirm) -> Result<UpdateOutcome, RspawnError> {
+    let mut options = Some(options);
+    let result = update_flow(&mut options, confirm_fn).await;
+    let options = options.expect(LENT_OUT);
+    unblock(move || record_run(options, result)).await
+}
+
+async fn update_flow(options: &mut Option<UpdateOptions>, mut confirm_fn: UpdateConfirm) -> Result<UpdateOutcome, RspawnError> {
+    let check = match blocking(options, start_check).await? {
+        ControlFlow::Continue(check) => check,
+        ControlFlow::Break(outcome) => return Ok(outcome),
+    };
+    let (check, found) = find_latest(options, check).await?;
+    let planned = match blocking(options, move |options| finish_plan(options, check, found)).await? {
+        ControlFlow::Continue(planned) => planned,
+        ControlFlow::Break(outcome) => return Ok(outcome),
+    };
+    let (planned, confirmed) = blocking(options, move |options| {
+        let confirmed = confirm_update(options, &planned, confirm_fn.as_mut());
+        (planned, confirmed)
+    })
+    .await;
+    if !confirmed {
+        return Ok(UpdateOutcome::Declined);
+    }
+
+    let (prepared, install_command) = blocking(options, move |options| prepare_install(options, planned)).await?;
+    let install_result = {
+        let options = options.as_mut().expect(LENT_OUT);
+        let echo = !prepared.show_progress;
+        let reporter = Mutex::new(InstallReporter::new(&mut options.events, &install_command));
+        let report = || reporter.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let on_line = &mut |line: &str| report().line(line);
+        let on_beat = &mut || report().beat();
+        let heartbeat = (!options.install_heartbeat.is_zero()).then_some((options.install_heartbeat, on_beat as &mut (dyn FnMut() + Send)));
+        options.process_runner.install_async(install_command, echo, on_line, heartbeat).await
+    };
+    blocking(options, move |options| finish_install(options, prepared, install_result)).await
+}
+
+// `find_latest`, asking crates.io through the async client. Other sources are
+// looked up as `relaunch_program` does.
+async fn find_latest(options: &mut Option<UpdateOptions>, check: Check) -> Result<(Check, FoundVersion)> {
+    let lookup = options.as_ref().expect(LENT_OUT);
+    let on_crates_io = check.simulated_latest.is_none()
+        && lookup.channel_manifest.is_none()
+        && lookup.version_source == VersionSource::CratesIo;
+    if !on_crates_io {
+        return blocking(options, move |options| {
+            let found = crate::find_latest(options, &check)?;
+            Ok((check, found))
+        })
+        .await;
+    }
+
+    // Other runs and peers may have found it already
+    let (check, cached, registry_token) = blocking(options, move |options| {
+        let cached = cached_latest(options, &check.crate_name, &check.current_version, check.channel.as_deref());
+        let registry_token = match cached {
+            Some(_) => Ok(None),
+            None => options.credential_store.token(REGISTRY_TOKEN),
+        };
+        (check, cached, registry_token)
+    })
+    .await;
+    let (check, latest) = match cached {
+        Some(latest) => (check, latest),
+        None => {
+            let (crate_name, channel) = (check.crate_name.as_str(), check.channel.as_deref());
+            let latest = registry::get_latest_version_from_crates_io_async(crate_name, registry_token?.as_deref(), channel)
+                .await
+                .context("Failed to get latest version")?;
+            blocking(options, move |options| {
+                share_latest(options, &check.crate_name, check.channel.as_deref(), &latest);
+                (check, latest)
+            })
+            .await
+        }
+    };
+    let found = FoundVersion {
+        latest,
+        release_notes: None,
+        release_assets: Vec::new(),
+    };
+    Ok((check, found))
+}
+
+// Runs `work` with the options on tokio's blocking pool, taking them out of
+// `options` meanwhile
+async fn blocking<T, F>(options: &mut Option<UpdateOptions>, work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&mut UpdateOptions) -> T + Send + 'static,
+{
+    let mut lent = options.take().expect(LENT_OUT);
+    let (lent, result) = unblock(move || {
+        let result = work(&mut lent);
+        (lent, result)
+    })
+    .await;
+    *options = Some(lent);
+    result
+}
+
+// Runs `work` on tokio's blocking pool, passing its panics on
+async fn unblock<T, F>(work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
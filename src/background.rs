@@ -0,0 +1,100 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Runtime-agnostic futures for rspawn's blocking operations.
+//!
+//! Work runs on a plain background thread which wakes the polling task when
+//! done, so these futures can be awaited from tokio, async-std, smol or any
+//! other executor without pulling a runtime into rspawn.
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use anyhow::Result;
+use crate::error::RspawnError;
+use crate::credentials::{CredentialStore, EnvCredentialStore, REGISTRY_TOKEN};
+
+// State shared between the future and its background thread
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future resolving to the result of work running on a background thread.
+pub struct Background<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Background<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match shared.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            // Surface a panic in the background work to whoever awaits it
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Runs `work` on a new thread, returning a future for its result
+pub(crate) fn run_in_background<T, W>(work: W) -> Background<T>
+where
+    T: Send + 'static,
+    W: FnOnce() -> T + Send + 'static,
+{
+    let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+    let thread_shared = Arc::clone(&shared);
+
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(work));
+        let mut shared = thread_shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    });
+
+    Background { shared }
+}
+
+/// Fetches the latest version of `crate_name` from crates.io without blocking the caller.
+///
+/// Works with any async runtime. The registry token, if any, is read from
+/// `RSPAWN_REGISTRY_TOKEN`.
+///
+/// # Example
+/// ```ignore
+/// let latest = rspawn::fetch_latest_version_async("rspawn").await?;
+/// ```
+pub fn fetch_latest_version_async(crate_name: &str) -> Background<Result<String, RspawnError>> {
+    fetch_latest_version_async_with(crate_name, Arc::new(EnvCredentialStore))
+}
+
+/// Same as `fetch_latest_version_async`, with the registry token read from
+/// `credentials` on the background thread.
+pub fn fetch_latest_version_async_with(crate_name: &str, credentials: Arc<dyn CredentialStore>) -> Background<Result<String, RspawnError>> {
+    let crate_name = crate_name.to_string();
+    run_in_background(move || {
+        let token = credentials.token(REGISTRY_TOKEN)?;
+        let latest = crate::registry::get_latest_version_from_crates_io(&crate_name, token.as_deref(), None)?;
+        Ok(latest.num)
+    })
+}
@@ -0,0 +1,200 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Backing off from a version whose install keeps failing, so a broken
+//! release doesn't trigger a long failed build on every start.
+use std::time::Duration;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use log::debug;
+use crate::log_target::CHECK;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::state::State;
+
+/// How long a version is skipped after its first failed install, see
+/// `RSpawn::failure_backoff`. The wait doubles with each further failure.
+pub const DEFAULT_FAILURE_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// The longest a failing version is skipped for, however many times it failed.
+pub const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Consecutive failed installs of a version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedInstalls {
+    /// The version that failed to install.
+    pub version: String,
+    /// How many installs of it failed in a row.
+    pub count: u32,
+    /// When the last one failed, in seconds since the Unix epoch.
+    pub last_failed_at: u64,
+}
+
+impl FailedInstalls {
+    // When the version may be tried again: `base`, doubled for each failure after the first
+    fn retry_at(&self, base: Duration) -> u64 {
+        let doublings = self.count.saturating_sub(1).min(31);
+        let wait = base.saturating_mul(1u32 << doublings).min(MAX_FAILURE_BACKOFF);
+        self.last_failed_at.saturating_add(wait.as_secs())
+    }
+}
+
+// Key of `crate_name`'s failures on `channel` in `State::backoff`, so every crate
+// a process updates (plugins, target binaries) backs off on its own
+fn key(crate_name: &str, channel: Option<&str>) -> String {
+    match channel {
+        Some(channel) => format!("{}@{}", crate_name, channel),
+        None => crate_name.to_string(),
+    }
+}
+
+// The failures of `version` of `crate_name`, if it is still being backed off from
+pub(crate) fn backing_off(
+    crate_name: &str,
+    channel: Option<&str>,
+    version: &str,
+    base: Duration,
+    clock: &dyn Clock,
+    fs: &dyn Fs,
+) -> Result<Option<(FailedInstalls, u64)>> {
+    let failed = State::load_in(fs)?.backoff.remove(&key(crate_name, channel));
+    let Some(failed) = failed.filter(|failed| failed.version == version) else {
+        return Ok(None);
+    };
+    let retry_at = failed.retry_at(base);
+    if clock.unix_now() >= retry_at {
+        debug!(target: CHECK, "Retrying {} after {} failed installs", version, failed.count);
+        return Ok(None);
+    }
+    Ok(Some((failed, retry_at)))
+}
+
+// Counts a failed install of `version` of `crate_name`; failures of an older version are forgotten
+pub(crate) fn record_failure(crate_name: &str, channel: Option<&str>, version: &str, clock: &dyn Clock, fs: &dyn Fs) -> Result<()> {
+    let now = clock.unix_now();
+    State::update_in(fs, |state| {
+        let key = key(crate_name, channel);
+        let count = match state.backoff.get(&key) {
+            Some(failed) if failed.version == version => failed.count.saturating_add(1),
+            _ => 1,
+        };
+        state.backoff.insert(key, FailedInstalls {
+            version: version.to_string(),
+            count,
+            last_failed_at: now,
+        });
+    })
+}
+
+// Forgets past failures of `crate_name` on `channel` once an install went through
+pub(crate) fn reset(crate_name: &str, channel: Option<&str>, fs: &dyn Fs) -> Result<()> {
+    let key = key(crate_name, channel);
+    if !State::load_in(fs)?.backoff.contains_key(&key) {
+        return Ok(());
+    }
+    State::update_in(fs, |state| {
+        state.backoff.remove(&key);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn backed_off(version: &str, clock: &MockClock, fs: &MemoryFs) -> bool {
+        backing_off("app", None, version, HOUR, clock, fs).unwrap().is_some()
+    }
+
+    #[test]
+    fn failed_version_is_skipped_until_the_backoff_ends() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+
+        let (failed, retry_at) = backing_off("app", None, "2.0.0", HOUR, &clock, &fs).unwrap().unwrap();
+        assert_eq!(failed.count, 1);
+        assert_eq!(retry_at, 1_700_000_000 + HOUR.as_secs());
+
+        clock.advance(HOUR - MINUTE);
+        assert!(backed_off("2.0.0", &clock, &fs));
+        clock.advance(MINUTE);
+        assert!(!backed_off("2.0.0", &clock, &fs));
+    }
+
+    #[test]
+    fn backoff_doubles_with_each_failure() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+        clock.advance(HOUR);
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+
+        clock.advance(HOUR * 2 - MINUTE);
+        assert!(backed_off("2.0.0", &clock, &fs));
+        clock.advance(MINUTE);
+        assert!(!backed_off("2.0.0", &clock, &fs));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        for _ in 0..40 {
+            record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+        }
+
+        let (failed, retry_at) = backing_off("app", None, "2.0.0", HOUR, &clock, &fs).unwrap().unwrap();
+        assert_eq!(failed.count, 40);
+        assert_eq!(retry_at, 1_700_000_000 + MAX_FAILURE_BACKOFF.as_secs());
+    }
+
+    #[test]
+    fn new_version_is_not_backed_off() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+
+        assert!(!backed_off("2.0.1", &clock, &fs));
+        record_failure("app", None, "2.0.1", &clock, &fs).unwrap();
+        let (failed, _) = backing_off("app", None, "2.0.1", HOUR, &clock, &fs).unwrap().unwrap();
+        assert_eq!(failed.count, 1);
+        assert!(!backed_off("2.0.0", &clock, &fs));
+    }
+
+    #[test]
+    fn channels_and_crates_back_off_separately() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        record_failure("app", Some("beta"), "2.0.0", &clock, &fs).unwrap();
+
+        assert!(backing_off("app", Some("beta"), "2.0.0", HOUR, &clock, &fs).unwrap().is_some());
+        assert!(!backed_off("2.0.0", &clock, &fs));
+        assert!(backing_off("plugin", Some("beta"), "2.0.0", HOUR, &clock, &fs).unwrap().is_none());
+    }
+
+    #[test]
+    fn reset_forgets_failures() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        record_failure("app", None, "2.0.0", &clock, &fs).unwrap();
+        reset("app", None, &fs).unwrap();
+
+        assert!(!backed_off("2.0.0", &clock, &fs));
+    }
+}
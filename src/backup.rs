@@ -0,0 +1,177 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{Result, Context};
+use log::{info, debug, warn};
+use crate::error::RspawnError;
+use crate::clock::{Clock, SystemClock};
+use crate::integrity::sha256_file;
+use crate::log_target::INSTALL;
+use crate::state::{current_exe_file_name, state_dir, state_root, InstalledBinary, State};
+
+/// How many backups of previous versions are kept by default.
+pub const DEFAULT_KEEP_BACKUPS: usize = 3;
+
+// Directory holding one subdirectory per backed up version
+fn backups_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("backups"))
+}
+
+// Name the executable is backed up (and restored) under
+fn exe_file_name(exe_path: &Path) -> Result<PathBuf> {
+    exe_path
+        .file_name()
+        .map(PathBuf::from)
+        .context("Current executable has no file name")
+}
+
+/// A backup of a previously installed version of the running program.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// The version that was backed up.
+    pub version: String,
+    /// Path to the backed up executable.
+    pub path: PathBuf,
+    /// When the backup was taken.
+    pub created: SystemTime,
+}
+
+// Backups directory and executable file name of another program, by executable name
+fn program_backups_dir(program: &str) -> Result<(PathBuf, PathBuf)> {
+    let dir = state_root()?.join(program).join("backups");
+    Ok((dir, PathBuf::from(format!("{}{}", program, env::consts::EXE_SUFFIX))))
+}
+
+/// Lists the available backups, newest first.
+pub fn list_backups() -> Result<Vec<Backup>, RspawnError> {
+    list_backups_in(&backups_dir()?, &current_exe_file_name()?).map_err(RspawnError::from)
+}
+
+/// Lists the available backups of `program` (by executable name), newest first.
+pub fn program_backups(program: &str) -> Result<Vec<Backup>, RspawnError> {
+    let (dir, exe_name) = program_backups_dir(program)?;
+    list_backups_in(&dir, &exe_name).map_err(RspawnError::from)
+}
+
+// Backups of `exe_name` in `dir`, newest first
+fn list_backups_in(dir: &Path, exe_name: &Path) -> Result<Vec<Backup>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path().join(exe_name);
+        if !path.is_file() {
+            continue;
+        }
+        let created = entry.metadata()?.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        backups.push(Backup {
+            version: entry.file_name().to_string_lossy().into_owned(),
+            path,
+            created,
+        });
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.created));
+    Ok(backups)
+}
+
+// Copies the running executable to `<state_dir>/backups/<version>/`, keeping only the newest `keep` backups
+pub(crate) fn backup_current_exe(version: &str, keep: usize) -> Result<PathBuf> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    let version_dir = backups_dir()?.join(version);
+    fs::create_dir_all(&version_dir)
+        .with_context(|| format!("Failed to create backup directory {}", version_dir.display()))?;
+
+    let backup_path = version_dir.join(exe_file_name(&exe_path)?);
+    fs::copy(&exe_path, &backup_path)
+        .with_context(|| format!("Failed to back up {} to {}", exe_path.display(), backup_path.display()))?;
+    info!(target: INSTALL, "Backed up version {} to {}", version, backup_path.display());
+
+    prune_backups(keep)?;
+    Ok(backup_path)
+}
+
+// Removes all but the newest `keep` backups
+fn prune_backups(keep: usize) -> Result<()> {
+    for backup in list_backups()?.into_iter().skip(keep) {
+        if let Some(version_dir) = backup.path.parent() {
+            debug!(target: INSTALL, "Removing old backup {}", version_dir.display());
+            if let Err(e) = fs::remove_dir_all(version_dir) {
+                warn!(target: INSTALL, "Failed to remove old backup {}: {}", version_dir.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Restores the backup of `version` over the running executable.
+///
+/// The backup is copied next to the executable first and then renamed over it,
+/// so the program is never left half-written. The restored version is picked up
+/// on the next launch.
+///
+/// # Returns
+/// * The path of the restored executable.
+pub fn restore_backup(version: &str) -> Result<PathBuf, RspawnError> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    let backup_path = backups_dir()?.join(version).join(exe_file_name(&exe_path)?);
+    restore(&backup_path, &exe_path, version)?;
+    Ok(exe_path)
+}
+
+/// Restores the backup of `version` over `program` (by executable name), for
+/// `rspawn rollback`.
+///
+/// The program is restored where rspawn last installed it, and the restored
+/// binary is recorded as installed, so its integrity check keeps passing.
+///
+/// # Returns
+/// * The path of the restored executable.
+pub fn restore_program_backup(program: &str, version: &str) -> Result<PathBuf, RspawnError> {
+    let installed = State::load_program(program)?
+        .installed
+        .with_context(|| format!("rspawn has no record of installing {}, so it doesn't know where to restore it", program))?;
+    let (dir, exe_name) = program_backups_dir(program)?;
+    restore(&dir.join(version).join(exe_name), &installed.path, version)?;
+
+    let restored = InstalledBinary {
+        version: version.to_string(),
+        path: installed.path.clone(),
+        sha256: sha256_file(&installed.path)?,
+        installed_at: SystemClock.unix_now(),
+    };
+    State::update_program(program, |state| state.installed = Some(restored))?;
+    Ok(installed.path)
+}
+
+// Copies `backup_path` next to `exe_path`, then renames it over it
+fn restore(backup_path: &Path, exe_path: &Path, version: &str) -> Result<()> {
+    if !backup_path.is_file() {
+        return Err(anyhow::anyhow!("No backup found for version {}", version));
+    }
+
+    let staging_path = exe_path.with_extension("rspawn-restore");
+    fs::copy(backup_path, &staging_path)
+        .with_context(|| format!("Failed to copy backup to {}", staging_path.display()))?;
+    fs::rename(&staging_path, exe_path)
+        .with_context(|| format!("Failed to replace {}", exe_path.display()))?;
+
+    info!(target: INSTALL, "Restored version {} to {}", version, exe_path.display());
+    Ok(())
+}
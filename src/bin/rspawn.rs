@@ -0,0 +1,646 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! `rspawn doctor [program]`: checks what updating needs from the environment,
+//! and explains why an rspawn-powered program did or didn't update on its last run.
+//!
+//! `rspawn watch --crate <name[@version]>... [--interval 6h] [--notify-cmd <cmd>] [--update-cmd <cmd>] [--once]`:
+//! checks crates for new versions periodically, for servers running tools that
+//! don't embed rspawn. The commands run through the shell when a crate has a
+//! new version, with `RSPAWN_CRATE`, `RSPAWN_CURRENT_VERSION` and
+//! `RSPAWN_LATEST_VERSION` set.
+//!
+//! `rspawn check <name[@version]>...`: checks crates once, for scripts to branch
+//! on the exit code.
+//!
+//! `rspawn status <program>`: the running and latest versions, the last check,
+//! and anything keeping the program from updating (pending restart, hold, backoff).
+//!
+//! `rspawn history <program> [--json]`: the program's past update attempts.
+//!
+//! `rspawn rollback <program> [--to <version>] [--list] [--yes]`: lists the backups
+//! of previous versions and restores one, the newest by default, after asking
+//! for confirmation.
+//!
+//! `rspawn state export <program> [file]` and `rspawn state import <program> [file]`:
+//! move a program's update state (settings, backoffs, history) to another machine
+//! as one JSON file, e.g. to reproduce a user's environment. Without a file (or
+//! with `-`), the state goes to stdout or comes from stdin.
+//!
+//! `rspawn config <program> list|get <key>|set <key> <value>|unset <key>`: changes
+//! how an installed program updates, without editing its files by hand. The keys
+//! are `channel`, `interval` (minimum time between checks, e.g. `6h`), `disabled`
+//! and `proxy`.
+//!
+//! `rspawn shell-hook bash|zsh|fish`: prints a prompt hook to add to the shell's
+//! profile, reminding of installed crates with a newer version in rspawn's shared
+//! cache. The hook only reads files, it never checks crates.io itself.
+//!
+//! `--quiet` (or `-q`), anywhere, silences everything but errors and the JSON
+//! asked for with `history --json` or `state export`. The exit code is:
+//! - 0: everything is up to date (and for `doctor`, healthy)
+//! - 1: an error, e.g. a failed lookup or command
+//! - 2: invalid arguments
+//! - 10: an update is available
+//! - 20: `--update-cmd` installed an update
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, ExitCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rspawn::{DefaultFormatter, Formatter};
+
+const USAGE: &str = "Usage: rspawn [--quiet] doctor [program]
+       rspawn [--quiet] watch --crate <name[@version]>... [--interval 6h] [--notify-cmd <cmd>] [--update-cmd <cmd>] [--once]
+       rspawn [--quiet] check <name[@version]>...
+       rspawn [--quiet] status <program>
+       rspawn [--quiet] history <program> [--json]
+       rspawn rollback <program> [--to <version>] [--list] [--yes]
+       rspawn state export|import <program> [file]
+       rspawn config <program> list|get <key>|set <key> <value>|unset <key>
+       rspawn shell-hook bash|zsh|fish";
+// Exit codes, part of the command line interface: scripts branch on them
+const EXIT_ERROR: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_UPDATE_AVAILABLE: u8 = 10;
+const EXIT_UPDATED: u8 = 20;
+// How often crates are checked when `--interval` isn't given
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Regular output, silenced by `--quiet`; errors always go to stderr
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn main() -> ExitCode {
+    let (quiet, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|arg| arg == "--quiet" || arg == "-q");
+    QUIET.store(!quiet.is_empty(), Ordering::Relaxed);
+    match args.split_first() {
+        Some((command, rest)) if command == "doctor" && rest.len() <= 1 => doctor(rest.first()),
+        Some((command, rest)) if command == "watch" => match WatchOptions::parse(rest) {
+            Ok(options) => watch(options),
+            Err(e) => {
+                eprintln!("Error: {e}\n{USAGE}");
+                ExitCode::from(EXIT_USAGE)
+            }
+        },
+        Some((command, rest)) if command == "check" && !rest.is_empty() => watch(WatchOptions::once(rest)),
+        Some((command, [program])) if command == "status" => status(program),
+        Some((command, [program, rest @ ..])) if command == "history" && rest.len() <= 1 => match rest {
+            [] => history(program, false),
+            [flag] if flag == "--json" => history(program, true),
+            _ => {
+                eprintln!("Error: unknown argument {}\n{USAGE}", rest[0]);
+                ExitCode::from(EXIT_USAGE)
+            }
+        },
+        Some((command, [program, rest @ ..])) if command == "rollback" => rollback(program, rest),
+        Some((command, [action, program, rest @ ..])) if command == "state" && rest.len() <= 1 => match action.as_str() {
+            "export" => export_state(program, rest.first()),
+            "import" => import_state(program, rest.first()),
+            _ => {
+                eprintln!("Error: unknown state action {action}\n{USAGE}");
+                ExitCode::from(EXIT_USAGE)
+            }
+        },
+        Some((command, [program, rest @ ..])) if command == "config" => config(program, rest),
+        Some((command, [shell])) if command == "shell-hook" => shell_hook(shell),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+fn doctor(program: Option<&String>) -> ExitCode {
+    let checks = rspawn::doctor();
+    for check in &checks {
+        say!("{check}");
+    }
+    let mut healthy = checks.iter().all(|check| check.passed);
+
+    if let Some(program) = program {
+        say!();
+        match rspawn::diagnose_program(program) {
+            Ok(Some(diagnosis)) => say!("{}", diagnosis.to_string().trim_end()),
+            Ok(None) => say!("{program} has no recorded update run yet."),
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                healthy = false;
+            }
+        }
+    }
+
+    if healthy {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_ERROR)
+    }
+}
+
+// How long ago `unix_time` was, e.g. `3h ago`
+fn ago(unix_time: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+    DefaultFormatter.relative_time(unix_time.min(now), now)
+}
+
+fn status(program: &str) -> ExitCode {
+    let status = match rspawn::program_status(program) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let last_run = status.last_run.as_ref();
+    let current = last_run
+        .and_then(|run| run.current_version.clone())
+        .or_else(|| status.installed.as_ref().map(|installed| installed.version.clone()));
+    // The registry has the last word; the last run's lookup stands in when it can't be reached
+    let latest = match rspawn::latest_version(program) {
+        Ok(latest) => Some(latest),
+        Err(e) => {
+            eprintln!("{program}: failed to check for updates: {:#}", e);
+            last_run.and_then(|run| run.latest_version.clone())
+        }
+    };
+
+    say!("{program}");
+    say!("  running:    {}", current.as_deref().unwrap_or("unknown"));
+    say!("  latest:     {}", latest.as_deref().unwrap_or("unknown"));
+    if let Some(installed) = &status.installed {
+        say!("  installed:  {} at {}, {}", installed.version, installed.path.display(), ago(installed.installed_at));
+    }
+    match last_run {
+        Some(run) => match (run.stopped_by(), &run.error) {
+            (_, Some(error)) => say!("  last check: {}, failed: {}", ago(run.ran_at), error),
+            (Some(stop), None) => say!("  last check: {}, stopped at {}: {}", ago(run.ran_at), stop.gate, stop.detail),
+            (None, None) => say!("  last check: {}", ago(run.ran_at)),
+        },
+        None => say!("  last check: never"),
+    }
+    if let Some(pending) = &status.pending_restart {
+        say!("  pending:    {} installed {}, runs from the next start", pending.version, ago(pending.installed_at));
+    }
+    if let Some(held) = &status.held {
+        say!("  held:       {held}");
+    }
+    for failed in status.backoff.values() {
+        say!("  backoff:    {} failed to install {} time(s), last {}", failed.version, failed.count, ago(failed.last_failed_at));
+    }
+    let mut settings = Vec::new();
+    if let Some(channel) = &status.channel {
+        settings.push(format!("channel {channel}"));
+    }
+    // Formatted the way `rspawn config` takes it, e.g. "6h"
+    if let Some(interval) = rspawn::get_setting(program, rspawn::Setting::Interval).ok().flatten() {
+        settings.push(format!("every {interval} at most"));
+    }
+    if status.settings.disabled {
+        settings.push("disabled".to_string());
+    }
+    if let Some(proxy) = &status.settings.proxy {
+        settings.push(format!("proxy {proxy}"));
+    }
+    if !settings.is_empty() {
+        say!("  settings:   {}", settings.join(", "));
+    }
+    if let Some(error) = &status.last_error {
+        say!("  last error: {} ({})", error.message, ago(error.occurred_at));
+    }
+
+    match (current, latest) {
+        (Some(current), Some(latest)) if rspawn::is_update(&current, &latest, rspawn::UpdatePolicy::Newer) => ExitCode::from(EXIT_UPDATE_AVAILABLE),
+        // Neither the registry nor the last run had it
+        (_, None) => ExitCode::from(EXIT_ERROR),
+        // Not knowing the running version (it never checked) isn't an error
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+fn history(program: &str, json: bool) -> ExitCode {
+    let entries = match rspawn::update_history(program) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    // Asked-for data, like `state export` to stdout: `--quiet` doesn't silence it
+    if json {
+        match serde_json::to_string_pretty(&entries) {
+            Ok(json) => println!("{json}"),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                return ExitCode::from(EXIT_ERROR);
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+    if entries.is_empty() {
+        say!("{program} has no recorded updates yet.");
+        return ExitCode::SUCCESS;
+    }
+    say!("{:<10} {:<20} {:<12} {:<12} RESULT", "WHEN", "CRATE", "FROM", "TO");
+    for entry in &entries {
+        let result = match &entry.error {
+            Some(error) => format!("failed: {error}"),
+            None => "installed".to_string(),
+        };
+        say!("{:<10} {:<20} {:<12} {:<12} {}", ago(entry.at), entry.crate_name, entry.from, entry.to, result);
+    }
+    ExitCode::SUCCESS
+}
+
+fn rollback(program: &str, args: &[String]) -> ExitCode {
+    let (mut to, mut list, mut yes) = (None, false, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--to" => match args.next() {
+                Some(version) => to = Some(version.as_str()),
+                None => {
+                    eprintln!("Error: --to needs a version\n{USAGE}");
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            },
+            "--list" => list = true,
+            "--yes" | "-y" => yes = true,
+            _ => {
+                eprintln!("Error: unknown argument {arg}\n{USAGE}");
+                return ExitCode::from(EXIT_USAGE);
+            }
+        }
+    }
+
+    let (backups, installed) = match rspawn::program_backups(program).and_then(|backups| Ok((backups, rspawn::program_status(program)?.installed))) {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let current = installed.map(|installed| installed.version);
+    if backups.is_empty() {
+        eprintln!("Error: {program} has no backups to roll back to");
+        return ExitCode::from(EXIT_ERROR);
+    }
+    if list || !yes {
+        say!("Backups of {program}:");
+        for backup in &backups {
+            let created = backup.created.duration_since(UNIX_EPOCH).map_or(0, |created| created.as_secs());
+            let marker = if current.as_deref() == Some(backup.version.as_str()) { " (installed)" } else { "" };
+            say!("  {:<12} {}{}", backup.version, ago(created), marker);
+        }
+    }
+    if list {
+        return ExitCode::SUCCESS;
+    }
+
+    // Without --to, the newest backup that isn't the installed version
+    let version = match to {
+        Some(version) if backups.iter().any(|backup| backup.version == version) => version,
+        Some(version) => {
+            eprintln!("Error: {program} has no backup of version {version}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+        None => match backups.iter().find(|backup| current.as_deref() != Some(backup.version.as_str())) {
+            Some(backup) => backup.version.as_str(),
+            None => {
+                eprintln!("Error: {program} has no backup of an older version");
+                return ExitCode::from(EXIT_ERROR);
+            }
+        },
+    };
+
+    if !yes {
+        print!("Roll {program} back from {} to {version}? [y/N] ", current.as_deref().unwrap_or("an unknown version"));
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() || !matches!(answer.trim(), "y" | "Y" | "yes") {
+            say!("Rollback cancelled.");
+            return ExitCode::SUCCESS;
+        }
+    }
+    match rspawn::restore_program_backup(program, version) {
+        Ok(path) => {
+            say!("Restored {program} {version} to {}", path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn export_state(program: &str, file: Option<&String>) -> ExitCode {
+    let json = match rspawn::export_state(program).and_then(|archive| archive.to_json()) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    match file {
+        Some(file) if file != "-" => {
+            if let Err(e) = std::fs::write(file, json + "\n") {
+                eprintln!("Error: failed to write {file}: {e}");
+                return ExitCode::from(EXIT_ERROR);
+            }
+            say!("Exported the state of {program} to {file}");
+        }
+        _ => println!("{json}"),
+    }
+    ExitCode::SUCCESS
+}
+
+fn import_state(program: &str, file: Option<&String>) -> ExitCode {
+    let json = match file {
+        Some(file) if file != "-" => std::fs::read_to_string(file),
+        _ => io::read_to_string(io::stdin()),
+    };
+    let json = match json {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Error: failed to read {}: {e}", file.map_or("stdin", |file| file.as_str()));
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let imported = rspawn::StateArchive::from_json(&json).and_then(|archive| {
+        rspawn::import_state(program, &archive)?;
+        Ok(archive)
+    });
+    match imported {
+        Ok(archive) => {
+            say!("Imported the state {} exported {} into {program}", archive.program, ago(archive.exported_at));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn config(program: &str, args: &[String]) -> ExitCode {
+    let setting = |name: &str| name.parse::<rspawn::Setting>();
+    let result = match args {
+        [action] if action == "list" => rspawn::list_settings(program).map(|settings| {
+            for (setting, value) in settings {
+                say!("{} = {}", setting, value.as_deref().unwrap_or("(not set)"));
+            }
+        }),
+        [action, name] if action == "get" => setting(name).and_then(|setting| rspawn::get_setting(program, setting)).map(|value| {
+            if let Some(value) = value {
+                say!("{value}");
+            }
+        }),
+        [action, name, value] if action == "set" => setting(name).and_then(|setting| rspawn::set_setting(program, setting, value)),
+        [action, name] if action == "unset" => setting(name).and_then(|setting| rspawn::unset_setting(program, setting)),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+// Prompt hooks; each prints the reminders only when they changed since the last prompt
+const BASH_HOOK: &str = r#"__rspawn_remind() {
+    local reminder
+    reminder="$(command rspawn shell-hook --remind 2>/dev/null)"
+    if [ -n "$reminder" ] && [ "$reminder" != "$__rspawn_reminded" ]; then
+        printf '%s\n' "$reminder"
+    fi
+    __rspawn_reminded="$reminder"
+}
+PROMPT_COMMAND="__rspawn_remind${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+"#;
+const ZSH_HOOK: &str = r#"__rspawn_remind() {
+    local reminder
+    reminder="$(command rspawn shell-hook --remind 2>/dev/null)"
+    if [[ -n "$reminder" && "$reminder" != "$__rspawn_reminded" ]]; then
+        printf '%s\n' "$reminder"
+    fi
+    __rspawn_reminded="$reminder"
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __rspawn_remind
+"#;
+const FISH_HOOK: &str = r#"function __rspawn_remind --on-event fish_prompt
+    set -l reminder (command rspawn shell-hook --remind 2>/dev/null | string collect)
+    if test -n "$reminder"; and test "$reminder" != "$__rspawn_reminded"
+        printf '%s\n' $reminder
+    end
+    set -g __rspawn_reminded $reminder
+end
+"#;
+
+fn shell_hook(shell: &str) -> ExitCode {
+    let hook = match shell {
+        "bash" => BASH_HOOK,
+        "zsh" => ZSH_HOOK,
+        "fish" => FISH_HOOK,
+        "--remind" => return remind(),
+        _ => {
+            eprintln!("Error: unsupported shell {shell}\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    print!("{hook}");
+    ExitCode::SUCCESS
+}
+
+// Run by the hooks on every prompt: no network, no cargo, just two files
+fn remind() -> ExitCode {
+    let installed = rspawn::installed_versions();
+    let mut available = false;
+    for (name, latest) in rspawn::cached_versions() {
+        let Some(current) = installed.get(&name) else {
+            continue;
+        };
+        if rspawn::is_update(current, &latest, rspawn::UpdatePolicy::Newer) {
+            say!("{name} {latest} available (installed: {current})");
+            available = true;
+        }
+    }
+    if available {
+        ExitCode::from(EXIT_UPDATE_AVAILABLE)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// A crate to watch, with its version if given on the command line
+struct Watched {
+    name: String,
+    version: Option<String>,
+}
+
+struct WatchOptions {
+    crates: Vec<Watched>,
+    interval: Duration,
+    notify_cmd: Option<String>,
+    update_cmd: Option<String>,
+    once: bool,
+}
+
+impl WatchOptions {
+    // `rspawn check`: a single check of the given crates, without commands
+    fn once(crates: &[String]) -> Self {
+        let crates = crates
+            .iter()
+            .map(|value| match value.split_once('@') {
+                Some((name, version)) => Watched { name: name.to_string(), version: Some(version.to_string()) },
+                None => Watched { name: value.clone(), version: None },
+            })
+            .collect();
+        WatchOptions { crates, interval: DEFAULT_INTERVAL, notify_cmd: None, update_cmd: None, once: true }
+    }
+
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = WatchOptions { crates: Vec::new(), interval: DEFAULT_INTERVAL, notify_cmd: None, update_cmd: None, once: false };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or_else(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--crate" => {
+                    let value = value()?;
+                    let (name, version) = match value.split_once('@') {
+                        Some((name, version)) => (name.to_string(), Some(version.to_string())),
+                        None => (value, None),
+                    };
+                    options.crates.push(Watched { name, version });
+                }
+                "--interval" => options.interval = rspawn::parse_interval(&value()?).map_err(|e| e.to_string())?,
+                "--notify-cmd" => options.notify_cmd = Some(value()?),
+                "--update-cmd" => options.update_cmd = Some(value()?),
+                "--once" => options.once = true,
+                _ => return Err(format!("unknown argument {arg}")),
+            }
+        }
+        if options.crates.is_empty() {
+            return Err("no --crate to watch".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// Runs `command` through the shell, telling it about the new version
+fn run_hook(command: &str, name: &str, current: &str, latest: &str) -> bool {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell
+        .arg(command)
+        .env("RSPAWN_CRATE", name)
+        .env("RSPAWN_CURRENT_VERSION", current)
+        .env("RSPAWN_LATEST_VERSION", latest);
+    match shell.status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            eprintln!("{name}: `{command}` exited with {status}");
+            false
+        }
+        Err(e) => {
+            eprintln!("{name}: failed to run `{command}`: {e}");
+            false
+        }
+    }
+}
+
+fn watch(mut options: WatchOptions) -> ExitCode {
+    // Each new version is announced once, not on every check
+    let mut announced: HashMap<String, String> = HashMap::new();
+    loop {
+        let (mut failed, mut available, mut installed) = (false, false, false);
+        for watched in &mut options.crates {
+            let current = match &watched.version {
+                Some(version) => Some(version.clone()),
+                None => match rspawn::installed_version(&watched.name) {
+                    Ok(version) => version,
+                    Err(e) => {
+                        eprintln!("{}: {:#}", watched.name, e);
+                        failed = true;
+                        continue;
+                    }
+                },
+            };
+            let latest = match rspawn::latest_version(&watched.name) {
+                Ok(latest) => latest,
+                Err(e) => {
+                    eprintln!("{}: failed to check for updates: {:#}", watched.name, e);
+                    failed = true;
+                    continue;
+                }
+            };
+            let Some(current) = current else {
+                say!("{}: not installed, latest is {}", watched.name, latest);
+                continue;
+            };
+            if !rspawn::is_update(&current, &latest, rspawn::UpdatePolicy::Newer) {
+                say!("{}: {} is up to date", watched.name, current);
+                continue;
+            }
+            say!("{}: {} is available (installed: {})", watched.name, latest, current);
+            if announced.get(&watched.name) == Some(&latest) {
+                continue;
+            }
+            let notified = options.notify_cmd.as_deref().is_none_or(|command| run_hook(command, &watched.name, &current, &latest));
+            let updated = options.update_cmd.as_deref().is_none_or(|command| run_hook(command, &watched.name, &current, &latest));
+            if options.update_cmd.is_some() {
+                installed |= updated;
+            } else {
+                available = true;
+            }
+            if notified && updated {
+                announced.insert(watched.name.clone(), latest.clone());
+                // The update command installed it: that's the version to compare with from now on
+                if options.update_cmd.is_some() && watched.version.is_some() {
+                    watched.version = Some(latest);
+                }
+            }
+            failed |= !(notified && updated);
+        }
+        if options.once {
+            return match (failed, available, installed) {
+                (true, _, _) => ExitCode::from(EXIT_ERROR),
+                (_, true, _) => ExitCode::from(EXIT_UPDATE_AVAILABLE),
+                (_, _, true) => ExitCode::from(EXIT_UPDATED),
+                _ => ExitCode::SUCCESS,
+            };
+        }
+        thread::sleep(options.interval);
+    }
+}
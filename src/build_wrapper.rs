@@ -0,0 +1,121 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The compiler wrapper (`RUSTC_WRAPPER`, e.g. sccache) updates are built with.
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use log::debug;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "json")]
+use serde_json::Value;
+use crate::install;
+use crate::log_target::INSTALL;
+
+/// The program cargo runs the compiler through while building an update, as
+/// `RUSTC_WRAPPER`. A caching wrapper like sccache turns rebuilding
+/// dependencies that didn't change into cache hits.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildWrapper {
+    /// `RUSTC_WRAPPER` from the program's environment, if it is set.
+    #[default]
+    Inherit,
+    /// `RUSTC_WRAPPER` if it is set, otherwise sccache when it is in PATH.
+    Detect,
+    /// This program, e.g. the path of a specific sccache binary.
+    Program(PathBuf),
+    /// No wrapper, even when `RUSTC_WRAPPER` is set.
+    Disabled,
+}
+
+// Sets the wrapper `command` builds with, returning it if there is one
+pub(crate) fn apply(command: &mut Command, wrapper: &BuildWrapper) -> Option<PathBuf> {
+    let configured = install::command_env(command, "RUSTC_WRAPPER");
+
+    let wrapper = match wrapper {
+        BuildWrapper::Inherit => configured,
+        BuildWrapper::Detect => configured.or_else(|| install::find_in_path(if cfg!(windows) { "sccache.exe" } else { "sccache" })),
+        BuildWrapper::Program(program) => Some(program.clone()),
+        BuildWrapper::Disabled => {
+            command.env_remove("RUSTC_WRAPPER");
+            None
+        }
+    }?;
+    debug!(target: INSTALL, "Building with RUSTC_WRAPPER={}", wrapper.display());
+    command.env("RUSTC_WRAPPER", &wrapper);
+    Some(wrapper)
+}
+
+// Compilations sccache answered from its cache, and those it had to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CompilerCacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl CompilerCacheStats {
+    // sccache's running totals, if `wrapper` is sccache and reports them
+    pub(crate) fn read(wrapper: &Path) -> Option<Self> {
+        // The stats are only readable as JSON
+        if !cfg!(feature = "json") || wrapper.file_stem() != Some(OsStr::new("sccache")) {
+            return None;
+        }
+        let output = Command::new(wrapper)
+            .args(["--show-stats", "--stats-format", "json"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        CompilerCacheStats::parse(&output.stdout)
+    }
+
+    // The totals in sccache's JSON stats
+    #[cfg(feature = "json")]
+    fn parse(json: &[u8]) -> Option<Self> {
+        let stats: Value = serde_json::from_slice(json).ok()?;
+        let stats = stats.get("stats")?;
+        Some(CompilerCacheStats {
+            hits: count(stats.get("cache_hits")?),
+            misses: count(stats.get("cache_misses")?),
+        })
+    }
+
+    #[cfg(not(feature = "json"))]
+    fn parse(_json: &[u8]) -> Option<Self> {
+        None
+    }
+
+    // The compilations since `before`, as sccache only keeps totals
+    pub(crate) fn since(self, before: Self) -> Self {
+        CompilerCacheStats {
+            hits: self.hits.saturating_sub(before.hits),
+            misses: self.misses.saturating_sub(before.misses),
+        }
+    }
+}
+
+// Adds up a count from sccache's stats: a plain number in older versions,
+// `{"counts": {"Rust": 12, "C/C++": 3}, ...}` in newer ones
+#[cfg(feature = "json")]
+fn count(value: &Value) -> u64 {
+    match value {
+        Value::Number(number) => number.as_u64().unwrap_or(0),
+        Value::Object(map) => match map.get("counts") {
+            Some(counts) => count(counts),
+            None => map.values().map(count).sum(),
+        },
+        _ => 0,
+    }
+}
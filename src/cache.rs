@@ -0,0 +1,193 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Version lookups shared by every rspawn-powered program on this machine.
+//!
+//! Results are kept in a single JSON file in the state root (not in a single
+//! program's `state_dir()`), keyed by crate name and channel, so tools that
+//! check the same crates don't each hit crates.io.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::debug;
+use crate::log_target::CHECK;
+use crate::clock::Clock;
+use crate::filesystem::{Fs, RealFs};
+use crate::json;
+use crate::registry::RegistryVersion;
+use crate::state::{state_root, with_file_lock, write_atomic};
+
+/// How long a cached lookup is reused by default, see `RSpawn::shared_cache`.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// File holding the cache, inside the state root
+const CACHE_FILE_NAME: &str = "version-cache.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    version: String,
+    #[serde(default)]
+    checksum: Option<String>,
+    // Lookup time, in seconds since the Unix epoch
+    fetched_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VersionCache {
+    #[serde(default)]
+    crates: BTreeMap<String, CacheEntry>,
+}
+
+// Entries are per channel, since each channel has its own latest version
+fn cache_key(crate_name: &str, channel: Option<&str>) -> String {
+    match channel {
+        Some(channel) => format!("{crate_name}@{channel}"),
+        None => crate_name.to_string(),
+    }
+}
+
+fn cache_path(fs: &dyn Fs) -> Result<PathBuf> {
+    let root = state_root()?;
+    fs.create_dir_all(&root)
+        .with_context(|| format!("Failed to create state directory {}", root.display()))?;
+    Ok(root.join(CACHE_FILE_NAME))
+}
+
+impl VersionCache {
+    // A missing or unreadable cache is just an empty one
+    fn load_from(path: &Path, fs: &dyn Fs) -> VersionCache {
+        fs.read_to_string(path)
+            .ok()
+            .and_then(|contents| json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_to(&self, path: &Path, fs: &dyn Fs) -> Result<()> {
+        // Nothing to read it back with
+        if !json::AVAILABLE {
+            return Ok(());
+        }
+        let contents = json::to_string_pretty(self).context("Failed to serialize version cache")?;
+        write_atomic(fs, path, contents.as_bytes())
+    }
+}
+
+// Returns the cached latest version of `crate_name`, if it was looked up less than `ttl` ago
+pub(crate) fn cached_latest_version(
+    crate_name: &str,
+    channel: Option<&str>,
+    ttl: Duration,
+    clock: &dyn Clock,
+    fs: &dyn Fs,
+) -> Option<RegistryVersion> {
+    let (version, age) = cached_lookup(crate_name, channel, clock, fs)?;
+    if age >= ttl.as_secs() {
+        debug!(target: CHECK, "Cached version of {} is {}s old, ignoring it", crate_name, age);
+        return None;
+    }
+
+    debug!(target: CHECK, "Using cached version {} of {} ({}s old)", version.num, crate_name, age);
+    Some(version)
+}
+
+// The last lookup of `crate_name` in the cache, however old, and its age in seconds
+pub(crate) fn cached_lookup(crate_name: &str, channel: Option<&str>, clock: &dyn Clock, fs: &dyn Fs) -> Option<(RegistryVersion, u64)> {
+    let path = cache_path(fs).ok()?;
+    let cache = VersionCache::load_from(&path, fs);
+    let entry = cache.crates.get(&cache_key(crate_name, channel))?;
+    let version = RegistryVersion {
+        num: entry.version.clone(),
+        checksum: entry.checksum.clone(),
+    };
+    Some((version, clock.unix_now().saturating_sub(entry.fetched_at)))
+}
+
+/// Returns the latest version of every crate in the shared cache, by crate name,
+/// however old the lookup.
+///
+/// Only reads the cache file, for prompt-time reminders like `rspawn shell-hook`;
+/// lookups on a release channel other than the default are left out.
+pub fn cached_versions() -> BTreeMap<String, String> {
+    let Ok(root) = state_root() else {
+        return BTreeMap::new();
+    };
+    VersionCache::load_from(&root.join(CACHE_FILE_NAME), &RealFs)
+        .crates
+        .into_iter()
+        .filter(|(key, _)| !key.contains('@'))
+        .map(|(crate_name, entry)| (crate_name, entry.version))
+        .collect()
+}
+
+// Records a fresh lookup of `crate_name` for the other programs sharing the cache
+pub(crate) fn store_latest_version(
+    crate_name: &str,
+    channel: Option<&str>,
+    version: &RegistryVersion,
+    clock: &dyn Clock,
+    fs: &dyn Fs,
+) -> Result<()> {
+    let path = cache_path(fs)?;
+    // Other programs may be storing their own lookups at the same time
+    with_file_lock(fs, &path, || {
+        let mut cache = VersionCache::load_from(&path, fs);
+        cache.crates.insert(
+            cache_key(crate_name, channel),
+            CacheEntry {
+                version: version.num.clone(),
+                checksum: version.checksum.clone(),
+                fetched_at: clock.unix_now(),
+            },
+        );
+        cache.save_to(&path, fs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    fn version(num: &str) -> RegistryVersion {
+        RegistryVersion { num: num.to_string(), checksum: None }
+    }
+
+    #[test]
+    fn corrupted_cache_is_replaced() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        fs.write(&cache_path(&fs).unwrap(), b"not json").unwrap();
+
+        assert!(cached_lookup("app", None, &clock, &fs).is_none());
+        store_latest_version("app", None, &version("2.0.0"), &clock, &fs).unwrap();
+        let cached = cached_latest_version("app", None, DEFAULT_CACHE_TTL, &clock, &fs).unwrap();
+        assert_eq!(cached.num, "2.0.0");
+    }
+
+    #[test]
+    fn cached_version_expires() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        store_latest_version("app", Some("beta"), &version("2.0.0-beta.1"), &clock, &fs).unwrap();
+
+        clock.advance(DEFAULT_CACHE_TTL - Duration::from_secs(1));
+        assert!(cached_latest_version("app", Some("beta"), DEFAULT_CACHE_TTL, &clock, &fs).is_some());
+        assert!(cached_latest_version("app", None, DEFAULT_CACHE_TTL, &clock, &fs).is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(cached_latest_version("app", Some("beta"), DEFAULT_CACHE_TTL, &clock, &fs).is_none());
+        assert_eq!(cached_lookup("app", Some("beta"), &clock, &fs).unwrap().1, DEFAULT_CACHE_TTL.as_secs());
+    }
+}
@@ -0,0 +1,190 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! C ABI for the check-and-update core.
+//!
+//! Build a shared or static library with
+//! `cargo rustc --lib --release --features capi --crate-type cdylib` (or `staticlib`),
+//! and regenerate `include/rspawn.h` with `cbindgen --output include/rspawn.h`.
+//!
+//! Functions return `RSPAWN_OK` on success or a negative `RSPAWN_ERR_*` code;
+//! `rspawn_last_error()` then describes the failure.
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+use anyhow::Result;
+use crate::clock::SystemClock;
+use crate::filesystem::RealFs;
+use crate::hold;
+use crate::install::{self, InstallError};
+use crate::registry::latest_version;
+use crate::version::{is_update, UpdatePolicy};
+
+/// The call succeeded.
+pub const RSPAWN_OK: c_int = 0;
+/// A required argument was NULL or not valid UTF-8.
+pub const RSPAWN_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The latest version could not be fetched from the registry.
+pub const RSPAWN_ERR_CHECK_FAILED: c_int = -2;
+/// `cargo install` failed.
+pub const RSPAWN_ERR_INSTALL_FAILED: c_int = -3;
+/// Updates are frozen by the operator's hold file.
+pub const RSPAWN_ERR_HELD: c_int = -4;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// Remembers the message for `rspawn_last_error()` and returns `code`
+fn fail(code: c_int, message: String) -> c_int {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+// Borrows a required C string argument as UTF-8
+unsafe fn required_str<'a>(arg: *const c_char, name: &str) -> std::result::Result<&'a str, c_int> {
+    if arg.is_null() {
+        return Err(fail(RSPAWN_ERR_INVALID_ARGUMENT, format!("{name} must not be NULL")));
+    }
+    CStr::from_ptr(arg)
+        .to_str()
+        .map_err(|_| fail(RSPAWN_ERR_INVALID_ARGUMENT, format!("{name} is not valid UTF-8")))
+}
+
+// Converts an owned string for handing over to C
+fn to_c_string(value: &str) -> *mut c_char {
+    CString::new(value).map(CString::into_raw).unwrap_or(ptr::null_mut())
+}
+
+/// Result of `rspawn_check`, released with `rspawn_free_info`.
+#[repr(C)]
+pub struct RspawnInfo {
+    /// Name of the checked crate.
+    pub crate_name: *mut c_char,
+    /// The version passed to `rspawn_check`.
+    pub current_version: *mut c_char,
+    /// The latest version on the registry.
+    pub latest_version: *mut c_char,
+    /// Whether `latest_version` is an update over `current_version`.
+    pub update_available: bool,
+}
+
+/// Returns a description of the last error on the calling thread, or NULL.
+///
+/// The string stays valid until the next rspawn call on the same thread.
+#[no_mangle]
+pub extern "C" fn rspawn_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Checks the registry for a newer version of `crate_name`.
+///
+/// On success `*out` points to a newly allocated `RspawnInfo`, to be released
+/// with `rspawn_free_info`.
+///
+/// # Safety
+/// `crate_name` and `current_version` must be NULL or valid NUL-terminated strings,
+/// and `out` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rspawn_check(
+    crate_name: *const c_char,
+    current_version: *const c_char,
+    out: *mut *mut RspawnInfo,
+) -> c_int {
+    let crate_name = match required_str(crate_name, "crate_name") {
+        Ok(crate_name) => crate_name,
+        Err(code) => return code,
+    };
+    let current_version = match required_str(current_version, "current_version") {
+        Ok(current_version) => current_version,
+        Err(code) => return code,
+    };
+    if out.is_null() {
+        return fail(RSPAWN_ERR_INVALID_ARGUMENT, "out must not be NULL".to_string());
+    }
+
+    let latest = match latest_version(crate_name) {
+        Ok(latest) => latest,
+        Err(e) => return fail(RSPAWN_ERR_CHECK_FAILED, format!("{:#}", e)),
+    };
+
+    let info = RspawnInfo {
+        crate_name: to_c_string(crate_name),
+        current_version: to_c_string(current_version),
+        update_available: is_update(current_version, &latest, UpdatePolicy::Newer),
+        latest_version: to_c_string(&latest),
+    };
+    *out = Box::into_raw(Box::new(info));
+    RSPAWN_OK
+}
+
+// Installs `crate_name` with cargo, features given as a comma separated list
+fn update(crate_name: &str, features: &str) -> Result<(), InstallError> {
+    let features: Vec<String> = features
+        .split(',')
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_string)
+        .collect();
+    install::run_install(install::cargo_install_command(crate_name, None, &features))
+}
+
+/// Installs the latest version of `crate_name` with `cargo install`.
+///
+/// `features` is an optional comma separated list of features to enable.
+/// The calling program is not relaunched: that is left to the caller.
+///
+/// # Safety
+/// `crate_name` and `features` must be NULL or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rspawn_update(crate_name: *const c_char, features: *const c_char) -> c_int {
+    let crate_name = match required_str(crate_name, "crate_name") {
+        Ok(crate_name) => crate_name,
+        Err(code) => return code,
+    };
+    let features = if features.is_null() {
+        ""
+    } else {
+        match required_str(features, "features") {
+            Ok(features) => features,
+            Err(code) => return code,
+        }
+    };
+
+    if let Err(hold) = hold::check_hold(&SystemClock, &RealFs) {
+        return fail(RSPAWN_ERR_HELD, hold.to_string());
+    }
+
+    match update(crate_name, features) {
+        Ok(()) => RSPAWN_OK,
+        Err(e) => fail(RSPAWN_ERR_INSTALL_FAILED, e.to_string()),
+    }
+}
+
+/// Releases an `RspawnInfo` returned by `rspawn_check`. Passing NULL is a no-op.
+///
+/// # Safety
+/// `info` must be NULL or a pointer returned by `rspawn_check` that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rspawn_free_info(info: *mut RspawnInfo) {
+    if info.is_null() {
+        return;
+    }
+    let info = Box::from_raw(info);
+    for string in [info.crate_name, info.current_version, info.latest_version] {
+        if !string.is_null() {
+            drop(CString::from_raw(string));
+        }
+    }
+}
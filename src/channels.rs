@@ -0,0 +1,331 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Release channels defined by the crate author in a manifest.
+//!
+//! Without a manifest, a channel only selects prereleases by their tag (see
+//! `RSpawn::channel`). With one, the author decides what each channel points
+//! to, and can roll a version out to part of the installs first.
+use std::collections::BTreeMap;
+use std::env;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use log::{info, debug};
+use crate::error::RspawnError;
+use crate::log_target::CHECK;
+use crate::assets::Asset;
+use crate::filesystem::Fs;
+use crate::host::{matches_pattern, HostInfo};
+use crate::http::{self, ClientConfig};
+use crate::json;
+use crate::state::State;
+use crate::unique::unique_token;
+use crate::url_resolver;
+
+/// Environment variable selecting the release channel to follow, when the
+/// program doesn't set one with `RSpawn::channel`.
+pub const CHANNEL_ENV: &str = "RSPAWN_CHANNEL";
+
+fn full_rollout() -> u8 {
+    100
+}
+
+fn stable() -> String {
+    "stable".to_string()
+}
+
+/// A release channel in a `ChannelManifest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channel {
+    /// The version installs on this channel should run.
+    pub version: String,
+    /// Percentage (0-100) of installs `version` is offered to; the others keep
+    /// the version they have. Defaults to 100.
+    #[serde(default = "full_rollout")]
+    pub rollout_percent: u8,
+}
+
+/// A version pinned for a group of machines in a `ChannelManifest`.
+///
+/// A machine is in the group when its hostname matches one of `hosts` (if
+/// any are given) and it has all of `labels`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostTarget {
+    /// Hostname patterns, where `*` matches any run of characters and `?` any
+    /// single one (e.g. `web-*`). Matched case-insensitively.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Labels the machine must have, see `HostInfo::detect`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Only applies to machines following this channel, if given.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// The version machines in the group should run.
+    pub version: String,
+}
+
+impl HostTarget {
+    fn matches(&self, host: &HostInfo, channel: &str) -> bool {
+        let hostname_matches = self.hosts.is_empty()
+            || host
+                .hostname
+                .as_deref()
+                .is_some_and(|hostname| self.hosts.iter().any(|pattern| matches_pattern(pattern, hostname)));
+        let labels_match = self
+            .labels
+            .iter()
+            .all(|(key, value)| host.labels.get(key) == Some(value));
+        let channel_matches = self.channel.as_deref().is_none_or(|target| target == channel);
+        hostname_matches && labels_match && channel_matches
+    }
+}
+
+/// Release channels published by the crate author, as JSON:
+///
+/// ```json
+/// {
+///     "default": "stable",
+///     "channels": {
+///         "stable": { "version": "1.4.2" },
+///         "beta": { "version": "1.5.0-beta.1", "rollout_percent": 20 }
+///     },
+///     "targets": [
+///         { "hosts": ["db-*"], "labels": { "region": "eu" }, "version": "1.3.9" }
+///     ],
+///     "assets": {
+///         "1.4.2": [
+///             { "url": "https://example.com/1.4.2/mytool.bash", "sha256": "9f86d0...", "path": "completions/mytool.bash" }
+///         ]
+///     }
+/// }
+/// ```
+///
+/// The first of `targets` matching the machine pins its version, whatever the
+/// channel's rollout; machines matching none follow their channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelManifest {
+    /// Channel followed when none is selected. Defaults to "stable".
+    #[serde(default = "stable")]
+    pub default: String,
+    /// The channels, by name.
+    pub channels: BTreeMap<String, Channel>,
+    /// Versions pinned for groups of machines, checked in order.
+    #[serde(default)]
+    pub targets: Vec<HostTarget>,
+    /// Data files published with each version, by version. They are placed in
+    /// `data_dir()` when updating to that version.
+    #[serde(default)]
+    pub assets: BTreeMap<String, Vec<Asset>>,
+}
+
+impl ChannelManifest {
+    /// Parses a manifest.
+    pub fn parse(json: &str) -> Result<Self, RspawnError> {
+        json::from_str(json).context("Failed to parse channel manifest").map_err(RspawnError::ParseError)
+    }
+
+    /// Downloads and parses the manifest at `url`, which can also be a
+    /// `file://` URL or use a scheme set up with `register_url_resolver()`.
+    pub fn fetch(url: &str) -> Result<Self, RspawnError> {
+        info!(target: CHECK, "Fetching channel manifest from: {}", url);
+        if let Some(json) = url_resolver::fetch_text(url) {
+            return ChannelManifest::parse(&json.context("Failed to fetch channel manifest")?);
+        }
+        let response = http::client(ClientConfig::default())?
+            .get(url)
+            .send()
+            .with_context(|| format!("Failed to fetch channel manifest {}", url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("Failed to fetch channel manifest {}: HTTP {}", url, status).into());
+        }
+        ChannelManifest::parse(&response.text().context("Failed to read channel manifest")?)
+    }
+
+    /// Returns the channel named `name`, or the default one.
+    pub fn channel(&self, name: Option<&str>) -> Result<&Channel, RspawnError> {
+        let name = name.unwrap_or(&self.default);
+        self.channels.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.channels.keys().map(String::as_str).collect();
+            anyhow::anyhow!("Unknown release channel {:?} (available: {})", name, known.join(", ")).into()
+        })
+    }
+
+    /// Returns the version the install identified by `install_id`, on machine
+    /// `host`, should run on channel `name`, or `None` if it is not part of that
+    /// version's rollout yet.
+    pub fn target_version(
+        &self,
+        crate_name: &str,
+        name: Option<&str>,
+        install_id: &str,
+        host: &HostInfo,
+    ) -> Result<Option<&str>, RspawnError> {
+        let channel_name = name.unwrap_or(&self.default);
+        if let Some(target) = self.targets.iter().find(|target| target.matches(host, channel_name)) {
+            debug!(target: CHECK, "{} is pinned to {} for this machine", crate_name, target.version);
+            return Ok(Some(&target.version));
+        }
+
+        let channel = self.channel(name)?;
+        if in_rollout(crate_name, &channel.version, install_id, channel.rollout_percent) {
+            Ok(Some(&channel.version))
+        } else {
+            debug!(target: CHECK, "{} {} is not rolled out to this install yet", crate_name, channel.version);
+            Ok(None)
+        }
+    }
+}
+
+// Places an install in one of 100 buckets, differently for every release, so
+// the same installs don't always get new versions first
+fn in_rollout(crate_name: &str, version: &str, install_id: &str, rollout_percent: u8) -> bool {
+    if rollout_percent >= 100 {
+        return true;
+    }
+    let digest = Sha256::digest(format!("{crate_name}/{version}/{install_id}").as_bytes());
+    let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap_or_default()) % 100;
+    bucket < u64::from(rollout_percent)
+}
+
+// This install's rollout identifier, generated on first use
+pub(crate) fn install_id(fs: &dyn Fs) -> Result<String> {
+    State::update_in(fs, |state| {
+        state
+            .install_id
+            .get_or_insert_with(|| unique_token("install"))
+            .clone()
+    })
+}
+
+// The channel to follow: the one set by the program, else the one from the
+// environment, else the one saved with `set_channel`
+pub(crate) fn resolve_channel(configured: Option<&str>, fs: &dyn Fs) -> Option<String> {
+    configured
+        .map(str::to_string)
+        .or_else(|| env::var(CHANNEL_ENV).ok().filter(|channel| !channel.is_empty()))
+        .or_else(|| State::load_in(fs).ok().and_then(|state| state.channel))
+}
+
+/// Makes the running program follow release `channel` from now on.
+///
+/// The choice is saved in the program's state and used whenever the program
+/// doesn't select a channel itself (see `RSpawn::channel`) and `$RSPAWN_CHANNEL`
+/// isn't set. Moving to a channel that is behind the running version (e.g.
+/// from "nightly" back to "stable") downgrades on the next update, after
+/// explaining it and asking for confirmation, which isn't given on the
+/// program's behalf unless `RSpawn::unattended_downgrades` allows it.
+pub fn set_channel(channel: &str) -> Result<(), RspawnError> {
+    let channel = channel.trim();
+    if channel.is_empty() {
+        return Err(anyhow::anyhow!("Release channel name can't be empty").into());
+    }
+    State::update(|state| state.channel = Some(channel.to_string()))?;
+    info!(target: CHECK, "Now following the {:?} release channel", channel);
+    Ok(())
+}
+
+/// Forgets the channel saved with `set_channel`, going back to the default one.
+pub fn reset_channel() -> Result<(), RspawnError> {
+    State::update(|state| state.channel = None)
+}
+
+/// Returns the channel saved with `set_channel`, if any.
+pub fn saved_channel() -> Result<Option<String>, RspawnError> {
+    Ok(State::load()?.channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"{
+        "channels": {
+            "stable": { "version": "1.4.2" },
+            "beta": { "version": "1.5.0-beta.1", "rollout_percent": 20 },
+            "frozen": { "version": "1.6.0", "rollout_percent": 0 }
+        },
+        "targets": [
+            { "hosts": ["db-*"], "labels": { "region": "eu" }, "version": "1.3.9" },
+            { "labels": { "canary": "yes" }, "channel": "stable", "version": "1.5.0-beta.1" }
+        ]
+    }"#;
+
+    fn host(hostname: &str, labels: &[(&str, &str)]) -> HostInfo {
+        HostInfo {
+            hostname: Some(hostname.to_string()),
+            labels: labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    fn install_ids() -> impl Iterator<Item = String> {
+        (0..1000).map(|n| format!("install-{n:04}"))
+    }
+
+    #[test]
+    fn rollout_bounds_are_exact() {
+        for install_id in install_ids() {
+            assert!(in_rollout("mytool", "1.5.0", &install_id, 100));
+            assert!(!in_rollout("mytool", "1.5.0", &install_id, 0));
+        }
+    }
+
+    #[test]
+    fn rollout_reaches_about_its_share_and_only_grows() {
+        let reached = |percent| install_ids().filter(|id| in_rollout("mytool", "1.5.0", id, percent)).collect::<Vec<_>>();
+        let (twenty, fifty) = (reached(20), reached(50));
+        assert!((150..250).contains(&twenty.len()), "{} of 1000 installs at 20%", twenty.len());
+        assert!((420..580).contains(&fifty.len()), "{} of 1000 installs at 50%", fifty.len());
+        // Raising the percentage keeps everyone who already had the version
+        assert!(twenty.iter().all(|id| fifty.contains(id)));
+    }
+
+    #[test]
+    fn every_release_picks_its_own_installs() {
+        let reached = |version| install_ids().filter(|id| in_rollout("mytool", version, id, 20)).collect::<Vec<_>>();
+        assert_ne!(reached("1.5.0"), reached("1.5.1"));
+        assert_eq!(reached("1.5.0"), reached("1.5.0"));
+    }
+
+    #[test]
+    fn targets_pin_matching_machines() {
+        let manifest = ChannelManifest::parse(MANIFEST).unwrap();
+        let version = |channel, host: &HostInfo| manifest.target_version("mytool", channel, "install-0001", host).unwrap();
+
+        assert_eq!(version(None, &host("db-03", &[("region", "eu")])), Some("1.3.9"));
+        assert_eq!(version(Some("beta"), &host("DB-03", &[("region", "eu")])), Some("1.3.9"));
+        // Every label has to match, and the channel when the target names one
+        assert_eq!(version(None, &host("db-03", &[("region", "us")])), Some("1.4.2"));
+        assert_eq!(version(None, &host("web-01", &[("canary", "yes")])), Some("1.5.0-beta.1"));
+        assert_eq!(version(Some("frozen"), &host("web-01", &[("canary", "yes")])), None);
+    }
+
+    #[test]
+    fn channels_follow_their_rollout() {
+        let manifest = ChannelManifest::parse(MANIFEST).unwrap();
+        let web = host("web-01", &[]);
+        assert_eq!(manifest.default, "stable");
+        assert_eq!(manifest.target_version("mytool", None, "install-0001", &web).unwrap(), Some("1.4.2"));
+        assert_eq!(manifest.target_version("mytool", Some("frozen"), "install-0001", &web).unwrap(), None);
+
+        let on_beta: Vec<_> = install_ids()
+            .filter(|id| manifest.target_version("mytool", Some("beta"), id, &web).unwrap().is_some())
+            .collect();
+        let expected: Vec<_> = install_ids().filter(|id| in_rollout("mytool", "1.5.0-beta.1", id, 20)).collect();
+        assert_eq!(on_beta, expected);
+        assert!(manifest.target_version("mytool", Some("nightly"), "install-0001", &web).is_err());
+    }
+}
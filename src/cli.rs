@@ -0,0 +1,95 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fmt;
+use std::str::FromStr;
+
+/// Update-related command line flags for host programs.
+///
+/// With the `clap` feature this derives `clap::Args`, so it can be flattened
+/// into the host's own CLI and handed to `RSpawn::from_cli`:
+///
+/// # Example
+/// ```ignore
+/// #[derive(clap::Parser)]
+/// struct Cli {
+///     #[command(flatten)]
+///     update: rspawn::CliFlags,
+/// }
+///
+/// let cli = Cli::parse();
+/// RSpawn::from_cli(&cli.update)
+///     .crate_name(env!("CARGO_PKG_NAME"))
+///     .current_version(env!("CARGO_PKG_VERSION"))
+///     .relaunch_program()?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct CliFlags {
+    /// Check whether a new version is available, without installing it
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub check_update: bool,
+
+    /// Install the latest version without asking for confirmation
+    #[cfg_attr(feature = "clap", arg(long, conflicts_with = "check_update"))]
+    pub update: bool,
+
+    /// Skip the update check entirely
+    #[cfg_attr(feature = "clap", arg(long, conflicts_with_all = ["check_update", "update"]))]
+    pub no_update_check: bool,
+
+    /// Release channel to follow (e.g. "stable" or "beta")
+    #[cfg_attr(feature = "clap", arg(long, value_name = "CHANNEL"))]
+    pub update_channel: Option<String>,
+
+    /// Report update events on stderr in this format, for wrapper tools
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FORMAT", value_enum))]
+    pub events: Option<EventFormat>,
+}
+
+/// How `RSpawn::event_stream` writes each `UpdateEvent` on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[non_exhaustive]
+pub enum EventFormat {
+    /// One JSON object per line, with the event's name in `"event"`, e.g.
+    /// `{"event":"install_started","version":"1.2.0"}`.
+    Ndjson,
+}
+
+impl fmt::Display for EventFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventFormat::Ndjson => write!(f, "ndjson"),
+        }
+    }
+}
+
+impl FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "ndjson" => Ok(EventFormat::Ndjson),
+            _ => Err(format!("Unknown event format {format:?}, expected \"ndjson\"")),
+        }
+    }
+}
+
+/// Adds the `CliFlags` arguments to a `clap::Command` built with clap's builder API.
+///
+/// Read them back with `<CliFlags as clap::FromArgMatches>::from_arg_matches`.
+#[cfg(feature = "clap")]
+pub fn cli_flags(command: clap::Command) -> clap::Command {
+    <CliFlags as clap::Args>::augment_args(command)
+}
@@ -0,0 +1,479 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The builder's configuration as data, see `Config`, and its validation as a
+//! whole, see `RSpawn::build`.
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::approval::RemoteApproval;
+use crate::build_wrapper::BuildWrapper;
+use crate::container::ContainerPolicy;
+use crate::decision::Decision;
+use crate::feed::VersionSource;
+use crate::install::InstallPriority;
+use crate::lock::LockBehavior;
+use crate::restart::{RelaunchMode, RelaunchStrategy};
+use crate::error::RspawnError;
+use crate::outcome::UpdateOutcome;
+use crate::plan::UpdatePlan;
+use crate::sandbox::InstallSandbox;
+use crate::install_root::InstallRoot;
+#[cfg(feature = "signed-releases")]
+use crate::signing::ReleaseSignature;
+#[cfg(feature = "sigstore")]
+use crate::sigstore::SigstoreVerification;
+use crate::style::{Style, Verbosity};
+use crate::target::TargetBinary;
+use crate::RSpawn;
+
+/// Every builder option that is plain data, for loading an update
+/// configuration from the host program's config file or from a server.
+///
+/// Each field left out (`None`) keeps the builder's setting; see the builder
+/// method of the same name for what it does. Durations are written as
+/// `{ "secs": 3600, "nanos": 0 }`.
+///
+/// # Example
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let config: rspawn::Config = serde_json::from_str(r#"{
+///     "channel": "beta",
+///     "container_policy": "proceed",
+///     "lock_behavior": { "wait_up_to": { "secs": 30, "nanos": 0 } }
+/// }"#)?;
+/// rspawn::self_update!().config(config)?.relaunch_program()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub features: Option<Vec<String>>,
+    pub crate_name: Option<String>,
+    pub current_version: Option<String>,
+    pub check_if_executed_from_path: Option<bool>,
+    pub keep_backups: Option<usize>,
+    pub verify_integrity: Option<bool>,
+    #[cfg(feature = "sigstore")]
+    pub sigstore: Option<SigstoreVerification>,
+    pub download_rate_limit: Option<u64>,
+    pub download_parallelism: Option<usize>,
+    pub artifact_mirror: Option<String>,
+    #[cfg(feature = "p2p")]
+    pub peer_downloads: Option<bool>,
+    #[cfg(feature = "p2p")]
+    pub lan_gossip: Option<bool>,
+    /// Hex-encoded ed25519 public key, used with `release_signature_url`.
+    #[cfg(feature = "signed-releases")]
+    pub release_public_key: Option<String>,
+    /// URL of the release signature, see `ReleaseSignature::new`.
+    #[cfg(feature = "signed-releases")]
+    pub release_signature_url: Option<String>,
+    pub enabled: Option<bool>,
+    pub check_only: Option<bool>,
+    pub assume_yes: Option<bool>,
+    pub unattended_downgrades: Option<bool>,
+    pub remote_approval: Option<RemoteApproval>,
+    pub channel: Option<String>,
+    pub channel_manifest: Option<String>,
+    pub version_source: Option<VersionSource>,
+    pub style: Option<Style>,
+    pub verbosity: Option<Verbosity>,
+    pub shared_cache: Option<Duration>,
+    pub lock_behavior: Option<LockBehavior>,
+    pub log_path: Option<PathBuf>,
+    pub failure_backoff: Option<Duration>,
+    pub fallback_install_root: Option<PathBuf>,
+    pub container_policy: Option<ContainerPolicy>,
+    pub relaunch_mode: Option<RelaunchMode>,
+    pub relaunch_strategy: Option<RelaunchStrategy>,
+    pub relaunch_target: Option<PathBuf>,
+    pub target_binary: Option<PathBuf>,
+    pub binary_name: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub install_priority: Option<InstallPriority>,
+    pub install_sandbox: Option<InstallSandbox>,
+    pub keep_build_cache: Option<bool>,
+    pub build_wrapper: Option<BuildWrapper>,
+    pub install_heartbeat: Option<Duration>,
+    pub install_root: Option<InstallRoot>,
+    pub allow_elevation: Option<bool>,
+    pub decision: Option<Decision>,
+}
+
+// Applies every option set in `config` to `builder`
+pub(crate) fn apply(mut builder: RSpawn, config: Config) -> Result<RSpawn, RspawnError> {
+    #[cfg(feature = "signed-releases")]
+    match (&config.release_public_key, &config.release_signature_url) {
+        (Some(public_key), Some(signature_url)) => {
+            let signature = ReleaseSignature::new(public_key, signature_url).map_err(|e| {
+                invalid(ConfigProblem::InvalidValue { option: "release_public_key", reason: format!("{:#}", e) })
+            })?;
+            builder.release_signature = Some(signature);
+        }
+        (None, None) => {}
+        (Some(_), None) => return Err(invalid(ConfigProblem::Missing { option: "release_signature_url" })),
+        (None, Some(_)) => return Err(invalid(ConfigProblem::Missing { option: "release_public_key" })),
+    }
+    #[cfg(feature = "sigstore")]
+    if config.sigstore.is_some() {
+        builder.sigstore = config.sigstore;
+    }
+    #[cfg(feature = "p2p")]
+    if config.peer_downloads.is_some() {
+        builder.peer_downloads = config.peer_downloads;
+    }
+    #[cfg(feature = "p2p")]
+    if config.lan_gossip.is_some() {
+        builder.lan_gossip = config.lan_gossip;
+    }
+
+    macro_rules! set {
+        ($($field:ident),* $(,)?) => {
+            $(if config.$field.is_some() {
+                builder.$field = config.$field;
+            })*
+        };
+    }
+    set!(
+        keep_backups,
+        verify_integrity,
+        download_rate_limit,
+        download_parallelism,
+        artifact_mirror,
+        enabled,
+        check_only,
+        assume_yes,
+        unattended_downgrades,
+        remote_approval,
+        channel,
+        channel_manifest,
+        version_source,
+        style,
+        verbosity,
+        shared_cache,
+        lock_behavior,
+        log_path,
+        failure_backoff,
+        fallback_install_root,
+        container_policy,
+        relaunch_mode,
+        relaunch_strategy,
+        relaunch_target,
+        target_binary,
+        binary_name,
+        data_dir,
+        install_priority,
+        install_sandbox,
+        keep_build_cache,
+        build_wrapper,
+        install_heartbeat,
+        install_root,
+        allow_elevation,
+        crate_name,
+        current_version,
+    );
+    if config.features.is_some() {
+        builder.active_features = config.features;
+    }
+    if config.check_if_executed_from_path.is_some() {
+        builder.check_if_executed_from_PATH = config.check_if_executed_from_path;
+    }
+    if let Some(decision) = config.decision {
+        builder.decision_provider = Some(Arc::new(decision));
+    }
+    Ok(builder)
+}
+
+// `problem`, as the error `RSpawn::config` fails with
+#[cfg(feature = "signed-releases")]
+fn invalid(problem: ConfigProblem) -> RspawnError {
+    RspawnError::Config(ConfigError { problems: vec![problem] })
+}
+
+/// A problem with a builder's configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigProblem {
+    /// Two options were both set, but only one of them can take effect.
+    Conflict {
+        /// The option that wins, or that is named first.
+        first: &'static str,
+        /// The option that would be ignored or can't work with it.
+        second: &'static str,
+        /// Why they don't go together.
+        reason: &'static str,
+    },
+    /// An option every update needs was not set.
+    Missing {
+        /// The option.
+        option: &'static str,
+    },
+    /// An option that needs a URL was given an empty one.
+    MissingUrl {
+        /// The option.
+        option: &'static str,
+    },
+    /// An option was given a value it can't use.
+    InvalidValue {
+        /// The option.
+        option: &'static str,
+        /// What is wrong with the value.
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::Conflict { first, second, reason } => {
+                write!(f, "`{}` and `{}` can't be used together: {}", first, second, reason)
+            }
+            ConfigProblem::Missing { option } => write!(f, "`{}` must be set", option),
+            ConfigProblem::MissingUrl { option } => write!(f, "`{}` needs a URL", option),
+            ConfigProblem::InvalidValue { option, reason } => write!(f, "invalid `{}`: {}", option, reason),
+        }
+    }
+}
+
+/// Error returned by `RSpawn::build`, listing every problem found in the configuration.
+///
+/// The update entry points return it too, as `RspawnError::Config`, when the
+/// crate or its running version is missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// The problems, in the order the options are checked.
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid update configuration")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A builder whose configuration was checked by `RSpawn::build`.
+pub struct ConfiguredRSpawn {
+    builder: RSpawn,
+}
+
+impl ConfiguredRSpawn {
+    /// Runs the update flow, see `RSpawn::relaunch_program`.
+    pub fn relaunch_program(self) -> Result<UpdateOutcome, RspawnError> {
+        self.builder.relaunch_program()
+    }
+
+    /// Runs the update flow on tokio, see `RSpawn::relaunch_async`.
+    #[cfg(feature = "tokio")]
+    pub async fn relaunch_async(self) -> Result<UpdateOutcome, RspawnError> {
+        self.builder.relaunch_async().await
+    }
+
+    /// Plans the update without applying it, see `RSpawn::plan`.
+    pub fn plan(self) -> Result<Option<UpdatePlan>, RspawnError> {
+        self.builder.plan()
+    }
+}
+
+// The options no update of the running program can do without: which crate,
+// and the version running. A target binary brings its own.
+pub(crate) fn package_problems(builder: &RSpawn) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    if builder.target_binary.is_some() {
+        return problems;
+    }
+    if builder.crate_name.as_ref().is_none_or(|name| name.trim().is_empty()) {
+        problems.push(ConfigProblem::Missing { option: "crate_name" });
+    }
+    if builder.current_version.as_ref().is_none_or(|version| version.trim().is_empty()) {
+        problems.push(ConfigProblem::Missing { option: "current_version" });
+    }
+    problems
+}
+
+// Checks every option against the others, collecting all problems at once
+pub(crate) fn validate(builder: RSpawn) -> Result<ConfiguredRSpawn, ConfigError> {
+    let mut problems = package_problems(&builder);
+    let feed = match &builder.version_source {
+        Some(VersionSource::Feed(url)) => Some(url),
+        _ => None,
+    };
+
+    if builder.channel_manifest.as_ref().is_some_and(|url| url.trim().is_empty()) {
+        problems.push(ConfigProblem::MissingUrl { option: "channel_manifest" });
+    }
+    if builder.artifact_mirror.as_ref().is_some_and(|url| url.trim().is_empty()) {
+        problems.push(ConfigProblem::MissingUrl { option: "artifact_mirror" });
+    }
+    if feed.is_some_and(|url| url.trim().is_empty()) {
+        problems.push(ConfigProblem::MissingUrl { option: "version_source" });
+    }
+    if builder.channel_manifest.is_some() && matches!(builder.version_source, Some(VersionSource::Mirror(_))) {
+        problems.push(ConfigProblem::Conflict {
+            first: "channel_manifest",
+            second: "version_source",
+            reason: "the channel manifest is fetched over the network and picks the version, so the mirror would never be read",
+        });
+    }
+    if builder.channel_manifest.is_some() && feed.is_some() {
+        problems.push(ConfigProblem::Conflict {
+            first: "channel_manifest",
+            second: "version_source",
+            reason: "the channel manifest picks the version, so the feed would never be read",
+        });
+    }
+    #[cfg(feature = "sigstore")]
+    if feed.is_some() && builder.sigstore.is_some() {
+        problems.push(ConfigProblem::Conflict {
+            first: "version_source",
+            second: "sigstore",
+            reason: "feeds carry no checksum to verify the release against",
+        });
+    }
+    #[cfg(feature = "signed-releases")]
+    if feed.is_some() && builder.release_signature.is_some() {
+        problems.push(ConfigProblem::Conflict {
+            first: "version_source",
+            second: "release_signature",
+            reason: "feeds carry no checksum to verify the release against",
+        });
+    }
+    if builder.download_parallelism == Some(0) {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "download_parallelism",
+            reason: "at least one chunk is needed".to_string(),
+        });
+    }
+    if builder.check_only == Some(true) && builder.assume_yes == Some(true) {
+        problems.push(ConfigProblem::Conflict {
+            first: "check_only",
+            second: "assume_yes",
+            reason: "nothing is installed when only checking",
+        });
+    }
+    if builder.remote_approval.is_some() {
+        if builder.user_confirm.is_some() {
+            problems.push(ConfigProblem::Conflict {
+                first: "remote_approval",
+                second: "user_confirm",
+                reason: "the approval endpoint decides, so the user is never asked",
+            });
+        }
+        if builder.confirm_plan.is_some() {
+            problems.push(ConfigProblem::Conflict {
+                first: "remote_approval",
+                second: "confirm_plan",
+                reason: "the approval endpoint decides, so the user is never asked",
+            });
+        }
+        if builder.decision_provider.is_some() {
+            problems.push(ConfigProblem::Conflict {
+                first: "remote_approval",
+                second: "decision_provider",
+                reason: "the approval endpoint decides, so the provider is never asked",
+            });
+        }
+    } else if builder.user_confirm.is_some() && builder.confirm_plan.is_some() {
+        problems.push(ConfigProblem::Conflict {
+            first: "confirm_plan",
+            second: "user_confirm",
+            reason: "only one confirmation is asked, and `confirm_plan` wins",
+        });
+    }
+    if let Some(target) = &builder.target_binary {
+        if let Err(e) = TargetBinary::new(target.clone()) {
+            problems.push(ConfigProblem::InvalidValue {
+                option: "target_binary",
+                reason: format!("{:#}", e),
+            });
+        }
+        if builder.relaunch_target.is_some() {
+            problems.push(ConfigProblem::Conflict {
+                first: "target_binary",
+                second: "relaunch_target",
+                reason: "nothing is relaunched when updating another binary, the launcher restarts it",
+            });
+        }
+        if builder.relaunch_mode == Some(RelaunchMode::OnNextStart) {
+            problems.push(ConfigProblem::Conflict {
+                first: "target_binary",
+                second: "relaunch_mode",
+                reason: "nothing is relaunched when updating another binary, the launcher restarts it",
+            });
+        }
+        if builder.binary_name.is_some() {
+            problems.push(ConfigProblem::Conflict {
+                first: "target_binary",
+                second: "binary_name",
+                reason: "the binary name is the running program's, which isn't the one updated",
+            });
+        }
+    }
+    if builder.binary_name.as_ref().is_some_and(|name| name.trim().is_empty() || name.contains(['/', '\\'])) {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "binary_name",
+            reason: "expected the name of a binary, not a path".to_string(),
+        });
+    }
+
+    if matches!(&builder.install_root, Some(InstallRoot::Path(root)) if root.as_os_str().is_empty()) {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "install_root",
+            reason: "expected the path of an install root".to_string(),
+        });
+    }
+
+    #[cfg(not(feature = "json"))]
+    problems.extend(json_problems(&builder));
+
+    if problems.is_empty() {
+        Ok(ConfiguredRSpawn { builder })
+    } else {
+        Err(ConfigError { problems })
+    }
+}
+
+// The options that need JSON, in a build without the `json` feature
+#[cfg(not(feature = "json"))]
+fn json_problems(builder: &RSpawn) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    if matches!(builder.version_source, None | Some(VersionSource::CratesIo)) {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "version_source",
+            reason: "crates.io answers in JSON, which needs rspawn's `json` feature: use a feed or a vendored mirror".to_string(),
+        });
+    }
+    if builder.channel_manifest.is_some() {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "channel_manifest",
+            reason: "manifests are JSON, which needs rspawn's `json` feature".to_string(),
+        });
+    }
+    if builder.remote_approval.is_some() {
+        problems.push(ConfigProblem::InvalidValue {
+            option: "remote_approval",
+            reason: "the endpoint answers in JSON, which needs rspawn's `json` feature".to_string(),
+        });
+    }
+    problems
+}
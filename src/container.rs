@@ -0,0 +1,102 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Detection of containerized environments, where self-updating is usually
+//! pointless: the container is thrown away with the new version in it, and
+//! building it only slows down every start.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Container runtime the program appears to run in, see `detect_container()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContainerRuntime {
+    /// A Kubernetes pod.
+    Kubernetes,
+    /// A Docker container.
+    Docker,
+    /// A Podman container.
+    Podman,
+    /// Some other runtime, e.g. containerd, LXC or systemd-nspawn.
+    Other,
+}
+
+impl fmt::Display for ContainerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerRuntime::Kubernetes => write!(f, "Kubernetes"),
+            ContainerRuntime::Docker => write!(f, "Docker"),
+            ContainerRuntime::Podman => write!(f, "Podman"),
+            ContainerRuntime::Other => write!(f, "container"),
+        }
+    }
+}
+
+/// What to do when the program runs in a container, see `RSpawn::container_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerPolicy {
+    /// Skip the update check entirely, without querying the registry.
+    Skip,
+    /// Report a new version, but never install it.
+    #[default]
+    NotifyOnly,
+    /// Update as usual.
+    Proceed,
+}
+
+// Runtime named by a cgroup path, e.g. `0::/kubepods/burstable/pod.../...`
+fn runtime_from_cgroup(cgroup: &str) -> Option<ContainerRuntime> {
+    if cgroup.contains("kubepods") {
+        Some(ContainerRuntime::Kubernetes)
+    } else if cgroup.contains("libpod") {
+        Some(ContainerRuntime::Podman)
+    } else if cgroup.contains("docker") {
+        Some(ContainerRuntime::Docker)
+    } else if cgroup.contains("containerd") || cgroup.contains("lxc") {
+        Some(ContainerRuntime::Other)
+    } else {
+        None
+    }
+}
+
+/// Returns the container runtime the program appears to run in, if any.
+///
+/// This is a heuristic: it looks at the variables Kubernetes and most runtimes
+/// set, the marker files Docker and Podman create, and the cgroup of the current
+/// process. Containers that hide all of these are not detected.
+pub fn detect_container() -> Option<ContainerRuntime> {
+    if env::var_os("KUBERNETES_SERVICE_HOST").is_some() {
+        return Some(ContainerRuntime::Kubernetes);
+    }
+    // Set by Podman, LXC and systemd-nspawn among others
+    if let Ok(container) = env::var("container") {
+        return Some(match container.as_str() {
+            "podman" => ContainerRuntime::Podman,
+            "docker" => ContainerRuntime::Docker,
+            _ => ContainerRuntime::Other,
+        });
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some(ContainerRuntime::Podman);
+    }
+    if Path::new("/.dockerenv").exists() {
+        return Some(ContainerRuntime::Docker);
+    }
+    fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|cgroup| runtime_from_cgroup(&cgroup))
+}
@@ -0,0 +1,110 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::env;
+use anyhow::Result;
+
+/// Name of the token sent to the registry when querying versions.
+pub const REGISTRY_TOKEN: &str = "registry";
+/// Name of the token sent with requests to GitHub: release feeds and the
+/// assets of private repositories.
+pub const GITHUB_TOKEN: &str = "github";
+
+/// A place tokens used by rspawn are read from.
+///
+/// Tokens are looked up by name (see `REGISTRY_TOKEN` and `GITHUB_TOKEN`).
+pub trait CredentialStore: Send + Sync {
+    /// Returns the token stored under `name`, if any.
+    fn token(&self, name: &str) -> Result<Option<String>>;
+
+    /// Stores `token` under `name`.
+    fn set_token(&self, name: &str, token: &str) -> Result<()>;
+
+    /// Removes the token stored under `name`, if any.
+    fn delete_token(&self, name: &str) -> Result<()>;
+}
+
+/// Reads tokens from `RSPAWN_<NAME>_TOKEN` environment variables.
+///
+/// This is the default store. It is read-only: setting or deleting tokens fails.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvCredentialStore;
+
+impl EnvCredentialStore {
+    // Environment variable holding the token called `name`
+    fn var_name(name: &str) -> String {
+        format!("RSPAWN_{}_TOKEN", name.to_uppercase().replace('-', "_"))
+    }
+}
+
+impl CredentialStore for EnvCredentialStore {
+    fn token(&self, name: &str) -> Result<Option<String>> {
+        Ok(env::var(Self::var_name(name)).ok().filter(|token| !token.is_empty()))
+    }
+
+    fn set_token(&self, name: &str, _token: &str) -> Result<()> {
+        Err(anyhow::anyhow!("Cannot store tokens in the environment; set {} instead", Self::var_name(name)))
+    }
+
+    fn delete_token(&self, name: &str) -> Result<()> {
+        Err(anyhow::anyhow!("Cannot delete tokens from the environment; unset {} instead", Self::var_name(name)))
+    }
+}
+
+/// Stores tokens in the OS keyring (Secret Service, Keychain or Credential Manager).
+///
+/// Each token is an entry of the given service, with the token name as the user.
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone)]
+pub struct KeyringCredentialStore {
+    service: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringCredentialStore {
+    /// Creates a store for entries of `service`, usually the name of the program.
+    pub fn new(service: &str) -> Self {
+        KeyringCredentialStore {
+            service: service.to_string(),
+        }
+    }
+
+    fn entry(&self, name: &str) -> Result<keyring::Entry> {
+        keyring::Entry::new(&self.service, name)
+            .map_err(|e| anyhow::anyhow!("Failed to open keyring entry {}/{}: {}", self.service, name, e))
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialStore for KeyringCredentialStore {
+    fn token(&self, name: &str) -> Result<Option<String>> {
+        match self.entry(name)?.get_password() {
+            Ok(token) => Ok(Some(token)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Failed to read {} token from the keyring: {}", name, e)),
+        }
+    }
+
+    fn set_token(&self, name: &str, token: &str) -> Result<()> {
+        self.entry(name)?
+            .set_password(token)
+            .map_err(|e| anyhow::anyhow!("Failed to store {} token in the keyring: {}", name, e))
+    }
+
+    fn delete_token(&self, name: &str) -> Result<()> {
+        match self.entry(name)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("Failed to delete {} token from the keyring: {}", name, e)),
+        }
+    }
+}
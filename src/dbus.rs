@@ -0,0 +1,291 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! D-Bus integration for Linux desktop programs.
+//!
+//! The program's update state is exposed on the session bus as the
+//! `io.github.jgabaut.rspawn.Updater` interface at `/io/github/jgabaut/rspawn`:
+//!
+//! * `Check()` looks up the latest version and returns whether it's an update.
+//! * `ApplyUpdate()` installs it and relaunches the program.
+//! * The `UpdateAvailable`, `CurrentVersion` and `LatestVersion` properties
+//!   hold the result of the last check.
+//!
+//! With `apply_on_shutdown`, an update found by the last check is installed
+//! when logind announces a shutdown, instead of interrupting the user.
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::{Result, Context};
+use log::{info, debug, warn};
+use crate::error::RspawnError;
+use crate::log_target::CONTROL;
+use zbus::blocking::{connection, Connection, Proxy};
+use zbus::zvariant::OwnedFd;
+use zbus::SignalContext;
+use crate::install::InstallError;
+use crate::process::{ProcessRunner, SystemRunner};
+use crate::version::{is_update, UpdatePolicy};
+use crate::{check_versions, RSpawn, UpdateOutcome};
+
+/// Object path of the updater object.
+pub const DBUS_OBJECT_PATH: &str = "/io/github/jgabaut/rspawn";
+
+type CheckFn = Box<dyn FnMut() -> Result<(String, String)> + Send>;
+type ApplyFn = Box<dyn FnMut(bool) -> Result<UpdateOutcome> + Send>;
+type RelaunchFn = Box<dyn FnMut(&UpdateOutcome) + Send>;
+
+// What both the bus object and the shutdown listener need
+struct Shared {
+    check: Mutex<CheckFn>,
+    apply: Mutex<ApplyFn>,
+    on_relaunch: Mutex<Option<RelaunchFn>>,
+    // Current and latest version found by the last check
+    last_check: Mutex<Option<(String, String)>>,
+}
+
+impl Shared {
+    fn check(&self) -> Result<bool> {
+        let (current_version, latest_version) = (lock(&self.check))()?;
+        let update_available = is_update(&current_version, &latest_version, UpdatePolicy::Newer);
+        *lock(&self.last_check) = Some((current_version, latest_version));
+        Ok(update_available)
+    }
+
+    fn update_available(&self) -> bool {
+        lock(&self.last_check)
+            .as_ref()
+            .is_some_and(|(current_version, latest_version)| is_update(current_version, latest_version, UpdatePolicy::Newer))
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Updater {
+    shared: Arc<Shared>,
+}
+
+#[zbus::interface(name = "io.github.jgabaut.rspawn.Updater")]
+impl Updater {
+    /// Looks up the latest version, returning whether it's an update.
+    async fn check(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> zbus::fdo::Result<bool> {
+        let update_available = self.shared.check().map_err(|e| zbus::fdo::Error::Failed(format!("{:#}", e)))?;
+        self.update_available_changed(&ctxt).await?;
+        self.current_version_changed(&ctxt).await?;
+        self.latest_version_changed(&ctxt).await?;
+        Ok(update_available)
+    }
+
+    /// Installs the latest version and relaunches the program.
+    ///
+    /// Once the new version is started, the service's `on_relaunch` handler
+    /// decides what happens to this one; if it exits, the call is left
+    /// without a reply.
+    fn apply_update(&self) -> zbus::fdo::Result<()> {
+        let outcome = (lock(&self.shared.apply))(true).map_err(|e| zbus::fdo::Error::Failed(format!("{:#}", e)))?;
+        if let UpdateOutcome::Relaunched { .. } = outcome {
+            if let Some(on_relaunch) = lock(&self.shared.on_relaunch).as_mut() {
+                on_relaunch(&outcome);
+            }
+        }
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn update_available(&self) -> bool {
+        self.shared.update_available()
+    }
+
+    #[zbus(property)]
+    fn current_version(&self) -> String {
+        lock(&self.shared.last_check).as_ref().map(|(current, _)| current.clone()).unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn latest_version(&self) -> String {
+        lock(&self.shared.last_check).as_ref().map(|(_, latest)| latest.clone()).unwrap_or_default()
+    }
+}
+
+// Installs like `SystemRunner` but starts no new instance, as the machine is going down
+struct NoRelaunch;
+
+impl ProcessRunner for NoRelaunch {
+    fn install(&self, command: Command, echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        SystemRunner.install(command, echo, on_line)
+    }
+
+    fn install_with_heartbeat(
+        &self,
+        command: Command,
+        echo: bool,
+        on_line: &mut dyn FnMut(&str),
+        interval: Duration,
+        on_beat: &mut dyn FnMut(),
+    ) -> Result<(), InstallError> {
+        SystemRunner.install_with_heartbeat(command, echo, on_line, interval, on_beat)
+    }
+
+    fn spawn(&self, _command: Command) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Publishes the update state of the program on the D-Bus session bus.
+///
+/// # Example
+/// ```ignore
+/// let _service = rspawn::DbusService::new("org.example.MyApp.Updater", || rspawn::self_update!())
+///     .apply_on_shutdown(true)
+///     .on_relaunch(|_| std::process::exit(0))
+///     .serve()?;
+/// ```
+pub struct DbusService {
+    bus_name: String,
+    shared: Arc<Shared>,
+    apply_on_shutdown: bool,
+    connection: Option<Connection>,
+}
+
+impl DbusService {
+    /// Creates the service, owning `bus_name` on the session bus once served.
+    ///
+    /// `builder` is called for each check and update, so it can pick up
+    /// configuration changes. Updates requested over the bus install without
+    /// asking: the request is the confirmation.
+    pub fn new<B>(bus_name: &str, builder: B) -> Self
+    where
+        B: FnMut() -> RSpawn + Send + 'static,
+    {
+        let builder = Arc::new(Mutex::new(builder));
+        let check_builder = Arc::clone(&builder);
+        let check: CheckFn = Box::new(move || check_versions((lock(&check_builder))()));
+        let apply: ApplyFn = Box::new(move |relaunch| {
+            let rspawn = (lock(&builder))().assume_yes(true);
+            let outcome = if relaunch {
+                rspawn.relaunch_program()?
+            } else {
+                rspawn.process_runner(NoRelaunch).relaunch_program()?
+            };
+            Ok(outcome)
+        });
+        DbusService {
+            bus_name: bus_name.to_string(),
+            shared: Arc::new(Shared {
+                check: Mutex::new(check),
+                apply: Mutex::new(apply),
+                on_relaunch: Mutex::new(None),
+                last_check: Mutex::new(None),
+            }),
+            apply_on_shutdown: false,
+            connection: None,
+        }
+    }
+
+    /// Sets whether an update found by the last check is installed when the
+    /// machine shuts down. Defaults to `false`.
+    ///
+    /// rspawn holds a logind delay inhibitor for this, but logind only waits
+    /// `InhibitDelayMaxSec` (5 seconds unless configured) before going ahead,
+    /// so longer builds need that raised. No new instance is started: the
+    /// update runs from the next start.
+    pub fn apply_on_shutdown(mut self, apply: bool) -> Self {
+        self.apply_on_shutdown = apply;
+        self
+    }
+
+    /// Sets what happens once `ApplyUpdate` has started the new version, e.g.
+    /// cleaning up and exiting so it can take over the bus name. Called with
+    /// `UpdateOutcome::Relaunched` and the new instance's pid.
+    ///
+    /// Without one, the program keeps running next to the new version.
+    pub fn on_relaunch<F>(self, on_relaunch: F) -> Self
+    where
+        F: FnMut(&UpdateOutcome) + Send + 'static,
+    {
+        *lock(&self.shared.on_relaunch) = Some(Box::new(on_relaunch));
+        self
+    }
+
+    /// Registers the object on the session bus and, if enabled, starts listening
+    /// for shutdowns. The object is served until the returned service is dropped.
+    pub fn serve(mut self) -> Result<Self, RspawnError> {
+        let updater = Updater { shared: Arc::clone(&self.shared) };
+        let connection = connection::Builder::session()
+            .context("Failed to connect to the D-Bus session bus")?
+            .name(self.bus_name.as_str())
+            .with_context(|| format!("Failed to request D-Bus name {}", self.bus_name))?
+            .serve_at(DBUS_OBJECT_PATH, updater)
+            .context("Failed to publish the updater object")?
+            .build()
+            .context("Failed to set up the D-Bus connection")?;
+        info!(target: CONTROL, "Serving update state on D-Bus as {}", self.bus_name);
+        self.connection = Some(connection);
+
+        if self.apply_on_shutdown {
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || {
+                if let Err(e) = apply_at_shutdown(&shared) {
+                    warn!(target: CONTROL, "Stopped waiting for shutdown to apply updates: {:#}", e);
+                }
+            });
+        }
+        Ok(self)
+    }
+}
+
+// Blocks shutdown briefly with a delay inhibitor, installing any pending update when it comes
+fn apply_at_shutdown(shared: &Shared) -> Result<()> {
+    let system = Connection::system().context("Failed to connect to the D-Bus system bus")?;
+    let logind = Proxy::new(
+        &system,
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+    )
+    .context("Failed to reach logind")?;
+
+    let take_inhibitor = || -> Result<OwnedFd> {
+        logind
+            .call("Inhibit", &("shutdown", "rspawn", "Installing a pending update", "delay"))
+            .context("Failed to take a shutdown inhibitor lock")
+    };
+    let mut inhibitor = Some(take_inhibitor()?);
+
+    for signal in logind.receive_signal("PrepareForShutdown").context("Failed to subscribe to PrepareForShutdown")? {
+        let starting: bool = signal.body().deserialize().context("Malformed PrepareForShutdown signal")?;
+        if !starting {
+            // Shutdown was cancelled: be ready for the next one
+            if inhibitor.is_none() {
+                inhibitor = Some(take_inhibitor()?);
+            }
+            continue;
+        }
+
+        if shared.update_available() {
+            info!(target: CONTROL, "Installing the pending update before shutdown");
+            if let Err(e) = (lock(&shared.apply))(false) {
+                warn!(target: CONTROL, "Failed to install the pending update at shutdown: {:#}", e);
+            }
+        } else {
+            debug!(target: CONTROL, "No pending update at shutdown");
+        }
+        // Let the shutdown go on
+        inhibitor = None;
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Deciding whether to install an update when nobody is there to confirm it.
+use std::fmt;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::error::RspawnError;
+use crate::approval::{self, RemoteApproval};
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::plan::InstallPlan;
+
+/// The update a `DecisionProvider` is asked about.
+pub struct UpdateRequest<'a> {
+    /// The crate being updated.
+    pub crate_name: &'a str,
+    /// The running version.
+    pub current_version: &'a str,
+    /// The version that would be installed.
+    pub version: &'a str,
+    /// Whether `version` is older than the running one, e.g. after moving to a
+    /// channel that is behind it. Only asked about with
+    /// `RSpawn::unattended_downgrades`.
+    pub downgrade: bool,
+    /// Everything the update would do.
+    pub plan: &'a InstallPlan,
+    /// The clock set with `RSpawn::clock`.
+    pub clock: &'a dyn Clock,
+    /// The filesystem set with `RSpawn::fs`, for providers that keep state.
+    pub fs: &'a dyn Fs,
+}
+
+/// Decides whether an update is installed when the program runs headless
+/// (stdin is not a terminal), in place of `user_confirm` or `confirm_plan`.
+///
+/// `Decision` covers the common policies; implement this for anything else,
+/// and set it with `RSpawn::decision_provider`.
+pub trait DecisionProvider: Send + Sync {
+    /// Returns `true` to install the update.
+    fn decide(&self, request: &UpdateRequest<'_>) -> bool;
+}
+
+/// The built-in `DecisionProvider`s, which can also be chosen in a `Config`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// Install every update.
+    Always,
+    /// Install nothing; updates are only reported.
+    Never,
+    /// Install updates only during the maintenance window.
+    MaintenanceWindow(MaintenanceWindow),
+    /// Install what a remote endpoint approves, see `RemoteApproval`.
+    Remote(RemoteApproval),
+}
+
+impl DecisionProvider for Decision {
+    fn decide(&self, request: &UpdateRequest<'_>) -> bool {
+        match self {
+            Decision::Always => true,
+            Decision::Never => false,
+            Decision::MaintenanceWindow(window) => window.contains(request.clock.unix_now()),
+            Decision::Remote(approval) => approval::is_approved(
+                approval,
+                request.crate_name,
+                request.version,
+                request.current_version,
+                request.clock,
+                request.fs,
+            ),
+        }
+    }
+}
+
+/// A daily window, in UTC, during which updates may be installed.
+///
+/// A window ending before it starts runs past midnight, e.g. 22:00 to 04:00.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Start of the window, in minutes after midnight.
+    pub start_minute: u16,
+    /// End of the window (excluded), in minutes after midnight.
+    pub end_minute: u16,
+}
+
+impl MaintenanceWindow {
+    /// Creates a window from two `HH:MM` times in UTC, e.g. `("02:00", "05:30")`.
+    pub fn new(start: &str, end: &str) -> Result<Self, RspawnError> {
+        Ok(MaintenanceWindow {
+            start_minute: parse_time(start).map_err(RspawnError::ParseError)?,
+            end_minute: parse_time(end).map_err(RspawnError::ParseError)?,
+        })
+    }
+
+    /// Whether the window is open at `unix_time` (seconds since the Unix epoch).
+    pub fn contains(&self, unix_time: u64) -> bool {
+        let minute = (unix_time / 60 % (24 * 60)) as u16;
+        if self.start_minute <= self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02} UTC",
+            self.start_minute / 60,
+            self.start_minute % 60,
+            self.end_minute / 60,
+            self.end_minute % 60
+        )
+    }
+}
+
+// Minutes after midnight of an `HH:MM` time
+fn parse_time(time: &str) -> Result<u16> {
+    let (hours, minutes) = time.trim().split_once(':').with_context(|| format!("Expected HH:MM, got {:?}", time))?;
+    let hours = hours.parse::<u16>().with_context(|| format!("Invalid hour in {:?}", time))?;
+    let minutes = minutes.parse::<u16>().with_context(|| format!("Invalid minute in {:?}", time))?;
+    if hours > 23 || minutes > 59 {
+        bail!("{:?} is not a time of day", time);
+    }
+    Ok(hours * 60 + minutes)
+}
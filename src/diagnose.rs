@@ -0,0 +1,362 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! What the last update run checked and why it stopped, kept in the program's
+//! state so "why wasn't I updated?" can be answered after the fact.
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use log::{debug, warn};
+use crate::error::RspawnError;
+use crate::log_target::CHECK;
+use crate::clock::{Clock, SystemClock};
+use crate::filesystem::{Fs, RealFs};
+use crate::http::{self, ClientConfig};
+use crate::preflight::{self, check_writable};
+use crate::registry::CRATES_IO_URL;
+use crate::state::{state_dir, State};
+
+// Earliest plausible time for the system clock: anything before this release is wrong
+const CLOCK_FLOOR: u64 = 1_735_689_600;
+// How far the clock may drift from the server's before TLS is likely to break
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+/// A check the update flow goes through before installing anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Gate {
+    /// Updates are enabled (`RSpawn::enabled`, `--no-update-check`).
+    Enabled,
+    /// The minimum time between checks, set with `rspawn config set interval`.
+    Interval,
+    /// The container policy, when running in a container.
+    Container,
+    /// The update lock shared by every instance of the program.
+    Lock,
+    /// The program was started from `PATH` (`check_if_executed_from_PATH`).
+    ExecutedFromPath,
+    /// The release channel, and the latest version found on it.
+    Channel,
+    /// Only checking for updates (`RSpawn::check_only`, `--check-update`).
+    CheckOnly,
+    /// Whether the latest version is newer than the running one.
+    UpToDate,
+    /// An update installed earlier that runs from the next start.
+    PendingRestart,
+    /// The running binary matches the recorded install.
+    Integrity,
+    /// The operator's hold file.
+    Hold,
+    /// Backing off from a version that keeps failing to install.
+    Backoff,
+    /// Cargo can write to its install directories.
+    Environment,
+    /// The user (or the approval endpoint) agreed to update.
+    Confirmation,
+}
+
+impl fmt::Display for Gate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Gate::Enabled => "enabled",
+            Gate::Interval => "interval",
+            Gate::Container => "container",
+            Gate::Lock => "lock",
+            Gate::ExecutedFromPath => "executed from PATH",
+            Gate::Channel => "channel",
+            Gate::CheckOnly => "check only",
+            Gate::UpToDate => "up to date",
+            Gate::PendingRestart => "pending restart",
+            Gate::Integrity => "integrity",
+            Gate::Hold => "hold",
+            Gate::Backoff => "backoff",
+            Gate::Environment => "environment",
+            Gate::Confirmation => "confirmation",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// The verdict of a `Gate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// The run went on past this gate.
+    Passed,
+    /// This gate ended the run before installing.
+    Stopped,
+}
+
+/// One gate evaluated on the last run, and what came of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GateCheck {
+    /// Which gate.
+    pub gate: Gate,
+    /// Whether the run went past it.
+    pub verdict: Verdict,
+    /// What the gate found, e.g. the channel followed or why it stopped.
+    pub detail: String,
+}
+
+/// Report of the last update run, see `diagnose()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnosis {
+    /// When the run started, in seconds since the Unix epoch.
+    pub ran_at: u64,
+    /// The version that was running.
+    pub current_version: Option<String>,
+    /// The latest version found, if the run got that far.
+    pub latest_version: Option<String>,
+    /// Every gate evaluated, in order. Gates after the one that stopped the run are missing.
+    pub gates: Vec<GateCheck>,
+    /// The error the run ended with, if any.
+    pub error: Option<String>,
+}
+
+impl Diagnosis {
+    pub(crate) fn new(clock: &dyn Clock) -> Self {
+        Diagnosis {
+            ran_at: clock.unix_now(),
+            ..Diagnosis::default()
+        }
+    }
+
+    pub(crate) fn pass(&mut self, gate: Gate, detail: impl Into<String>) {
+        self.push(gate, Verdict::Passed, detail.into());
+    }
+
+    pub(crate) fn stop(&mut self, gate: Gate, detail: impl Into<String>) {
+        self.push(gate, Verdict::Stopped, detail.into());
+    }
+
+    // Records the outcome of a gate that fails with an error
+    pub(crate) fn check<T>(&mut self, gate: Gate, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => self.pass(gate, ""),
+            Err(e) => self.stop(gate, format!("{:#}", e)),
+        }
+        result
+    }
+
+    fn push(&mut self, gate: Gate, verdict: Verdict, detail: String) {
+        debug!(target: CHECK, "Gate {}: {:?} {}", gate, verdict, detail);
+        self.gates.push(GateCheck { gate, verdict, detail });
+    }
+
+    /// The gate that stopped the run, if one did.
+    pub fn stopped_by(&self) -> Option<&GateCheck> {
+        self.gates.iter().find(|check| check.verdict == Verdict::Stopped)
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Last update run at {} (Unix time)", self.ran_at)?;
+        if let Some(current_version) = &self.current_version {
+            writeln!(f, "  running version: {}", current_version)?;
+        }
+        if let Some(latest_version) = &self.latest_version {
+            writeln!(f, "  latest version:  {}", latest_version)?;
+        }
+        for check in &self.gates {
+            let verdict = match check.verdict {
+                Verdict::Passed => "ok",
+                Verdict::Stopped => "STOPPED",
+            };
+            write!(f, "  [{:>7}] {}", verdict, check.gate)?;
+            if !check.detail.is_empty() {
+                write!(f, ": {}", check.detail)?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "  failed with: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns what the running program's last update run checked, and which
+/// gate (if any) kept it from updating.
+pub fn diagnose() -> Result<Option<Diagnosis>, RspawnError> {
+    Ok(State::load()?.last_run)
+}
+
+/// Like `diagnose()`, for another rspawn-powered program, by executable name.
+pub fn diagnose_program(program: &str) -> Result<Option<Diagnosis>, RspawnError> {
+    Ok(State::load_program(program)?.last_run)
+}
+
+// Keeps `diagnosis` as the last run's
+pub(crate) fn record(diagnosis: Diagnosis, fs: &dyn Fs) {
+    if let Err(e) = State::update_in(fs, |state| state.last_run = Some(diagnosis)) {
+        warn!(target: CHECK, "Failed to record the update run's diagnosis: {:#}", e);
+    }
+}
+
+/// An environment prerequisite checked by `doctor()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoctorCheck {
+    /// What was checked, e.g. "cargo" or "network".
+    pub name: &'static str,
+    /// Whether the prerequisite is met.
+    pub passed: bool,
+    /// What was found: a version, a path, or what went wrong.
+    pub detail: String,
+    /// How to fix it, for failed checks.
+    pub remediation: Option<&'static str>,
+}
+
+impl DoctorCheck {
+    fn new(name: &'static str, result: Result<String, String>, remediation: &'static str) -> Self {
+        match result {
+            Ok(detail) => DoctorCheck { name, passed: true, detail, remediation: None },
+            Err(detail) => DoctorCheck { name, passed: false, detail, remediation: Some(remediation) },
+        }
+    }
+}
+
+impl fmt::Display for DoctorCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.passed { "ok" } else { "FAILED" };
+        write!(f, "[{:>6}] {}: {}", verdict, self.name, self.detail)?;
+        if let Some(remediation) = self.remediation {
+            write!(f, "\n         {}", remediation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks what updating needs from the environment: cargo and a Rust toolchain,
+/// reaching crates.io, a sane clock, and writable state and install directories.
+///
+/// Use `RSpawn::doctor` to check the program's configured version source and
+/// fallback install root instead.
+pub fn doctor() -> Vec<DoctorCheck> {
+    run_checks(CRATES_IO_URL, None)
+}
+
+// Runs every check, against `source_url` for the network and `fallback` for installs
+pub(crate) fn run_checks(source_url: &str, fallback: Option<&Path>) -> Vec<DoctorCheck> {
+    let (network, server_time) = check_network(source_url);
+    vec![
+        DoctorCheck::new(
+            "cargo",
+            tool_version("cargo"),
+            "Install Rust with rustup (https://rustup.rs) and make sure cargo is on your PATH.",
+        ),
+        DoctorCheck::new(
+            "toolchain",
+            tool_version("rustc"),
+            "Install a toolchain, e.g. with `rustup default stable`.",
+        ),
+        DoctorCheck::new(
+            "network",
+            network,
+            "Check your network connection and proxy settings (HTTPS_PROXY).",
+        ),
+        DoctorCheck::new(
+            "clock",
+            check_clock(SystemClock.unix_now(), server_time),
+            "Synchronize the system clock (e.g. enable NTP): TLS certificates are rejected when it is off.",
+        ),
+        DoctorCheck::new(
+            "state directory",
+            state_dir()
+                .map_err(|e| format!("{:#}", e))
+                .and_then(|dir| {
+                    check_writable(&dir, &RealFs).map_err(|e| e.to_string())?;
+                    Ok(format!("{} is writable", dir.display()))
+                }),
+            "Make the state directory writable, or point RSPAWN_STATE_DIR somewhere that is.",
+        ),
+        DoctorCheck::new(
+            "install directory",
+            match preflight::check_install_environment(fallback, &RealFs) {
+                Ok(Some(fallback)) => Ok(format!("installing into {}", fallback.bin_dir().display())),
+                Ok(None) => Ok("cargo can write to its home and bin directories".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            "Fix the permissions of your cargo home, or set RSpawn::fallback_install_root to a writable directory.",
+        ),
+    ]
+}
+
+// The first line `<tool> --version` prints
+fn tool_version(tool: &str) -> Result<String, String> {
+    let output = Command::new(tool)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("failed to run {} --version: {}", tool, e))?;
+    if !output.status.success() {
+        return Err(format!("{} --version exited with {}", tool, output.status));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().trim().to_string())
+}
+
+// Whether `url` answers, along with the server's time from its `Date` header
+fn check_network(url: &str) -> (Result<String, String>, Option<u64>) {
+    let response = http::client(ClientConfig::default()).and_then(|client| client.head(url).send());
+    match response {
+        Ok(response) => {
+            let server_time = response.header("date").and_then(parse_http_date);
+            let status = response.status();
+            let result = if status.is_success() {
+                Ok(format!("reached {} (HTTP {})", url, status))
+            } else {
+                Err(format!("{} answered with HTTP {}", url, status))
+            };
+            (result, server_time)
+        }
+        Err(e) => (Err(format!("failed to reach {}: {:#}", url, e)), None),
+    }
+}
+
+// Compares the system clock with the server's, or at least with a known past date
+fn check_clock(now: u64, server_time: Option<u64>) -> Result<String, String> {
+    match server_time {
+        Some(server_time) if now.abs_diff(server_time) > MAX_CLOCK_SKEW => Err(format!(
+            "the system clock is {}s off from the server's",
+            now.abs_diff(server_time)
+        )),
+        Some(_) => Ok("in sync with the server's".to_string()),
+        None if now < CLOCK_FLOOR => Err(format!("the system clock is in the past ({} Unix time)", now)),
+        None => Ok("plausible, but not compared with a server".to_string()),
+    }
+}
+
+// Parses an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`) to seconds since the Unix epoch
+fn parse_http_date(date: &str) -> Option<u64> {
+    let [_, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let day: i64 = day.parse().ok()?;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+
+    // Days since the epoch, from Howard Hinnant's `days_from_civil`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
@@ -0,0 +1,406 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Downloads of release artifacts made by rspawn itself (as opposed to the
+//! ones cargo makes while installing).
+use std::fmt;
+use std::io::Read;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use log::{debug, warn};
+use crate::log_target::INSTALL;
+use crate::events::{Events, UpdateEvent};
+use crate::http::{self, Client, ClientConfig, Response, StatusCode};
+use crate::url_resolver;
+
+// Size of the reads from the response body
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Smallest artifact worth splitting into parallel ranges
+const MIN_PARALLEL_SIZE: u64 = 1024 * 1024;
+
+/// How rspawn downloads artifacts.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub(crate) struct DownloadSettings {
+    // Maximum average speed, in bytes per second
+    pub rate_limit: Option<u64>,
+    // How many ranges of a large artifact to fetch at once; 0 and 1 fetch it in one go
+    pub parallel_chunks: usize,
+    // Base URL of a cache tried before each artifact's own URL
+    pub mirror: Option<String>,
+    // Whether artifacts are looked for on LAN peers first, see `p2p`
+    #[cfg(feature = "p2p")]
+    pub peers: bool,
+    // Sent with requests to GitHub, for the assets of private repositories
+    pub github_token: Option<String>,
+}
+
+impl fmt::Debug for DownloadSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("DownloadSettings");
+        debug
+            .field("rate_limit", &self.rate_limit)
+            .field("parallel_chunks", &self.parallel_chunks)
+            .field("mirror", &self.mirror);
+        #[cfg(feature = "p2p")]
+        debug.field("peers", &self.peers);
+        debug.field("github_token", &self.github_token.as_ref().map(|_| "***")).finish()
+    }
+}
+
+// Where `url` is cached on `mirror`: its host and path under the mirror's URL, e.g.
+// https://cache.internal/rspawn/github.com/owner/tool/releases/download/v1.2.0/data.tar.gz
+fn mirror_url(mirror: &str, url: &str) -> Option<String> {
+    let (_, location) = url.split_once("://")?;
+    Some(format!("{}/{}", mirror.trim_end_matches('/'), location))
+}
+
+// Sleeps as needed to keep the average speed since `started` under `rate_limit`
+fn throttle(started: Instant, downloaded: u64, rate_limit: u64) {
+    let expected = Duration::from_secs_f64(downloaded as f64 / rate_limit as f64);
+    if let Some(ahead) = expected.checked_sub(started.elapsed()) {
+        thread::sleep(ahead);
+    }
+}
+
+// Reads a response body, reporting the size of each read to `on_read`
+fn read_body(mut response: Response, url: &str, rate_limit: Option<u64>, mut on_read: impl FnMut(usize)) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(response.content_length().unwrap_or_default() as usize);
+    // Smaller reads keep a low rate limit smooth
+    let rate_limit = rate_limit.filter(|limit| *limit > 0);
+    let chunk_size = rate_limit.map_or(CHUNK_SIZE, |limit| CHUNK_SIZE.min(limit as usize));
+    let mut chunk = vec![0u8; chunk_size];
+    let started = Instant::now();
+    loop {
+        let read = response.read(&mut chunk).with_context(|| format!("Failed to read {}", url))?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+        on_read(read);
+        if let Some(limit) = rate_limit {
+            throttle(started, body.len() as u64, limit);
+        }
+    }
+    Ok(body)
+}
+
+// Size of `url`, if its server accepts byte ranges for it
+fn range_support(client: &Client, url: &str, github_token: Option<&str>) -> Option<u64> {
+    let response = client.head(url).github_token(github_token).send().ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let accepts_ranges = response
+        .header("Accept-Ranges")
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
+    accepts_ranges.then(|| response.content_length()).flatten()
+}
+
+// Fetches bytes `start..end` of `url`
+fn fetch_range(client: &Client, url: &str, start: u64, end: u64, settings: &DownloadSettings, rate_limit: Option<u64>, progress: &Sender<usize>) -> Result<Vec<u8>> {
+    let response = client
+        .get(url)
+        .header("Range", format!("bytes={}-{}", start, end - 1))
+        .github_token(settings.github_token.as_deref())
+        .send()
+        .with_context(|| format!("Failed to fetch {} (bytes {}-{})", url, start, end - 1))?;
+    // Anything but a partial response would be the wrong bytes
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(anyhow::anyhow!("Failed to fetch {} (bytes {}-{}): HTTP {}", url, start, end - 1, response.status()));
+    }
+
+    let part = read_body(response, url, rate_limit, |read| {
+        let _ = progress.send(read);
+    })?;
+    if part.len() as u64 != end - start {
+        return Err(anyhow::anyhow!(
+            "Got {} bytes instead of {} for bytes {}-{} of {}",
+            part.len(), end - start, start, end - 1, url
+        ));
+    }
+    Ok(part)
+}
+
+// Downloads `total_bytes` of `url` as `chunks` ranges fetched at the same time,
+// spread over `sources` (copies of `url`, e.g. on peers)
+fn download_parallel(
+    client: &Client,
+    url: &str,
+    sources: &[&str],
+    total_bytes: u64,
+    chunks: usize,
+    settings: &DownloadSettings,
+    events: &mut Events,
+) -> Result<Vec<u8>> {
+    if sources.is_empty() {
+        return Err(anyhow::anyhow!("No source to download {} from", url));
+    }
+    let chunk_len = total_bytes.div_ceil(chunks as u64).max(1);
+    let ranges: Vec<(u64, u64)> = (0..total_bytes)
+        .step_by(chunk_len as usize)
+        .map(|start| (start, (start + chunk_len).min(total_bytes)))
+        .collect();
+    debug!(target: INSTALL, "Downloading {} in {} chunks", url, ranges.len());
+    // The chunks share the rate limit
+    let rate_limit = settings.rate_limit.map(|limit| (limit / ranges.len() as u64).max(1));
+
+    events.emit(UpdateEvent::DownloadStarted { url: url.to_string(), total_bytes: Some(total_bytes) });
+    let (progress, reads) = mpsc::channel();
+    let parts = thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .iter()
+            .enumerate()
+            .map(|(index, &(start, end))| {
+                let progress = progress.clone();
+                let source = sources[index % sources.len()];
+                scope.spawn(move || fetch_range(client, source, start, end, settings, rate_limit, &progress))
+            })
+            .collect();
+        drop(progress);
+
+        // Progress events go out from here, as handlers don't have to be thread safe
+        let mut downloaded_bytes = 0;
+        for read in reads {
+            downloaded_bytes += read as u64;
+            events.emit(UpdateEvent::DownloadProgress {
+                url: url.to_string(),
+                downloaded_bytes,
+                total_bytes: Some(total_bytes),
+            });
+        }
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("A download thread panicked"))))
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let body = parts.concat();
+    if body.len() as u64 != total_bytes {
+        return Err(anyhow::anyhow!("Reassembled {} has {} bytes instead of {}", url, body.len(), total_bytes));
+    }
+    events.emit(UpdateEvent::DownloadFinished { url: url.to_string() });
+    Ok(body)
+}
+
+// Downloads `url` from the `sources` that have a copy of it, each serving some
+// of its `total_bytes` as byte ranges
+#[cfg(feature = "p2p")]
+pub(crate) fn download_shared(url: &str, sources: &[&str], total_bytes: u64, settings: &DownloadSettings, events: &mut Events) -> Result<Vec<u8>> {
+    if sources.is_empty() {
+        return Err(anyhow::anyhow!("No peer has a copy of {}", url));
+    }
+    let client = http::client(ClientConfig::artifacts())?;
+    // A few ranges per source, so a slow peer holds up less of the download
+    let chunks = (sources.len() * 2).max(settings.parallel_chunks);
+    download_parallel(&client, url, sources, total_bytes, chunks, settings, events)
+}
+
+// Downloads `url`, as stored on the server, see `download_checked`
+#[cfg(feature = "sigstore")]
+pub(crate) fn download(url: &str, settings: &DownloadSettings, events: &mut Events) -> Result<Vec<u8>> {
+    download_checked(url, settings, events, |_| Ok(()))
+}
+
+// Downloads `url`, from the artifact mirror first if there is one. The copy on the
+// mirror has to pass the same `check` (e.g. its hash) as the original; when it
+// can't be fetched or doesn't pass, `url` itself is downloaded.
+pub(crate) fn download_checked(
+    url: &str,
+    settings: &DownloadSettings,
+    events: &mut Events,
+    check: impl Fn(&[u8]) -> Result<()>,
+) -> Result<Vec<u8>> {
+    if let Some(mirror_url) = settings.mirror.as_deref().and_then(|mirror| mirror_url(mirror, url)) {
+        match fetch(&mirror_url, settings, events).and_then(|body| check(&body).map(|()| body)) {
+            Ok(body) => return Ok(body),
+            Err(e) => warn!(target: INSTALL, "Mirror copy {} unusable, downloading {}: {:#}", mirror_url, url, e),
+        }
+    }
+    let body = fetch(url, settings, events)?;
+    check(&body)?;
+    Ok(body)
+}
+
+// Downloads `url` from wherever it points
+fn fetch(url: &str, settings: &DownloadSettings, events: &mut Events) -> Result<Vec<u8>> {
+    debug!(target: INSTALL, "Downloading {}", url);
+    if let Some(body) = url_resolver::fetch(url) {
+        return body;
+    }
+    let client = http::client(ClientConfig::artifacts())?;
+
+    // Large artifacts come faster as parallel ranges, when the server allows them
+    if settings.parallel_chunks > 1 {
+        match range_support(&client, url, settings.github_token.as_deref()) {
+            Some(total_bytes) if total_bytes >= MIN_PARALLEL_SIZE => {
+                return download_parallel(&client, url, &[url], total_bytes, settings.parallel_chunks, settings, events);
+            }
+            Some(_) => debug!(target: INSTALL, "{} is small, fetching it in one go", url),
+            None => debug!(target: INSTALL, "{} can't be fetched in ranges, fetching it in one go", url),
+        }
+    }
+
+    let response = client
+        .get(url)
+        .github_token(settings.github_token.as_deref())
+        .send()
+        .with_context(|| format!("Failed to fetch {}", url))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("Failed to fetch {}: HTTP {}", url, status));
+    }
+
+    let total_bytes = response.content_length();
+    events.emit(UpdateEvent::DownloadStarted { url: url.to_string(), total_bytes });
+    let mut downloaded_bytes = 0;
+    let body = read_body(response, url, settings.rate_limit, |read| {
+        downloaded_bytes += read as u64;
+        events.emit(UpdateEvent::DownloadProgress {
+            url: url.to_string(),
+            downloaded_bytes,
+            total_bytes,
+        });
+    })?;
+    events.emit(UpdateEvent::DownloadFinished { url: url.to_string() });
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    // Byte ranges asked of a test server, inclusive as in the `Range` header
+    type Requested = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    // Serves `body` over HTTP with byte ranges, on a port of its own, cutting every
+    // range short by `missing` bytes. Returns its URL and the ranges it was asked for.
+    fn range_server(body: Vec<u8>, missing: u64) -> (String, Requested) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}/artifact.bin", listener.local_addr().unwrap());
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let ranges = Arc::clone(&requested);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((start.parse::<u64>().unwrap(), end.parse::<u64>().unwrap()));
+                    }
+                }
+                let (status, part) = match range {
+                    Some((start, end)) => {
+                        ranges.lock().unwrap().push((start, end));
+                        let end = (end + 1).saturating_sub(missing).max(start);
+                        ("206 Partial Content", &body[start as usize..end as usize])
+                    }
+                    None => ("200 OK", &body[..]),
+                };
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n", status, part.len());
+                if !request_line.starts_with("HEAD") {
+                    let _ = stream.write_all(part);
+                }
+            }
+        });
+        (url, requested)
+    }
+
+    // An artifact large enough to be split, with every byte depending on its offset
+    fn artifact() -> Vec<u8> {
+        (0..MIN_PARALLEL_SIZE + 12_345).map(|offset| (offset % 251) as u8).collect()
+    }
+
+    fn recorded_events() -> (Events, Arc<Mutex<Vec<UpdateEvent>>>) {
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mut events = Events::default();
+        let sink = Arc::clone(&recorded);
+        events.add(Box::new(move |event| sink.lock().unwrap().push(event.clone())));
+        (events, recorded)
+    }
+
+    #[test]
+    fn large_artifact_is_fetched_in_ranges_and_reassembled() {
+        let body = artifact();
+        let (url, requested) = range_server(body.clone(), 0);
+        let settings = DownloadSettings { parallel_chunks: 4, ..DownloadSettings::default() };
+        let (mut events, recorded) = recorded_events();
+
+        assert_eq!(fetch(&url, &settings, &mut events).unwrap(), body);
+
+        // Four ranges covering every byte once, the last one shorter
+        let mut ranges = requested.lock().unwrap().clone();
+        ranges.sort();
+        let total = body.len() as u64;
+        let chunk = total.div_ceil(4);
+        assert_eq!(ranges, [(0, chunk - 1), (chunk, 2 * chunk - 1), (2 * chunk, 3 * chunk - 1), (3 * chunk, total - 1)]);
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.first(), Some(&UpdateEvent::DownloadStarted { url: url.clone(), total_bytes: Some(total) }));
+        assert!(recorded.contains(&UpdateEvent::DownloadProgress { url: url.clone(), downloaded_bytes: total, total_bytes: Some(total) }));
+        assert_eq!(recorded.last(), Some(&UpdateEvent::DownloadFinished { url }));
+    }
+
+    #[test]
+    fn short_range_fails_the_download() {
+        let (url, _) = range_server(artifact(), 10);
+        let settings = DownloadSettings { parallel_chunks: 3, ..DownloadSettings::default() };
+
+        let error = fetch(&url, &settings, &mut Events::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("instead of"), "{:#}", error);
+    }
+
+    #[test]
+    fn small_artifact_is_fetched_in_one_go() {
+        let body = b"not worth splitting".to_vec();
+        let (url, requested) = range_server(body.clone(), 0);
+        let settings = DownloadSettings { parallel_chunks: 4, ..DownloadSettings::default() };
+
+        assert_eq!(fetch(&url, &settings, &mut Events::default()).unwrap(), body);
+        assert!(requested.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ranges_are_spread_over_the_sources() {
+        let body = artifact();
+        let (first, first_requested) = range_server(body.clone(), 0);
+        let (second, second_requested) = range_server(body.clone(), 0);
+        let client = http::client(ClientConfig::artifacts()).unwrap();
+        let settings = DownloadSettings::default();
+
+        let downloaded = download_parallel(&client, &first, &[&first, &second], body.len() as u64, 4, &settings, &mut Events::default());
+        assert_eq!(downloaded.unwrap(), body);
+        assert_eq!(first_requested.lock().unwrap().len(), 2);
+        assert_eq!(second_requested.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn no_source_is_an_error() {
+        let client = http::client(ClientConfig::artifacts()).unwrap();
+        let downloaded = download_parallel(&client, "http://127.0.0.1:9/artifact.bin", &[], 10, 2, &DownloadSettings::default(), &mut Events::default());
+        assert!(downloaded.is_err());
+    }
+}
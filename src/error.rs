@@ -0,0 +1,150 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The error returned by rspawn's fallible functions, see `RspawnError`.
+#[cfg(feature = "install")]
+use std::io;
+#[cfg(feature = "install")]
+use std::path::PathBuf;
+#[cfg(feature = "install")]
+use std::process::ExitStatus;
+use thiserror::Error;
+#[cfg(feature = "install")]
+use crate::config::ConfigError;
+#[cfg(feature = "install")]
+use crate::hold::HeldByOperator;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::http;
+#[cfg(feature = "install")]
+use crate::install::{InstallError, InstallFailureKind};
+#[cfg(feature = "install")]
+use crate::integrity::IntegrityError;
+#[cfg(feature = "install")]
+use crate::preflight::EnvironmentUnsupported;
+
+/// Why a call into rspawn failed, so callers can match on the kind of failure.
+///
+/// Returned by the update entry points (`relaunch_program`, `RSpawn::plan`,
+/// `UpdatePlan::apply`) and by every other public function that can fail,
+/// from `check_update` and `latest_version` to `restore_backup` and
+/// `set_setting`. Check-only builds (`default-features = false`) have the
+/// variants that don't involve installing. Traits the program implements
+/// (`CredentialStore`, `UrlResolver`, `DecisionProvider`...) keep returning
+/// `anyhow::Result`; their errors come back sorted into these kinds.
+///
+/// Variants wrapping an `anyhow::Error` display it unchanged, with its causes
+/// available through `source()`; `{:#}` prints the whole chain.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RspawnError {
+    /// The registry (or another server) could not be reached.
+    #[error(transparent)]
+    NetworkError(anyhow::Error),
+    /// A response, manifest or file could not be parsed.
+    #[error(transparent)]
+    ParseError(anyhow::Error),
+    /// A program rspawn runs could not be found: cargo, to install the update,
+    /// or the new version, to relaunch it.
+    #[cfg(feature = "install")]
+    #[error("{} was not found. Make sure it is installed and on your PATH.", program.display())]
+    NotOnPath {
+        /// The program that was looked for.
+        program: PathBuf,
+    },
+    /// `cargo install` ran but exited unsuccessfully.
+    #[cfg(feature = "install")]
+    #[error("cargo install failed with {kind} ({status}). {}", kind.hint())]
+    InstallFailed {
+        /// Cargo's exit status.
+        status: ExitStatus,
+        /// The classified kind of failure.
+        kind: InstallFailureKind,
+        /// The last lines cargo printed on stderr.
+        stderr: String,
+    },
+    /// The new version was installed, but could not be started.
+    #[cfg(feature = "install")]
+    #[error(transparent)]
+    RelaunchFailed(anyhow::Error),
+    /// Another instance of the program is already updating it, see `LockBehavior`.
+    #[cfg(feature = "install")]
+    #[error("Program is already being updated by another instance (lock file {}); avoiding infinite loop.", lock_path.display())]
+    AlreadyRunning {
+        /// The lock file held by the other instance.
+        lock_path: PathBuf,
+    },
+    /// Updates are frozen by the operator's hold file.
+    #[cfg(feature = "install")]
+    #[error(transparent)]
+    Held(#[from] HeldByOperator),
+    /// `cargo install` can't work in this environment.
+    #[cfg(feature = "install")]
+    #[error(transparent)]
+    Environment(#[from] EnvironmentUnsupported),
+    /// The running binary did not match the recorded install.
+    #[cfg(feature = "install")]
+    #[error(transparent)]
+    Integrity(#[from] IntegrityError),
+    /// The builder is missing options the update needs, see `RSpawn::build`.
+    #[cfg(feature = "install")]
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    /// Anything else.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+// Whether `cause` comes from the HTTP client failing to reach a server
+fn is_network_error(cause: &(dyn std::error::Error + 'static)) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    return http::is_network_error(cause);
+    #[cfg(target_arch = "wasm32")]
+    return cause.is::<reqwest::Error>();
+}
+
+// Sorts errors into kinds, from their chain of causes
+impl From<anyhow::Error> for RspawnError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<RspawnError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        #[cfg(feature = "install")]
+        let error = match error.downcast::<InstallError>() {
+            Ok(InstallError::InstallFailed { status, kind, stderr }) => return RspawnError::InstallFailed { status, kind, stderr },
+            Ok(InstallError::Spawn(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return RspawnError::NotOnPath { program: PathBuf::from("cargo") };
+            }
+            Ok(install) => return RspawnError::Other(install.into()),
+            Err(error) => error,
+        };
+        #[cfg(feature = "install")]
+        if error.is::<HeldByOperator>() {
+            return error.downcast().map_or_else(RspawnError::Other, RspawnError::Held);
+        } else if error.is::<EnvironmentUnsupported>() {
+            return error.downcast().map_or_else(RspawnError::Other, RspawnError::Environment);
+        } else if error.is::<IntegrityError>() {
+            return error.downcast().map_or_else(RspawnError::Other, RspawnError::Integrity);
+        } else if error.is::<ConfigError>() {
+            return error.downcast().map_or_else(RspawnError::Other, RspawnError::Config);
+        }
+        if error.chain().any(is_network_error) {
+            return RspawnError::NetworkError(error);
+        }
+        #[cfg(feature = "json")]
+        if error.chain().any(|cause| cause.is::<serde_json::Error>()) {
+            return RspawnError::ParseError(error);
+        }
+        RspawnError::Other(error)
+    }
+}
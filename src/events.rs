@@ -0,0 +1,162 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Serialize, Serializer};
+use crate::cli::EventFormat;
+use crate::install::InstalledFile;
+use crate::json;
+use crate::path_hint::Shell;
+
+/// Something that happened during an update, as reported to `RSpawn::on_event` handlers.
+///
+/// Serializes with the event's name in `"event"` (e.g. `"install_started"`) next
+/// to its fields, as `RSpawn::event_stream` writes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum UpdateEvent {
+    /// Started looking up the latest version of a crate.
+    CheckStarted {
+        crate_name: String,
+    },
+    /// Finished looking up the latest version.
+    CheckFinished {
+        current_version: String,
+        latest_version: String,
+    },
+    /// Started downloading a file.
+    DownloadStarted {
+        url: String,
+        /// Size of the download, when the server reported it.
+        total_bytes: Option<u64>,
+    },
+    /// Some more of a download arrived.
+    DownloadProgress {
+        url: String,
+        downloaded_bytes: u64,
+        total_bytes: Option<u64>,
+    },
+    /// A download completed.
+    DownloadFinished {
+        url: String,
+    },
+    /// `cargo install` was started.
+    InstallStarted {
+        version: String,
+    },
+    /// `cargo install` printed a line on stderr (e.g. "Compiling tokio v1.38.0").
+    InstallOutput {
+        line: String,
+    },
+    /// Another package of the build was compiled.
+    InstallProgress {
+        /// Packages compiled so far.
+        compiled: usize,
+        /// How many packages cargo locked for the build, when it said so. It's
+        /// an estimate: packages only used on other platforms are never compiled.
+        total: Option<usize>,
+    },
+    /// `cargo install` is still running, sent every `RSpawn::install_heartbeat`
+    /// interval, for UIs to show something for builds that have no percentage.
+    InstallHeartbeat {
+        /// Time since `cargo install` started, serialized in seconds.
+        #[serde(serialize_with = "serialize_secs")]
+        elapsed: Duration,
+        /// The last status line cargo printed, trimmed (e.g. "Compiling tokio v1.38.0").
+        last_status: Option<String>,
+    },
+    /// `cargo install` exited.
+    InstallFinished {
+        version: String,
+        success: bool,
+    },
+    /// How much of the build sccache answered from its cache, after `InstallFinished`
+    /// when the update was built with sccache, see `RSpawn::build_wrapper`.
+    CompilerCache {
+        /// Compilations answered from the cache.
+        hits: u64,
+        /// Compilations that had to run, and were cached for next time.
+        misses: u64,
+    },
+    /// The update is in place: the binaries `cargo install --list` has for the
+    /// crate, for hooks and health checks to know what to look at.
+    Installed {
+        files: Vec<InstalledFile>,
+    },
+    /// Another binary with the same name comes first in PATH, so `winner`
+    /// runs instead of the newly installed `loser` when the program is typed.
+    /// The relaunch uses `loser` directly.
+    ShadowedInstall {
+        winner: PathBuf,
+        loser: PathBuf,
+    },
+    /// The directory the new version was installed into isn't in PATH, so
+    /// new shell sessions won't find the program by name. `export_line` adds
+    /// it for `shell`, to be put in the shell's profile.
+    NotInPath {
+        dir: PathBuf,
+        shell: Shell,
+        export_line: String,
+    },
+    /// The new version is about to be launched.
+    Relaunching,
+    /// The new version was installed and runs from the next start, see `RelaunchMode::OnNextStart`.
+    RestartPending {
+        version: String,
+    },
+    /// A binary set with `RSpawn::target_binary` was updated, and can be restarted.
+    TargetInstalled {
+        name: String,
+        version: String,
+    },
+}
+
+pub(crate) type EventHandler = Box<dyn FnMut(&UpdateEvent) + Send>;
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+// Handler writing each event on stderr as it happens, see `RSpawn::event_stream`
+pub(crate) fn stream(format: EventFormat) -> EventHandler {
+    Box::new(move |event| match format {
+        EventFormat::Ndjson => {
+            if let Ok(line) = json::to_string(event) {
+                let mut stderr = io::stderr().lock();
+                let _ = writeln!(stderr, "{line}");
+                let _ = stderr.flush();
+            }
+        }
+    })
+}
+
+// Handlers registered for `UpdateEvent`s, called in registration order
+#[derive(Default)]
+pub(crate) struct Events {
+    handlers: Vec<EventHandler>,
+}
+
+impl Events {
+    pub(crate) fn add(&mut self, handler: EventHandler) {
+        self.handlers.push(handler);
+    }
+
+    pub(crate) fn emit(&mut self, event: UpdateEvent) {
+        for handler in self.handlers.iter_mut() {
+            handler(&event);
+        }
+    }
+}
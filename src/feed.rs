@@ -0,0 +1,305 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Version discovery from RSS/Atom release feeds, like the ones GitHub
+//! (`https://github.com/<owner>/<repo>/releases.atom`) and GitLab publish.
+//!
+//! Only the handful of elements those feeds use are read, so this is a small
+//! scanner rather than a full XML parser.
+use std::path::PathBuf;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::{info, debug};
+use crate::log_target::CHECK;
+use crate::http::{self, ClientConfig};
+use crate::registry::is_on_channel;
+use crate::url_resolver;
+use crate::version::{compare_versions, is_prerelease};
+
+/// Where rspawn looks for new versions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum VersionSource {
+    /// The crates.io API (the default).
+    #[default]
+    CratesIo,
+    /// An RSS or Atom release feed. Entry titles (or, failing that, links) must
+    /// contain the version, e.g. "v1.2.0" or "Release 1.2.0". The highest
+    /// version listed (stable, unless a channel is set) is still installed
+    /// from crates.io.
+    Feed(String),
+    /// A local registry (made with `cargo local-registry`) or a directory of
+    /// vendored sources (made with `cargo vendor`), for machines that can't
+    /// reach crates.io. The highest version in it is installed from it, with
+    /// `cargo install --offline`.
+    Mirror(PathBuf),
+}
+
+/// A release listed in a feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedRelease {
+    /// The version, without any leading `v`.
+    pub version: String,
+    /// The entry's title.
+    pub title: String,
+    /// Link to the release notes, if the entry has one.
+    pub link: Option<String>,
+}
+
+// Text between the first `<tag ...>` and its `</tag>` in `xml`
+fn element_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = find_open_tag(xml, tag)?;
+    let content_start = start + xml[start..].find('>')? + 1;
+    if xml[..content_start].ends_with("/>") {
+        return None;
+    }
+    let content_end = content_start + xml[content_start..].find(&format!("</{}>", tag))?;
+    Some(&xml[content_start..content_end])
+}
+
+// Position of the first `<tag` followed by a delimiter (so `<link` doesn't match `<linkage`)
+fn find_open_tag(xml: &str, tag: &str) -> Option<usize> {
+    let needle = format!("<{}", tag);
+    let mut offset = 0;
+    while let Some(found) = xml[offset..].find(&needle) {
+        let position = offset + found;
+        match xml[position + needle.len()..].chars().next() {
+            Some(c) if c == '>' || c == '/' || c.is_whitespace() => return Some(position),
+            _ => offset = position + needle.len(),
+        }
+    }
+    None
+}
+
+// Value of `name="..."` within a tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let needle = format!(" {}=", name);
+    let start = tag.find(&needle)? + needle.len();
+    let quote = tag[start..].chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value_start = start + 1;
+    let value_end = value_start + tag[value_start..].find(quote)?;
+    Some(&tag[value_start..value_end])
+}
+
+fn unescape(text: &str) -> String {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|text| text.strip_suffix("]]>"))
+        .unwrap_or(text);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// The entry's release notes link: Atom's `<link rel="alternate" href=...>`, or RSS's `<link>url</link>`
+fn entry_link(entry: &str) -> Option<String> {
+    let mut offset = 0;
+    while let Some(found) = find_open_tag(&entry[offset..], "link") {
+        let start = offset + found;
+        let end = start + entry[start..].find('>')?;
+        let tag = &entry[start..=end];
+        if let Some(href) = attribute(tag, "href") {
+            if attribute(tag, "rel").is_none_or(|rel| rel == "alternate") {
+                return Some(unescape(href));
+            }
+        } else if let Some(text) = element_text(&entry[start..], "link") {
+            return Some(unescape(text));
+        }
+        offset = end;
+    }
+    None
+}
+
+// First thing in `text` that looks like a version: digits, a dot, and more,
+// with an optional leading `v` and prerelease/build suffix. Anything a version
+// can't contain separates words, e.g. the markup of HTML titles or a URL's query.
+fn find_version(text: &str) -> Option<String> {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+')))
+        .map(|word| word.trim_start_matches(['v', 'V']))
+        .map(|word| word.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .find(|word| {
+            let release = word.split(['-', '+']).next().unwrap_or(word);
+            let mut parts = release.split('.');
+            release.contains('.') && parts.all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(str::to_string)
+}
+
+/// Lists the releases in an RSS or Atom feed, in feed order (newest first for
+/// GitHub and GitLab). Entries without a recognizable version are skipped.
+pub fn parse_feed(xml: &str) -> Vec<FeedRelease> {
+    let entry_tag = if find_open_tag(xml, "entry").is_some() { "entry" } else { "item" };
+    let mut releases = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = find_open_tag(rest, entry_tag) {
+        let Some(end) = rest[start..].find(&format!("</{}>", entry_tag)) else {
+            break;
+        };
+        let entry = &rest[start..start + end];
+        rest = &rest[start + end..];
+
+        let title = element_text(entry, "title").map(unescape).unwrap_or_default();
+        let link = entry_link(entry);
+        let version = find_version(&title).or_else(|| link.as_deref().and_then(find_version));
+        match version {
+            Some(version) => releases.push(FeedRelease { version, title, link }),
+            None => debug!(target: CHECK, "Skipping feed entry without a version: {:?}", title),
+        }
+    }
+    releases
+}
+
+// The highest release on `channel`, or the highest stable one without a channel.
+// Feeds list releases by publication, so a backport may come before a newer major.
+fn highest_release(releases: Vec<FeedRelease>, channel: Option<&str>) -> Option<FeedRelease> {
+    releases
+        .into_iter()
+        .filter(|release| match channel {
+            Some(channel) => is_on_channel(&release.version, channel),
+            None => !is_prerelease(&release.version),
+        })
+        // Reversed, so the first of equal versions in the feed wins
+        .rev()
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
+// The highest release in the feed at `url`, on `channel` if given. A GitHub
+// feed is fetched with `github_token`, if there is one.
+pub(crate) fn latest_release(url: &str, channel: Option<&str>, github_token: Option<&str>) -> Result<FeedRelease> {
+    info!(target: CHECK, "Fetching release feed from: {}", url);
+    let xml = match url_resolver::fetch_text(url) {
+        Some(xml) => xml.context("Failed to fetch release feed")?,
+        None => {
+            let response = http::client(ClientConfig::default())?
+                .get(url)
+                .github_token(github_token)
+                .send()
+                .with_context(|| format!("Failed to fetch release feed {}", url))?;
+
+            let status = response.status();
+            if !status.is_success() {
+                return Err(anyhow::anyhow!("Failed to fetch release feed {}: HTTP {}", url, status));
+            }
+            response.text().context("Failed to read release feed")?
+        }
+    };
+
+    highest_release(parse_feed(&xml), channel)
+        .ok_or_else(|| anyhow::anyhow!("No release found in feed {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Release notes from mytool</title>
+  <link type="text/html" rel="alternate" href="https://github.com/owner/mytool/releases"/>
+  <entry>
+    <id>tag:github.com,2008:Repository/1/v1.4.5</id>
+    <link rel="alternate" type="text/html" href="https://github.com/owner/mytool/releases/tag/v1.4.5"/>
+    <title>v1.4.5 &amp; backports</title>
+  </entry>
+  <entry>
+    <link rel="enclosure" href="https://example.com/mytool-2.1.0-beta.1.tar.gz"/>
+    <linkage>https://example.com/not-a-link</linkage>
+    <link rel="alternate" href="https://github.com/owner/mytool/releases/tag/v2.1.0-beta.1"/>
+    <title>Release 2.1.0-beta.1</title>
+  </entry>
+  <entry>
+    <link rel="alternate" href="https://github.com/owner/mytool/releases/tag/v2.0.0?a=1&amp;b=2"/>
+    <title type="html">&lt;b&gt;v2.0.0&lt;/b&gt;</title>
+  </entry>
+  <entry>
+    <title>Nightly build</title>
+  </entry>
+</feed>"#;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>mytool releases</title>
+    <link>https://gitlab.com/owner/mytool/-/releases</link>
+    <item>
+      <title><![CDATA[mytool 0.9.1 <fixes>]]></title>
+      <link>https://gitlab.com/owner/mytool/-/releases/v0.9.1</link>
+    </item>
+    <item>
+      <title>Untitled</title>
+      <link><![CDATA[https://gitlab.com/owner/mytool/-/releases/v0.9.0]]></link>
+    </item>
+  </channel>
+</rss>"#;
+
+    fn versions(releases: &[FeedRelease]) -> Vec<&str> {
+        releases.iter().map(|release| release.version.as_str()).collect()
+    }
+
+    #[test]
+    fn atom_entries_are_read_in_feed_order() {
+        let releases = parse_feed(ATOM);
+        assert_eq!(versions(&releases), ["1.4.5", "2.1.0-beta.1", "2.0.0"]);
+        assert_eq!(releases[0].title, "v1.4.5 & backports");
+        assert_eq!(releases[0].link.as_deref(), Some("https://github.com/owner/mytool/releases/tag/v1.4.5"));
+        // Neither the enclosure nor `<linkage>` is the release notes link
+        assert_eq!(releases[1].link.as_deref(), Some("https://github.com/owner/mytool/releases/tag/v2.1.0-beta.1"));
+        assert_eq!(releases[2].title, "<b>v2.0.0</b>");
+        assert_eq!(releases[2].link.as_deref(), Some("https://github.com/owner/mytool/releases/tag/v2.0.0?a=1&b=2"));
+    }
+
+    #[test]
+    fn rss_items_take_the_version_from_the_title_or_link() {
+        let releases = parse_feed(RSS);
+        assert_eq!(versions(&releases), ["0.9.1", "0.9.0"]);
+        assert_eq!(releases[0].title, "mytool 0.9.1 <fixes>");
+        assert_eq!(releases[1].link.as_deref(), Some("https://gitlab.com/owner/mytool/-/releases/v0.9.0"));
+    }
+
+    #[test]
+    fn versions_are_found_in_titles_and_links() {
+        for (text, version) in [
+            ("v1.2.0", Some("1.2.0")),
+            ("Release 1.2.0-rc.1: the big one", Some("1.2.0-rc.1")),
+            ("mytool@1.2.0+build.7", Some("1.2.0+build.7")),
+            ("https://example.com/releases/tag/V3.0", Some("3.0")),
+            ("mytool_2.0.1.", Some("2.0.1")),
+            ("Version 2 is out", None),
+            ("1..2", None),
+            ("", None),
+        ] {
+            assert_eq!(find_version(text).as_deref(), version, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn links_are_read_from_atom_and_rss() {
+        assert_eq!(entry_link(r#"<entry><link href="https://a/1"/></entry>"#).as_deref(), Some("https://a/1"));
+        assert_eq!(entry_link(r#"<entry><link rel='self' href="https://a/self"/><link href='https://a/2'/></entry>"#).as_deref(), Some("https://a/2"));
+        assert_eq!(entry_link("<item><link>https://a/3?x=1&amp;y=2</link></item>").as_deref(), Some("https://a/3?x=1&y=2"));
+        assert_eq!(entry_link("<item><linkage>https://a/4</linkage></item>"), None);
+        assert_eq!(entry_link("<item><title>none</title></item>"), None);
+    }
+
+    #[test]
+    fn highest_release_wins_over_feed_order() {
+        assert_eq!(highest_release(parse_feed(ATOM), None).unwrap().version, "2.0.0");
+        assert_eq!(highest_release(parse_feed(ATOM), Some("beta")).unwrap().version, "2.1.0-beta.1");
+        assert_eq!(highest_release(parse_feed(ATOM), Some("rc")).unwrap().version, "2.0.0");
+        assert!(highest_release(Vec::new(), None).is_none());
+    }
+}
@@ -0,0 +1,445 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// An advisory lock on a file, released when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: Option<fs::File>,
+}
+
+impl FileLock {
+    /// Wraps a file an exclusive lock was taken on, which closing the file releases.
+    pub fn from_file(file: fs::File) -> Self {
+        FileLock { _file: Some(file) }
+    }
+
+    /// A lock that holds nothing, for filesystems that serialize access on their own.
+    pub fn noop() -> Self {
+        FileLock { _file: None }
+    }
+}
+
+/// The filesystem operations rspawn uses for its lock, state and cache files.
+///
+/// `RealFs` is used unless another one is set with `RSpawn::fs`; `MemoryFs`
+/// keeps everything in memory, so cleanup and corruption paths can be tested
+/// without touching the host filesystem.
+pub trait Fs: Send + Sync {
+    /// Reads a whole file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Creates or truncates a file with `contents`.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    /// Creates a file only the current user can access (0600 on Unix),
+    /// failing with `AlreadyExists` if there already is one.
+    fn create_new(&self, path: &Path) -> io::Result<()>;
+    /// Renames a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Creates a directory and all of its parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Removes an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    /// Creates a directory only the current user can access (0700 on Unix), whose
+    /// parent must exist. An existing one is only accepted if it is a real directory
+    /// owned by the current user, and is made private if it wasn't.
+    fn create_private_dir(&self, path: &Path) -> io::Result<()>;
+    /// Whether a file or directory exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+    /// Takes an exclusive advisory lock on `path` (created if needed), blocking
+    /// until any other holder releases it.
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock>;
+
+    /// Makes the file at `from` also appear at `to`, failing with `AlreadyExists`
+    /// if there already is one. Filesystems without hard links fail with any
+    /// other error, which is the default.
+    fn hard_link(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "hard links are not supported"))
+    }
+
+    /// When the file was last written. Filesystems that don't keep track fail,
+    /// which is the default.
+    fn modified(&self, _path: &Path) -> io::Result<SystemTime> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "modification times are not kept"))
+    }
+
+    /// Reads a whole file as UTF-8.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Copies a file, replacing `to` if it exists. Filesystems with permissions
+    /// should copy them too, so a copied binary stays executable.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)
+    }
+}
+
+/// The host filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path).map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    #[cfg(unix)]
+    fn create_private_dir(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+        match fs::DirBuilder::new().mode(0o700).create(path) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+
+        // Don't follow symlinks: someone else may have planted one in a shared temp dir
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        // SAFETY: geteuid has no preconditions and cannot fail
+        if metadata.uid() != unsafe { libc::geteuid() } {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is owned by another user", path.display()),
+            ));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn create_private_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(path)?;
+        lock_exclusive(&file)?;
+        Ok(FileLock::from_file(file))
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+}
+
+// Blocks until `file` is locked exclusively. `File::lock` would do, but needs Rust 1.89.
+#[cfg(unix)]
+fn lock_exclusive(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    loop {
+        // SAFETY: the descriptor is open for as long as `file` lives
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock_exclusive(file: &fs::File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+
+    // OVERLAPPED from minwinbase.h
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut std::ffi::c_void,
+    }
+    // From fileapi.h and minwinbase.h
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut std::ffi::c_void,
+            flags: u32,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x0000_0002;
+
+    let mut overlapped = Overlapped { internal: 0, internal_high: 0, offset: 0, offset_high: 0, event: std::ptr::null_mut() };
+    // SAFETY: the handle is open for as long as `file` lives, and the call is
+    // synchronous, so `overlapped` outlives it
+    let locked = unsafe { LockFileEx(file.as_raw_handle(), LOCKFILE_EXCLUSIVE_LOCK, 0, u32::MAX, u32::MAX, &mut overlapped) };
+    if locked == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// No advisory locks: concurrent updates of the same file may lose changes
+#[cfg(not(any(unix, windows)))]
+fn lock_exclusive(_file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct MemoryFsInner {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    dirs: BTreeSet<PathBuf>,
+    modified: BTreeMap<PathBuf, SystemTime>,
+}
+
+/// An in-memory filesystem.
+///
+/// Parent directories are not required to exist, and every path is taken
+/// literally (no normalization). Hard links are copies, and files are stamped
+/// with the host's time when written unless `set_modified` says otherwise.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    inner: Mutex<MemoryFsInner>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl MemoryFs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        MemoryFs::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryFsInner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the paths of all files, sorted.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.lock().files.keys().cloned().collect()
+    }
+
+    /// Sets when the file at `path` was last written, e.g. to a `MockClock`'s time.
+    pub fn set_modified(&self, path: &Path, modified: SystemTime) {
+        self.lock().modified.insert(path.to_path_buf(), modified);
+    }
+}
+
+impl Fs for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.lock().files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.files.insert(path.to_path_buf(), contents.to_vec());
+        inner.modified.insert(path.to_path_buf(), SystemTime::now());
+        Ok(())
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.files.contains_key(path) || inner.dirs.contains(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+        }
+        inner.files.insert(path.to_path_buf(), Vec::new());
+        inner.modified.insert(path.to_path_buf(), SystemTime::now());
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        let contents = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+        let modified = inner.modified.remove(from).unwrap_or_else(SystemTime::now);
+        inner.files.insert(to.to_path_buf(), contents);
+        inner.modified.insert(to.to_path_buf(), modified);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.modified.remove(path);
+        inner.files.remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            inner.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        if !inner.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        let in_dir = |entry: &PathBuf| entry != path && entry.starts_with(path);
+        if inner.files.keys().any(in_dir) || inner.dirs.iter().any(in_dir) {
+            return Err(io::Error::other(format!("{} is not empty", path.display())));
+        }
+        inner.dirs.remove(path);
+        Ok(())
+    }
+
+    fn create_private_dir(&self, path: &Path) -> io::Result<()> {
+        self.create_dir_all(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let inner = self.lock();
+        inner.files.contains_key(path) || inner.dirs.contains(path)
+    }
+
+    // Not serialized: tests driving concurrent writers need a real filesystem
+    fn lock_file(&self, _path: &Path) -> io::Result<FileLock> {
+        Ok(FileLock::noop())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.files.contains_key(to) || inner.dirs.contains(to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+        }
+        let contents = inner.files.get(from).cloned().ok_or_else(|| not_found(from))?;
+        let modified = inner.modified.get(from).copied().unwrap_or_else(SystemTime::now);
+        inner.files.insert(to.to_path_buf(), contents);
+        inner.modified.insert(to.to_path_buf(), modified);
+        Ok(())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.lock().modified.get(path).copied().ok_or_else(|| not_found(path))
+    }
+}
+
+// Lets a filesystem shared with the host program (e.g. an `Arc<MemoryFs>` kept
+// by a test to inspect it afterwards) be handed to the builder
+impl<T: Fs + ?Sized> Fs for std::sync::Arc<T> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        (**self).read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        (**self).write(path, contents)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<()> {
+        (**self).create_new(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).copy(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        (**self).create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn create_private_dir(&self, path: &Path) -> io::Result<()> {
+        (**self).create_private_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock> {
+        (**self).lock_file(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).hard_link(from, to)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        (**self).modified(path)
+    }
+}
@@ -0,0 +1,84 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Recorded registry responses, for deterministic tests and offline demos.
+//!
+//! With `RSPAWN_RECORD_REGISTRY=<dir>`, every successful crates.io response is
+//! saved under `<dir>` (e.g. `<dir>/mytool/versions.json`). With
+//! `RSPAWN_REPLAY_REGISTRY=<dir>`, checks are served from those files instead,
+//! without touching the network; a request with no recorded response fails.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use log::{debug, warn};
+use crate::log_target::CHECK;
+use crate::registry::CRATES_IO_URL;
+
+/// Environment variable naming a directory to save registry responses to.
+pub const RECORD_REGISTRY_ENV: &str = "RSPAWN_RECORD_REGISTRY";
+/// Environment variable naming a directory of saved registry responses to serve checks from.
+pub const REPLAY_REGISTRY_ENV: &str = "RSPAWN_REPLAY_REGISTRY";
+
+fn dir_from_env(name: &str) -> Option<PathBuf> {
+    env::var_os(name).filter(|dir| !dir.is_empty()).map(PathBuf::from)
+}
+
+// Where the response for `url` is kept in `dir`: one file per API path below the crates endpoint
+fn fixture_path(dir: &Path, url: &str) -> PathBuf {
+    let path = url
+        .strip_prefix(CRATES_IO_URL)
+        .map(|path| path.trim_start_matches("/api/v1/crates/"))
+        .unwrap_or(url);
+    let mut fixture = dir.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment: String = segment
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+') { c } else { '_' })
+            .collect();
+        // Keep every fixture inside `dir`
+        fixture.push(if segment.chars().all(|c| c == '.') { "_".to_string() } else { segment });
+    }
+    fixture.set_extension(match fixture.extension() {
+        Some(extension) => format!("{}.json", extension.to_string_lossy()),
+        None => "json".to_string(),
+    });
+    fixture
+}
+
+// The recorded response for `url`, when replaying; `None` means the request should go out
+pub(crate) fn replay(url: &str) -> Option<Result<String>> {
+    let dir = dir_from_env(REPLAY_REGISTRY_ENV)?;
+    let fixture = fixture_path(&dir, url);
+    debug!(target: CHECK, "Replaying {} from {}", url, fixture.display());
+    Some(
+        fs::read_to_string(&fixture)
+            .with_context(|| format!("No recorded response for {} (expected {})", url, fixture.display())),
+    )
+}
+
+// Saves the response to `url`, when recording
+pub(crate) fn record(url: &str, body: &str) {
+    let Some(dir) = dir_from_env(RECORD_REGISTRY_ENV) else {
+        return;
+    };
+    let fixture = fixture_path(&dir, url);
+    let saved = fixture
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&fixture, body));
+    match saved {
+        Ok(()) => debug!(target: CHECK, "Recorded {} to {}", url, fixture.display()),
+        Err(e) => warn!(target: CHECK, "Failed to record {} to {}: {}", url, fixture.display(), e),
+    }
+}
@@ -0,0 +1,75 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! How sizes, durations and dates are written for users, see `Formatter`.
+use std::time::Duration;
+
+/// Writes the sizes, durations and dates rspawn shows users: in its own status
+/// lines and warnings, and in the progress bars. Set with `RSpawn::formatter`
+/// to match the host program's conventions or locale.
+///
+/// Every method has a default, in English with binary units, so implementors
+/// only override what they need.
+pub trait Formatter: Send + Sync {
+    /// A size, e.g. `12.3 MiB`.
+    fn size(&self, bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if bytes < 1024 {
+            return format!("{bytes} B");
+        }
+        let mut size = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{size:.1} {}", UNITS[unit])
+    }
+
+    /// A duration, in its two largest units, e.g. `2h 5m` or `42s`.
+    fn duration(&self, duration: Duration) -> String {
+        let secs = duration.as_secs();
+        let parts = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+        let first = parts.iter().position(|(amount, _)| *amount > 0).unwrap_or(parts.len() - 1);
+        parts[first..]
+            .iter()
+            .take(2)
+            .filter(|(amount, _)| *amount > 0 || secs == 0)
+            .map(|(amount, unit)| format!("{amount}{unit}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// A moment relative to `now`, both in seconds since the Unix epoch, e.g.
+    /// `5m ago` or `in 2h`.
+    fn relative_time(&self, unix_time: u64, now: u64) -> String {
+        // Only the largest unit: "3d ago" reads better than "3d 4h ago"
+        let coarse = |secs: u64| match secs {
+            0..60 => format!("{secs}s"),
+            60..3600 => format!("{}m", secs / 60),
+            3600..86400 => format!("{}h", secs / 3600),
+            _ => format!("{}d", secs / 86400),
+        };
+        if unix_time > now {
+            format!("in {}", coarse(unix_time - now))
+        } else {
+            format!("{} ago", coarse(now - unix_time))
+        }
+    }
+}
+
+/// rspawn's own formatting, see `Formatter`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultFormatter;
+
+impl Formatter for DefaultFormatter {}
@@ -0,0 +1,29 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Entry points for the fuzz targets in `fuzz/` and the property tests in
+//! `tests/`, which can only reach the public API.
+//!
+//! Not part of the supported API: anything in here may change in any release.
+use anyhow::Result;
+use crate::registry;
+
+/// Parses a crates.io versions response, returning the latest version on `channel`.
+pub fn parse_latest_version(body: &str, channel: Option<&str>) -> Result<String> {
+    registry::parse_latest_version(body, channel).map(|latest| latest.num)
+}
+
+/// Whether version `num` is offered on `channel`.
+pub fn is_on_channel(num: &str, channel: &str) -> bool {
+    registry::is_on_channel(num, channel)
+}
@@ -0,0 +1,94 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Refusing to work with a peer that needs a newer version of the program,
+//! whether or not the program updates itself.
+use std::fmt;
+use log::warn;
+use crate::credentials::{CredentialStore, EnvCredentialStore};
+use crate::log_target::CHECK;
+use crate::registry::latest_version_with;
+use crate::version::VersionReq;
+
+/// Error returned by `require_min_version` when the running version doesn't
+/// satisfy the requirement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompatibleVersion {
+    /// The running version.
+    pub current_version: String,
+    /// The requirement it doesn't satisfy.
+    pub requirement: VersionReq,
+    /// The latest version on crates.io, if it satisfies the requirement.
+    pub available: Option<String>,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Version {} is not supported (requires {})", self.current_version, self.requirement)?;
+        if let Some(available) = &self.available {
+            write!(f, ": update to {}", available)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for IncompatibleVersion {}
+
+/// Checks the running version against a requirement declared elsewhere, e.g.
+/// by a server that refuses too old clients.
+///
+/// If it isn't satisfied, crates.io is asked for the latest version of
+/// `crate_name` (with the token from `RSPAWN_REGISTRY_TOKEN`, if any), so the
+/// error can tell whether updating would help. Failing to reach it is not an
+/// error: the check itself needs no network.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # let client_requirement = ">=1.2, <2";
+/// let requirement = rspawn::VersionReq::parse(client_requirement)?;
+/// rspawn::require_min_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), &requirement)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn require_min_version(
+    crate_name: &str,
+    current_version: &str,
+    requirement: &VersionReq,
+) -> Result<(), IncompatibleVersion> {
+    require_min_version_with(crate_name, current_version, requirement, &EnvCredentialStore)
+}
+
+/// Same as `require_min_version`, with the registry token read from `credentials`.
+pub fn require_min_version_with(
+    crate_name: &str,
+    current_version: &str,
+    requirement: &VersionReq,
+    credentials: &dyn CredentialStore,
+) -> Result<(), IncompatibleVersion> {
+    if requirement.matches(current_version) {
+        return Ok(());
+    }
+
+    let available = match latest_version_with(crate_name, credentials) {
+        Ok(latest) => Some(latest).filter(|latest| requirement.matches(latest)),
+        Err(e) => {
+            warn!(target: CHECK, "Failed to look up the latest version of {}: {:#}", crate_name, e);
+            None
+        }
+    };
+    Err(IncompatibleVersion {
+        current_version: current_version.to_string(),
+        requirement: requirement.clone(),
+        available,
+    })
+}
@@ -0,0 +1,84 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Learning the latest version from peers on the LAN, so that in a fleet
+//! only a few instances ask the registry.
+//!
+//! A query goes to the peer discovery multicast group, and instances serving
+//! with `share_with_peers()` answer from the shared version cache.
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+use log::debug;
+use crate::cache::{self, DEFAULT_CACHE_TTL};
+use crate::clock::SystemClock;
+use crate::filesystem::RealFs;
+use crate::log_target::CHECK;
+use crate::p2p::{DISCOVERY_GROUP, DISCOVERY_WAIT, PEER_PORT, POLL_INTERVAL};
+use crate::version::{self, compare_versions};
+
+const VERSION_QUERY: &str = "rspawn-version?";
+const VERSION_REPLY: &str = "rspawn-version";
+// Stands for the default channel, so every message has the same fields
+const DEFAULT_CHANNEL: &str = "-";
+
+// The answer to a version query, from the lookups cached on this machine
+pub(crate) fn answer(query: &str) -> Option<String> {
+    let mut fields = query.split_whitespace();
+    let (VERSION_QUERY, Some(crate_name), Some(channel)) = (fields.next()?, fields.next(), fields.next()) else {
+        return None;
+    };
+    let cache_channel = Some(channel).filter(|channel| *channel != DEFAULT_CHANNEL);
+    let (version, age) = cache::cached_lookup(crate_name, cache_channel, &SystemClock, &RealFs)?;
+    // Peers don't keep asking what a stale lookup said
+    (age < DEFAULT_CACHE_TTL.as_secs()).then(|| format!("{} {} {} {} {}", VERSION_REPLY, crate_name, channel, version.num, age))
+}
+
+// The newest version of `crate_name` on `channel` that peers looked up less
+// than `max_age` ago, if any answered
+pub(crate) fn latest_version(crate_name: &str, channel: Option<&str>, max_age: Duration) -> Option<String> {
+    let channel = channel.unwrap_or(DEFAULT_CHANNEL);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.set_read_timeout(Some(POLL_INTERVAL)).ok()?;
+    let query = format!("{} {} {}", VERSION_QUERY, crate_name, channel);
+    if let Err(e) = socket.send_to(query.as_bytes(), (DISCOVERY_GROUP, PEER_PORT)) {
+        debug!(target: CHECK, "Could not ask peers for {}: {}", crate_name, e);
+        return None;
+    }
+
+    let mut latest: Option<String> = None;
+    let mut buf = [0u8; 512];
+    let deadline = Instant::now() + DISCOVERY_WAIT;
+    while Instant::now() < deadline {
+        let Ok((read, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let reply = String::from_utf8_lossy(&buf[..read]);
+        let fields: Vec<&str> = reply.split_whitespace().collect();
+        let [VERSION_REPLY, name, reply_channel, version, age] = fields[..] else {
+            continue;
+        };
+        let fresh = age.parse::<u64>().is_ok_and(|age| age < max_age.as_secs());
+        if name != crate_name || reply_channel != channel || !fresh {
+            continue;
+        }
+        if !version::is_valid(version) {
+            debug!(target: CHECK, "Ignoring {} from {}: not a valid version", version, from);
+            continue;
+        }
+        debug!(target: CHECK, "{} says {} {} is the latest, {}s ago", from, crate_name, version, age);
+        if latest.as_deref().is_none_or(|latest| compare_versions(version, latest).is_gt()) {
+            latest = Some(version.to_string());
+        }
+    }
+    latest
+}
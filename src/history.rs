@@ -0,0 +1,65 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The update history of each program, see `update_history`.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::error::RspawnError;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::state::State;
+
+// Attempts kept per program, oldest dropped first
+const MAX_HISTORY_ENTRIES: usize = 100;
+
+/// One attempt at installing an update, see `update_history`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the attempt finished, in seconds since the Unix epoch.
+    pub at: u64,
+    /// The crate installed: the program's own, or the one of `RSpawn::target_binary`.
+    pub crate_name: String,
+    /// The version that was running, or installed for a target binary.
+    pub from: String,
+    /// The version installed, or that failed to install.
+    pub to: String,
+    /// Whether it installed.
+    pub success: bool,
+    /// Why it failed, if it did.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// Appends an attempt to the running program's history
+pub(crate) fn record(crate_name: &str, from: &str, to: &str, error: Option<&anyhow::Error>, clock: &dyn Clock, fs: &dyn Fs) -> Result<()> {
+    let entry = HistoryEntry {
+        at: clock.unix_now(),
+        crate_name: crate_name.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        success: error.is_none(),
+        error: error.map(|e| format!("{:#}", e)),
+    };
+    State::update_in(fs, |state| {
+        state.history.push(entry);
+        let excess = state.history.len().saturating_sub(MAX_HISTORY_ENTRIES);
+        state.history.drain(..excess);
+    })
+}
+
+/// Returns the update attempts of `program` (by executable name), oldest first.
+///
+/// Only the last 100 attempts are kept.
+pub fn update_history(program: &str) -> Result<Vec<HistoryEntry>, RspawnError> {
+    Ok(State::load_program(program)?.history)
+}
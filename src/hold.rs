@@ -0,0 +1,121 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Machine-wide update freeze set by operators, e.g. during a maintenance window.
+//!
+//! While the hold file exists, no rspawn-powered program installs anything.
+//! It may contain `key = value` lines:
+//!
+//! ```text
+//! # Lifted automatically at this time, in seconds since the Unix epoch
+//! until = 1735689600
+//! reason = release freeze until the new year
+//! ```
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use crate::log_target::CHECK;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+
+/// Environment variable overriding the location of the hold file.
+pub const HOLD_FILE_ENV: &str = "RSPAWN_HOLD_FILE";
+
+/// Error returned when updates are frozen by the operator's hold file.
+///
+/// `relaunch_program` returns it as `RspawnError::Held`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldByOperator {
+    /// The hold file.
+    pub path: PathBuf,
+    /// When the hold is lifted, in seconds since the Unix epoch, if it expires.
+    pub until: Option<u64>,
+    /// Why updates are held, if the operator said.
+    pub reason: Option<String>,
+}
+
+impl fmt::Display for HeldByOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Updates are held by the operator ({})", self.path.display())?;
+        if let Some(until) = self.until {
+            write!(f, " until {} (Unix time)", until)?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HeldByOperator {}
+
+/// Returns the location of the hold file: `$RSPAWN_HOLD_FILE` if set, otherwise
+/// `/etc/rspawn/hold`, or `%ProgramData%\rspawn\hold` on Windows.
+pub fn hold_file_path() -> PathBuf {
+    if let Some(path) = env::var_os(HOLD_FILE_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    if cfg!(windows) {
+        let program_data = env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+        return PathBuf::from(program_data).join("rspawn").join("hold");
+    }
+    PathBuf::from("/etc/rspawn/hold")
+}
+
+// Reads the hold at `path`, if there is one still in effect
+fn read_hold(path: &Path, clock: &dyn Clock, fs: &dyn Fs) -> Option<HeldByOperator> {
+    if !fs.exists(path) {
+        return None;
+    }
+    // A hold file we can't read still means the operator wants updates frozen
+    let contents = fs.read_to_string(path).unwrap_or_else(|e| {
+        warn!(target: CHECK, "Failed to read hold file {}: {}", path.display(), e);
+        String::new()
+    });
+
+    let mut hold = HeldByOperator {
+        path: path.to_path_buf(),
+        until: None,
+        reason: None,
+    };
+    for (key, value) in contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+    {
+        match key.trim() {
+            "until" => match value.trim().parse() {
+                Ok(until) => hold.until = Some(until),
+                Err(_) => warn!(target: CHECK, "Ignoring invalid expiry {:?} in {}", value.trim(), path.display()),
+            },
+            "reason" => hold.reason = Some(value.trim().to_string()),
+            other => warn!(target: CHECK, "Ignoring unknown key {:?} in {}", other, path.display()),
+        }
+    }
+
+    if hold.until.is_some_and(|until| clock.unix_now() >= until) {
+        info!(target: CHECK, "Hold file {} has expired", path.display());
+        return None;
+    }
+    Some(hold)
+}
+
+// Fails with `HeldByOperator` while the operator's hold is in effect
+pub(crate) fn check_hold(clock: &dyn Clock, fs: &dyn Fs) -> Result<(), HeldByOperator> {
+    match read_hold(&hold_file_path(), clock, fs) {
+        Some(hold) => Err(hold),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,95 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+// Name of this machine, as reported to fleet endpoints
+pub(crate) fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Environment variable adding labels to this machine for fleet manifests,
+/// as comma-separated `key=value` pairs (e.g. `role=db,region=eu`).
+pub const HOST_LABELS_ENV: &str = "RSPAWN_HOST_LABELS";
+
+/// File adding labels to this machine for fleet manifests, one `key=value` per line.
+pub const HOST_LABELS_FILE: &str = "/etc/rspawn/labels";
+
+/// What a fleet manifest's host selectors are matched against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostInfo {
+    /// This machine's name.
+    pub hostname: Option<String>,
+    /// This machine's labels.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl HostInfo {
+    /// Describes this machine: its hostname, and the labels from `HOST_LABELS_FILE`
+    /// overridden by those in `$RSPAWN_HOST_LABELS`.
+    pub fn detect() -> Self {
+        let mut labels = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(HOST_LABELS_FILE) {
+            labels.extend(parse_labels(contents.lines()));
+        }
+        if let Ok(from_env) = env::var(HOST_LABELS_ENV) {
+            labels.extend(parse_labels(from_env.split(',')));
+        }
+        HostInfo {
+            hostname: hostname(),
+            labels,
+        }
+    }
+}
+
+// Parses `key=value` pairs, skipping blanks and `#` comments
+fn parse_labels<'a>(pairs: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    pairs
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && !pair.starts_with('#'))
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+// Matches `text` against a pattern where `*` is any run of characters and `?` any single one
+pub(crate) fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Classic wildcard matching, backtracking to the last `*`
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p].eq_ignore_ascii_case(&text[t])) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
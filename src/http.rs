@@ -0,0 +1,377 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! HTTP requests, through reqwest (`http-reqwest`) or ureq (`http-ureq`).
+//!
+//! When both are enabled, reqwest is used. Setting up a client starts its TLS
+//! stack (and, for reqwest, a runtime thread), so each configuration is built
+//! on first use and then reused, which also lets repeated requests share
+//! keep-alive connections.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use anyhow::{Result, Context};
+use log::debug;
+use crate::log_target::CHECK;
+use crate::registry::user_agent;
+
+// How long an idle connection is kept open for the next request
+const KEEP_ALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[cfg(feature = "http-reqwest")]
+type Backend = reqwest::blocking::Client;
+#[cfg(all(feature = "http-ureq", not(feature = "http-reqwest")))]
+type Backend = ureq::Agent;
+
+// What tells clients apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ClientConfig {
+    // Timeout for whole requests, if any
+    pub(crate) timeout: Option<Duration>,
+    // Whether responses are asked for compressed and decoded transparently
+    // (gzip and brotli with reqwest, gzip with ureq). Artifacts are fetched
+    // as-is, so byte ranges and checksums apply to what the server stores.
+    pub(crate) compression: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            timeout: None,
+            compression: true,
+        }
+    }
+}
+
+impl ClientConfig {
+    // Settings for downloading artifacts
+    #[cfg(feature = "install")]
+    pub(crate) fn artifacts() -> Self {
+        ClientConfig {
+            compression: false,
+            ..ClientConfig::default()
+        }
+    }
+
+    #[cfg(feature = "http-reqwest")]
+    fn build(&self, proxy: Option<&str>) -> Result<Backend> {
+        debug!(target: CHECK, "Setting up an HTTP client for {:?}", self);
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(user_agent())
+            .gzip(self.compression)
+            .brotli(self.compression)
+            .pool_idle_timeout(Some(KEEP_ALIVE_IDLE_TIMEOUT));
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {}", proxy))?);
+        }
+        builder.build().context("Failed to set up the HTTP client")
+    }
+
+    #[cfg(all(feature = "http-ureq", not(feature = "http-reqwest")))]
+    fn build(&self, proxy: Option<&str>) -> Result<Backend> {
+        debug!(target: CHECK, "Setting up an HTTP agent for {:?}", self);
+        // ureq closes idle connections on its own schedule
+        let _ = KEEP_ALIVE_IDLE_TIMEOUT;
+        let mut builder = ureq::AgentBuilder::new().user_agent(&user_agent());
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(ureq::Proxy::new(proxy).with_context(|| format!("Invalid proxy {}", proxy))?);
+        }
+        Ok(builder.build())
+    }
+}
+
+/// An HTTP status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StatusCode(u16);
+
+impl StatusCode {
+    #[cfg(feature = "install")]
+    pub(crate) const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    #[cfg(feature = "oci")]
+    pub(crate) const UNAUTHORIZED: StatusCode = StatusCode(401);
+
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// A client for one configuration, see `client()`
+#[derive(Clone)]
+pub(crate) struct Client {
+    backend: Backend,
+    config: ClientConfig,
+}
+
+impl Client {
+    pub(crate) fn get(&self, url: &str) -> Request {
+        self.request("GET", url)
+    }
+
+    #[cfg(feature = "install")]
+    pub(crate) fn head(&self, url: &str) -> Request {
+        self.request("HEAD", url)
+    }
+
+    fn request(&self, method: &'static str, url: &str) -> Request {
+        let mut headers = Vec::new();
+        if !self.config.compression {
+            headers.push(("Accept-Encoding".to_string(), "identity".to_string()));
+        }
+        Request {
+            client: self.clone(),
+            method,
+            url: url.to_string(),
+            headers,
+        }
+    }
+}
+
+// A request being built
+pub(crate) struct Request {
+    client: Client,
+    method: &'static str,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub(crate) fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    // Sends `github_token` along, if there is one and the request goes to GitHub
+    #[cfg(feature = "install")]
+    pub(crate) fn github_token(self, github_token: Option<&str>) -> Self {
+        match github_token {
+            Some(token) if is_github_url(&self.url) => self.header("Authorization", format!("Bearer {}", token)),
+            _ => self,
+        }
+    }
+
+    #[cfg(feature = "http-reqwest")]
+    pub(crate) fn send(self) -> Result<Response> {
+        let mut request = match self.method {
+            "HEAD" => self.client.backend.head(&self.url),
+            _ => self.client.backend.get(&self.url),
+        };
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let response = request.send()?;
+        let headers: HashMap<String, String> = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        // reqwest sizes the (empty) body of a HEAD response, not what the server advertised
+        let content_length = match self.method {
+            "HEAD" => headers.get("content-length").and_then(|length| length.parse().ok()),
+            _ => response.content_length(),
+        };
+        Ok(Response {
+            status: StatusCode(response.status().as_u16()),
+            content_length,
+            headers,
+            body: Box::new(response),
+        })
+    }
+
+    #[cfg(all(feature = "http-ureq", not(feature = "http-reqwest")))]
+    pub(crate) fn send(self) -> Result<Response> {
+        let mut request = self.client.backend.request(self.method, &self.url);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        // Error statuses are for the caller to handle, like with reqwest
+        let response = match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e.into()),
+        };
+        let headers: HashMap<String, String> = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_string();
+                Some((name.to_lowercase(), value))
+            })
+            .collect();
+        // A decoded body no longer has the advertised length
+        let content_length = match headers.contains_key("content-encoding") {
+            true => None,
+            false => headers.get("content-length").and_then(|length| length.parse().ok()),
+        };
+        Ok(Response {
+            status: StatusCode(response.status()),
+            content_length,
+            headers,
+            body: Box::new(response.into_reader()),
+        })
+    }
+}
+
+// A response, whose body is read through `Read`
+pub(crate) struct Response {
+    status: StatusCode,
+    content_length: Option<u64>,
+    // Header names are lowercase
+    headers: HashMap<String, String>,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    pub(crate) fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    #[cfg_attr(not(feature = "install"), allow(dead_code))]
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    // Length of the (decoded) body, if known in advance
+    #[cfg_attr(not(feature = "install"), allow(dead_code))]
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    pub(crate) fn text(mut self) -> Result<String> {
+        let mut text = String::new();
+        self.body.read_to_string(&mut text).context("Failed to read response body")?;
+        Ok(text)
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+// Whether `url` is served by GitHub over HTTPS, so the GitHub token may be sent
+// to it: releases, their assets and the API
+#[cfg(feature = "install")]
+pub(crate) fn is_github_url(url: &str) -> bool {
+    let Some(rest) = url.strip_prefix("https://") else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    if authority.contains('@') {
+        return false;
+    }
+    let host = authority.split(':').next().unwrap_or(authority).to_ascii_lowercase();
+    matches!(host.as_str(), "github.com" | "api.github.com") || host.ends_with(".githubusercontent.com")
+}
+
+// Whether `error` comes from the HTTP client failing to reach a server
+pub(crate) fn is_network_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    #[cfg(feature = "http-reqwest")]
+    if error.is::<reqwest::Error>() {
+        return true;
+    }
+    #[cfg(feature = "http-ureq")]
+    if error.is::<ureq::Error>() {
+        return true;
+    }
+    false
+}
+
+// Clients built so far, by configuration and proxy
+type Clients = HashMap<(ClientConfig, Option<String>), Backend>;
+
+// Proxy every client goes through from now on, instead of the environment's
+static PROXY: RwLock<Option<String>> = RwLock::new(None);
+
+// Sets the proxy for the clients set up from now on, see `Setting::Proxy`
+#[cfg(feature = "install")]
+pub(crate) fn set_proxy(proxy: Option<&str>) {
+    *PROXY.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = proxy.map(str::to_string);
+}
+
+// An async client for the version check of `RSpawn::relaunch_async`, set up like
+// the blocking ones. It isn't kept: its connections belong to the runtime it ran on.
+#[cfg(feature = "tokio")]
+pub(crate) fn async_client() -> Result<reqwest::Client> {
+    let proxy = PROXY.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent())
+        .gzip(true)
+        .brotli(true)
+        .pool_idle_timeout(Some(KEEP_ALIVE_IDLE_TIMEOUT));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(&proxy).with_context(|| format!("Invalid proxy {}", proxy))?);
+    }
+    builder.build().context("Failed to set up the HTTP client")
+}
+
+// The process-wide client for `config`, built on first use. Clients are
+// reference counted, so the returned clone shares the cached one's connections.
+pub(crate) fn client(config: ClientConfig) -> Result<Client> {
+    static CLIENTS: OnceLock<Mutex<Clients>> = OnceLock::new();
+    let proxy = PROXY.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    let mut clients = CLIENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = (config, proxy);
+    let backend = match clients.get(&key) {
+        Some(backend) => backend.clone(),
+        None => {
+            let backend = config.build(key.1.as_deref())?;
+            clients.insert(key, backend.clone());
+            backend
+        }
+    };
+    Ok(Client { backend, config })
+}
+
+#[cfg(all(test, feature = "install"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_token_goes_only_to_github_over_https() {
+        for url in [
+            "https://github.com/owner/tool/releases.atom",
+            "https://api.github.com/repos/owner/tool/releases/assets/1",
+            "https://objects.githubusercontent.com/github-production-release-asset/1",
+            "https://GitHub.com:443/owner/tool",
+        ] {
+            assert!(is_github_url(url), "{url}");
+        }
+        for url in [
+            "http://github.com/owner/tool",
+            "https://github.com.evil.example/owner/tool",
+            "https://evil.example/github.com/owner/tool",
+            "https://github.com@evil.example/owner/tool",
+            "https://notgithubusercontent.com/x",
+            "file:///github.com/x",
+        ] {
+            assert!(!is_github_url(url), "{url}");
+        }
+    }
+}
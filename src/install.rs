@@ -0,0 +1,1054 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use log::{debug, error};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncBufReadExt, BufReader as AsyncBufReader, Lines};
+use crate::error::RspawnError;
+use crate::filesystem::Fs;
+use crate::json;
+use crate::log_target::INSTALL;
+use crate::state::{state_dir, state_dir_in};
+
+// How many trailing lines of cargo's stderr we keep around for classification
+const STDERR_TAIL_LINES: usize = 64;
+
+/// The kind of failure reported by `cargo install`.
+///
+/// Classification is done on cargo's exit status and the tail of its stderr,
+/// so it is a best-effort guess: when nothing matches, `Unknown` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallFailureKind {
+    /// Downloading the crate or one of its dependencies failed.
+    Network,
+    /// The crate (or a dependency) failed to compile.
+    Compile,
+    /// Compilation succeeded but linking the final binary failed.
+    Linker,
+    /// The disk filled up during the build or install.
+    NoSpace,
+    /// Cargo could not write to its build or install directories.
+    PermissionDenied,
+    /// Cargo or a compiler it ran was killed by a signal, most often by the
+    /// out-of-memory killer.
+    Killed,
+    /// Cargo failed for a reason we could not recognize.
+    Unknown,
+}
+
+impl InstallFailureKind {
+    /// Classifies a failure from cargo's exit status and stderr output.
+    ///
+    /// Cargo itself dying from a signal is `Killed`, whatever it printed.
+    /// Otherwise patterns are checked from the most to the least specific: a
+    /// full disk or a permission problem usually also shows up as a compile
+    /// error, and a killed compiler or a linker error is always followed by
+    /// cargo's "could not compile".
+    pub fn classify(status: ExitStatus, stderr: &str) -> Self {
+        if killed_by_signal(status) {
+            return InstallFailureKind::Killed;
+        }
+        let stderr = stderr.to_lowercase();
+        let matches = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+
+        if matches(&["no space left on device", "os error 28", "disk quota exceeded"]) {
+            InstallFailureKind::NoSpace
+        } else if matches(&["permission denied", "os error 13", "access is denied"]) {
+            InstallFailureKind::PermissionDenied
+        } else if matches(&[
+            "failed to download",
+            "failed to fetch",
+            "failed to query replaced source registry",
+            "spurious network error",
+            "could not resolve host",
+            "couldn't resolve host",
+            "failed to connect",
+            "ssl connect error",
+            "operation timed out",
+        ]) {
+            InstallFailureKind::Network
+        } else if matches(&["(signal: 9, sigkill", "memory allocation of", "out of memory"]) {
+            InstallFailureKind::Killed
+        } else if matches(&["linking with `", "error: linker `", "ld: cannot find", "undefined reference to", "ld returned 1 exit status"]) {
+            InstallFailureKind::Linker
+        } else if matches(&["could not compile", "error[e"]) {
+            InstallFailureKind::Compile
+        } else {
+            InstallFailureKind::Unknown
+        }
+    }
+
+    /// Returns a short remediation hint for this kind of failure.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            InstallFailureKind::Network => "Check your network connection and proxy settings, then retry.",
+            InstallFailureKind::Compile => "The new version failed to build; try updating your toolchain with `rustup update`, or report it upstream.",
+            InstallFailureKind::Linker => "Install a working C linker (e.g. build-essential, Xcode command line tools or MSVC build tools).",
+            InstallFailureKind::NoSpace => "Free up some disk space (cargo builds in a temporary target dir) and retry.",
+            InstallFailureKind::PermissionDenied => "Check that you can write to your cargo install root (usually ~/.cargo/bin).",
+            InstallFailureKind::Killed => "The build was killed, likely for running out of memory; free some up or build with fewer jobs (CARGO_BUILD_JOBS=1).",
+            InstallFailureKind::Unknown => "See cargo's output above for details.",
+        }
+    }
+}
+
+// Whether the process exited because of a signal rather than on its own
+#[cfg(unix)]
+fn killed_by_signal(status: ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().is_some()
+}
+
+#[cfg(not(unix))]
+fn killed_by_signal(_status: ExitStatus) -> bool {
+    false
+}
+
+impl fmt::Display for InstallFailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InstallFailureKind::Network => "network error",
+            InstallFailureKind::Compile => "compile error",
+            InstallFailureKind::Linker => "linker error",
+            InstallFailureKind::NoSpace => "no space left on device",
+            InstallFailureKind::PermissionDenied => "permission denied",
+            InstallFailureKind::Killed => "build killed",
+            InstallFailureKind::Unknown => "unknown error",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Error returned when `cargo install` does not complete successfully.
+///
+/// `relaunch_program` returns a failed install as `RspawnError::InstallFailed`,
+/// and cargo missing from PATH as `RspawnError::NotOnPath`. The program is
+/// never relaunched after any of these.
+#[derive(Debug)]
+pub enum InstallError {
+    /// Cargo could not be started at all (e.g. it is not installed).
+    Spawn(io::Error),
+    /// Waiting on the cargo process failed.
+    Wait(io::Error),
+    /// Cargo ran but exited unsuccessfully.
+    InstallFailed {
+        /// Cargo's exit status.
+        status: ExitStatus,
+        /// The classified kind of failure.
+        kind: InstallFailureKind,
+        /// The last lines cargo printed on stderr.
+        stderr: String,
+    },
+}
+
+impl InstallError {
+    fn failed(status: ExitStatus, stderr: String) -> Self {
+        InstallError::InstallFailed {
+            kind: InstallFailureKind::classify(status, &stderr),
+            status,
+            stderr,
+        }
+    }
+
+    /// Returns the classified kind of failure.
+    pub fn kind(&self) -> InstallFailureKind {
+        match self {
+            InstallError::Spawn(e) | InstallError::Wait(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                InstallFailureKind::PermissionDenied
+            }
+            InstallError::Spawn(_) | InstallError::Wait(_) => InstallFailureKind::Unknown,
+            InstallError::InstallFailed { kind, .. } => *kind,
+        }
+    }
+
+    /// Returns cargo's exit status, if it ran to completion.
+    pub fn status(&self) -> Option<ExitStatus> {
+        match self {
+            InstallError::InstallFailed { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Returns a short remediation hint for this failure.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            InstallError::Spawn(e) if e.kind() == io::ErrorKind::NotFound => {
+                "Make sure cargo is installed and on your PATH."
+            }
+            _ => self.kind().hint(),
+        }
+    }
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstallError::Spawn(e) => write!(f, "Failed to run cargo install: {}. {}", e, self.hint()),
+            InstallError::Wait(e) => write!(f, "Failed to wait for cargo install: {}", e),
+            InstallError::InstallFailed { status, kind, .. } => {
+                write!(f, "cargo install failed with {} ({}). {}", kind, status, self.hint())
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstallError::Spawn(e) | InstallError::Wait(e) => Some(e),
+            InstallError::InstallFailed { .. } => None,
+        }
+    }
+}
+
+// Reads the child's stderr, optionally echoing it to ours, sending each line to
+// `lines` and keeping the last ones for classification
+fn tee_stderr(child: &mut Child, echo: bool, lines: Sender<String>) -> Option<JoinHandle<String>> {
+    let stderr = child.stderr.take()?;
+
+    Some(thread::spawn(move || {
+        let mut tail: VecDeque<String> = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => {
+                    if echo {
+                        eprintln!("{line}");
+                    }
+                    // The receiving end only goes away once we're done
+                    let _ = lines.send(line.clone());
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+                Err(e) => {
+                    debug!(target: INSTALL, "Stopped reading cargo stderr: {}", e);
+                    break;
+                }
+            }
+        }
+        Vec::from(tail).join("\n")
+    }))
+}
+
+// Passes each line of the child's stdout to `lines`: the JSON messages asked for with
+// `--message-format`, and anything else (echoed to our stdout if `echo`)
+fn forward_stdout(child: &mut Child, echo: bool, lines: Sender<String>) -> Option<JoinHandle<()>> {
+    let stdout = child.stdout.take()?;
+
+    Some(thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if echo && !line.starts_with('{') {
+                println!("{line}");
+            }
+            let _ = lines.send(line);
+        }
+    }))
+}
+
+// The fields of cargo's JSON messages `BuildProgress` reads
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    package_id: Option<String>,
+}
+
+// Progress of a build, from cargo's JSON messages (see `--message-format`): the
+// packages compiled so far, out of the packages locked. With `--locked`, cargo
+// builds from the lockfile published with the crate and doesn't say how many
+// packages it locks, so they are counted in the lockfile it extracted.
+#[derive(Debug, Default)]
+pub(crate) struct BuildProgress {
+    compiled: HashSet<String>,
+    total: Option<usize>,
+    // Where cargo extracts the crates it downloads, under `registry/src`
+    cargo_home: Option<PathBuf>,
+}
+
+impl BuildProgress {
+    // Progress of a build whose crates cargo extracts under `cargo_home`
+    pub(crate) fn new(cargo_home: Option<PathBuf>) -> Self {
+        BuildProgress {
+            cargo_home,
+            ..BuildProgress::default()
+        }
+    }
+
+    // Reads a line of cargo's output, returning whether it was a JSON message
+    // rather than something to show
+    pub(crate) fn read(&mut self, line: &str) -> bool {
+        if !line.starts_with('{') {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                // "Locking 57 packages to latest compatible versions"
+                ["Locking", count, ..] => {
+                    if let Ok(total) = count.parse() {
+                        self.total = Some(total);
+                    }
+                }
+                // "Installing ripgrep v14.1.0", once the crate was extracted
+                ["Installing", name, version] if self.total.is_none() => {
+                    if let Some(version) = version.strip_prefix('v') {
+                        self.total = self.published_lockfile_packages(name, version);
+                    }
+                }
+                _ => {}
+            }
+            return false;
+        }
+        if let Ok(message) = json::from_str::<CargoMessage>(line) {
+            if message.reason == "compiler-artifact" {
+                if let Some(package_id) = message.package_id {
+                    self.compiled.insert(package_id);
+                }
+            }
+        }
+        true
+    }
+
+    // The packages in the lockfile published with `name` `version`, as extracted
+    // into any of the registries' sources
+    fn published_lockfile_packages(&self, name: &str, version: &str) -> Option<usize> {
+        let sources = self.cargo_home.as_ref()?.join("registry").join("src");
+        let lockfile = fs::read_dir(sources)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|registry| registry.path().join(format!("{name}-{version}")).join("Cargo.lock"))
+            .find(|lockfile| lockfile.is_file())?;
+        let contents = fs::read_to_string(&lockfile).ok()?;
+        let packages = contents.lines().filter(|line| line.trim() == "[[package]]").count();
+        debug!(target: INSTALL, "{} locks {} packages", lockfile.display(), packages);
+        (packages > 0).then_some(packages)
+    }
+
+    // Packages compiled so far
+    pub(crate) fn compiled(&self) -> usize {
+        self.compiled.len()
+    }
+
+    // Packages the build has, at most; platform-specific ones may never be compiled
+    pub(crate) fn total(&self) -> Option<usize> {
+        // Never below what was already compiled, e.g. the installed crate itself
+        self.total.map(|total| total.max(self.compiled.len()))
+    }
+}
+
+pub(crate) type InstallCommandHook = Box<dyn FnMut(&mut Command) + Send>;
+
+// Niceness cargo runs with in the background: low, but above what most batch jobs use
+#[cfg(unix)]
+const BACKGROUND_NICE: libc::c_int = 10;
+// `ioprio_set` arguments for the idle I/O class, from linux/ioprio.h
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+// Process creation flag from winbase.h
+#[cfg(windows)]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+
+/// CPU and I/O priority `cargo install` runs with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallPriority {
+    /// The same priority as the program.
+    #[default]
+    Normal,
+    /// Reduced priority, so a background update doesn't slow down the machine
+    /// while compiling: niceness 10 and the idle I/O class on Linux (niceness
+    /// only on other Unixes), `BELOW_NORMAL_PRIORITY_CLASS` on Windows. The
+    /// compiler processes cargo starts inherit it.
+    Background,
+}
+
+// Runs the command with `priority`
+pub(crate) fn apply_priority(command: &mut Command, priority: InstallPriority) {
+    if priority == InstallPriority::Background {
+        lower_priority(command);
+    }
+}
+
+#[cfg(unix)]
+fn lower_priority(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: the closure only makes async-signal-safe system calls, between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            // A build at normal priority is better than no update, so failures are ignored
+            libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICE);
+            #[cfg(target_os = "linux")]
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn lower_priority(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    command.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lower_priority(_command: &mut Command) {}
+
+// How often a running `cargo install` is reported by default, see `RSpawn::install_heartbeat`
+pub(crate) const DEFAULT_INSTALL_HEARTBEAT: Duration = Duration::from_secs(1);
+
+// Whether `line` is one of cargo's status lines, e.g. "   Compiling tokio v1.38.0"
+pub(crate) fn is_status_line(line: &str) -> bool {
+    let verb = line.trim_start().split(' ').next().unwrap_or_default();
+    let mut chars = verb.chars();
+    chars.next().is_some_and(|first| first.is_ascii_uppercase())
+        && verb.len() > 1
+        && chars.all(|c| c.is_ascii_lowercase())
+}
+
+// Looks `program` up in PATH
+pub(crate) fn find_in_path(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+// Whether `a` and `b` are the same file or directory, through symlinks
+pub(crate) fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// The binary that runs instead of `installed` when its name is typed, if
+// another one with the same name comes first in PATH
+pub(crate) fn shadowing_binary(installed: &Path) -> Option<PathBuf> {
+    let winner = find_in_path(installed.file_name()?.to_str()?)?;
+    (!same_path(&winner, installed)).then_some(winner)
+}
+
+// Directory updates are built in when the build cache is kept, inside `state_dir()`
+const BUILD_CACHE_DIR_NAME: &str = "build-cache";
+
+// Builds `command` in the persistent build cache, so the next update only recompiles
+// what changed. A target directory chosen by the user or the command is left alone.
+pub(crate) fn use_build_cache(command: &mut Command, fs: &dyn Fs) -> Result<()> {
+    let has_target_dir = command.get_args().any(|arg| arg == "--target-dir")
+        || command.get_envs().any(|(key, _)| key == "CARGO_TARGET_DIR")
+        || env::var_os("CARGO_TARGET_DIR").is_some_and(|dir| !dir.is_empty());
+    if has_target_dir {
+        return Ok(());
+    }
+    let dir = state_dir_in(fs)?.join(BUILD_CACHE_DIR_NAME);
+    fs.create_dir_all(&dir)
+        .with_context(|| format!("Failed to create build cache {}", dir.display()))?;
+    debug!(target: INSTALL, "Building in {}", dir.display());
+    command.env("CARGO_TARGET_DIR", dir);
+    Ok(())
+}
+
+/// Deletes the build cache kept by `RSpawn::keep_build_cache`, to reclaim its
+/// disk space. The next update builds from scratch.
+pub fn clear_build_cache() -> Result<(), RspawnError> {
+    let dir = state_dir()?.join(BUILD_CACHE_DIR_NAME);
+    match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to delete build cache {}", dir.display()))?
+        }
+        _ => Ok(()),
+    }
+}
+
+// Builds the `cargo install` command for `crate_name` with the given features,
+// pinned to `version` if given (otherwise cargo picks the latest stable release).
+// `--locked` builds with the dependencies the release was published with, not
+// whatever semver-compatible versions came out since.
+pub(crate) fn cargo_install_command(crate_name: &str, version: Option<&str>, features: &[String]) -> Command {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("install").arg(crate_name);
+    if let Some(version) = version {
+        cmd.args(["--version", version]);
+    }
+    cmd.arg("--locked");
+
+    if !features.is_empty() {
+        cmd.args(features.iter().flat_map(|f| ["--features", f]));
+    }
+    cmd // Return the fully configured `Command`
+}
+
+// Runs cargo install to completion, echoing its output, see `run_install_with`
+#[cfg(any(feature = "capi", feature = "python"))]
+pub(crate) fn run_install(command: Command) -> Result<(), InstallError> {
+    run_install_with(command, true, &mut |_| {}, None)
+}
+
+// Runs cargo install to completion, turning every way it can fail into an `InstallError`.
+// Each line cargo prints is passed to `on_line`, and those on stderr echoed to ours if `echo`.
+// With a `heartbeat`, its callback is also called at its interval, output or not.
+// Only an `Ok` from here means the new version was actually installed.
+pub(crate) fn run_install_with(
+    mut command: Command,
+    echo: bool,
+    on_line: &mut dyn FnMut(&str),
+    heartbeat: Option<(Duration, &mut dyn FnMut())>,
+) -> Result<(), InstallError> {
+    // Capture stderr so a failure can be classified, and stdout for cargo's JSON messages
+    command.stderr(Stdio::piped()).stdout(Stdio::piped());
+
+    let mut child = command.spawn().map_err(InstallError::Spawn)?;
+    let (line_sender, line_receiver) = mpsc::channel();
+    let stdout_reader = forward_stdout(&mut child, echo, line_sender.clone());
+    let stderr_reader = tee_stderr(&mut child, echo, line_sender);
+    // Ends once the reader thread is done with cargo's stderr
+    match heartbeat {
+        None => {
+            for line in line_receiver {
+                on_line(&line);
+            }
+        }
+        Some((interval, on_beat)) => {
+            let mut next_beat = Instant::now() + interval;
+            loop {
+                match line_receiver.recv_timeout(next_beat.saturating_duration_since(Instant::now())) {
+                    Ok(line) => on_line(&line),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if Instant::now() >= next_beat {
+                    on_beat();
+                    next_beat += interval;
+                }
+            }
+        }
+    }
+    let status = child.wait().map_err(InstallError::Wait)?;
+    if let Some(handle) = stdout_reader {
+        let _ = handle.join();
+    }
+    let stderr = stderr_reader
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default();
+
+    if !status.success() {
+        let install_error = InstallError::failed(status, stderr);
+        error!(target: INSTALL, "{install_error}");
+        return Err(install_error);
+    }
+
+    debug!(target: INSTALL, "cargo install exited with {}", status);
+    Ok(())
+}
+
+// `run_install_with` on tokio, for `RSpawn::relaunch_async`: cargo's output is
+// read by the runtime rather than by reader threads, and its exit awaited
+#[cfg(feature = "tokio")]
+pub(crate) async fn run_install_async(
+    command: Command,
+    echo: bool,
+    on_line: &mut (dyn FnMut(&str) + Send),
+    heartbeat: Option<(Duration, &mut (dyn FnMut() + Send))>,
+) -> Result<(), InstallError> {
+    let mut command = tokio::process::Command::from(command);
+    // Capture stderr so a failure can be classified, and stdout for cargo's JSON messages.
+    // A dropped future doesn't leave cargo building in the background.
+    command.stderr(Stdio::piped()).stdout(Stdio::piped()).kill_on_drop(true);
+
+    let mut child = command.spawn().map_err(InstallError::Spawn)?;
+    let mut stdout = child.stdout.take().map(|stdout| AsyncBufReader::new(stdout).lines());
+    let mut stderr = child.stderr.take().map(|stderr| AsyncBufReader::new(stderr).lines());
+    let mut heartbeat = heartbeat.map(|(interval, on_beat)| {
+        (tokio::time::interval_at(tokio::time::Instant::now() + interval, interval), on_beat)
+    });
+    let mut tail: VecDeque<String> = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    // Ends once cargo closed both its stdout and its stderr
+    while stdout.is_some() || stderr.is_some() {
+        tokio::select! {
+            line = next_line(&mut stdout) => {
+                if let Some(line) = line {
+                    if echo && !line.starts_with('{') {
+                        println!("{line}");
+                    }
+                    on_line(&line);
+                }
+            }
+            line = next_line(&mut stderr) => {
+                if let Some(line) = line {
+                    if echo {
+                        eprintln!("{line}");
+                    }
+                    on_line(&line);
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }
+            () = next_beat(&mut heartbeat) => {}
+        }
+    }
+    let status = child.wait().await.map_err(InstallError::Wait)?;
+
+    if !status.success() {
+        let install_error = InstallError::failed(status, Vec::from(tail).join("\n"));
+        error!(target: INSTALL, "{install_error}");
+        return Err(install_error);
+    }
+
+    debug!(target: INSTALL, "cargo install exited with {}", status);
+    Ok(())
+}
+
+// The next line of one of cargo's outputs, `None` once it's closed (after which
+// it's set to `None` and never ready again)
+#[cfg(feature = "tokio")]
+async fn next_line<R>(lines: &mut Option<Lines<R>>) -> Option<String>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let Some(reader) = lines else {
+        return std::future::pending().await;
+    };
+    match reader.next_line().await {
+        Ok(Some(line)) => Some(line),
+        Ok(None) => {
+            *lines = None;
+            None
+        }
+        Err(e) => {
+            debug!(target: INSTALL, "Stopped reading cargo output: {}", e);
+            *lines = None;
+            None
+        }
+    }
+}
+
+// Calls the heartbeat's callback at its next tick, never ready without one
+#[cfg(feature = "tokio")]
+async fn next_beat(heartbeat: &mut Option<(tokio::time::Interval, &mut (dyn FnMut() + Send))>) {
+    match heartbeat {
+        Some((interval, on_beat)) => {
+            interval.tick().await;
+            on_beat();
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Cargo's home directory, where it keeps the registry cache: `$CARGO_HOME` or `~/.cargo`
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    if let Some(cargo_home) = env::var_os("CARGO_HOME").filter(|home| !home.is_empty()) {
+        return Some(PathBuf::from(cargo_home));
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).filter(|home| !home.is_empty())?;
+    Some(PathBuf::from(home).join(".cargo"))
+}
+
+// A path from an environment variable, as `command` sees it: its own setting (or
+// removal) wins over ours, and an empty value counts as unset
+pub(crate) fn command_env(command: &Command, key: &str) -> Option<PathBuf> {
+    let value = match command.get_envs().find(|(name, _)| *name == OsStr::new(key)) {
+        Some((_, value)) => value.map(OsStr::to_os_string),
+        None => env::var_os(key),
+    };
+    value.filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+// Where `command` (a `cargo install`) installs: `--root`, `$CARGO_INSTALL_ROOT` or cargo's home
+pub(crate) fn command_install_root(command: &Command) -> Option<PathBuf> {
+    let mut args = command.get_args();
+    args.by_ref()
+        .find(|arg| *arg == "--root")
+        .and(args.next())
+        .map(PathBuf::from)
+        .or_else(|| command_env(command, "CARGO_INSTALL_ROOT"))
+        .or_else(|| command_env(command, "CARGO_HOME"))
+        .or_else(cargo_home)
+}
+
+/// A binary put in place by an update, as reported by `UpdateEvent::Installed`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledFile {
+    /// Where the binary is.
+    pub path: PathBuf,
+    /// Its size, in bytes.
+    pub size: u64,
+    /// The version cargo recorded for it.
+    pub version: String,
+}
+
+// Parses `cargo install --list`, returning the version of `crate_name` and its binaries:
+//
+// ripgrep v14.1.0:
+//     rg
+fn parse_install_list(list: &str, crate_name: &str) -> Option<(String, Vec<String>)> {
+    let mut lines = list.lines();
+    let version = lines.by_ref().find_map(|line| {
+        // Crates not from a registry are followed by their source, e.g. `foo v0.1.0 (/src/foo):`
+        let mut words = line.strip_suffix(':')?.split(' ');
+        let (name, version) = (words.next()?, words.next()?);
+        (name == crate_name).then(|| version.trim_start_matches('v').to_string())
+    })?;
+    let binaries = lines
+        .take_while(|line| line.starts_with(char::is_whitespace))
+        .map(|line| line.trim().to_string())
+        .collect();
+    Some((version, binaries))
+}
+
+// Where a `cargo install` command installs, and keeps its records
+#[derive(Debug, Clone)]
+pub(crate) struct InstallLocation {
+    root: Option<PathBuf>,
+    cargo_home: Option<PathBuf>,
+}
+
+impl InstallLocation {
+    pub(crate) fn of(install_command: &Command) -> Self {
+        InstallLocation {
+            root: command_install_root(install_command),
+            cargo_home: command_env(install_command, "CARGO_HOME"),
+        }
+    }
+
+    // The binaries of `crate_name` installed here, as cargo recorded them
+    pub(crate) fn installed_files(&self, crate_name: &str) -> Result<Vec<InstalledFile>> {
+        let root = self.root.as_ref().context("Could not tell where cargo installed to")?;
+        let mut list = Command::new("cargo");
+        list.args(["install", "--list", "--root"]).arg(root);
+        if let Some(cargo_home) = &self.cargo_home {
+            list.env("CARGO_HOME", cargo_home);
+        }
+        let list = run_install_list(list)?;
+        let (version, binaries) = parse_install_list(&list, crate_name)
+            .with_context(|| format!("{} is not in cargo's list of installed crates", crate_name))?;
+        binaries
+            .into_iter()
+            .map(|binary| {
+                let path = root.join("bin").join(binary);
+                let size = fs::metadata(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+                    .len();
+                Ok(InstalledFile { path, size, version: version.clone() })
+            })
+            .collect()
+    }
+
+    // Every binary of `crate_name` installed here, from cargo's `.crates.toml`;
+    // empty if cargo has no record of it
+    pub(crate) fn installed_binaries(&self, crate_name: &str, fs: &dyn Fs) -> Vec<PathBuf> {
+        let Some(root) = &self.root else {
+            return Vec::new();
+        };
+        fs.read_to_string(&root.join(".crates.toml"))
+            .map(|records| {
+                crates_toml_binaries(&records, crate_name)
+                    .into_iter()
+                    .map(|binary| root.join("bin").join(binary))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+// The output of `cargo install --list`
+fn run_install_list(mut list: Command) -> Result<String> {
+    let output = list.stderr(Stdio::null()).output().context("Failed to run cargo install --list")?;
+    if !output.status.success() {
+        anyhow::bail!("cargo install --list exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the version of `crate_name` that `cargo install` installed, or
+/// `None` if cargo's list doesn't have it.
+///
+/// For watching crates that aren't running, like with `rspawn watch`; a
+/// program knows its own version from `env!("CARGO_PKG_VERSION")`.
+pub fn installed_version(crate_name: &str) -> Result<Option<String>, RspawnError> {
+    let mut list = Command::new("cargo");
+    list.args(["install", "--list"]);
+    let list = run_install_list(list)?;
+    Ok(parse_install_list(&list, crate_name).map(|(version, _)| version))
+}
+
+/// Returns the version of every crate `cargo install` installed, by crate name.
+///
+/// Reads cargo's `.crates.toml` instead of running cargo, so it's cheap enough
+/// to call on every shell prompt; crates it can't find or parse are left out.
+pub fn installed_versions() -> BTreeMap<String, String> {
+    let Some(records) = cargo_bin_dir().and_then(|bin| Some(bin.parent()?.join(".crates.toml"))) else {
+        return BTreeMap::new();
+    };
+    fs::read_to_string(records)
+        .map(|records| parse_crates_toml(&records))
+        .unwrap_or_default()
+}
+
+// Entries look like `"foo 0.1.0 (registry+https://...)" = ["foo"]`, under `[v1]`
+fn parse_crates_toml(records: &str) -> BTreeMap<String, String> {
+    records
+        .lines()
+        .filter_map(|line| {
+            let (key, _) = line.strip_prefix('"')?.split_once('"')?;
+            let mut words = key.split(' ');
+            Some((words.next()?.to_string(), words.next()?.to_string()))
+        })
+        .collect()
+}
+
+// The binaries listed for `crate_name` in `.crates.toml`, e.g. `["foo", "foo-helper"]`
+fn crates_toml_binaries(records: &str, crate_name: &str) -> Vec<String> {
+    records
+        .lines()
+        .find_map(|line| {
+            let (key, binaries) = line.strip_prefix('"')?.split_once('"')?;
+            (key.split(' ').next()? == crate_name).then_some(binaries)
+        })
+        .and_then(|binaries| binaries.split_once('[')?.1.rsplit_once(']').map(|(list, _)| list))
+        .map(|list| {
+            list.split(',')
+                .map(|binary| binary.trim().trim_matches('"').to_string())
+                .filter(|binary| !binary.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Directory `cargo install` puts binaries in, following cargo's own lookup order
+pub(crate) fn cargo_bin_dir() -> Option<PathBuf> {
+    if let Some(root) = env::var_os("CARGO_INSTALL_ROOT").filter(|root| !root.is_empty()) {
+        return Some(PathBuf::from(root).join("bin"));
+    }
+    Some(cargo_home()?.join("bin"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cargo's exit status when a build fails
+    #[cfg(unix)]
+    fn cargo_failed() -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(101 << 8)
+    }
+
+    #[cfg(windows)]
+    fn cargo_failed() -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(101)
+    }
+
+    const NETWORK: &str = "\
+    Updating crates.io index
+warning: spurious network error (3 tries remaining): [6] Couldn't resolve host name (Could not resolve host: index.crates.io)
+error: failed to query replaced source registry `crates-io`
+
+Caused by:
+  download of config.json failed";
+
+    const COMPILE: &str = "\
+   Compiling mytool v1.5.0
+error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:2:13
+  |
+2 |     let y = x;
+  |             ^ not found in this scope
+
+For more information about this error, try `rustc --explain E0425`.
+error: could not compile `mytool` (bin \"mytool\") due to 1 previous error
+error: failed to compile `mytool v1.5.0`, intermediate artifacts can be found at `/tmp/cargo-installAbC`.";
+
+    const LINKER: &str = "\
+   Compiling mytool v1.5.0
+error: linking with `cc` failed: exit status: 1
+  |
+  = note: /usr/bin/ld: cannot find -lssl: No such file or directory
+          collect2: error: ld returned 1 exit status
+
+error: could not compile `mytool` (bin \"mytool\") due to 1 previous error";
+
+    const NO_SPACE: &str = "\
+   Compiling regex-syntax v0.8.5
+error: failed to write /tmp/cargo-installAbC/release/deps/regex_syntax-1a2b.rmeta: No space left on device (os error 28)
+
+error: could not compile `regex-syntax` (lib) due to 1 previous error";
+
+    const PERMISSION_DENIED: &str = "\
+    Finished `release` profile [optimized] target(s) in 41.02s
+  Installing /usr/local/bin/mytool
+error: failed to move `/tmp/cargo-installAbC/release/mytool` to `/usr/local/bin/mytool`
+
+Caused by:
+  Permission denied (os error 13)";
+
+    const KILLED: &str = "\
+   Compiling mytool v1.5.0
+error: could not compile `mytool` (bin \"mytool\")
+
+Caused by:
+  process didn't exit successfully: `rustc --crate-name mytool --edition=2021 src/main.rs` (signal: 9, SIGKILL: kill)";
+
+    #[test]
+    fn failures_are_classified_from_cargo_stderr() {
+        for (stderr, kind) in [
+            (NETWORK, InstallFailureKind::Network),
+            (COMPILE, InstallFailureKind::Compile),
+            (LINKER, InstallFailureKind::Linker),
+            (NO_SPACE, InstallFailureKind::NoSpace),
+            (PERMISSION_DENIED, InstallFailureKind::PermissionDenied),
+            (KILLED, InstallFailureKind::Killed),
+            ("error: something new went wrong", InstallFailureKind::Unknown),
+            ("", InstallFailureKind::Unknown),
+        ] {
+            assert_eq!(InstallFailureKind::classify(cargo_failed(), stderr), kind, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn specific_failures_win_over_the_compile_error_they_cause() {
+        // Each of these also has cargo's "could not compile"
+        for stderr in [NO_SPACE, LINKER, KILLED] {
+            assert!(stderr.contains("could not compile"));
+            assert_ne!(InstallFailureKind::classify(cargo_failed(), stderr), InstallFailureKind::Compile);
+        }
+        let full_disk_while_downloading = format!("{}\n{}", NETWORK, "Caused by:\n  No space left on device (os error 28)");
+        assert_eq!(InstallFailureKind::classify(cargo_failed(), &full_disk_while_downloading), InstallFailureKind::NoSpace);
+        let denied_while_linking = format!("{}\n{}", LINKER, "Permission denied (os error 13)");
+        assert_eq!(InstallFailureKind::classify(cargo_failed(), &denied_while_linking), InstallFailureKind::PermissionDenied);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cargo_killed_by_a_signal_is_killed() {
+        use std::os::unix::process::ExitStatusExt;
+        let sigkill = ExitStatus::from_raw(9);
+        assert_eq!(InstallFailureKind::classify(sigkill, "   Compiling mytool v1.5.0"), InstallFailureKind::Killed);
+        assert_eq!(InstallFailureKind::classify(sigkill, COMPILE), InstallFailureKind::Killed);
+    }
+
+    // A build as `cargo install --locked --message-format json` reports it,
+    // stderr and stdout interleaved
+    const LOCKED_BUILD: &[&str] = &[
+        "    Updating crates.io index",
+        "  Downloaded tinyapp v0.3.1",
+        "  Downloaded 1 crate (12.0 KB) in 0.31s",
+        "  Installing tinyapp v0.3.1",
+        "   Compiling memchr v2.7.4",
+        r#"{"reason":"compiler-artifact","package_id":"registry+https://github.com/rust-lang/crates.io-index#memchr@2.7.4","manifest_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/memchr-2.7.4/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"memchr","src_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/memchr-2.7.4/src/lib.rs","edition":"2021","doc":true,"doctest":true,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":["alloc","std"],"filenames":["/tmp/cargo-installXyZ/release/deps/libmemchr-0a1b2c3d.rlib"],"executable":null,"fresh":false}"#,
+        r#"{"reason":"build-script-executed","package_id":"registry+https://github.com/rust-lang/crates.io-index#libc@0.2.168","linked_libs":[],"linked_paths":[],"cfgs":["freebsd11"],"env":[],"out_dir":"/tmp/cargo-installXyZ/release/build/libc-1a2b3c/out"}"#,
+        r#"{"reason":"compiler-artifact","package_id":"registry+https://github.com/rust-lang/crates.io-index#libc@0.2.168","manifest_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/libc-0.2.168/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"libc","src_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/libc-0.2.168/src/lib.rs","edition":"2015","doc":true,"doctest":true,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":["default","std"],"filenames":["/tmp/cargo-installXyZ/release/deps/liblibc-4d5e6f.rlib"],"executable":null,"fresh":false}"#,
+        r#"{"reason":"compiler-message","package_id":"registry+https://github.com/rust-lang/crates.io-index#tinyapp@0.3.1","manifest_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tinyapp-0.3.1/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"tinyapp","src_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tinyapp-0.3.1/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"message":{"rendered":"warning: unused variable: `x`\n","$message_type":"diagnostic","children":[],"code":null,"level":"warning","message":"unused variable: `x`","spans":[]}}"#,
+        r#"{"reason":"compiler-artifact","package_id":"registry+https://github.com/rust-lang/crates.io-index#tinyapp@0.3.1","manifest_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tinyapp-0.3.1/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"tinyapp","src_path":"/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tinyapp-0.3.1/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":[],"filenames":["/tmp/cargo-installXyZ/release/tinyapp"],"executable":"/tmp/cargo-installXyZ/release/tinyapp","fresh":false}"#,
+        r#"{"reason":"build-finished","success":true}"#,
+        "    Finished `release` profile [optimized] target(s) in 9.87s",
+        "  Installing /home/user/.cargo/bin/tinyapp",
+    ];
+
+    const TINYAPP_LOCKFILE: &str = r#"# This file is automatically @generated by Cargo.
+version = 4
+
+[[package]]
+name = "libc"
+version = "0.2.168"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "memchr"
+version = "2.7.4"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "tinyapp"
+version = "0.3.1"
+dependencies = [
+ "libc",
+ "memchr",
+]
+
+[[package]]
+name = "winapi"
+version = "0.3.9"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn locked_build_counts_packages_in_the_published_lockfile() {
+        let cargo_home = env::temp_dir().join(format!("rspawn-build-progress-{}", std::process::id()));
+        let source = cargo_home.join("registry/src/index.crates.io-6f17d22bba15001f/tinyapp-0.3.1");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("Cargo.lock"), TINYAPP_LOCKFILE).unwrap();
+
+        let mut progress = BuildProgress::new(Some(cargo_home.clone()));
+        let mut seen = Vec::new();
+        for line in LOCKED_BUILD {
+            if progress.read(line) {
+                seen.push((progress.compiled(), progress.total()));
+            }
+        }
+        fs::remove_dir_all(&cargo_home).unwrap();
+
+        assert_eq!(seen, [(1, Some(4)), (1, Some(4)), (2, Some(4)), (2, Some(4)), (3, Some(4)), (3, Some(4))]);
+    }
+
+    #[test]
+    fn unlocked_build_counts_the_packages_cargo_locks() {
+        let mut progress = BuildProgress::new(None);
+        assert!(!progress.read("     Locking 57 packages to latest compatible versions"));
+        for line in LOCKED_BUILD {
+            progress.read(line);
+        }
+        assert_eq!((progress.compiled(), progress.total()), (3, Some(57)));
+    }
+
+    #[test]
+    fn build_without_a_lockfile_has_no_total() {
+        let mut progress = BuildProgress::new(Some(env::temp_dir().join("rspawn-no-cargo-home")));
+        for line in LOCKED_BUILD {
+            progress.read(line);
+        }
+        assert_eq!((progress.compiled(), progress.total()), (3, None));
+    }
+
+    #[test]
+    fn every_binary_of_the_crate_is_found_in_cargos_records() {
+        let fs = crate::filesystem::MemoryFs::new();
+        let root = PathBuf::from("/home/user/.cargo");
+        fs.write(&root.join(".crates.toml"), br#"[v1]
+"mytool 1.4.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["mytool", "mytool-helper"]
+"ripgrep 14.1.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["rg"]
+"#).unwrap();
+        let location = InstallLocation { root: Some(root.clone()), cargo_home: None };
+        assert_eq!(
+            location.installed_binaries("mytool", &fs),
+            vec![root.join("bin/mytool"), root.join("bin/mytool-helper")]
+        );
+        assert!(location.installed_binaries("bat", &fs).is_empty());
+    }
+}
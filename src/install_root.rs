@@ -0,0 +1,119 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Choosing where an update is installed: next to the running binary, in the
+//! user's cargo root or in a system-wide root.
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{bail, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use crate::current_exe_path;
+use crate::install;
+use crate::log_target::INSTALL;
+
+/// Where `cargo install` puts the new version.
+///
+/// Installing anywhere but where the running binary lives leaves the old one
+/// in place, and whichever comes first in `PATH` keeps running: a system-wide
+/// install updated into `~/.cargo/bin` ends up with a per-user copy silently
+/// shadowing it (or being shadowed). The default updates in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallRoot {
+    /// The root the running binary was installed into, found from its path
+    /// (`<root>/bin/<name>`). Programs that don't live in a `bin` directory
+    /// are updated into the user's root.
+    #[default]
+    InPlace,
+    /// Cargo's own root: `$CARGO_INSTALL_ROOT`, or `~/.cargo`.
+    User,
+    /// The system-wide root, `/usr/local` (`%ProgramFiles%\cargo` on Windows).
+    /// Installing there usually needs elevation, see `RSpawn::allow_elevation`.
+    System,
+    /// A root of your choosing, installing into its `bin` directory.
+    Path(PathBuf),
+}
+
+impl InstallRoot {
+    // The root to pass to `cargo install --root`, `None` for cargo's default
+    pub(crate) fn resolve(&self) -> Option<PathBuf> {
+        let root = match self {
+            InstallRoot::InPlace => exe_install_root(&current_exe_path().ok()?)?,
+            InstallRoot::User => return None,
+            InstallRoot::System => system_root()?,
+            InstallRoot::Path(root) => root.clone(),
+        };
+        // Spelling out cargo's default keeps `.crates.toml` where cargo expects it anyway
+        let default_root = install::cargo_bin_dir().and_then(|bin_dir| Some(bin_dir.parent()?.to_path_buf()));
+        if default_root.is_some_and(|default_root| install::same_path(&default_root, &root)) {
+            return None;
+        }
+        Some(root)
+    }
+}
+
+// `<root>` for an executable at `<root>/bin/<name>`
+fn exe_install_root(exe_path: &Path) -> Option<PathBuf> {
+    let bin_dir = exe_path.parent()?;
+    if bin_dir.file_name()? != "bin" {
+        debug!(target: INSTALL, "{} is not in a bin directory, updating into cargo's root", exe_path.display());
+        return None;
+    }
+    Some(bin_dir.parent()?.to_path_buf())
+}
+
+fn system_root() -> Option<PathBuf> {
+    if cfg!(windows) {
+        Some(PathBuf::from(env::var_os("ProgramFiles")?).join("cargo"))
+    } else {
+        Some(PathBuf::from("/usr/local"))
+    }
+}
+
+// Runs `command` as root through sudo, for a root the user can't write to.
+// The toolchain is the user's (rustup's home is passed along), but cargo uses
+// root's home and a temporary target directory, so nothing the build writes
+// ends up owned by root in the user's home.
+pub(crate) fn elevate(command: Command, allowed: bool) -> Result<Command> {
+    let root = install::command_install_root(&command).unwrap_or_default();
+    if !allowed {
+        bail!("{} is not writable: run as an administrator, or allow elevation to update there", root.display());
+    }
+    if cfg!(windows) {
+        bail!("{} is not writable: updating there needs an elevated prompt", root.display());
+    }
+    if install::find_in_path("sudo").is_none() {
+        bail!("{} is not writable and sudo was not found in PATH", root.display());
+    }
+
+    let mut elevated = Command::new("sudo");
+    elevated.arg("--preserve-env=PATH,RUSTUP_HOME,RUSTUP_TOOLCHAIN").arg("--");
+    elevated.arg(command.get_program()).args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => elevated.env(key, value),
+            None => elevated.env_remove(key),
+        };
+    }
+    let home = install::command_env(&command, "HOME");
+    if let Some(rustup_home) = install::command_env(&command, "RUSTUP_HOME").or_else(|| Some(home?.join(".rustup"))) {
+        elevated.env("RUSTUP_HOME", rustup_home);
+    }
+    if let Some(dir) = command.get_current_dir() {
+        elevated.current_dir(dir);
+    }
+    debug!(target: INSTALL, "Installing into {} through sudo", root.display());
+    Ok(elevated)
+}
@@ -0,0 +1,149 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use sha2::{Digest, Sha256};
+use log::{info, debug};
+use crate::error::RspawnError;
+use crate::log_target::INSTALL;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::state::{InstalledBinary, State};
+
+/// Error returned when a binary's hash does not match the expected one.
+#[derive(Debug)]
+pub struct IntegrityError {
+    /// The binary that was checked.
+    pub path: PathBuf,
+    /// The expected hex-encoded SHA-256.
+    pub expected: String,
+    /// The actual hex-encoded SHA-256.
+    pub actual: String,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Integrity check failed for {}: expected sha256 {}, found {}",
+            self.path.display(),
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Computes the hex-encoded SHA-256 of a file.
+pub fn sha256_file(path: &Path) -> Result<String, RspawnError> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Compares the hash of `path` against `expected`, ignoring case
+fn verify_file(path: &Path, expected_sha256: &str) -> Result<()> {
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected_sha256.trim()) {
+        return Err(IntegrityError {
+            path: path.to_path_buf(),
+            expected: expected_sha256.trim().to_lowercase(),
+            actual,
+        }
+        .into());
+    }
+    debug!(target: INSTALL, "{} matches sha256 {}", path.display(), actual);
+    Ok(())
+}
+
+/// Checks that the running executable hashes to `expected_sha256`.
+///
+/// # Returns
+/// * `Ok(())` if the hashes match, or `RspawnError::Integrity` if not.
+pub fn verify_self(expected_sha256: &str) -> Result<(), RspawnError> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    verify_file(&exe_path, expected_sha256).map_err(RspawnError::from)
+}
+
+// Resolves symlinks and `..` in `path`, so the same binary reached through a
+// symlinked directory (e.g. a cargo root under /home -> /usr/home) is
+// recognized. Paths that can't be resolved are kept as they are.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Checks the running executable against the hash recorded when rspawn installed it.
+// Nothing is checked when the running binary is not the one rspawn last installed.
+pub(crate) fn verify_against_state(exe_path: &Path, current_version: &str, fs: &dyn Fs) -> Result<()> {
+    let state = State::load_in(fs)?;
+    match state.installed {
+        // Records from before paths were stored resolved may hold any spelling of it
+        Some(installed) if canonical(&installed.path) == canonical(exe_path) && installed.version == current_version => {
+            verify_file(exe_path, &installed.sha256)
+        }
+        _ => {
+            debug!(target: INSTALL, "No install record for {} {}, skipping integrity check", exe_path.display(), current_version);
+            Ok(())
+        }
+    }
+}
+
+// Records the hash of a freshly installed binary in state, returning it
+pub(crate) fn record_install(exe_path: &Path, version: &str, clock: &dyn Clock, fs: &dyn Fs) -> Result<String> {
+    let sha256 = sha256_file(exe_path)?;
+    info!(target: INSTALL, "Installed {} {} (sha256 {})", exe_path.display(), version, sha256);
+
+    let installed = InstalledBinary {
+        version: version.to_string(),
+        path: canonical(exe_path),
+        sha256: sha256.clone(),
+        installed_at: clock.unix_now(),
+    };
+    State::update_in(fs, |state| state.installed = Some(installed))?;
+    Ok(sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    #[cfg(unix)]
+    #[test]
+    fn binary_reached_through_a_symlink_is_checked() {
+        let dir = env::temp_dir().join(format!("rspawn-integrity-{}", std::process::id()));
+        let link = env::temp_dir().join(format!("rspawn-integrity-link-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        let binary = dir.join("app");
+        std::fs::write(&binary, b"1.0.0").unwrap();
+        let fs = MemoryFs::new();
+
+        record_install(&link.join("app"), "1.0.0", &MockClock::from_unix(1_700_000_000), &fs).unwrap();
+        std::fs::write(&binary, b"tampered").unwrap();
+        let result = verify_against_state(&binary, "1.0.0", &fs);
+
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.unwrap_err().downcast_ref::<IntegrityError>().is_some());
+    }
+}
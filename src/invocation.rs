@@ -0,0 +1,61 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The program's command line, environment and working directory as it was
+//! started, so it can be relaunched exactly the same way.
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+static CAPTURED: OnceLock<Invocation> = OnceLock::new();
+
+/// How the program was started, see `capture_invocation()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The arguments, without the program itself.
+    pub args: Vec<OsString>,
+    /// The environment variables.
+    pub env: Vec<(OsString, OsString)>,
+    /// The working directory, if it could be read.
+    pub current_dir: Option<PathBuf>,
+}
+
+impl Invocation {
+    // Sets up `command` to run as the program was started
+    pub(crate) fn apply(&self, command: &mut Command) {
+        command.args(&self.args).env_clear().envs(self.env.iter().map(|(key, value)| (key, value)));
+        if let Some(current_dir) = &self.current_dir {
+            command.current_dir(current_dir);
+        }
+    }
+}
+
+/// Snapshots the program's arguments, environment and working directory.
+///
+/// Call it first thing in `main`, before the program consumes or rewrites any
+/// of them: relaunches after an update then use the snapshot, so the new
+/// version starts exactly as the old one was. Later calls return the first snapshot.
+pub fn capture_invocation() -> &'static Invocation {
+    CAPTURED.get_or_init(|| Invocation {
+        args: env::args_os().skip(1).collect(),
+        env: env::vars_os().collect(),
+        current_dir: env::current_dir().ok(),
+    })
+}
+
+// The snapshot taken by `capture_invocation()`, if it was called
+pub(crate) fn captured() -> Option<&'static Invocation> {
+    CAPTURED.get()
+}
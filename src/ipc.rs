@@ -0,0 +1,347 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Control interface for long-running programs.
+//!
+//! A daemon serves newline-delimited JSON-RPC 2.0 requests on a Unix socket,
+//! so operators can trigger the update flow from outside the process
+//! (e.g. `myctl update`):
+//!
+//! * `check` looks up the latest version.
+//! * `update` installs the latest version and relaunches the daemon, unless it
+//!   was built with `RelaunchMode::OnNextStart` (`restart_required` is then set).
+//! * `status` reports the running version and the last check.
+//! * `restart` relaunches the daemon without updating.
+//!
+//! Once a new instance of the daemon is running, serving stops and the outcome
+//! goes back to the daemon, to clean up and exit.
+//!
+//! ```ignore
+//! // In the daemon
+//! let socket = rspawn::control_socket_path("mydaemon")?;
+//! let server = rspawn::ControlServer::bind(&socket)?.spawn(|| rspawn::self_update!().check_if_executed_from_PATH(false));
+//! // ...
+//! if let Ok(Ok(UpdateOutcome::Relaunched { .. })) = server.join() {
+//!     std::process::exit(0);
+//! }
+//!
+//! // In `myctl`
+//! let result = rspawn::send_control_request(&rspawn::control_socket_path("mydaemon")?, "update")?;
+//! ```
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use log::{info, debug, warn};
+use crate::log_target::CONTROL;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, ConfigError};
+use crate::error::RspawnError;
+use crate::events::UpdateEvent;
+use crate::filesystem::RealFs;
+use crate::state::runtime_dir;
+use crate::version::{is_update, UpdatePolicy};
+use crate::{check_executable, check_versions, current_exe_path, relaunch_command, RSpawn, UpdateOutcome};
+
+// How long `bind` waits for a previous instance (e.g. one that is relaunching) to release the socket
+const BIND_WAIT: Duration = Duration::from_secs(5);
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_ERROR: i64 = -32000;
+
+/// Returns the default control socket of `program`, in rspawn's runtime directory.
+///
+/// Takes the program name rather than using the running executable, so a separate
+/// control tool can find its daemon's socket.
+pub fn control_socket_path(program: &str) -> Result<PathBuf, RspawnError> {
+    Ok(runtime_dir(&RealFs)?.join(format!("{}.sock", program)))
+}
+
+/// Result of a `check` request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckResult {
+    /// The running version.
+    pub current_version: String,
+    /// The latest version available.
+    pub latest_version: String,
+    /// Whether `latest_version` is an update over `current_version`.
+    pub update_available: bool,
+    /// Check time, in seconds since the Unix epoch.
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+}
+
+/// A Unix socket serving the control interface.
+#[derive(Debug)]
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Binds the control socket at `path`, readable and writable by the current user only.
+    ///
+    /// A socket left behind by an instance that is gone is replaced. If another
+    /// instance is still serving on `path`, this waits briefly for it to exit
+    /// (as it does when relaunching) before giving up.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self, RspawnError> {
+        let path = path.as_ref().to_path_buf();
+        let started = Instant::now();
+        while path.exists() {
+            if UnixStream::connect(&path).is_err() {
+                debug!(target: CONTROL, "Removing stale control socket {}", path.display());
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove stale control socket {}", path.display()))?;
+                break;
+            }
+            if started.elapsed() >= BIND_WAIT {
+                return Err(anyhow::anyhow!("Control socket {} is in use by another instance", path.display()).into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict permissions of {}", path.display()))?;
+        info!(target: CONTROL, "Serving rspawn control requests on {}", path.display());
+        Ok(ControlServer { listener, path })
+    }
+
+    /// The path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves requests on the current thread until an `update` or `restart`
+    /// request starts a new instance of the daemon.
+    ///
+    /// `builder` is called for each `check` and `update` request, so it can pick up
+    /// configuration changes. `update` always installs without asking: the
+    /// request is the confirmation.
+    ///
+    /// Returns `UpdateOutcome::Relaunched` with the new instance's pid. The
+    /// socket is closed by then, so the new instance can bind it once this one
+    /// exits, which is up to the caller.
+    pub fn serve<B>(self, mut builder: B) -> Result<UpdateOutcome, RspawnError>
+    where
+        B: FnMut() -> RSpawn,
+    {
+        let mut last_check = None;
+        let started_at = SystemClock.unix_now();
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(target: CONTROL, "Failed to accept a control connection: {}", e);
+                    continue;
+                }
+            };
+            match self.handle_connection(stream, &mut builder, &mut last_check, started_at) {
+                Ok(Some(relaunched)) => return Ok(relaunched),
+                Ok(None) => {}
+                Err(e) => warn!(target: CONTROL, "Control connection failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Serves requests on a new thread, see `serve`.
+    pub fn spawn<B>(self, builder: B) -> thread::JoinHandle<Result<UpdateOutcome, RspawnError>>
+    where
+        B: FnMut() -> RSpawn + Send + 'static,
+    {
+        thread::spawn(move || self.serve(builder))
+    }
+
+    fn handle_connection<B>(
+        &self,
+        stream: UnixStream,
+        builder: &mut B,
+        last_check: &mut Option<CheckResult>,
+        started_at: u64,
+    ) -> Result<Option<UpdateOutcome>>
+    where
+        B: FnMut() -> RSpawn,
+    {
+        let reader = BufReader::new(stream.try_clone().context("Failed to clone control connection")?);
+        let mut writer = stream;
+        for line in reader.lines() {
+            let line = line.context("Failed to read control request")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request: Request = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    respond(&mut writer, &Value::Null, Err((PARSE_ERROR, e.to_string())))?;
+                    continue;
+                }
+            };
+            debug!(target: CONTROL, "Control request: {}", request.method);
+
+            let result = match request.method.as_str() {
+                "check" => check(builder()).map(|result| {
+                    *last_check = Some(result.clone());
+                    json!(result)
+                }),
+                "update" => match update(builder()) {
+                    Ok(Updated::Relaunched(relaunched, result)) => {
+                        // Serving stops either way: the new instance is already running
+                        if let Err(e) = respond(&mut writer, &request.id, Ok(result)) {
+                            warn!(target: CONTROL, "{:#}", e);
+                        }
+                        return Ok(Some(relaunched));
+                    }
+                    Ok(Updated::Running(result)) => Ok(result),
+                    Err(e) => Err(e),
+                },
+                "status" => status(builder(), started_at, last_check),
+                "restart" => match relaunch() {
+                    Ok(relaunched) => {
+                        if let Err(e) = respond(&mut writer, &request.id, Ok(json!({ "restarting": true }))) {
+                            warn!(target: CONTROL, "{:#}", e);
+                        }
+                        return Ok(Some(relaunched));
+                    }
+                    Err(e) => Err(e),
+                },
+                method => {
+                    respond(&mut writer, &request.id, Err((METHOD_NOT_FOUND, format!("Unknown method: {}", method))))?;
+                    continue;
+                }
+            };
+            respond(&mut writer, &request.id, result.map_err(|e| (SERVER_ERROR, format!("{:#}", e))))?;
+        }
+        Ok(None)
+    }
+}
+
+// Writes a JSON-RPC response line
+fn respond(writer: &mut UnixStream, id: &Value, result: std::result::Result<Value, (i64, String)>) -> Result<()> {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+    };
+    writeln!(writer, "{}", response).context("Failed to write control response")
+}
+
+// The daemon's pid and version, when it started and what the last check found.
+// The version is the program's, so the builder must have one (or a target binary).
+fn status(rspawn: RSpawn, started_at: u64, last_check: &Option<CheckResult>) -> Result<Value> {
+    let problems = config::package_problems(&rspawn);
+    if !problems.is_empty() {
+        return Err(RspawnError::Config(ConfigError { problems }).into());
+    }
+    Ok(json!({
+        "pid": std::process::id(),
+        "version": rspawn.current_version,
+        "started_at": started_at,
+        "last_check": last_check,
+    }))
+}
+
+// Runs the flow in check-only mode
+fn check(rspawn: RSpawn) -> Result<CheckResult> {
+    let (current_version, latest_version) = check_versions(rspawn)?;
+    Ok(CheckResult {
+        update_available: is_update(&current_version, &latest_version, UpdatePolicy::Newer),
+        current_version,
+        latest_version,
+        checked_at: SystemClock.unix_now(),
+    })
+}
+
+// How an `update` request went: the daemon relaunched, or is still the one running
+enum Updated {
+    // The outcome for the host, and the response for the client
+    Relaunched(UpdateOutcome, Value),
+    Running(Value),
+}
+
+// Runs the full flow, returning the response once it is over: after the new
+// instance started, or failed to
+fn update(rspawn: RSpawn) -> Result<Updated> {
+    let installed = Arc::new(Mutex::new(None));
+    let installed_version = Arc::clone(&installed);
+    // Set when the daemon keeps running, see `RelaunchMode::OnNextStart`
+    let pending = Arc::new(Mutex::new(None));
+    let pending_version = Arc::clone(&pending);
+    let outcome = rspawn
+        .assume_yes(true)
+        .on_event(move |event| match event {
+            UpdateEvent::InstallStarted { version } => *lock(&installed_version) = Some(version.clone()),
+            UpdateEvent::RestartPending { version } => *lock(&pending_version) = Some(version.clone()),
+            _ => {}
+        })
+        .relaunch_program()?;
+    if let UpdateOutcome::Relaunched { pid } = outcome {
+        let result = json!({ "updated": true, "version": lock(&installed).take(), "pid": pid });
+        return Ok(Updated::Relaunched(outcome, result));
+    }
+    let pending = lock(&pending).take();
+    Ok(Updated::Running(match pending {
+        Some(version) => json!({ "updated": true, "version": version, "restart_required": true }),
+        None => json!({ "updated": false }),
+    }))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Starts a new instance of the daemon, to take over once this one exits
+fn relaunch() -> Result<UpdateOutcome> {
+    let program = current_exe_path()?;
+    check_executable(&program)?;
+    let child = relaunch_command(&program).spawn().context("Failed to start the new instance")?;
+    Ok(UpdateOutcome::Relaunched { pid: Some(child.id()) })
+}
+
+/// Sends a control request to the daemon listening on `path` and returns its result.
+///
+/// `method` is one of `check`, `update`, `status` or `restart`. Blocks until the
+/// daemon answers, which for `update` includes the whole install.
+pub fn send_control_request(path: impl AsRef<Path>, method: &str) -> Result<Value, RspawnError> {
+    let path = path.as_ref();
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to control socket {}", path.display()))?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method });
+    writeln!(stream, "{}", request).context("Failed to send control request")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).context("Failed to read control response")?;
+    if line.is_empty() {
+        return Err(anyhow::anyhow!("The daemon closed the connection without answering").into());
+    }
+    let mut response: Value = serde_json::from_str(&line).context("Failed to parse control response")?;
+    if let Some(error) = response.get("error") {
+        let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error");
+        return Err(anyhow::anyhow!("{} failed: {}", method, message).into());
+    }
+    Ok(response["result"].take())
+}
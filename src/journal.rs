@@ -0,0 +1,296 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::{info, debug, warn};
+use crate::error::RspawnError;
+use crate::json;
+use crate::log_target::INSTALL;
+use crate::clock::Clock;
+use crate::filesystem::{Fs, RealFs};
+use crate::state::{state_dir_in, write_atomic};
+
+// Journal of the update in progress, inside `state_dir()`
+const JOURNAL_FILE_NAME: &str = "journal.json";
+// Directory holding the journal's copies of the files it replaces
+const JOURNAL_DIR_NAME: &str = "journal";
+
+// A file the update is about to replace, and where its previous contents are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalEntry {
+    path: PathBuf,
+    // `None` if there was no file yet, so rolling back removes it
+    backup: Option<PathBuf>,
+}
+
+// Everything an update replaced so far, saved before each change so a crash
+// leaves enough behind to undo it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Journal {
+    from_version: String,
+    to_version: String,
+    started_at: u64,
+    entries: Vec<JournalEntry>,
+}
+
+// An update replacing several files (the binary, its assets), undone as a whole
+// unless it is committed
+pub(crate) struct Transaction {
+    fs: Arc<dyn Fs>,
+    journal_path: PathBuf,
+    backups_dir: PathBuf,
+    journal: Journal,
+}
+
+impl Transaction {
+    // Starts journaling the update from `from_version` to `to_version`
+    pub(crate) fn begin(fs: Arc<dyn Fs>, from_version: &str, to_version: &str, clock: &dyn Clock) -> Result<Transaction> {
+        let dir = state_dir_in(fs.as_ref())?;
+        let transaction = Transaction {
+            fs,
+            journal_path: dir.join(JOURNAL_FILE_NAME),
+            backups_dir: dir.join(JOURNAL_DIR_NAME),
+            journal: Journal {
+                from_version: from_version.to_string(),
+                to_version: to_version.to_string(),
+                started_at: clock.unix_now(),
+                entries: Vec::new(),
+            },
+        };
+        transaction.fs.create_dir_all(&transaction.backups_dir)
+            .with_context(|| format!("Failed to create {}", transaction.backups_dir.display()))?;
+        transaction.save()?;
+        Ok(transaction)
+    }
+
+    fn save(&self) -> Result<()> {
+        // Only read back after a crash, which needs JSON too: rolling back
+        // from this process goes by the journal in memory
+        if !json::AVAILABLE {
+            return Ok(());
+        }
+        let contents = json::to_string_pretty(&self.journal).context("Failed to serialize the update journal")?;
+        write_atomic(self.fs.as_ref(), &self.journal_path, contents.as_bytes())
+    }
+
+    // Keeps a copy of `path` before the update replaces it, recorded in the journal first
+    pub(crate) fn track(&mut self, path: &Path) -> Result<()> {
+        if self.journal.entries.iter().any(|entry| entry.path == path) {
+            return Ok(());
+        }
+        let backup = if self.fs.exists(path) {
+            let backup = self.backups_dir.join(self.journal.entries.len().to_string());
+            // The copy keeps the permissions, so a restored binary is still executable
+            self.fs.copy(path, &backup)
+                .with_context(|| format!("Failed to copy {} to {}", path.display(), backup.display()))?;
+            Some(backup)
+        } else {
+            None
+        };
+        debug!(target: INSTALL, "Journaling {}", path.display());
+        self.journal.entries.push(JournalEntry { path: path.to_path_buf(), backup });
+        self.save()
+    }
+
+    // The update went through: forget the journal and its copies
+    pub(crate) fn commit(self) {
+        discard(self.fs.as_ref(), &self.journal, &self.journal_path, &self.backups_dir);
+    }
+
+    // Puts back every file the update replaced, newest first
+    pub(crate) fn rollback(self) -> Result<()> {
+        rollback(self.fs.as_ref(), &self.journal, &self.journal_path, &self.backups_dir)
+    }
+}
+
+fn discard(fs: &dyn Fs, journal: &Journal, journal_path: &Path, backups_dir: &Path) {
+    if let Err(e) = fs.remove_file(journal_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!(target: INSTALL, "Failed to remove the update journal {}: {}", journal_path.display(), e);
+        }
+    }
+    for backup in journal.entries.iter().filter_map(|entry| entry.backup.as_deref()) {
+        if let Err(e) = fs.remove_file(backup) {
+            if e.kind() != io::ErrorKind::NotFound {
+                debug!(target: INSTALL, "Failed to remove {}: {}", backup.display(), e);
+            }
+        }
+    }
+    if let Err(e) = fs.remove_dir(backups_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            debug!(target: INSTALL, "Failed to remove {}: {}", backups_dir.display(), e);
+        }
+    }
+}
+
+// Restores every journaled file, keeping the journal around if any of them
+// couldn't be, so the next run tries again
+fn rollback(fs: &dyn Fs, journal: &Journal, journal_path: &Path, backups_dir: &Path) -> Result<()> {
+    let mut failed = Vec::new();
+    for entry in journal.entries.iter().rev() {
+        if let Err(e) = restore(fs, entry) {
+            warn!(target: INSTALL, "Failed to roll back {}: {:#}", entry.path.display(), e);
+            failed.push(entry.path.display().to_string());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("Failed to roll back {}", failed.join(", ")));
+    }
+    info!(target: INSTALL, "Rolled back the update from {} to {}", journal.from_version, journal.to_version);
+    discard(fs, journal, journal_path, backups_dir);
+    Ok(())
+}
+
+// Puts `entry` back as it was, replacing the file in one step
+fn restore(fs: &dyn Fs, entry: &JournalEntry) -> Result<()> {
+    match &entry.backup {
+        Some(backup) => {
+            let mut file_name = entry.path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(".rspawn-rollback");
+            let staging = entry.path.with_file_name(file_name);
+            fs.copy(backup, &staging)
+                .with_context(|| format!("Failed to copy {} to {}", backup.display(), staging.display()))?;
+            fs.rename(&staging, &entry.path)
+                .with_context(|| format!("Failed to replace {}", entry.path.display()))?;
+        }
+        None => match fs.remove_file(&entry.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {}", entry.path.display()));
+            }
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+/// Rolls back an update that was interrupted before it finished.
+///
+/// Updates record the files they replace in a journal in `state_dir()`; if the
+/// process died halfway, this puts the previous version's files back. Returns
+/// the versions of the interrupted update, or `None` if there was nothing to
+/// roll back. Called at the start of every update.
+pub fn recover_interrupted_update() -> Result<Option<(String, String)>, RspawnError> {
+    recover_interrupted_update_in(&RealFs).map_err(RspawnError::from)
+}
+
+// `recover_interrupted_update`, with the journal kept in `fs`
+pub(crate) fn recover_interrupted_update_in(fs: &dyn Fs) -> Result<Option<(String, String)>> {
+    let dir = state_dir_in(fs)?;
+    let journal_path = dir.join(JOURNAL_FILE_NAME);
+    if !json::AVAILABLE || !fs.exists(&journal_path) {
+        return Ok(None);
+    }
+    let contents = fs.read_to_string(&journal_path)
+        .with_context(|| format!("Failed to read the update journal {}", journal_path.display()))?;
+    let journal: Journal = json::from_str(&contents)
+        .with_context(|| format!("Failed to parse the update journal {}", journal_path.display()))?;
+    warn!(
+        target: INSTALL,
+        "Found an interrupted update from {} to {} (started at {}), rolling it back",
+        journal.from_version, journal.to_version, journal.started_at
+    );
+    rollback(fs, &journal, &journal_path, &dir.join(JOURNAL_DIR_NAME))?;
+    Ok(Some((journal.from_version, journal.to_version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    const BINARY: &str = "/home/user/.cargo/bin/mytool";
+    const HELPER: &str = "/home/user/.cargo/bin/mytool-helper";
+    const NEW_ASSET: &str = "/home/user/.local/share/mytool/themes.json";
+
+    // A 1.4.0 install, with the binary, a companion binary and no assets yet
+    fn installed() -> Arc<MemoryFs> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write(Path::new(BINARY), b"mytool 1.4.0").unwrap();
+        fs.write(Path::new(HELPER), b"mytool-helper 1.4.0").unwrap();
+        fs
+    }
+
+    // Starts the update to 1.5.0, journaling and then replacing every file
+    fn update(fs: &Arc<MemoryFs>) -> Transaction {
+        let clock = MockClock::from_unix(1_700_000_000);
+        let mut transaction = Transaction::begin(fs.clone(), "1.4.0", "1.5.0", &clock).unwrap();
+        for (path, contents) in [(BINARY, "mytool 1.5.0"), (HELPER, "mytool-helper 1.5.0"), (NEW_ASSET, "{}")] {
+            transaction.track(Path::new(path)).unwrap();
+            fs.write(Path::new(path), contents.as_bytes()).unwrap();
+        }
+        transaction
+    }
+
+    fn contents(fs: &MemoryFs, path: &str) -> Option<String> {
+        fs.read_to_string(Path::new(path)).ok()
+    }
+
+    fn journal_is_gone(fs: &MemoryFs) -> bool {
+        let dir = state_dir_in(fs).unwrap();
+        !fs.exists(&dir.join(JOURNAL_FILE_NAME)) && !fs.exists(&dir.join(JOURNAL_DIR_NAME))
+    }
+
+    #[test]
+    fn rollback_restores_every_replaced_file() {
+        let fs = installed();
+        update(&fs).rollback().unwrap();
+        assert_eq!(contents(&fs, BINARY).as_deref(), Some("mytool 1.4.0"));
+        assert_eq!(contents(&fs, HELPER).as_deref(), Some("mytool-helper 1.4.0"));
+        assert_eq!(contents(&fs, NEW_ASSET), None);
+        assert!(journal_is_gone(&fs));
+    }
+
+    #[test]
+    fn commit_keeps_the_update_and_forgets_the_journal() {
+        let fs = installed();
+        update(&fs).commit();
+        assert_eq!(contents(&fs, BINARY).as_deref(), Some("mytool 1.5.0"));
+        assert_eq!(contents(&fs, NEW_ASSET).as_deref(), Some("{}"));
+        assert!(journal_is_gone(&fs));
+        assert_eq!(recover_interrupted_update_in(fs.as_ref()).unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_update_is_rolled_back_by_the_next_run() {
+        let fs = installed();
+        // The process dies before committing
+        drop(update(&fs));
+        assert_eq!(contents(&fs, BINARY).as_deref(), Some("mytool 1.5.0"));
+
+        let recovered = recover_interrupted_update_in(fs.as_ref()).unwrap();
+        assert_eq!(recovered, Some(("1.4.0".to_string(), "1.5.0".to_string())));
+        assert_eq!(contents(&fs, BINARY).as_deref(), Some("mytool 1.4.0"));
+        assert_eq!(contents(&fs, HELPER).as_deref(), Some("mytool-helper 1.4.0"));
+        assert_eq!(contents(&fs, NEW_ASSET), None);
+        assert!(journal_is_gone(&fs));
+        assert_eq!(recover_interrupted_update_in(fs.as_ref()).unwrap(), None);
+    }
+
+    #[test]
+    fn crash_before_replacing_anything_restores_the_same_files() {
+        let fs = installed();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let mut transaction = Transaction::begin(fs.clone(), "1.4.0", "1.5.0", &clock).unwrap();
+        transaction.track(Path::new(BINARY)).unwrap();
+        drop(transaction);
+
+        assert!(recover_interrupted_update_in(fs.as_ref()).unwrap().is_some());
+        assert_eq!(contents(&fs, BINARY).as_deref(), Some("mytool 1.4.0"));
+        assert!(journal_is_gone(&fs));
+    }
+}
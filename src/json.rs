@@ -0,0 +1,63 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! JSON, for crates.io's API and the files rspawn keeps.
+//!
+//! serde_json is only built with the `json` feature. Without it every call
+//! fails with `Error`: the `core` build then reads versions from plain-text
+//! sources (feeds and vendored mirrors) and keeps no state between runs.
+#[cfg(not(feature = "json"))]
+use std::fmt;
+#[cfg(not(feature = "json"))]
+use serde::de::DeserializeOwned;
+#[cfg(not(feature = "json"))]
+use serde::Serialize;
+
+#[cfg(feature = "json")]
+pub(crate) use serde_json::from_str;
+#[cfg(all(feature = "json", feature = "install"))]
+pub(crate) use serde_json::{to_string, to_string_pretty};
+
+// Whether JSON can be read and written at all
+#[cfg(feature = "install")]
+pub(crate) const AVAILABLE: bool = cfg!(feature = "json");
+
+// What everything here fails with when built without the `json` feature
+#[cfg(not(feature = "json"))]
+#[derive(Debug)]
+pub(crate) struct Error;
+
+#[cfg(not(feature = "json"))]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rspawn was built without the `json` feature")
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl std::error::Error for Error {}
+
+#[cfg(not(feature = "json"))]
+pub(crate) fn from_str<T: DeserializeOwned>(_json: &str) -> Result<T, Error> {
+    Err(Error)
+}
+
+#[cfg(not(feature = "json"))]
+pub(crate) fn to_string<T: Serialize + ?Sized>(_value: &T) -> Result<String, Error> {
+    Err(Error)
+}
+
+#[cfg(not(feature = "json"))]
+pub(crate) fn to_string_pretty<T: Serialize + ?Sized>(_value: &T) -> Result<String, Error> {
+    Err(Error)
+}
@@ -0,0 +1,139 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The most recent failed update attempt, kept in the program's state so host
+//! UIs can report it without running the update flow again.
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use log::{debug, warn};
+use crate::log_target::INSTALL;
+use crate::clock::Clock;
+use crate::error::RspawnError;
+use crate::filesystem::Fs;
+use crate::install::{InstallError, InstallFailureKind};
+use crate::state::{state_dir_in, write_atomic, State};
+
+// File holding cargo's output for the last failed install, inside `state_dir()`
+const INSTALL_LOG_FILE_NAME: &str = "last-install.log";
+
+/// What kind of problem stopped an update attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum UpdateErrorKind {
+    /// The registry (or another server) could not be reached.
+    Network,
+    /// Updates were frozen by the operator's hold file.
+    Held,
+    /// Cargo can't install anything here, e.g. its home is read-only.
+    Environment,
+    /// The running binary did not match the recorded install.
+    Integrity,
+    /// `cargo install` failed.
+    Install(InstallFailureKind),
+    /// Anything else.
+    Other,
+}
+
+impl UpdateErrorKind {
+    fn classify(error: &RspawnError) -> Self {
+        match error {
+            RspawnError::NetworkError(_) => UpdateErrorKind::Network,
+            RspawnError::Held(_) => UpdateErrorKind::Held,
+            RspawnError::Environment(_) => UpdateErrorKind::Environment,
+            RspawnError::Integrity(_) => UpdateErrorKind::Integrity,
+            RspawnError::InstallFailed { kind, .. } => UpdateErrorKind::Install(*kind),
+            // Cargo itself couldn't be started
+            RspawnError::NotOnPath { program } if program == Path::new("cargo") => UpdateErrorKind::Install(InstallFailureKind::Unknown),
+            RspawnError::Other(e) => e
+                .downcast_ref::<InstallError>()
+                .map_or(UpdateErrorKind::Other, |install| UpdateErrorKind::Install(install.kind())),
+            _ => UpdateErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for UpdateErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateErrorKind::Network => write!(f, "network error"),
+            UpdateErrorKind::Held => write!(f, "held by operator"),
+            UpdateErrorKind::Environment => write!(f, "unsupported environment"),
+            UpdateErrorKind::Integrity => write!(f, "integrity check failed"),
+            UpdateErrorKind::Install(kind) => write!(f, "{kind}"),
+            UpdateErrorKind::Other => write!(f, "error"),
+        }
+    }
+}
+
+/// The last failed update attempt, see `last_error()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastError {
+    /// What kind of problem it was.
+    pub kind: UpdateErrorKind,
+    /// The full error message.
+    pub message: String,
+    /// When the attempt failed, in seconds since the Unix epoch.
+    pub occurred_at: u64,
+    /// Where to find more details: cargo's output for failed installs,
+    /// otherwise the log file set with `RSpawn::log_path`, if any.
+    pub log_path: Option<PathBuf>,
+}
+
+/// Returns the running program's last failed update attempt, if the last
+/// attempt failed.
+///
+/// Cleared once an update installs, or the program is found to be up to date.
+pub fn last_error() -> Result<Option<LastError>, RspawnError> {
+    Ok(State::load()?.last_error)
+}
+
+// Remembers `error` as the last failed attempt
+pub(crate) fn record(error: &RspawnError, log_path: Option<&Path>, clock: &dyn Clock, fs: &dyn Fs) {
+    if let Err(e) = try_record(error, log_path, clock, fs) {
+        warn!(target: INSTALL, "Failed to record the failed update attempt: {:#}", e);
+    }
+}
+
+fn try_record(error: &RspawnError, log_path: Option<&Path>, clock: &dyn Clock, fs: &dyn Fs) -> Result<()> {
+    let mut log_path = log_path.map(Path::to_path_buf);
+    // Cargo's output is usually the most useful thing to point at
+    if let RspawnError::InstallFailed { stderr, .. } = error {
+        let path = state_dir_in(fs)?.join(INSTALL_LOG_FILE_NAME);
+        write_atomic(fs, &path, stderr.as_bytes())?;
+        log_path = Some(path);
+    }
+
+    let last_error = LastError {
+        kind: UpdateErrorKind::classify(error),
+        message: format!("{:#}", error),
+        occurred_at: clock.unix_now(),
+        log_path,
+    };
+    debug!(target: INSTALL, "Recording failed update attempt: {}", last_error.kind);
+    State::update_in(fs, |state| state.last_error = Some(last_error))
+}
+
+// Forgets the last failed attempt, once there is nothing left to retry
+pub(crate) fn clear(fs: &dyn Fs) {
+    // Most runs have nothing to clear, so avoid rewriting the state for them
+    let cleared = State::load_in(fs).and_then(|state| match state.last_error {
+        Some(_) => State::update_in(fs, |state| state.last_error = None),
+        None => Ok(()),
+    });
+    if let Err(e) = cleared {
+        warn!(target: INSTALL, "Failed to clear the last update error: {:#}", e);
+    }
+}
@@ -11,90 +11,342 @@
  *  You should have received a copy of the GNU General Public License
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+#[cfg(feature = "install")]
+use std::cmp::Ordering;
+#[cfg(feature = "install")]
 use std::env;
-use std::fs::{File, remove_file};
-use std::io;
-use std::process::{Command, exit};
+#[cfg(feature = "install")]
+use std::fmt;
+#[cfg(feature = "install")]
+use std::io::{self, IsTerminal};
+#[cfg(feature = "install")]
+use std::process::Command;
+#[cfg(feature = "install")]
 use std::path::{Path, PathBuf};
-use serde_json::Value;
+#[cfg(feature = "install")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "install")]
+use std::cell::RefCell;
+#[cfg(feature = "install")]
+use std::ops::ControlFlow;
+#[cfg(feature = "install")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "install")]
 use anyhow::{Result, Context}; // For better error handling
-use uuid::Uuid; // For generating unique filenames
-use log::{info, debug, error};
+#[cfg(feature = "install")]
+use log::{info, warn};
 
-/// Current rspawn version.
-pub const RSPAWN_VERSION: &str = env!("CARGO_PKG_VERSION");
-
-// Function to generate a unique lock file path with a UUID
-fn generate_lock_file_path() -> PathBuf {
-    let lock_dir = "/tmp"; // Adjust as needed
-    let lock_file_name = format!("{}.lock", Uuid::new_v4());
-    Path::new(lock_dir).join(lock_file_name)
-}
-
-// Struct to handle lock file cleanup when the program exits
-struct LockFileGuard {
-    lock_file_path: PathBuf,
-}
-
-impl Drop for LockFileGuard {
-    fn drop(&mut self) {
-        // Ensure the lock file is removed when the program exits
-        if let Err(e) = remove_file(&self.lock_file_path) {
-            let error_msg = format!("Failed to remove lock file: {}", e);
-            eprintln!("{error_msg}");
-            error!("{error_msg}");
-        }
-    }
-}
-
-fn create_lock_file(lock_file_path: &Path) -> io::Result<()> {
-    // Create the lock file to indicate that the program is in the process of relaunching
-    File::create(lock_file_path).map(|_| ())
-}
-
-fn get_latest_version_from_crates_io(crate_name: &str) -> Result<String> {
-    let url = format!("https://crates.io/api/v1/crates/{}/versions", crate_name);
-    let user_agent = format!("rspawn/{RSPAWN_VERSION} (https://github.com/jgabaut/rspawn");
-
-    info!("Fetching latest version for {} from: {}", crate_name, url);
-
-    // Create a client with a User-Agent header
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(&url)
-        .header("User-Agent", user_agent)
-        .send()
-        .context("Failed to fetch from crates.io")?;
-
-    let status = response.status();
-    debug!("Response status: {}", status);
+mod registry;
+#[cfg(not(target_arch = "wasm32"))]
+mod fixtures;
+mod version;
+pub mod log_target;
+#[cfg(not(target_arch = "wasm32"))]
+mod gate;
+mod credentials;
+mod cli;
+#[doc(hidden)]
+pub mod fuzzing;
+#[cfg(not(target_arch = "wasm32"))]
+mod http;
 
-    if !status.is_success() {
-        let error_msg = format!("Failed to fetch crate info: HTTP {}", status);
-        error!("{error_msg}");
-        return Err(anyhow::anyhow!("{error_msg}"));
-    }
-
-    let body = response.text().context("Failed to read response body")?;
-    debug!("Response body: {}", body);
+#[cfg(all(not(target_arch = "wasm32"), not(any(feature = "http-reqwest", feature = "http-ureq"))))]
+compile_error!("rspawn needs an HTTP client: enable the `http-reqwest` or `http-ureq` feature");
+#[cfg(all(target_arch = "wasm32", not(feature = "http-reqwest")))]
+compile_error!("rspawn needs the `http-reqwest` feature on wasm32");
+#[cfg(all(not(feature = "install"), not(feature = "json")))]
+compile_error!("rspawn's check-only build reads crates.io's JSON: enable the `json` feature");
+mod json;
+#[cfg(feature = "install")]
+mod style;
+#[cfg(feature = "install")]
+mod install;
+#[cfg(feature = "install")]
+mod sandbox;
+#[cfg(feature = "install")]
+mod install_root;
+#[cfg(feature = "install")]
+mod path_hint;
+#[cfg(feature = "install")]
+mod mirror;
+#[cfg(feature = "install")]
+mod build_wrapper;
+#[cfg(feature = "install")]
+mod events;
+#[cfg(feature = "progress")]
+mod progress;
+#[cfg(feature = "install")]
+mod state;
+#[cfg(feature = "install")]
+mod settings;
+#[cfg(feature = "install")]
+mod history;
+#[cfg(feature = "install")]
+mod status;
+#[cfg(feature = "install")]
+mod archive;
+#[cfg(feature = "install")]
+mod backup;
+#[cfg(feature = "install")]
+mod cache;
+#[cfg(feature = "install")]
+mod approval;
+#[cfg(feature = "install")]
+mod decision;
+#[cfg(feature = "install")]
+mod host;
+#[cfg(feature = "install")]
+mod hold;
+#[cfg(feature = "install")]
+mod last_error;
+#[cfg(feature = "install")]
+mod backoff;
+#[cfg(feature = "install")]
+mod unique;
+#[cfg(feature = "install")]
+mod preflight;
+#[cfg(feature = "install")]
+mod container;
+#[cfg(feature = "install")]
+mod restart;
+#[cfg(feature = "install")]
+mod plan;
+#[cfg(feature = "install")]
+mod outcome;
+mod error;
+#[cfg(feature = "install")]
+mod format;
+#[cfg(feature = "install")]
+mod invocation;
+#[cfg(feature = "install")]
+mod target;
+#[cfg(feature = "install")]
+mod plugins;
+#[cfg(feature = "install")]
+mod assets;
+#[cfg(feature = "install")]
+mod journal;
+#[cfg(feature = "install")]
+mod diagnose;
+#[cfg(feature = "install")]
+mod config;
+#[cfg(feature = "simulate")]
+mod simulate;
+#[cfg(feature = "install")]
+mod feed;
+#[cfg(feature = "install")]
+mod channels;
+#[cfg(feature = "install")]
+mod clock;
+#[cfg(feature = "install")]
+mod lock;
+#[cfg(feature = "install")]
+mod filesystem;
+#[cfg(feature = "install")]
+mod process;
+#[cfg(feature = "install")]
+mod integrity;
+#[cfg(feature = "install")]
+mod provenance;
+#[cfg(all(feature = "ipc", unix))]
+mod ipc;
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+mod dbus;
+#[cfg(feature = "async")]
+mod background;
+#[cfg(feature = "tokio")]
+mod async_update;
+#[cfg(feature = "sigstore")]
+mod sigstore;
+#[cfg(feature = "install")]
+mod download;
+#[cfg(feature = "install")]
+mod url_resolver;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "oci")]
+mod oci;
+#[cfg(feature = "p2p")]
+mod p2p;
+#[cfg(feature = "p2p")]
+mod gossip;
+#[cfg(feature = "signed-releases")]
+mod signing;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "python")]
+mod python;
+pub use registry::{check_update, check_update_with, is_outdated, latest_version, latest_version_with, UpdateInfo};
+pub use version::{compare_versions, is_prerelease, is_update, UpdatePolicy, VersionReq};
+#[cfg(not(target_arch = "wasm32"))]
+pub use gate::{require_min_version, require_min_version_with, IncompatibleVersion};
+pub use cli::{CliFlags, EventFormat};
+#[cfg(feature = "clap")]
+pub use cli::cli_flags;
+pub use credentials::{CredentialStore, EnvCredentialStore, GITHUB_TOKEN, REGISTRY_TOKEN};
+#[cfg(feature = "keyring")]
+pub use credentials::KeyringCredentialStore;
+#[cfg(feature = "install")]
+pub use install::{clear_build_cache, installed_version, installed_versions, InstallError, InstallFailureKind, InstallPriority, InstalledFile};
+#[cfg(feature = "install")]
+pub use sandbox::InstallSandbox;
+#[cfg(feature = "install")]
+pub use install_root::InstallRoot;
+#[cfg(feature = "install")]
+pub use path_hint::Shell;
+#[cfg(feature = "install")]
+pub use build_wrapper::BuildWrapper;
+#[cfg(feature = "install")]
+use build_wrapper::CompilerCacheStats;
+#[cfg(feature = "install")]
+pub use events::UpdateEvent;
+#[cfg(feature = "install")]
+use events::Events;
+#[cfg(feature = "install")]
+use registry::RegistryVersion;
+#[cfg(feature = "install")]
+pub use style::{Style, Verbosity};
+#[cfg(feature = "install")]
+use style::Printer;
+#[cfg(feature = "install")]
+pub use state::{state_dir, InstalledBinary, State, STATE_DIR_ENV};
+#[cfg(feature = "install")]
+pub use integrity::{sha256_file, verify_self, IntegrityError};
+#[cfg(feature = "install")]
+pub use provenance::{install_provenance, Provenance, Toolchain};
+#[cfg(feature = "install")]
+pub use backup::{Backup, list_backups, program_backups, restore_backup, restore_program_backup, DEFAULT_KEEP_BACKUPS};
+#[cfg(feature = "install")]
+pub use cache::{cached_versions, DEFAULT_CACHE_TTL};
+#[cfg(feature = "install")]
+pub use approval::RemoteApproval;
+#[cfg(feature = "install")]
+pub use decision::{Decision, DecisionProvider, MaintenanceWindow, UpdateRequest};
+#[cfg(feature = "install")]
+pub use channels::{reset_channel, saved_channel, set_channel, Channel, ChannelManifest, HostTarget, CHANNEL_ENV};
+#[cfg(feature = "install")]
+pub use host::{HostInfo, HOST_LABELS_ENV, HOST_LABELS_FILE};
+#[cfg(feature = "install")]
+pub use hold::{hold_file_path, HeldByOperator, HOLD_FILE_ENV};
+#[cfg(feature = "install")]
+pub use last_error::{last_error, LastError, UpdateErrorKind};
+#[cfg(feature = "install")]
+pub use backoff::{FailedInstalls, DEFAULT_FAILURE_BACKOFF, MAX_FAILURE_BACKOFF};
+#[cfg(feature = "install")]
+pub use preflight::EnvironmentUnsupported;
+#[cfg(feature = "install")]
+pub use container::{detect_container, ContainerPolicy, ContainerRuntime};
+#[cfg(feature = "install")]
+pub use restart::{PendingRestart, RelaunchMode, RelaunchStrategy};
+#[cfg(feature = "install")]
+pub use history::{update_history, HistoryEntry};
+#[cfg(feature = "install")]
+pub use status::{program_status, ProgramStatus};
+#[cfg(feature = "install")]
+pub use archive::{export_state, import_state, StateArchive};
+#[cfg(feature = "install")]
+pub use settings::{get_setting, list_settings, parse_interval, set_setting, unset_setting, Setting, UserSettings};
+#[cfg(feature = "install")]
+pub use plan::{InstallPlan, UpdatePlan};
+#[cfg(feature = "install")]
+pub use outcome::UpdateOutcome;
+pub use error::RspawnError;
+#[cfg(feature = "install")]
+pub use format::{DefaultFormatter, Formatter};
+#[cfg(feature = "install")]
+pub use invocation::{capture_invocation, Invocation};
+#[cfg(feature = "install")]
+pub use url_resolver::{register_url_resolver, UrlResolver};
+#[cfg(feature = "s3")]
+pub use s3::S3Resolver;
+#[cfg(feature = "p2p")]
+pub use p2p::{share_with_peers, PeerServer, PEER_PORT};
+#[cfg(feature = "install")]
+pub use plugins::{update_plugins, update_plugins_with, Plugin, PluginOutcome, PluginReport, PluginUpdate};
+#[cfg(feature = "install")]
+pub use assets::{data_dir, Asset, DATA_DIR_ENV};
+#[cfg(feature = "install")]
+pub use journal::recover_interrupted_update;
+#[cfg(feature = "install")]
+#[cfg(not(target_arch = "wasm32"))]
+pub use fixtures::{RECORD_REGISTRY_ENV, REPLAY_REGISTRY_ENV};
+#[cfg(feature = "simulate")]
+pub use simulate::{SIMULATE_INSTALL_ENV, SIMULATE_LATEST_ENV};
+#[cfg(feature = "install")]
+pub use config::{Config, ConfigError, ConfigProblem, ConfiguredRSpawn};
+#[cfg(feature = "install")]
+pub use diagnose::{diagnose, diagnose_program, doctor, Diagnosis, DoctorCheck, Gate, GateCheck, Verdict};
+#[cfg(feature = "install")]
+use plan::PlanConfirm;
+#[cfg(feature = "install")]
+use install::{BuildProgress, InstallCommandHook, InstallLocation};
+#[cfg(feature = "install")]
+use target::TargetBinary;
+#[cfg(feature = "install")]
+pub use feed::{parse_feed, FeedRelease, VersionSource};
+#[cfg(feature = "install")]
+pub use clock::{Clock, MockClock, SystemClock};
+#[cfg(feature = "install")]
+pub use lock::{LockBehavior, DEFAULT_LOCK_STALE_AFTER};
+#[cfg(feature = "install")]
+pub use filesystem::{Fs, MemoryFs, RealFs};
+#[cfg(feature = "install")]
+pub use process::{ProcessRunner, RecordedCommand, RecordingRunner, SystemRunner};
+#[cfg(feature = "tokio")]
+pub use process::InstallFuture;
+#[cfg(all(feature = "ipc", unix))]
+pub use ipc::{control_socket_path, send_control_request, CheckResult, ControlServer};
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+pub use dbus::{DbusService, DBUS_OBJECT_PATH};
+#[cfg(feature = "async")]
+pub use background::{fetch_latest_version_async, fetch_latest_version_async_with, Background};
+#[cfg(feature = "sigstore")]
+pub use sigstore::{CosignIdentity, SigstoreVerification};
+#[cfg(feature = "install")]
+use download::DownloadSettings;
+#[cfg(feature = "signed-releases")]
+pub use signing::{release_statement, sign_release, ReleaseSignature};
 
-    let json: Value = serde_json::from_str(&body).context("Failed to parse JSON response")?;
-    debug!("Parsed JSON: {:?}", json);
-
-    let latest_version = json["versions"]
-        .as_array()
-        .and_then(|versions| versions.first())
-        .and_then(|version| version["num"].as_str())
-        .ok_or_else(|| anyhow::anyhow!("Failed to get the latest version"))?;
+/// Current rspawn version.
+pub const RSPAWN_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-    Ok(latest_version.to_string())
+/// Creates an `RSpawn` set up to update the calling crate.
+///
+/// `env!("CARGO_PKG_NAME")` and `env!("CARGO_PKG_VERSION")` are expanded in
+/// the caller, so the builder checks and installs the program's own package
+/// rather than rspawn's. Everything else can be set on the returned builder.
+///
+/// # Example
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// rspawn::self_update!()
+///     .user_confirm(|version: &str| version.starts_with('1'))
+///     .relaunch_program()?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "install")]
+#[macro_export]
+macro_rules! self_update {
+    () => {
+        $crate::RSpawn::new()
+            .crate_name(env!("CARGO_PKG_NAME"))
+            .current_version(env!("CARGO_PKG_VERSION"))
+    };
 }
 
+#[cfg(feature = "install")]
 /// This function checks if the program is executed from the PATH or a full/relative path.
 ///
 /// # Returns
 /// * `true` if the program is executed from the PATH, `false` otherwise.
 pub fn is_executed_from_path() -> bool {
+    executed_from_path(None)
+}
+
+// `is_executed_from_path`, looking for `binary_name` in PATH instead of the executable's own name
+#[cfg(feature = "install")]
+fn executed_from_path(binary_name: Option<&str>) -> bool {
     let exe_path = env::current_exe().unwrap_or_else(|_| PathBuf::new());
 
     // If the program was executed with a relative or absolute path (e.g., ./bum or /usr/local/bin/bum),
@@ -104,8 +356,11 @@ pub fn is_executed_from_path() -> bool {
     }
 
     // Extract the executable name
-    if let Some(exe_name) = exe_path.file_name() {
-        let exe_name = exe_name.to_string_lossy();
+    let exe_name = match binary_name {
+        Some(binary_name) => Some(format!("{}{}", binary_name, env::consts::EXE_SUFFIX)),
+        None => exe_path.file_name().map(|exe_name| exe_name.to_string_lossy().into_owned()),
+    };
+    if let Some(exe_name) = exe_name {
 
         // Loop through directories in the PATH
         for dir in env::var("PATH").unwrap_or_else(|_| String::new()).split(':') {
@@ -119,6 +374,7 @@ pub fn is_executed_from_path() -> bool {
     false // Executed from a full or relative path
 }
 
+#[cfg(feature = "install")]
 /// A builder for configuring an update query.
 ///
 /// The `RSpawn` allows users to configure various options such as
@@ -130,41 +386,314 @@ pub fn is_executed_from_path() -> bool {
 /// the `relaunch_program` function can be called to actually start the update query.
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # use rspawn::RSpawn;
+/// # use std::io;
 /// let builder = RSpawn::new()
+///     .crate_name(env!("CARGO_PKG_NAME"))
+///     .current_version(env!("CARGO_PKG_VERSION"))
 ///     .active_features(vec!["feature1".to_string(), "feature2".to_string()])
-///     .user_confirm(Some(|version| {
+///     .user_confirm(|version: &str| {
 ///         println!("A new version {} is available. Would you like to install it? (y/n): ", version);
 ///         let mut response = String::new();
 ///         io::stdin().read_line(&mut response).unwrap();
 ///         response.trim().to_lowercase() == "y"
-///     }))
+///     })
 ///     .relaunch_program();
 /// ```
 #[allow(non_snake_case)]
-pub struct RSpawn<F>
-where
-    F: FnMut(&str) -> bool + 'static,
-{
+pub struct RSpawn {
     active_features: Option<Vec<String>>,
-    user_confirm: Option<F>,
+    crate_name: Option<String>,
+    current_version: Option<String>,
+    user_confirm: Option<SharedConfirm>,
     check_if_executed_from_PATH: Option<bool>,
+    keep_backups: Option<usize>,
+    verify_integrity: Option<bool>,
+    #[cfg(feature = "sigstore")]
+    sigstore: Option<SigstoreVerification>,
+    download_rate_limit: Option<u64>,
+    download_parallelism: Option<usize>,
+    artifact_mirror: Option<String>,
+    #[cfg(feature = "p2p")]
+    peer_downloads: Option<bool>,
+    #[cfg(feature = "p2p")]
+    lan_gossip: Option<bool>,
+    #[cfg(feature = "signed-releases")]
+    release_signature: Option<ReleaseSignature>,
+    credential_store: Option<Arc<dyn CredentialStore>>,
+    enabled: Option<bool>,
+    check_only: Option<bool>,
+    assume_yes: Option<bool>,
+    unattended_downgrades: Option<bool>,
+    remote_approval: Option<RemoteApproval>,
+    decision_provider: Option<Arc<dyn DecisionProvider>>,
+    formatter: Option<Arc<dyn Formatter>>,
+    channel: Option<String>,
+    channel_manifest: Option<String>,
+    version_source: Option<VersionSource>,
+    style: Option<Style>,
+    verbosity: Option<Verbosity>,
+    shared_cache: Option<Duration>,
+    clock: Option<Arc<dyn Clock>>,
+    fs: Option<Arc<dyn Fs>>,
+    process_runner: Option<Arc<dyn ProcessRunner>>,
+    lock_behavior: Option<LockBehavior>,
+    log_path: Option<PathBuf>,
+    failure_backoff: Option<Duration>,
+    fallback_install_root: Option<PathBuf>,
+    container_policy: Option<ContainerPolicy>,
+    relaunch_mode: Option<RelaunchMode>,
+    relaunch_strategy: Option<RelaunchStrategy>,
+    confirm_plan: Option<PlanConfirm>,
+    relaunch_target: Option<PathBuf>,
+    target_binary: Option<PathBuf>,
+    binary_name: Option<String>,
+    data_dir: Option<PathBuf>,
+    install_priority: Option<InstallPriority>,
+    install_sandbox: Option<InstallSandbox>,
+    keep_build_cache: Option<bool>,
+    build_wrapper: Option<BuildWrapper>,
+    install_heartbeat: Option<Duration>,
+    install_root: Option<InstallRoot>,
+    allow_elevation: Option<bool>,
+    install_command_hook: Option<InstallCommandHook>,
+    events: Events,
 }
 
-impl<F> RSpawn<F>
-where
-    F: FnMut(&str) -> bool + 'static,
-{
+#[cfg(feature = "install")]
+impl Default for RSpawn {
+    fn default() -> Self {
+        RSpawn::new()
+    }
+}
+
+/// Clones every option, to reuse one configuration for several checks.
+///
+/// The `user_confirm` function is shared: the clones call the same one.
+/// `confirm_plan`, `customize_install_command` and the `on_event` handlers are
+/// callbacks that can't be cloned: a clone starts without them, so register
+/// them on each clone.
+#[cfg(feature = "install")]
+impl Clone for RSpawn {
+    fn clone(&self) -> Self {
+        RSpawn {
+            active_features: self.active_features.clone(),
+            crate_name: self.crate_name.clone(),
+            current_version: self.current_version.clone(),
+            user_confirm: self.user_confirm.clone(),
+            check_if_executed_from_PATH: self.check_if_executed_from_PATH,
+            keep_backups: self.keep_backups,
+            verify_integrity: self.verify_integrity,
+            #[cfg(feature = "sigstore")]
+            sigstore: self.sigstore.clone(),
+            download_rate_limit: self.download_rate_limit,
+            download_parallelism: self.download_parallelism,
+            artifact_mirror: self.artifact_mirror.clone(),
+            #[cfg(feature = "p2p")]
+            peer_downloads: self.peer_downloads,
+            #[cfg(feature = "p2p")]
+            lan_gossip: self.lan_gossip,
+            #[cfg(feature = "signed-releases")]
+            release_signature: self.release_signature.clone(),
+            credential_store: self.credential_store.clone(),
+            enabled: self.enabled,
+            check_only: self.check_only,
+            assume_yes: self.assume_yes,
+            unattended_downgrades: self.unattended_downgrades,
+            remote_approval: self.remote_approval.clone(),
+            decision_provider: self.decision_provider.clone(),
+            formatter: self.formatter.clone(),
+            channel: self.channel.clone(),
+            channel_manifest: self.channel_manifest.clone(),
+            version_source: self.version_source.clone(),
+            style: self.style,
+            verbosity: self.verbosity,
+            shared_cache: self.shared_cache,
+            clock: self.clock.clone(),
+            fs: self.fs.clone(),
+            process_runner: self.process_runner.clone(),
+            lock_behavior: self.lock_behavior,
+            log_path: self.log_path.clone(),
+            failure_backoff: self.failure_backoff,
+            fallback_install_root: self.fallback_install_root.clone(),
+            container_policy: self.container_policy,
+            relaunch_mode: self.relaunch_mode,
+            relaunch_strategy: self.relaunch_strategy,
+            confirm_plan: None,
+            relaunch_target: self.relaunch_target.clone(),
+            target_binary: self.target_binary.clone(),
+            binary_name: self.binary_name.clone(),
+            data_dir: self.data_dir.clone(),
+            install_priority: self.install_priority,
+            install_sandbox: self.install_sandbox,
+            keep_build_cache: self.keep_build_cache,
+            build_wrapper: self.build_wrapper.clone(),
+            install_heartbeat: self.install_heartbeat,
+            install_root: self.install_root.clone(),
+            allow_elevation: self.allow_elevation,
+            install_command_hook: None,
+            events: Events::default(),
+        }
+    }
+}
+
+/// Shows every option that is plain data, and which callbacks are set.
+#[cfg(feature = "install")]
+impl fmt::Debug for RSpawn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("RSpawn");
+        debug
+            .field("active_features", &self.active_features)
+            .field("crate_name", &self.crate_name)
+            .field("current_version", &self.current_version)
+            .field("user_confirm", &self.user_confirm.is_some())
+            .field("check_if_executed_from_PATH", &self.check_if_executed_from_PATH)
+            .field("keep_backups", &self.keep_backups)
+            .field("verify_integrity", &self.verify_integrity);
+        #[cfg(feature = "sigstore")]
+        debug.field("sigstore", &self.sigstore);
+        debug
+            .field("download_rate_limit", &self.download_rate_limit)
+            .field("download_parallelism", &self.download_parallelism)
+            .field("artifact_mirror", &self.artifact_mirror);
+        #[cfg(feature = "p2p")]
+        debug.field("peer_downloads", &self.peer_downloads).field("lan_gossip", &self.lan_gossip);
+        #[cfg(feature = "signed-releases")]
+        debug.field("release_signature", &self.release_signature);
+        debug
+            .field("credential_store", &self.credential_store.is_some())
+            .field("enabled", &self.enabled)
+            .field("check_only", &self.check_only)
+            .field("assume_yes", &self.assume_yes)
+            .field("unattended_downgrades", &self.unattended_downgrades)
+            .field("remote_approval", &self.remote_approval)
+            .field("decision_provider", &self.decision_provider.is_some())
+            .field("formatter", &self.formatter.is_some())
+            .field("channel", &self.channel)
+            .field("channel_manifest", &self.channel_manifest)
+            .field("version_source", &self.version_source)
+            .field("style", &self.style)
+            .field("verbosity", &self.verbosity)
+            .field("shared_cache", &self.shared_cache)
+            .field("clock", &self.clock.is_some())
+            .field("fs", &self.fs.is_some())
+            .field("process_runner", &self.process_runner.is_some())
+            .field("lock_behavior", &self.lock_behavior)
+            .field("log_path", &self.log_path)
+            .field("failure_backoff", &self.failure_backoff)
+            .field("fallback_install_root", &self.fallback_install_root)
+            .field("container_policy", &self.container_policy)
+            .field("relaunch_mode", &self.relaunch_mode)
+            .field("relaunch_strategy", &self.relaunch_strategy)
+            .field("confirm_plan", &self.confirm_plan.is_some())
+            .field("relaunch_target", &self.relaunch_target)
+            .field("target_binary", &self.target_binary)
+            .field("binary_name", &self.binary_name)
+            .field("data_dir", &self.data_dir)
+            .field("install_priority", &self.install_priority)
+            .field("install_sandbox", &self.install_sandbox)
+            .field("keep_build_cache", &self.keep_build_cache)
+            .field("build_wrapper", &self.build_wrapper)
+            .field("install_heartbeat", &self.install_heartbeat)
+            .field("install_root", &self.install_root)
+            .field("allow_elevation", &self.allow_elevation)
+            .field("install_command_hook", &self.install_command_hook.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "install")]
+impl RSpawn {
     // Create a new builder with default values
     pub fn new() -> Self {
         RSpawn {
             active_features: None,
+            crate_name: None,
+            current_version: None,
             user_confirm: None,
             #[allow(non_snake_case)]
             check_if_executed_from_PATH: Some(true),
+            keep_backups: None,
+            verify_integrity: None,
+            #[cfg(feature = "sigstore")]
+            sigstore: None,
+            download_rate_limit: None,
+            download_parallelism: None,
+            artifact_mirror: None,
+            #[cfg(feature = "p2p")]
+            peer_downloads: None,
+            #[cfg(feature = "p2p")]
+            lan_gossip: None,
+            #[cfg(feature = "signed-releases")]
+            release_signature: None,
+            credential_store: None,
+            enabled: None,
+            check_only: None,
+            assume_yes: None,
+            unattended_downgrades: None,
+            remote_approval: None,
+            decision_provider: None,
+            formatter: None,
+            channel: None,
+            channel_manifest: None,
+            version_source: None,
+            style: None,
+            verbosity: None,
+            shared_cache: None,
+            clock: None,
+            fs: None,
+            process_runner: None,
+            lock_behavior: None,
+            log_path: None,
+            failure_backoff: None,
+            fallback_install_root: None,
+            container_policy: None,
+            relaunch_mode: None,
+            relaunch_strategy: None,
+            confirm_plan: None,
+            relaunch_target: None,
+            target_binary: None,
+            binary_name: None,
+            data_dir: None,
+            install_priority: None,
+            install_sandbox: None,
+            keep_build_cache: None,
+            build_wrapper: None,
+            install_heartbeat: None,
+            install_root: None,
+            allow_elevation: None,
+            install_command_hook: None,
+            events: Events::default(),
         }
     }
 
+    /// Creates a builder configured from the host program's command line flags.
+    ///
+    /// * `--no-update-check` disables the update check entirely.
+    /// * `--check-update` only reports whether a new version is available.
+    /// * `--update` installs the latest version without asking for confirmation,
+    ///   even if installing it failed recently or the program runs in a container.
+    /// * `--update-channel` selects the release channel to follow.
+    pub fn from_cli(flags: &CliFlags) -> Self {
+        let mut builder = RSpawn::new()
+            .enabled(!flags.no_update_check)
+            .check_only(flags.check_update)
+            .assume_yes(flags.update);
+        if flags.update {
+            // An explicit request to update is worth retrying a failing version for,
+            // and is honoured inside containers too
+            builder = builder.failure_backoff(Duration::ZERO).container_policy(ContainerPolicy::Proceed);
+        }
+        if let Some(channel) = &flags.update_channel {
+            builder = builder.channel(channel);
+        }
+        if let Some(format) = flags.events {
+            builder = builder.event_stream(format);
+        }
+        builder
+    }
+
     /// Sets the active features for the program.
     ///
     /// This method allows users to specify which features should be enabled
@@ -178,14 +707,36 @@ where
     ///
     /// # Example
     /// ```
+    /// # use rspawn::RSpawn;
     /// let builder = RSpawn::new()
-    ///     .features(vec!["feature1".to_string(), "feature2".to_string()]);
+    ///     .active_features(vec!["feature1".to_string(), "feature2".to_string()]);
     /// ```
     pub fn active_features(mut self, active_features: Vec<String>) -> Self {
         self.active_features = Some(active_features);
         self
     }
 
+    /// Sets the crate that is checked and installed: the program's own, as
+    /// published on the registry.
+    ///
+    /// Usually `env!("CARGO_PKG_NAME")`, expanded in the program (see
+    /// `self_update!`). Required unless `target_binary` is set: without it the
+    /// update fails with `RspawnError::Config`.
+    pub fn crate_name(mut self, crate_name: &str) -> Self {
+        self.crate_name = Some(crate_name.to_string());
+        self
+    }
+
+    /// Sets the version of the running program, compared with the latest one.
+    ///
+    /// Usually `env!("CARGO_PKG_VERSION")`, expanded in the program (see
+    /// `self_update!`). Required unless `target_binary` is set: without it the
+    /// update fails with `RspawnError::Config`.
+    pub fn current_version(mut self, version: &str) -> Self {
+        self.current_version = Some(version.to_string());
+        self
+    }
+
     /// Sets a custom user confirmation function.
     ///
     /// This method allows users to provide their own confirmation logic. The
@@ -193,22 +744,45 @@ where
     /// if the program should continue, or `false` if the operation should be aborted.
     ///
     /// # Arguments
-    /// * `user_confirm` - Optional closure or function that takes a message and returns
-    ///   a boolean indicating whether the operation should proceed.
+    /// * `user_confirm` - Closure or function that takes the new version and returns
+    ///   a boolean indicating whether the operation should proceed. Without one,
+    ///   the default prompt asks on the terminal.
     ///
     /// # Example
-    /// ```
+    /// ```no_run
+    /// # use rspawn::RSpawn;
+    /// # use std::io;
     /// let builder = RSpawn::new()
-    ///     .user_confirm(Some(|version| {
+    ///     .user_confirm(|version: &str| {
     ///         println!("A new version {} is available. Would you like to install it? (y/n): ", version);
     ///         let mut response = String::new();
     ///         io::stdin().read_line(&mut response).unwrap();
     ///         response.trim().to_lowercase() == "y"
-    ///         true
-    ///     }));
+    ///     });
+    /// ```
+    pub fn user_confirm<C>(mut self, user_confirm: C) -> Self
+    where
+        C: FnMut(&str) -> bool + Send + 'static,
+    {
+        self.user_confirm = Some(Arc::new(Mutex::new(user_confirm)));
+        self
+    }
+
+    /// Sets a confirmation function shown everything the update will do: the
+    /// exact `cargo install` command, the binary it replaces and how the
+    /// program is relaunched. Takes precedence over `user_confirm`.
+    ///
+    /// ```ignore
+    /// let builder = RSpawn::new().confirm_plan(|plan| {
+    ///     println!("{}", plan);
+    ///     ask_yes_no("Proceed?")
+    /// });
     /// ```
-    pub fn user_confirm(mut self, user_confirm: F) -> Self {
-        self.user_confirm = Some(user_confirm);
+    pub fn confirm_plan<C>(mut self, confirm: C) -> Self
+    where
+        C: FnMut(&InstallPlan) -> bool + Send + 'static,
+    {
+        self.confirm_plan = Some(Box::new(confirm));
         self
     }
 
@@ -218,6 +792,507 @@ where
         self
     }
 
+    /// Sets how many backups of previous versions are kept.
+    ///
+    /// Before installing, the running executable is copied to
+    /// `<state_dir>/backups/<version>/` so it can be brought back with
+    /// `restore_backup`. Defaults to `DEFAULT_KEEP_BACKUPS`; `0` disables backups.
+    pub fn keep_backups(mut self, keep: usize) -> Self {
+        self.keep_backups = Some(keep);
+        self
+    }
+
+    /// Sets whether the running binary is checked before updating it.
+    ///
+    /// When enabled, the running executable's SHA-256 is compared with the one
+    /// recorded in state when rspawn installed it, and the update is aborted
+    /// with `RspawnError::Integrity` if they differ. Defaults to `false`.
+    pub fn verify_integrity(mut self, verify: bool) -> Self {
+        self.verify_integrity = Some(verify);
+        self
+    }
+
+    /// Requires releases to be verified with sigstore/cosign before installing.
+    ///
+    /// The published `.crate` is checked against the registry checksum and then
+    /// against the cosign bundle at `verification.bundle_url`; the update is
+    /// aborted if either check fails. Needs the `cosign` CLI on PATH.
+    #[cfg(feature = "sigstore")]
+    pub fn sigstore(mut self, verification: SigstoreVerification) -> Self {
+        self.sigstore = Some(verification);
+        self
+    }
+
+    /// Caps the average speed of rspawn's own downloads (release assets, and
+    /// the published `.crate` and its cosign bundle with sigstore), in bytes
+    /// per second. Unlimited by default.
+    ///
+    /// The crates cargo downloads while installing are not affected.
+    pub fn download_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.download_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets how many byte ranges of a large artifact rspawn fetches at the same
+    /// time, which helps on high-latency links. Defaults to 1.
+    ///
+    /// Only used when the server accepts ranges; the ranges are checked and
+    /// reassembled before the usual checksum verification.
+    pub fn download_parallelism(mut self, chunks: usize) -> Self {
+        self.download_parallelism = Some(chunks);
+        self
+    }
+
+    /// Sets an internal cache (e.g. an S3 bucket or Artifactory repository)
+    /// that rspawn's own downloads are tried from first, to keep a fleet of
+    /// machines from all fetching the same release from its public host.
+    ///
+    /// An artifact is looked up under its host and path, so
+    /// `https://github.com/owner/tool/releases/download/v1.2.0/data.tar.gz` is
+    /// `<url>/github.com/owner/tool/releases/download/v1.2.0/data.tar.gz`. The
+    /// cached copy is verified against the same hash as the original, and the
+    /// original is downloaded when the copy is missing or doesn't match.
+    pub fn artifact_mirror(mut self, url: &str) -> Self {
+        self.artifact_mirror = Some(url.to_string());
+        self
+    }
+
+    /// Looks for release assets on peers on the LAN before downloading them,
+    /// and keeps downloaded assets for `share_with_peers()` to serve. Disabled
+    /// by default.
+    ///
+    /// Experimental. Peers answer a multicast query on `PEER_PORT`, and
+    /// serve byte ranges of what they have; the assembled asset is checked
+    /// against its hash, and downloaded from its URL when no peer has it or
+    /// the check fails.
+    #[cfg(feature = "p2p")]
+    pub fn peer_downloads(mut self, enabled: bool) -> Self {
+        self.peer_downloads = Some(enabled);
+        self
+    }
+
+    /// Asks peers on the LAN for the latest version before the registry, so
+    /// in a fleet only a few instances query it. Disabled by default.
+    ///
+    /// Experimental. Peers running `share_with_peers()` answer from the
+    /// version cache shared by the programs on their machine (see
+    /// `shared_cache`), with lookups younger than the cache's time to live.
+    /// A version newer than the running one is still looked up on the
+    /// registry before installing, so a peer can't make up a release.
+    #[cfg(feature = "p2p")]
+    pub fn lan_gossip(mut self, enabled: bool) -> Self {
+        self.lan_gossip = Some(enabled);
+        self
+    }
+
+    /// Requires releases to be signed by the author before installing.
+    ///
+    /// The signature published at `signature.signature_url` must cover the
+    /// crate name, version and registry checksum of the release (see
+    /// `release_statement` and `sign_release`); the update is aborted otherwise.
+    #[cfg(feature = "signed-releases")]
+    pub fn release_signature(mut self, signature: ReleaseSignature) -> Self {
+        self.release_signature = Some(signature);
+        self
+    }
+
+    /// Sets where registry and GitHub tokens are read from.
+    ///
+    /// Defaults to `EnvCredentialStore`, which reads `RSPAWN_<NAME>_TOKEN`
+    /// environment variables. With the `keyring` feature, `KeyringCredentialStore`
+    /// keeps tokens in the OS keyring instead.
+    pub fn credential_store<C>(mut self, store: C) -> Self
+    where
+        C: CredentialStore + 'static,
+    {
+        self.credential_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Sets whether the update check runs at all. Defaults to `true`.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = Some(enabled);
+        self
+    }
+
+    /// Sets whether to only report an available update, without installing it.
+    ///
+    /// Defaults to `false`.
+    pub fn check_only(mut self, check_only: bool) -> Self {
+        self.check_only = Some(check_only);
+        self
+    }
+
+    /// Sets whether to install updates without asking for confirmation.
+    ///
+    /// Defaults to `false`.
+    pub fn assume_yes(mut self, assume_yes: bool) -> Self {
+        self.assume_yes = Some(assume_yes);
+        self
+    }
+
+    /// Sets whether a downgrade may be installed without anyone confirming it:
+    /// through `assume_yes`, `remote_approval` or `decision_provider`.
+    ///
+    /// Defaults to `false`: going back to an older version, e.g. after
+    /// `set_channel` moved from "nightly" back to "stable", is only installed
+    /// once `confirm_plan`, `user_confirm` or the default prompt (which explains
+    /// the downgrade) agrees. With stdin not a terminal, the default prompt
+    /// declines it.
+    pub fn unattended_downgrades(mut self, unattended_downgrades: bool) -> Self {
+        self.unattended_downgrades = Some(unattended_downgrades);
+        self
+    }
+
+    /// Lets a remote endpoint decide whether an update may be installed,
+    /// instead of asking on stdin.
+    ///
+    /// This takes precedence over `user_confirm` and `assume_yes`: without the
+    /// endpoint's approval nothing is installed.
+    pub fn remote_approval(mut self, approval: RemoteApproval) -> Self {
+        self.remote_approval = Some(approval);
+        self
+    }
+
+    /// Sets what decides whether to update when the program runs headless,
+    /// with stdin not connected to a terminal (a service, a cron job, CI).
+    ///
+    /// Without one, `user_confirm` or `confirm_plan` is called even then. With
+    /// a terminal they still are, so an operator can always answer. See
+    /// `Decision` for the built-in providers.
+    pub fn decision_provider<P>(mut self, provider: P) -> Self
+    where
+        P: DecisionProvider + 'static,
+    {
+        self.decision_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Sets how sizes, durations and dates are written in rspawn's status
+    /// lines, warnings and progress bars, e.g. to follow the host program's
+    /// locale. Defaults to `DefaultFormatter`.
+    pub fn formatter<T>(mut self, formatter: T) -> Self
+    where
+        T: Formatter + 'static,
+    {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
+    /// Sets the release channel to follow.
+    ///
+    /// Stable releases are offered on every channel; prereleases only on the
+    /// channel their tag starts with (e.g. `1.2.0-beta.1` on `"beta"`). Yanked
+    /// versions are skipped. Without a channel, the most recently published
+    /// version is used.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = Some(channel.to_string());
+        self
+    }
+
+    /// Follows the release channels the author publishes as a `ChannelManifest` at `url`.
+    ///
+    /// The channel set with `channel` (or `$RSPAWN_CHANNEL`, or the manifest's
+    /// default) is looked up in the manifest, and its version is installed
+    /// when this install is part of its rollout.
+    pub fn channel_manifest(mut self, url: &str) -> Self {
+        self.channel_manifest = Some(url.to_string());
+        self
+    }
+
+    /// Sets where new versions are looked up. Defaults to `VersionSource::CratesIo`.
+    ///
+    /// Ignored when a `channel_manifest` is set.
+    pub fn version_source(mut self, source: VersionSource) -> Self {
+        self.version_source = Some(source);
+        self
+    }
+
+    /// Sets whether rspawn's own prompts and status lines are colored.
+    ///
+    /// Defaults to `Style::Auto`, which colors only when writing to a terminal
+    /// and the `NO_COLOR` environment variable is not set.
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = Some(style);
+        self
+    }
+
+    /// Sets how much rspawn prints on its own. Defaults to `Verbosity::Normal`.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = Some(verbosity);
+        self
+    }
+
+    /// Shares version lookups with the other rspawn-powered programs on this machine.
+    ///
+    /// A lookup of the same crate (and channel) done by any of them less than `ttl`
+    /// ago is reused instead of querying crates.io again. `DEFAULT_CACHE_TTL` is a
+    /// reasonable value. Off by default.
+    pub fn shared_cache(mut self, ttl: Duration) -> Self {
+        self.shared_cache = Some(ttl);
+        self
+    }
+
+    /// Sets the time source used for cache expiry and install timestamps.
+    ///
+    /// Defaults to `SystemClock`; tests can pass a `MockClock` (or an
+    /// `Arc<MockClock>` to keep advancing it from the outside).
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Sets the filesystem used for the lock file, state and shared cache.
+    ///
+    /// Defaults to `RealFs`; tests can pass a `MemoryFs` (or an `Arc<MemoryFs>`
+    /// to inspect it afterwards). Installed binaries and backups always live on
+    /// the host filesystem.
+    pub fn fs<T>(mut self, fs: T) -> Self
+    where
+        T: Fs + 'static,
+    {
+        self.fs = Some(Arc::new(fs));
+        self
+    }
+
+    /// Sets what runs `cargo install` and relaunches the program.
+    ///
+    /// Defaults to `SystemRunner`; tests can pass a `RecordingRunner` (or an
+    /// `Arc<RecordingRunner>`) to check the exact commands without running them.
+    pub fn process_runner<R>(mut self, runner: R) -> Self
+    where
+        R: ProcessRunner + 'static,
+    {
+        self.process_runner = Some(Arc::new(runner));
+        self
+    }
+
+    /// Sets what happens when another instance of the program is already updating it.
+    ///
+    /// Defaults to `LockBehavior::Steal(DEFAULT_LOCK_STALE_AFTER)`: fail, unless the
+    /// other instance took the lock long ago and is no longer running.
+    pub fn lock_behavior(mut self, behavior: LockBehavior) -> Self {
+        self.lock_behavior = Some(behavior);
+        self
+    }
+
+    /// Sets how long a version is skipped after its install failed. The wait
+    /// doubles with each consecutive failure, up to `MAX_FAILURE_BACKOFF`.
+    /// Failures are counted separately for each crate and channel, so a
+    /// plugin or target binary that fails doesn't hold back the others.
+    ///
+    /// Defaults to `DEFAULT_FAILURE_BACKOFF`; `Duration::ZERO` retries on every run.
+    pub fn failure_backoff(mut self, backoff: Duration) -> Self {
+        self.failure_backoff = Some(backoff);
+        self
+    }
+
+    /// Sets the host program's log file, recorded with failed update attempts
+    /// so UIs can point users to it. See `last_error()`.
+    pub fn log_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.log_path = Some(path.into());
+        self
+    }
+
+    /// Sets where to install updates when cargo can't write to its usual
+    /// locations, e.g. a read-only `$CARGO_HOME` in a container, instead of
+    /// failing with `EnvironmentUnsupported`.
+    ///
+    /// The new binary goes to `<root>/bin` and is relaunched from there; it
+    /// needs to come first on `PATH` for later starts to run the new version.
+    /// If cargo's home is unusable too, its registry cache is kept in `<root>/cargo-home`.
+    pub fn fallback_install_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.fallback_install_root = Some(root.into());
+        self
+    }
+
+    /// Sets what happens when the program runs in a container (see `detect_container()`),
+    /// where self-updating is usually pointless: the update is lost with the container.
+    ///
+    /// Defaults to `ContainerPolicy::NotifyOnly`.
+    pub fn container_policy(mut self, policy: ContainerPolicy) -> Self {
+        self.container_policy = Some(policy);
+        self
+    }
+
+    /// Sets when the new version takes over after an update.
+    ///
+    /// Defaults to `RelaunchMode::Immediately`. With `RelaunchMode::OnNextStart`
+    /// the update is installed and the running process carries on; see `State::pending_restart`.
+    pub fn relaunch_mode(mut self, mode: RelaunchMode) -> Self {
+        self.relaunch_mode = Some(mode);
+        self
+    }
+
+    /// Sets how the new version is started with `RelaunchMode::Immediately`.
+    ///
+    /// Defaults to `RelaunchStrategy::Spawn`. On Unix, `RelaunchStrategy::Exec`
+    /// replaces the running process instead, so the new version keeps its pid,
+    /// open file descriptors and terminal.
+    pub fn relaunch_strategy(mut self, strategy: RelaunchStrategy) -> Self {
+        self.relaunch_strategy = Some(strategy);
+        self
+    }
+
+    /// Sets the binary started after an update, e.g. a wrapper script.
+    ///
+    /// Defaults to the running executable, or the freshly installed binary when
+    /// it went to a `fallback_install_root`. It must exist and be executable.
+    pub fn relaunch_target<P: Into<PathBuf>>(mut self, target: P) -> Self {
+        self.relaunch_target = Some(target.into());
+        self
+    }
+
+    /// Sets the name of the program's binary, when it isn't the crate's name
+    /// (e.g. `[[bin]] name = "foo"` in crate `foo-cli`).
+    ///
+    /// It is the name looked up in PATH by `check_if_executed_from_PATH`, the
+    /// only binary installed (`cargo install --bin <name>`), and the one
+    /// relaunched. The update fails, and is rolled back, if cargo doesn't
+    /// install it. Defaults to the running executable's name.
+    pub fn binary_name(mut self, name: &str) -> Self {
+        self.binary_name = Some(name.to_string());
+        self
+    }
+
+    /// Updates another binary instead of the running one, for launchers that
+    /// keep a worker up to date: the worker's crate is checked and installed,
+    /// and the launcher keeps running instead of relaunching.
+    ///
+    /// Takes the worker's name, looked up in cargo's bin directory, or its path,
+    /// which must be `<root>/bin/<name>`. The name is also the crate's name. The
+    /// installed version comes from `<worker> --version`, or else the last one
+    /// rspawn installed (see `State::targets`). Restarting the worker is up to
+    /// the launcher, on `UpdateEvent::TargetInstalled`.
+    pub fn target_binary<P: Into<PathBuf>>(mut self, name_or_path: P) -> Self {
+        self.target_binary = Some(name_or_path.into());
+        self
+    }
+
+    /// Sets where the assets listed in the channel manifest are placed.
+    ///
+    /// Defaults to `data_dir()`.
+    pub fn data_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the CPU and I/O priority `cargo install` runs with.
+    ///
+    /// Defaults to `InstallPriority::Normal`; `InstallPriority::Background`
+    /// keeps the machine responsive while an unattended update compiles.
+    pub fn install_priority(mut self, priority: InstallPriority) -> Self {
+        self.install_priority = Some(priority);
+        self
+    }
+
+    /// Builds the update inside a sandbox, limiting what the code of the new
+    /// release (its build scripts and proc macros) can read and write.
+    ///
+    /// Defaults to `InstallSandbox::Off`. A sandbox that isn't available stops
+    /// the update rather than letting it build unconfined.
+    pub fn install_sandbox(mut self, sandbox: InstallSandbox) -> Self {
+        self.install_sandbox = Some(sandbox);
+        self
+    }
+
+    /// Keeps cargo's build directory between updates, in `state_dir()`, so an
+    /// update only recompiles the dependencies that changed since the last one.
+    ///
+    /// Defaults to `false`: cargo builds in a temporary directory, from scratch.
+    /// The cache can take a few hundred megabytes; `rspawn::clear_build_cache`
+    /// deletes it. A `CARGO_TARGET_DIR` set by the user is used instead.
+    pub fn keep_build_cache(mut self, keep: bool) -> Self {
+        self.keep_build_cache = Some(keep);
+        self
+    }
+
+    /// Sets the compiler wrapper updates are built with, as `RUSTC_WRAPPER`.
+    ///
+    /// Defaults to `BuildWrapper::Inherit`, the program's own `RUSTC_WRAPPER`.
+    /// When the wrapper is sccache, its cache hits and misses for the build are
+    /// reported as an `UpdateEvent::CompilerCache`.
+    pub fn build_wrapper(mut self, wrapper: BuildWrapper) -> Self {
+        self.build_wrapper = Some(wrapper);
+        self
+    }
+
+    /// Sets how often an `UpdateEvent::InstallHeartbeat` reports a running
+    /// `cargo install`, with the time spent and cargo's last status line.
+    ///
+    /// Defaults to every second; `Duration::ZERO` sends none.
+    pub fn install_heartbeat(mut self, interval: Duration) -> Self {
+        self.install_heartbeat = Some(interval);
+        self
+    }
+
+    /// Sets where updates are installed.
+    ///
+    /// Defaults to `InstallRoot::InPlace`: the root the running binary was
+    /// installed into, so a system-wide install is updated system-wide instead
+    /// of gaining a per-user copy that shadows it (or that it shadows).
+    pub fn install_root(mut self, root: InstallRoot) -> Self {
+        self.install_root = Some(root);
+        self
+    }
+
+    /// Lets updates into a root the user can't write to, like `/usr/local`,
+    /// run `cargo install` through sudo.
+    ///
+    /// Disabled by default: such an update fails before asking, telling the
+    /// user to run the program as an administrator. Elevation isn't available
+    /// on Windows.
+    pub fn allow_elevation(mut self, allow: bool) -> Self {
+        self.allow_elevation = Some(allow);
+        self
+    }
+
+    /// Lets `customize` change the `cargo install` command before it runs, e.g.
+    /// to set `RUSTFLAGS` or `RUSTC_WRAPPER`, or to wrap it in another program.
+    ///
+    /// It is called once the command is complete, before it is shown to
+    /// `confirm_plan`, so the plan shows the command as it will run.
+    ///
+    /// # Example
+    /// ```
+    /// # use rspawn::RSpawn;
+    /// let builder = RSpawn::new().customize_install_command(|command| {
+    ///     command.env("RUSTFLAGS", "-C target-cpu=native");
+    /// });
+    /// ```
+    pub fn customize_install_command<C>(mut self, customize: C) -> Self
+    where
+        C: FnMut(&mut Command) + Send + 'static,
+    {
+        self.install_command_hook = Some(Box::new(customize));
+        self
+    }
+
+    /// Registers a handler called with each `UpdateEvent` as the update progresses.
+    ///
+    /// Can be called several times; handlers run in the order they were added.
+    pub fn on_event<H>(mut self, handler: H) -> Self
+    where
+        H: FnMut(&UpdateEvent) + Send + 'static,
+    {
+        self.events.add(Box::new(handler));
+        self
+    }
+
+    /// Writes each `UpdateEvent` on stderr as it happens, in `format`, so wrapper
+    /// tools (installers, GUIs in other languages) can show the update's progress
+    /// by reading the program's stderr. Also set by `--events ndjson`, see `CliFlags`.
+    ///
+    /// The events are interleaved with the rest of the program's stderr: wrappers
+    /// should skip lines that aren't events.
+    pub fn event_stream(mut self, format: EventFormat) -> Self {
+        self.events.add(events::stream(format));
+        self
+    }
+
     /// Run update query with the configured options.
     ///
     /// This method queries crates.io for latest version and installs it with
@@ -225,39 +1300,282 @@ where
     /// and whether the program should be executed from the PATH.
     ///
     /// # Example
-    /// ```
+    /// ```no_run
+    /// # use rspawn::RSpawn;
+    /// # use std::io;
     /// let builder = RSpawn::new()
+    ///     .crate_name(env!("CARGO_PKG_NAME"))
+    ///     .current_version(env!("CARGO_PKG_VERSION"))
     ///     .active_features(vec!["feature1".to_string(), "feature2".to_string()])
-    ///     .user_confirm(Some(|version| {
+    ///     .user_confirm(|version: &str| {
     ///         println!("A new version {} is available. Would you like to install it? (y/n): ", version);
     ///         let mut response = String::new();
     ///         io::stdin().read_line(&mut response).unwrap();
     ///         response.trim().to_lowercase() == "y"
-    ///         true
-    ///     }));
+    ///     });
     ///
     /// builder.relaunch_program().expect("Failed to launch program");
     /// ```
     ///
+    /// Without `user_confirm`, the default prompt asks on the terminal:
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// rspawn::self_update!().relaunch_program()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
     /// # Returns
-    /// * `Result<(), SomeError>` - A `Result` indicating whether the program was
-    ///   successfully updated or if an error occurred.
-    pub fn relaunch_program(self) -> Result<()> {
+    /// * `Result<UpdateOutcome, RspawnError>` - How the run ended (up to date,
+    ///   declined, updated...), or the error that stopped it. After relaunching
+    ///   into the new version this returns `UpdateOutcome::Relaunched`: the
+    ///   caller should then exit, once it has cleaned up.
+    pub fn relaunch_program(self) -> Result<UpdateOutcome, RspawnError> {
+        let (options, confirm_fn) = self.into_update()?;
+        run_update(options, confirm_fn)
+    }
+
+    /// Like `relaunch_program`, as a future for programs running on tokio
+    /// (`tokio` feature). The version check on crates.io goes through reqwest's
+    /// async client and `cargo install` through `tokio::process`, so neither
+    /// blocks the runtime while the program keeps serving.
+    ///
+    /// The steps that still block (other version sources such as
+    /// `channel_manifest` or `VersionSource::Feed`, release assets and
+    /// signatures, the lock and state files, and the confirmation) run on
+    /// tokio's blocking pool. The future is `Send`, so it can also be handed
+    /// to `tokio::spawn`.
+    ///
+    /// # Example
+    /// ```ignore
+    /// if let UpdateOutcome::Relaunched { .. } = rspawn::self_update!().assume_yes(true).relaunch_async().await? {
+    ///     std::process::exit(0);
+    /// }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn relaunch_async(self) -> Result<UpdateOutcome, RspawnError> {
+        let (options, confirm_fn) = self.into_update()?;
+        async_update::run_update(options, confirm_fn).await
+    }
+
+    /// Checks for an update and works out what installing it would do, without
+    /// asking for confirmation or installing anything.
+    ///
+    /// Returns `None` when there is nothing to install, e.g. already up to date,
+    /// in check-only mode, or held by an operator. Otherwise the `UpdatePlan`
+    /// can be logged, audited or kept for later, and `UpdatePlan::apply` runs it:
+    /// calling it is the confirmation, so `user_confirm`, `confirm_plan`,
+    /// `remote_approval` and `decision_provider` aren't asked.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// if let Some(plan) = rspawn::self_update!().plan()? {
+    ///     log::info!("{}", plan);
+    ///     plan.apply()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn plan(self) -> Result<Option<UpdatePlan>, RspawnError> {
+        let (mut options, _) = self.into_update()?;
+        match plan_update(&mut options) {
+            Ok(ControlFlow::Continue(planned)) => Ok(Some(UpdatePlan::new(options, planned))),
+            Ok(ControlFlow::Break(_)) => record_run(options, Ok(None)),
+            Err(e) => record_run(options, Err(e.into())),
+        }
+    }
 
-        let active_features = self.active_features.unwrap_or_default();
-        #[allow(non_snake_case)]
-        let check_if_executed_from_PATH = self.check_if_executed_from_PATH.unwrap_or(true);
+    // The options and confirmation the update flow runs with, or which of the
+    // required options are missing
+    fn into_update(self) -> Result<(UpdateOptions, UpdateConfirm), RspawnError> {
+        let problems = config::package_problems(&self);
+        if !problems.is_empty() {
+            return Err(RspawnError::Config(ConfigError { problems }));
+        }
+        let options = UpdateOptions {
+            active_features: self.active_features.unwrap_or_default(),
+            // Both checked above
+            crate_name: self.crate_name.unwrap_or_default(),
+            current_version: self.current_version.unwrap_or_default(),
+            check_if_executed_from_PATH: self.check_if_executed_from_PATH.unwrap_or(true),
+            keep_backups: self.keep_backups.unwrap_or(DEFAULT_KEEP_BACKUPS),
+            verify_integrity: self.verify_integrity.unwrap_or(false),
+            #[cfg(feature = "sigstore")]
+            sigstore: self.sigstore,
+            downloads: DownloadSettings {
+                rate_limit: self.download_rate_limit,
+                parallel_chunks: self.download_parallelism.unwrap_or(1),
+                mirror: self.artifact_mirror,
+                #[cfg(feature = "p2p")]
+                peers: self.peer_downloads.unwrap_or(false),
+                // Only read once a download needs it
+                github_token: None,
+            },
+            #[cfg(feature = "p2p")]
+            lan_gossip: self.lan_gossip.unwrap_or(false),
+            #[cfg(feature = "signed-releases")]
+            release_signature: self.release_signature,
+            credential_store: self.credential_store.unwrap_or_else(|| Arc::new(EnvCredentialStore)),
+            enabled: self.enabled.unwrap_or(true),
+            check_only: self.check_only.unwrap_or(false),
+            assume_yes: self.assume_yes.unwrap_or(false),
+            unattended_downgrades: self.unattended_downgrades.unwrap_or(false),
+            remote_approval: self.remote_approval,
+            decision_provider: self.decision_provider,
+            formatter: self.formatter.unwrap_or_else(|| Arc::new(DefaultFormatter)),
+            channel: self.channel,
+            channel_manifest: self.channel_manifest,
+            version_source: self.version_source.unwrap_or_default(),
+            printer: Printer::new(self.style.unwrap_or_default(), self.verbosity.unwrap_or_default()),
+            shared_cache: self.shared_cache,
+            clock: self.clock.map_or_else(|| Box::new(SystemClock) as Box<dyn Clock>, |clock| Box::new(clock)),
+            fs: self.fs.unwrap_or_else(|| Arc::new(RealFs)),
+            process_runner: self.process_runner.map_or_else(|| Box::new(SystemRunner) as Box<dyn ProcessRunner>, |runner| Box::new(runner)),
+            lock_behavior: self.lock_behavior.unwrap_or_default(),
+            log_path: self.log_path,
+            failure_backoff: self.failure_backoff.unwrap_or(DEFAULT_FAILURE_BACKOFF),
+            fallback_install_root: self.fallback_install_root,
+            container_policy: self.container_policy.unwrap_or_default(),
+            relaunch_mode: self.relaunch_mode.unwrap_or_default(),
+            relaunch_strategy: self.relaunch_strategy.unwrap_or_default(),
+            confirm_plan: self.confirm_plan,
+            relaunch_target: self.relaunch_target,
+            target_binary: self.target_binary,
+            binary_name: self.binary_name,
+            data_dir: self.data_dir,
+            install_priority: self.install_priority.unwrap_or_default(),
+            install_sandbox: self.install_sandbox.unwrap_or_default(),
+            keep_build_cache: self.keep_build_cache.unwrap_or(false),
+            build_wrapper: self.build_wrapper.unwrap_or_default(),
+            install_heartbeat: self.install_heartbeat.unwrap_or(install::DEFAULT_INSTALL_HEARTBEAT),
+            install_root: self.install_root.unwrap_or_default(),
+            allow_elevation: self.allow_elevation.unwrap_or(false),
+            install_command_hook: self.install_command_hook,
+            events: self.events,
+            diagnosis: Diagnosis::default(),
+        };
 
-        let confirm_fn: Box<dyn FnMut(&str) -> bool> = if let Some(mut custom_confirm) = self.user_confirm {
-            Box::new(move |version| custom_confirm(version))
+        let printer = options.printer;
+        let confirm_fn: UpdateConfirm = if let Some(custom_confirm) = self.user_confirm {
+            Box::new(move |plan| (custom_confirm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))(&plan.version))
         } else {
-            Box::new(default_user_confirm)
+            Box::new(move |plan| default_user_confirm(&printer, plan))
         };
 
-        relaunch_program(Some(active_features), Some(confirm_fn), check_if_executed_from_PATH)
+        Ok((options, confirm_fn))
+    }
+
+    /// Applies the options set in `config`, e.g. loaded from the host
+    /// program's config file, over the ones already set.
+    ///
+    /// Fails with `RspawnError::Config` if the release signature's public key
+    /// is invalid, or only one of it and the signature URL is set.
+    pub fn config(self, config: Config) -> Result<Self, RspawnError> {
+        config::apply(self, config)
+    }
+
+    /// Checks the configuration as a whole, before running anything.
+    ///
+    /// Reports every option that can't take effect or doesn't fit with the
+    /// others (e.g. a channel manifest and a release feed, or a relaunch target
+    /// for a worker binary) at once, instead of failing or silently ignoring
+    /// one of them halfway through `relaunch_program`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # const MANIFEST_URL: &str = "https://example.com/channels.json";
+    /// # fn main() -> anyhow::Result<()> {
+    /// let updater = rspawn::self_update!().channel_manifest(MANIFEST_URL).build()?;
+    /// updater.relaunch_program()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build(self) -> std::result::Result<ConfiguredRSpawn, ConfigError> {
+        config::validate(self)
     }
+
+    /// Checks what updating needs from the environment, see `rspawn::doctor()`.
+    ///
+    /// The network check goes to the configured channel manifest or release
+    /// feed, and the install directory check accounts for
+    /// `fallback_install_root`.
+    pub fn doctor(&self) -> Vec<DoctorCheck> {
+        let source_url = match (&self.channel_manifest, &self.version_source) {
+            (Some(manifest_url), _) => manifest_url.as_str(),
+            (None, Some(VersionSource::Feed(feed_url))) => feed_url.as_str(),
+            _ => registry::CRATES_IO_URL,
+        };
+        diagnose::run_checks(source_url, self.fallback_install_root.as_deref())
+    }
+}
+
+// The confirmation asked for before installing, given what the update does
+#[cfg(feature = "install")]
+type UpdateConfirm = Box<dyn FnMut(&InstallPlan) -> bool + Send>;
+
+// `RSpawn::user_confirm`, shared by the builder's clones
+#[cfg(feature = "install")]
+type SharedConfirm = Arc<Mutex<dyn FnMut(&str) -> bool + Send>>;
+
+// Options shared by the builder and the `relaunch_program` free function
+#[cfg(feature = "install")]
+#[allow(non_snake_case)]
+pub(crate) struct UpdateOptions {
+    active_features: Vec<String>,
+    crate_name: String,
+    current_version: String,
+    check_if_executed_from_PATH: bool,
+    keep_backups: usize,
+    verify_integrity: bool,
+    #[cfg(feature = "sigstore")]
+    sigstore: Option<SigstoreVerification>,
+    downloads: DownloadSettings,
+    #[cfg(feature = "p2p")]
+    lan_gossip: bool,
+    #[cfg(feature = "signed-releases")]
+    release_signature: Option<ReleaseSignature>,
+    credential_store: Arc<dyn CredentialStore>,
+    enabled: bool,
+    check_only: bool,
+    assume_yes: bool,
+    unattended_downgrades: bool,
+    remote_approval: Option<RemoteApproval>,
+    decision_provider: Option<Arc<dyn DecisionProvider>>,
+    formatter: Arc<dyn Formatter>,
+    channel: Option<String>,
+    channel_manifest: Option<String>,
+    version_source: VersionSource,
+    printer: Printer,
+    shared_cache: Option<Duration>,
+    clock: Box<dyn Clock>,
+    fs: Arc<dyn Fs>,
+    process_runner: Box<dyn ProcessRunner>,
+    lock_behavior: LockBehavior,
+    log_path: Option<PathBuf>,
+    failure_backoff: Duration,
+    fallback_install_root: Option<PathBuf>,
+    container_policy: ContainerPolicy,
+    relaunch_mode: RelaunchMode,
+    relaunch_strategy: RelaunchStrategy,
+    confirm_plan: Option<PlanConfirm>,
+    relaunch_target: Option<PathBuf>,
+    target_binary: Option<PathBuf>,
+    binary_name: Option<String>,
+    data_dir: Option<PathBuf>,
+    install_priority: InstallPriority,
+    install_sandbox: InstallSandbox,
+    keep_build_cache: bool,
+    build_wrapper: BuildWrapper,
+    install_heartbeat: Duration,
+    install_root: InstallRoot,
+    allow_elevation: bool,
+    install_command_hook: Option<InstallCommandHook>,
+    events: Events,
+    diagnosis: Diagnosis,
 }
 
+#[cfg(feature = "install")]
 /// Run update query with the configured options.
 ///
 /// This method queries crates.io for latest version and installs it with
@@ -265,7 +1583,9 @@ where
 /// and whether the program should be executed from the PATH.
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # use rspawn::relaunch_program;
+/// # use std::io;
 /// let active_features = vec!["feature1".to_string(), "feature2".to_string()];
 /// let user_confirm = |version: &str| {
 ///     println!("A new version {} is available. Would you like to install it? (yes/n): ", version);
@@ -274,106 +1594,1121 @@ where
 ///     response.trim().to_lowercase() == "yes"
 /// };
 /// let check_if_executed_from_PATH = false;
-/// let res = relaunch_program(Some(active_features), Some(user_confirm),
-/// check_if_executed_from_PATH);
+/// let res = relaunch_program(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),
+/// Some(active_features), Some(user_confirm), check_if_executed_from_PATH);
 /// ```
 ///
+/// Passing `None` for `user_confirm` needs its type spelled out, as in
+/// `None::<fn(&str) -> bool>`; the `RSpawn` builder needs none. Everything
+/// this doesn't take is left at the builder's defaults.
+///
 /// # Returns
-/// * `Result<(), SomeError>` - A `Result` indicating whether the program was
-///   successfully updated or if an error occurred.
+/// * `Result<UpdateOutcome, RspawnError>` - How the run ended (up to date,
+///   declined, updated...), or the error that stopped it. After relaunching
+///   into the new version this returns `UpdateOutcome::Relaunched`: the
+///   caller should then exit, once it has cleaned up.
 pub fn relaunch_program<F>(
+    crate_name: &str,
+    current_version: &str,
     active_features: Option<Vec<String>>,
     user_confirm: Option<F>,
     #[allow(non_snake_case)]
     check_if_executed_from_PATH: bool
-) -> Result<()>
+) -> Result<UpdateOutcome, RspawnError>
 where
-    F: FnMut(&str) -> bool + 'static,
+    F: FnMut(&str) -> bool + Send + 'static,
 {
-    // Generate the lock file path with a unique name
-    let lock_file_path = generate_lock_file_path();
+    program_update(crate_name, current_version, active_features, user_confirm, check_if_executed_from_PATH).relaunch_program()
+}
+
+/// `relaunch_program`, as a future for programs running on tokio (`tokio` feature).
+///
+/// See `RSpawn::relaunch_async` for what runs without blocking the runtime.
+///
+/// # Example
+/// ```ignore
+/// let outcome = relaunch_program_async(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),
+///     None, Some(|_: &str| true), false).await?;
+/// ```
+#[cfg(feature = "tokio")]
+pub async fn relaunch_program_async<F>(
+    crate_name: &str,
+    current_version: &str,
+    active_features: Option<Vec<String>>,
+    user_confirm: Option<F>,
+    #[allow(non_snake_case)]
+    check_if_executed_from_PATH: bool
+) -> Result<UpdateOutcome, RspawnError>
+where
+    F: FnMut(&str) -> bool + Send + 'static,
+{
+    program_update(crate_name, current_version, active_features, user_confirm, check_if_executed_from_PATH)
+        .relaunch_async()
+        .await
+}
+
+// The builder `relaunch_program` runs the update with: the defaults are the builder's
+#[cfg(feature = "install")]
+#[allow(non_snake_case)]
+fn program_update<F>(
+    crate_name: &str,
+    current_version: &str,
+    active_features: Option<Vec<String>>,
+    user_confirm: Option<F>,
+    check_if_executed_from_PATH: bool,
+) -> RSpawn
+where
+    F: FnMut(&str) -> bool + Send + 'static,
+{
+    let mut builder = RSpawn::new()
+        .crate_name(crate_name)
+        .current_version(current_version)
+        .check_if_executed_from_PATH(check_if_executed_from_PATH);
+    if let Some(active_features) = active_features {
+        builder = builder.active_features(active_features);
+    }
+    if let Some(user_confirm) = user_confirm {
+        builder = builder.user_confirm(user_confirm);
+    }
+    builder
+}
+
+// The actual update flow behind both the builder and the free function,
+// remembering failures for `last_error()`
+#[cfg(feature = "install")]
+fn run_update(mut options: UpdateOptions, confirm_fn: UpdateConfirm) -> Result<UpdateOutcome, RspawnError> {
+    let result = update_flow(&mut options, confirm_fn);
+    record_run(options, result)
+}
+
+// Records how a run of the flow ended, for `last_error()` and `rspawn doctor`
+#[cfg(feature = "install")]
+pub(crate) fn record_run<T>(mut options: UpdateOptions, result: Result<T, RspawnError>) -> Result<T, RspawnError> {
+    if let Err(e) = &result {
+        last_error::record(e, options.log_path.as_deref(), options.clock.as_ref(), options.fs.as_ref());
+        options.diagnosis.error = Some(format!("{:#}", e));
+    }
+    diagnose::record(options.diagnosis, options.fs.as_ref());
+    result
+}
+
+#[cfg(feature = "install")]
+fn update_flow(options: &mut UpdateOptions, mut confirm_fn: UpdateConfirm) -> Result<UpdateOutcome, RspawnError> {
+    let planned = match plan_update(options)? {
+        ControlFlow::Continue(planned) => planned,
+        ControlFlow::Break(outcome) => return Ok(outcome),
+    };
+    if !confirm_update(options, &planned, confirm_fn.as_mut()) {
+        return Ok(UpdateOutcome::Declined);
+    }
+    apply_update(options, planned)
+}
 
-    // Check if the lock file already exists
-    if lock_file_path.exists() {
-        return Err(anyhow::anyhow!("Program is already relaunching; avoiding infinite loop.").into());
+// Asks whether to install `planned`: the remote approval endpoint, or the
+// user-provided or default confirmation function
+#[cfg(feature = "install")]
+fn confirm_update(options: &mut UpdateOptions, planned: &PlannedUpdate, confirm_fn: &mut dyn FnMut(&InstallPlan) -> bool) -> bool {
+    // Going back a version is only decided without asking when the program allows it
+    let unattended = !planned.plan.downgrade || options.unattended_downgrades;
+    let confirmed = match &options.remote_approval {
+        Some(approval) if unattended => approval::is_approved(
+            approval,
+            &planned.crate_name,
+            &planned.latest.num,
+            &planned.current_version,
+            options.clock.as_ref(),
+            options.fs.as_ref(),
+        ),
+        _ if unattended && options.assume_yes => true,
+        // Nobody would see the prompt, so the provider decides
+        _ => match &options.decision_provider {
+            Some(provider) if unattended && !io::stdin().is_terminal() => provider.decide(&UpdateRequest {
+                crate_name: &planned.crate_name,
+                current_version: &planned.current_version,
+                version: &planned.latest.num,
+                downgrade: planned.plan.downgrade,
+                plan: &planned.plan,
+                clock: options.clock.as_ref(),
+                fs: options.fs.as_ref(),
+            }),
+            _ => match &mut options.confirm_plan {
+                Some(confirm_plan) => confirm_plan(&planned.plan),
+                None => confirm_fn(&planned.plan),
+            },
+        },
+    };
+    if confirmed {
+        options.diagnosis.pass(Gate::Confirmation, "");
+    } else if planned.plan.downgrade {
+        info!(target: log_target::CHECK, "You chose not to downgrade.");
+        options.diagnosis.stop(Gate::Confirmation, "the downgrade was declined");
+    } else {
+        info!(target: log_target::CHECK, "You chose not to update.");
+        options.diagnosis.stop(Gate::Confirmation, "the update was declined");
     }
+    confirmed
+}
 
-    // Create the lock file to prevent future executions from relaunching
-    create_lock_file(&lock_file_path).context("Failed to create lock file")?;
+// An update `plan_update` found, with everything needed to install it
+#[cfg(feature = "install")]
+pub(crate) struct PlannedUpdate {
+    // Held until the update is applied or dropped
+    lock_guard: lock::LockGuard,
+    plan: InstallPlan,
+    crate_name: String,
+    current_version: String,
+    latest: RegistryVersion,
+    channel: Option<String>,
+    release_assets: Vec<Asset>,
+    target: Option<TargetBinary>,
+    install_command: Command,
+    compiler_wrapper: Option<PathBuf>,
+    installed_path: Option<PathBuf>,
+    keep_backups: usize,
+    relaunch_program: PathBuf,
+    relaunch: Option<Command>,
+    simulating: bool,
+    show_progress: bool,
+}
 
-    // Create a LockFileGuard to ensure cleanup on exit
-    let _lock_guard = LockFileGuard {
-        lock_file_path,
+// Checks for an update and works out how to install it, or how the run ends if there is nothing to install
+#[cfg(feature = "install")]
+fn plan_update(options: &mut UpdateOptions) -> Result<ControlFlow<UpdateOutcome, PlannedUpdate>> {
+    let check = match start_check(options)? {
+        ControlFlow::Continue(check) => check,
+        ControlFlow::Break(outcome) => return Ok(ControlFlow::Break(outcome)),
     };
+    let found = find_latest(options, &check)?;
+    finish_plan(options, check, found)
+}
+
+// How far `plan_update` got before looking up the latest version
+#[cfg(feature = "install")]
+struct Check {
+    // Held until the update is applied or dropped
+    lock_guard: lock::LockGuard,
+    state: State,
+    simulating: bool,
+    simulated_latest: Option<String>,
+    show_progress: bool,
+    target: Option<TargetBinary>,
+    target_path: Option<PathBuf>,
+    crate_name: String,
+    current_version: String,
+    channel: Option<String>,
+}
+
+// Runs the checks that don't need the latest version, taking the update lock
+#[cfg(feature = "install")]
+fn start_check(options: &mut UpdateOptions) -> Result<ControlFlow<UpdateOutcome, Check>> {
+    options.diagnosis = Diagnosis::new(options.clock.as_ref());
+    if !options.enabled {
+        info!(target: log_target::CHECK, "Update check is disabled.");
+        options.diagnosis.stop(Gate::Enabled, "update checks are disabled");
+        return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::Enabled)));
+    }
+
+    // What the user set with `rspawn config` goes over the program's own settings
+    let state = State::load_in(options.fs.as_ref()).unwrap_or_else(|e| {
+        warn!(target: log_target::CHECK, "Failed to read the user's settings: {:#}", e);
+        State::default()
+    });
+    if state.settings.disabled {
+        info!(target: log_target::CHECK, "Update check is disabled by the user.");
+        options.diagnosis.stop(Gate::Enabled, "update checks are disabled with rspawn config");
+        return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::Enabled)));
+    }
+    options.diagnosis.pass(Gate::Enabled, "");
+    if let Some(interval) = state.settings.check_interval {
+        let since = state.last_check.map(|checked_at| options.clock.unix_now().saturating_sub(checked_at));
+        if since.is_some_and(|since| since < interval) {
+            info!(target: log_target::CHECK, "Checked for updates less than {}s ago, skipping.", interval);
+            options.diagnosis.stop(Gate::Interval, format!("checked {}s ago, the interval is {}s", since.unwrap_or_default(), interval));
+            return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::Interval)));
+        }
+        options.diagnosis.pass(Gate::Interval, format!("checking every {}s", interval));
+    }
+    http::set_proxy(state.settings.proxy.as_deref());
+
+    // Updates don't outlive a container, so don't spend its startup time on them
+    if options.container_policy != ContainerPolicy::Proceed {
+        if let Some(runtime) = container::detect_container() {
+            if options.container_policy == ContainerPolicy::Skip {
+                info!(target: log_target::CHECK, "Running in a {} container, skipping the update check.", runtime);
+                options.diagnosis.stop(Gate::Container, format!("running in a {} container, policy is to skip", runtime));
+                return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::Container)));
+            }
+            info!(target: log_target::CHECK, "Running in a {} container, only checking for updates.", runtime);
+            options.diagnosis.pass(Gate::Container, format!("running in a {} container, only checking", runtime));
+            options.check_only = true;
+        }
+    }
+
+    // Take the update lock, released when the guard goes out of scope
+    let lock_guard = options.diagnosis.check(
+        Gate::Lock,
+        lock::acquire(options.lock_behavior, Arc::clone(&options.fs), options.clock.as_ref()),
+    )?;
+
+    // Undo whatever an update that died halfway left behind
+    if let Some((from, to)) = journal::recover_interrupted_update_in(options.fs.as_ref())? {
+        options.printer.warning(&format!("rolled back an interrupted update from {} to {}", from, to));
+    }
 
     // Check if the program was executed from PATH
-    if check_if_executed_from_PATH && !is_executed_from_path() {
-        return Err(anyhow::anyhow!("Program must be executed from PATH, not from a full or relative path.").into());
+    if options.check_if_executed_from_PATH && !executed_from_path(options.binary_name.as_deref()) {
+        options.diagnosis.stop(Gate::ExecutedFromPath, "started with a full or relative path");
+        warn!(target: log_target::CHECK, "Program must be executed from PATH, not from a full or relative path.");
+        return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::ExecutedFromPath)));
     }
 
-    let crate_name = env!("CARGO_PKG_NAME").to_string();
-    // Get the latest version from crates.io
-    let latest_version = get_latest_version_from_crates_io(&crate_name).context("Failed to get latest version")?;
+    // Pretend there is an update, and fake installing it, when asked to through the environment
+    #[cfg(feature = "simulate")]
+    let simulation = simulate::Simulation::from_env();
+    #[cfg(feature = "simulate")]
+    if let Some(simulation) = &simulation {
+        options.printer.warning("simulating the update, nothing will be installed");
+        options.process_runner = Box::new(simulation.runner());
+    }
+    #[cfg(feature = "simulate")]
+    let (simulating, simulated_latest) = (simulation.is_some(), simulation.and_then(|simulation| simulation.latest));
+    #[cfg(not(feature = "simulate"))]
+    let (simulating, simulated_latest): (bool, Option<String>) = (false, None);
 
+    // Draw progress bars from the same events the host program sees
+    #[cfg(feature = "progress")]
+    let show_progress = options.printer.show_progress();
+    #[cfg(not(feature = "progress"))]
+    let show_progress = false;
+    #[cfg(feature = "progress")]
+    if show_progress {
+        let mut renderer = progress::ProgressRenderer::new(Arc::clone(&options.formatter));
+        options.events.add(Box::new(move |event| renderer.handle(event)));
+    }
+
+    // A launcher updates its worker's crate, tracked apart from its own version
+    let target = options.target_binary.clone().map(TargetBinary::new).transpose()?;
+    let target_path = target.as_ref().and_then(|target| target.path(install::cargo_bin_dir()));
+    let target_version;
+
+    let crate_name = match &target {
+        Some(target) => target.name().to_string(),
+        None => options.crate_name.clone(),
+    };
+    options.events.emit(UpdateEvent::CheckStarted { crate_name: crate_name.clone() });
     // Get the current version of the program
-    let current_version = env!("CARGO_PKG_VERSION"); // This gets the version from Cargo.toml at build time
+    let current_version = match &target {
+        Some(target) => {
+            target_version = target.installed_version(target_path.as_deref(), options.fs.as_ref())?.unwrap_or_else(|| {
+                info!(target: log_target::CHECK, "{} is not installed yet", crate_name);
+                "0.0.0".to_string()
+            });
+            target_version.as_str()
+        }
+        None => options.current_version.as_str(),
+    };
+
+    let channel = channels::resolve_channel(options.channel.as_deref(), options.fs.as_ref());
+
+    Ok(ControlFlow::Continue(Check {
+        lock_guard,
+        state,
+        simulating,
+        simulated_latest,
+        show_progress,
+        target,
+        target_path,
+        crate_name,
+        current_version: current_version.to_string(),
+        channel,
+    }))
+}
+
+// The latest version, with what its source said about it
+#[cfg(feature = "install")]
+struct FoundVersion {
+    latest: RegistryVersion,
+    release_notes: Option<String>,
+    release_assets: Vec<Asset>,
+}
+
+// Looks up the latest version from wherever the options say releases come from
+#[cfg(feature = "install")]
+fn find_latest(options: &UpdateOptions, check: &Check) -> Result<FoundVersion> {
+    let (crate_name, current_version, channel) = (check.crate_name.as_str(), check.current_version.as_str(), check.channel.as_deref());
+    let mut release_notes = None;
+    let mut release_assets = Vec::new();
+    let latest = if let Some(num) = check.simulated_latest.clone() {
+        RegistryVersion { num, checksum: None }
+    } else if let Some(manifest_url) = &options.channel_manifest {
+        // The author's manifest decides which version this install should run
+        let manifest = ChannelManifest::fetch(manifest_url)?;
+        let install_id = channels::install_id(options.fs.as_ref())?;
+        let latest = match manifest.target_version(crate_name, channel, &install_id, &HostInfo::detect())? {
+            Some(target) if is_outdated(current_version, target) => {
+                let registry_token = options.credential_store.token(REGISTRY_TOKEN)?;
+                registry::get_version_from_crates_io(crate_name, target, registry_token.as_deref())
+                    .context("Failed to get the channel's version")?
+            }
+            // Already there, or not part of the rollout yet: stay on the current version
+            _ => RegistryVersion {
+                num: current_version.to_string(),
+                checksum: None,
+            },
+        };
+        release_assets = manifest.assets.get(&latest.num).cloned().unwrap_or_default();
+        latest
+    } else if let VersionSource::Mirror(mirror) = &options.version_source {
+        mirror::latest_version(mirror, crate_name, channel)?
+    } else if let VersionSource::Feed(feed_url) = &options.version_source {
+        let github_token = match http::is_github_url(feed_url) {
+            true => options.credential_store.token(GITHUB_TOKEN)?,
+            false => None,
+        };
+        let release = feed::latest_release(feed_url, channel, github_token.as_deref())?;
+        release_notes = release.link;
+        RegistryVersion {
+            num: release.version,
+            checksum: None,
+        }
+    } else {
+        // Get the latest version from crates.io
+        match cached_latest(options, crate_name, current_version, channel) {
+            Some(latest) => latest,
+            None => {
+                let registry_token = options.credential_store.token(REGISTRY_TOKEN)?;
+                let latest = registry::get_latest_version_from_crates_io(crate_name, registry_token.as_deref(), channel)
+                    .context("Failed to get latest version")?;
+                share_latest(options, crate_name, channel, &latest);
+                latest
+            }
+        }
+    };
+    Ok(FoundVersion { latest, release_notes, release_assets })
+}
+
+// The latest version on crates.io as another run or a peer recently found it, if any
+#[cfg(feature = "install")]
+#[cfg_attr(not(feature = "p2p"), allow(unused_variables))]
+fn cached_latest(options: &UpdateOptions, crate_name: &str, current_version: &str, channel: Option<&str>) -> Option<RegistryVersion> {
+    let cached = options.shared_cache.and_then(|ttl| cache::cached_latest_version(crate_name, channel, ttl, options.clock.as_ref(), options.fs.as_ref()));
+    #[cfg(feature = "p2p")]
+    let cached = cached.or_else(|| {
+        if !options.lan_gossip {
+            return None;
+        }
+        let max_age = options.shared_cache.unwrap_or(cache::DEFAULT_CACHE_TTL);
+        let gossiped = gossip::latest_version(crate_name, channel, max_age)?;
+        // Replies are unauthenticated: one that isn't an update must not stop
+        // (or downgrade) this install, so the registry answers instead
+        if !is_update(current_version, &gossiped, UpdatePolicy::Newer) {
+            log::debug!(target: log_target::CHECK, "Peers reported {} {}, not newer than {}", crate_name, gossiped, current_version);
+            return None;
+        }
+        // An update is only taken from peers once the registry has it, with its checksum
+        let registry_token = options.credential_store.token(REGISTRY_TOKEN).ok()?;
+        match registry::get_version_from_crates_io(crate_name, &gossiped, registry_token.as_deref()) {
+            Ok(latest) => Some(latest),
+            Err(e) => {
+                warn!(target: log_target::CHECK, "Peers reported {} {}, but the registry doesn't confirm it: {:#}", crate_name, gossiped, e);
+                None
+            }
+        }
+    });
+    cached
+}
+
+// Shares what crates.io answered with other runs and peers, when they ask
+#[cfg(feature = "install")]
+fn share_latest(options: &UpdateOptions, crate_name: &str, channel: Option<&str>, latest: &RegistryVersion) {
+    // Peers answer from the shared cache
+    #[cfg(feature = "p2p")]
+    let share_lookup = options.shared_cache.is_some() || options.lan_gossip;
+    #[cfg(not(feature = "p2p"))]
+    let share_lookup = options.shared_cache.is_some();
+    if share_lookup {
+        if let Err(e) = cache::store_latest_version(crate_name, channel, latest, options.clock.as_ref(), options.fs.as_ref()) {
+            warn!(target: log_target::CHECK, "Failed to update the shared version cache: {:#}", e);
+        }
+    }
+}
+
+// Works out how to install the version found, or how the run ends if it isn't an update
+#[cfg(feature = "install")]
+fn finish_plan(options: &mut UpdateOptions, check: Check, found: FoundVersion) -> Result<ControlFlow<UpdateOutcome, PlannedUpdate>> {
+    let Check {
+        lock_guard,
+        state,
+        simulating,
+        simulated_latest: _,
+        show_progress,
+        target,
+        target_path,
+        crate_name,
+        current_version,
+        channel,
+    } = check;
+    let (current_version, channel) = (current_version.as_str(), channel.as_deref());
+    let FoundVersion { latest, release_notes, release_assets } = found;
+    let latest_version = latest.num.clone();
+    if state.settings.check_interval.is_some() {
+        let checked_at = options.clock.unix_now();
+        if let Err(e) = State::update_in(options.fs.as_ref(), |state| state.last_check = Some(checked_at)) {
+            warn!(target: log_target::CHECK, "Failed to record the update check: {:#}", e);
+        }
+    }
+    options.diagnosis.current_version = Some(current_version.to_string());
+    options.diagnosis.latest_version = Some(latest_version.clone());
+    options.diagnosis.pass(Gate::Channel, format!("following {}", channel.unwrap_or("the default channel")));
 
-    if latest_version != current_version {
-        // Determine the confirmation function
-        let mut confirm_fn: Box<dyn FnMut(&str) -> bool> = if let Some(mut custom_confirm) = user_confirm {
-            Box::new(move |version| custom_confirm(version))
+    options.events.emit(UpdateEvent::CheckFinished {
+        current_version: current_version.to_string(),
+        latest_version: latest_version.clone(),
+    });
+
+    if options.check_only {
+        options.diagnosis.stop(Gate::CheckOnly, "only checking for updates");
+        let printer = &options.printer;
+        if is_outdated(current_version, &latest_version) {
+            printer.status(&format!(
+                "A new version {} is available (current: {}).",
+                printer.version(&latest_version),
+                current_version
+            ));
+            if let Some(release_notes) = &release_notes {
+                printer.status(&format!("Release notes: {}", release_notes));
+            }
         } else {
-            Box::new(default_user_confirm)
+            printer.status(&format!(
+                "You are already using the latest version ({}).",
+                printer.version(current_version)
+            ));
+        }
+        let outcome = if is_outdated(current_version, &latest_version) {
+            UpdateOutcome::Available { version: latest_version }
+        } else {
+            UpdateOutcome::UpToDate
         };
+        return Ok(ControlFlow::Break(outcome));
+    }
+
+    if !is_outdated(current_version, &latest_version) {
+        info!(target: log_target::CHECK, "You are already using the latest version.");
+        options.diagnosis.stop(Gate::UpToDate, "already running the latest version");
+        last_error::clear(options.fs.as_ref());
+        return Ok(ControlFlow::Break(UpdateOutcome::UpToDate));
+    }
 
-        // Use the user-provided or default confirmation function
+    options.diagnosis.pass(Gate::UpToDate, format!("{} is available", latest_version));
+    // Already installed by an earlier run, waiting for the program to be restarted
+    if target.is_none() && restart::pending_version(current_version, options.fs.as_ref())?.as_deref() == Some(latest_version.as_str()) {
+        options.diagnosis.stop(Gate::PendingRestart, format!("{} is already installed, waiting for a restart", latest_version));
+        options.printer.status(&format!(
+            "Version {} is already installed and runs from the next start.",
+            options.printer.version(&latest_version)
+        ));
+        return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::PendingRestart)));
+    }
 
-        if confirm_fn(&latest_version) {
-            // Install the new version (e.g., using cargo install or similar method)
-            let mut install_command = {
-                let mut cmd = Command::new("cargo");
-                cmd.arg("install").arg(crate_name);
+    // Make sure we are not about to update on top of a tampered or partially written binary
+    if options.verify_integrity && target.is_none() {
+        let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+        options.diagnosis.check(Gate::Integrity, integrity::verify_against_state(&exe_path, current_version, options.fs.as_ref()))?;
+    }
 
-                if let Some(features) = active_features {
-                    if !features.is_empty() {
-                        cmd.args(features.iter().flat_map(|f| ["--features", f]));
-                    }
-                }
-                cmd // Return the fully configured `Command`
+    // Operators can freeze updates machine-wide, e.g. during a maintenance window
+    options.diagnosis.check(Gate::Hold, hold::check_hold(options.clock.as_ref(), options.fs.as_ref()).map_err(anyhow::Error::from))?;
+
+    // Don't rebuild a version that keeps failing on every start
+    if !options.failure_backoff.is_zero() {
+        let backing_off = backoff::backing_off(&crate_name, channel, &latest_version, options.failure_backoff, options.clock.as_ref(), options.fs.as_ref())?;
+        if let Some((failed, retry_at)) = backing_off {
+            options.diagnosis.stop(Gate::Backoff, format!("{} failed to install {} time(s) in a row", latest_version, failed.count));
+            options.printer.warning(&format!(
+                "not retrying {} for now: its install failed {} time(s) in a row. Retrying {}.",
+                options.printer.version(&latest_version),
+                failed.count,
+                options.formatter.relative_time(retry_at, options.clock.unix_now()),
+            ));
+            return Ok(ControlFlow::Break(UpdateOutcome::Skipped(Gate::Backoff)));
+        }
+    }
+
+    // Fail before asking if cargo has nowhere to write, e.g. in a locked-down container
+    let fallback_root = options.diagnosis.check(
+        Gate::Environment,
+        preflight::check_install_environment(options.fallback_install_root.as_deref(), options.fs.as_ref()).map_err(anyhow::Error::from),
+    )?;
+
+    // Moving to a channel that is behind us (e.g. nightly back to stable) means going back
+    if version::compare_versions(&latest_version, current_version) == Ordering::Less {
+        options.printer.warning(&format!(
+            "{} is older than the running {}: the {} channel is behind the version you're on, so updating means downgrading.",
+            options.printer.version(&latest_version),
+            current_version,
+            channel.unwrap_or("default"),
+        ));
+    }
+
+    // Everything the update is about to run, so confirmation dialogs can show it
+    let mut install_command = install::cargo_install_command(&crate_name, Some(&latest_version), &options.active_features);
+    if let VersionSource::Mirror(mirror) = &options.version_source {
+        mirror::apply(&mut install_command, mirror)?;
+    }
+    // Only the program's own binary, for crates that have several
+    if let Some(binary_name) = options.binary_name.as_ref().filter(|_| target.is_none()) {
+        install_command.args(["--bin", binary_name]);
+    }
+    // Compiled packages are counted from cargo's JSON messages; diagnostics still go to stderr
+    install_command.args(["--message-format", "json-render-diagnostics"]);
+    if let Some(proxy) = &state.settings.proxy {
+        install_command.env("CARGO_HTTP_PROXY", proxy);
+    }
+    let target_root = target.as_ref().map(TargetBinary::install_root).transpose()?.flatten();
+    // Another binary is updated where it lives, not where we do
+    let install_root = if target.is_none() { options.install_root.resolve() } else { None };
+    if let Some(target_root) = &target_root {
+        install_command.arg("--root").arg(target_root);
+    } else if let Some(install_root) = &install_root {
+        install_command.arg("--root").arg(install_root);
+    } else if let Some(fallback_root) = &fallback_root {
+        fallback_root.apply(&mut install_command);
+    }
+    // A build from scratch beats no update, so a cache that can't be set up is skipped
+    if options.keep_build_cache {
+        if let Err(e) = install::use_build_cache(&mut install_command, options.fs.as_ref()) {
+            warn!(target: log_target::INSTALL, "Building without the build cache: {:#}", e);
+        }
+    }
+    let compiler_wrapper = build_wrapper::apply(&mut install_command, &options.build_wrapper);
+    let mut install_command = options.diagnosis.check(Gate::Environment, sandbox::wrap(install_command, options.install_sandbox))?;
+    if install_root.as_ref().is_some_and(|root| preflight::check_writable(&root.join("bin"), options.fs.as_ref()).is_err()) {
+        install_command = options.diagnosis.check(Gate::Environment, install_root::elevate(install_command, options.allow_elevation))?;
+    }
+    install::apply_priority(&mut install_command, options.install_priority);
+    if let Some(customize) = &mut options.install_command_hook {
+        customize(&mut install_command);
+    }
+    let bin_dir = match &install_root {
+        Some(install_root) => Some(install_root.join("bin")),
+        None => fallback_root.as_ref().map(|root| root.bin_dir()).or_else(install::cargo_bin_dir),
+    };
+    let installed_path = match &target {
+        Some(_) if target_root.is_some() => target_path.clone(),
+        Some(target) => target.path(bin_dir),
+        None => {
+            let exe_name = match &options.binary_name {
+                Some(binary_name) => Some(PathBuf::from(format!("{}{}", binary_name, env::consts::EXE_SUFFIX))),
+                None => state::current_exe_file_name().ok(),
             };
-            let mut child = install_command.spawn()
-                .context("Failed to run cargo install")?; // Install the crate
-
-            // Wait for the install process to complete
-            let _ = child.wait().context("Failed to wait for cargo install")?;
-
-            // After installing, relaunch the program
-            let args: Vec<String> = env::args().collect();
-            let child = Command::new(&args[0])
-                .args(&args[1..]) // Pass all the arguments to the new process
-                .spawn();
-
-            match child {
-                Ok(_) => {
-                    exit(0); // Exit the old process immediately after launching the new one
-                },
-                Err(e) => {
-                    return Err(anyhow::anyhow!("Failed to relaunch the program: {}", e).into());
+            bin_dir.zip(exe_name).map(|(bin_dir, exe_name)| bin_dir.join(exe_name))
+        }
+    };
+    let keep_backups = if target.is_some() { 0 } else { options.keep_backups };
+    let relaunch_program = match &options.relaunch_target {
+        Some(target) => target.clone(),
+        // The running executable may not be the installed binary, e.g. a renamed copy
+        None => match installed_path.as_ref().filter(|_| fallback_root.is_some() || install_root.is_some() || options.binary_name.is_some()) {
+            Some(installed_path) => installed_path.clone(),
+            None => current_exe_path()?,
+        },
+    };
+    let relaunch = (options.relaunch_mode == RelaunchMode::Immediately && target.is_none())
+        .then(|| relaunch_command(&relaunch_program));
+    let plan = InstallPlan::new(
+        &crate_name,
+        current_version,
+        &latest_version,
+        keep_backups > 0,
+        &install_command,
+        installed_path.clone(),
+        relaunch.as_ref(),
+    );
+
+    Ok(ControlFlow::Continue(PlannedUpdate {
+        lock_guard,
+        plan,
+        crate_name,
+        current_version: current_version.to_string(),
+        latest,
+        channel: channel.map(str::to_string),
+        release_assets,
+        target,
+        install_command,
+        compiler_wrapper,
+        installed_path,
+        keep_backups,
+        relaunch_program,
+        relaunch,
+        simulating,
+        show_progress,
+    }))
+}
+
+// Installs a planned update and relaunches into it, the update having been confirmed
+#[cfg(feature = "install")]
+pub(crate) fn apply_update(options: &mut UpdateOptions, planned: PlannedUpdate) -> Result<UpdateOutcome, RspawnError> {
+    let (prepared, install_command) = prepare_install(options, planned)?;
+    // Install the crate and wait for it, bailing out before the relaunch on any failure
+    let echo = !prepared.show_progress;
+    let reporter = RefCell::new(InstallReporter::new(&mut options.events, &install_command));
+    let on_line = &mut |line: &str| reporter.borrow_mut().line(line);
+    let install_result = if options.install_heartbeat.is_zero() {
+        options.process_runner.install(install_command, echo, on_line)
+    } else {
+        options.process_runner.install_with_heartbeat(install_command, echo, on_line, options.install_heartbeat, &mut || {
+            reporter.borrow_mut().beat();
+        })
+    };
+    finish_install(options, prepared, install_result)
+}
+
+// A confirmed update about to be installed, see `prepare_install`
+#[cfg(feature = "install")]
+struct PreparedInstall {
+    lock_guard: lock::LockGuard,
+    crate_name: String,
+    current_version: String,
+    latest: RegistryVersion,
+    channel: Option<String>,
+    target: Option<TargetBinary>,
+    compiler_wrapper: Option<PathBuf>,
+    installed_path: Option<PathBuf>,
+    relaunch_program: PathBuf,
+    relaunch: Option<Command>,
+    simulating: bool,
+    // Cargo's output is left to the progress bars when they are drawn
+    show_progress: bool,
+    staged_assets: Option<assets::StagedAssets>,
+    transaction: journal::Transaction,
+    install_location: InstallLocation,
+    cache_stats: Option<CompilerCacheStats>,
+}
+
+// Gets a confirmed update ready for `cargo install`, returning the command to run:
+// the release is verified, its assets are staged and the files it replaces journaled
+#[cfg(feature = "install")]
+fn prepare_install(options: &mut UpdateOptions, planned: PlannedUpdate) -> Result<(PreparedInstall, Command)> {
+    let PlannedUpdate {
+        lock_guard,
+        plan: _,
+        crate_name,
+        current_version,
+        latest,
+        channel,
+        release_assets,
+        target,
+        install_command,
+        compiler_wrapper,
+        installed_path,
+        keep_backups,
+        relaunch_program,
+        relaunch,
+        simulating,
+        show_progress,
+    } = planned;
+    let latest_version = latest.num.clone();
+
+    // Refuse to install a release that isn't signed by the expected identity
+    #[cfg(feature = "sigstore")]
+    if let Some(verification) = options.sigstore.as_ref().filter(|_| !simulating) {
+        sigstore::verify_release(verification, &crate_name, &latest, &options.downloads, &mut options.events, options.process_runner.as_ref())?;
+    }
+    #[cfg(feature = "signed-releases")]
+    if let Some(signature) = options.release_signature.as_ref().filter(|_| !simulating) {
+        signing::verify_release(signature, &crate_name, &latest)?;
+    }
+
+    // Fetch and check the release's data files first, so a bad one stops the update
+    let staged_assets = if release_assets.is_empty() {
+        None
+    } else {
+        // Assets of private GitHub repositories need the token
+        if release_assets.iter().any(|asset| http::is_github_url(&asset.url)) {
+            options.downloads.github_token = options.credential_store.token(GITHUB_TOKEN)?;
+        }
+        let data_dir = match &options.data_dir {
+            Some(dir) => dir.clone(),
+            None => assets::data_dir()?,
+        };
+        Some(assets::stage(&release_assets, &data_dir, &options.downloads, &mut options.events, options.fs.as_ref())?)
+    };
+
+    // Keep a copy of the running version around so it can be restored later
+    if keep_backups > 0 {
+        if let Err(e) = backup::backup_current_exe(&current_version, keep_backups) {
+            warn!(target: log_target::INSTALL, "Failed to back up the current version: {:#}", e);
+            options.printer.warning(&format!("could not back up the current version: {:#}", e));
+        }
+    }
+
+    // Journal every file the update replaces, so a failure or crash from here on rolls them all back:
+    // the running binary, the crate's other binaries and the assets. Binaries only the new
+    // version has aren't known yet, so a rollback leaves them behind
+    let install_location = InstallLocation::of(&install_command);
+    let mut transaction =
+        journal::Transaction::begin(Arc::clone(&options.fs), &current_version, &latest_version, options.clock.as_ref())?;
+    if let Some(installed_path) = &installed_path {
+        transaction.track(installed_path)?;
+    }
+    for binary in install_location.installed_binaries(&crate_name, options.fs.as_ref()) {
+        transaction.track(&binary)?;
+    }
+    if let Some(staged_assets) = &staged_assets {
+        for target in staged_assets.targets() {
+            transaction.track(target)?;
+        }
+    }
+
+    let cache_stats = compiler_wrapper.as_deref().filter(|_| !simulating).and_then(CompilerCacheStats::read);
+    options.events.emit(UpdateEvent::InstallStarted { version: latest_version });
+
+    let prepared = PreparedInstall {
+        lock_guard,
+        crate_name,
+        current_version,
+        latest,
+        channel,
+        target,
+        compiler_wrapper,
+        installed_path,
+        relaunch_program,
+        relaunch,
+        simulating,
+        show_progress,
+        staged_assets,
+        transaction,
+        install_location,
+        cache_stats,
+    };
+    Ok((prepared, install_command))
+}
+
+// Reports cargo's output while it builds: the packages compiled, its other
+// lines, and heartbeats repeating its last status line
+#[cfg(feature = "install")]
+struct InstallReporter<'a> {
+    events: &'a mut Events,
+    build_progress: BuildProgress,
+    last_status: Option<String>,
+    started: Instant,
+}
+
+#[cfg(feature = "install")]
+impl<'a> InstallReporter<'a> {
+    fn new(events: &'a mut Events, install_command: &Command) -> Self {
+        let cargo_home = install::command_env(install_command, "CARGO_HOME").or_else(install::cargo_home);
+        InstallReporter {
+            events,
+            build_progress: BuildProgress::new(cargo_home),
+            last_status: None,
+            started: Instant::now(),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        let compiled = self.build_progress.compiled();
+        if self.build_progress.read(line) {
+            if self.build_progress.compiled() > compiled {
+                self.events.emit(UpdateEvent::InstallProgress {
+                    compiled: self.build_progress.compiled(),
+                    total: self.build_progress.total(),
+                });
+            }
+            return;
+        }
+        if install::is_status_line(line) {
+            self.last_status = Some(line.trim().to_string());
+        }
+        self.events.emit(UpdateEvent::InstallOutput { line: line.to_string() });
+    }
+
+    fn beat(&mut self) {
+        self.events.emit(UpdateEvent::InstallHeartbeat {
+            elapsed: self.started.elapsed(),
+            last_status: self.last_status.clone(),
+        });
+    }
+}
+
+// Records how `cargo install` went, and relaunches into the new version if it went through
+#[cfg(feature = "install")]
+fn finish_install(options: &mut UpdateOptions, prepared: PreparedInstall, install_result: Result<(), InstallError>) -> Result<UpdateOutcome, RspawnError> {
+    let PreparedInstall {
+        lock_guard,
+        crate_name,
+        current_version,
+        latest,
+        channel,
+        target,
+        compiler_wrapper,
+        installed_path,
+        mut relaunch_program,
+        mut relaunch,
+        simulating,
+        show_progress: _,
+        staged_assets,
+        transaction,
+        install_location,
+        cache_stats,
+    } = prepared;
+    let (current_version, channel) = (current_version.as_str(), channel.as_deref());
+    let latest_version = latest.num.clone();
+    options.events.emit(UpdateEvent::InstallFinished {
+        version: latest_version.clone(),
+        success: install_result.is_ok(),
+    });
+    if let Some((before, wrapper)) = cache_stats.zip(compiler_wrapper.as_deref()) {
+        if let Some(build) = CompilerCacheStats::read(wrapper).map(|after| after.since(before)) {
+            info!(target: log_target::INSTALL, "sccache: {} cache hits, {} misses", build.hits, build.misses);
+            options.events.emit(UpdateEvent::CompilerCache { hits: build.hits, misses: build.misses });
+        }
+    }
+    if install_result.is_err() {
+        if let Some(staged_assets) = &staged_assets {
+            staged_assets.discard(options.fs.as_ref());
+        }
+        if let Err(e) = backoff::record_failure(&crate_name, channel, &latest_version, options.clock.as_ref(), options.fs.as_ref()) {
+            warn!(target: log_target::INSTALL, "Failed to record the failed install: {:#}", e);
+        }
+    }
+    let result = install_result.map_err(anyhow::Error::from).and_then(|()| {
+        // A binary name that doesn't match the crate's would leave the running version in place
+        match installed_path.as_ref().filter(|_| options.binary_name.is_some() && target.is_none() && !simulating) {
+            Some(installed_path) if !installed_path.is_file() => Err(anyhow::anyhow!(
+                "cargo install did not put {} in place, check the binary name",
+                installed_path.display()
+            )),
+            _ => Ok(()),
+        }
+    }).and_then(|()| match staged_assets {
+        Some(staged_assets) => staged_assets.place(options.fs.as_ref()).context("Failed to place the release's assets"),
+        None => Ok(()),
+    });
+    // Simulated updates don't make it into the history
+    let record_history = |error: Option<&anyhow::Error>| {
+        if simulating {
+            return;
+        }
+        if let Err(e) = history::record(&crate_name, current_version, &latest_version, error, options.clock.as_ref(), options.fs.as_ref()) {
+            warn!(target: log_target::INSTALL, "Failed to record the update history: {:#}", e);
+        }
+    };
+    if let Err(e) = result {
+        record_history(Some(&e));
+        if let Err(rollback_error) = transaction.rollback() {
+            warn!(target: log_target::INSTALL, "Failed to roll back the update: {:#}", rollback_error);
+            options.printer.warning(&format!("could not roll back the update, it is retried on the next run: {:#}", rollback_error));
+        }
+        return Err(e.into());
+    }
+    transaction.commit();
+    record_history(None);
+    if let Err(e) = backoff::reset(&crate_name, channel, options.fs.as_ref()) {
+        warn!(target: log_target::INSTALL, "Failed to reset the install backoff: {:#}", e);
+    }
+    if !simulating {
+        match install_location.installed_files(&crate_name) {
+            Ok(files) => options.events.emit(UpdateEvent::Installed { files }),
+            Err(e) => warn!(target: log_target::INSTALL, "Failed to list the installed binaries: {:#}", e),
+        }
+    }
+
+    // The launcher carries on, and restarts its worker itself
+    if let Some(target) = &target {
+        if let Some(installed_path) = installed_path.filter(|path| !simulating && path.is_file()) {
+            if let Err(e) = target::record_install(target.name(), &installed_path, &latest_version, options.clock.as_ref(), options.fs.as_ref()) {
+                warn!(target: log_target::INSTALL, "Failed to record the installed binary: {:#}", e);
+            }
+        }
+        last_error::clear(options.fs.as_ref());
+        options.events.emit(UpdateEvent::TargetInstalled {
+            name: target.name().to_string(),
+            version: latest_version.clone(),
+        });
+        options.printer.status(&format!("Installed {} {}.", target.name(), options.printer.version(&latest_version)));
+        return Ok(UpdateOutcome::Updated { from: current_version.to_string(), to: latest_version });
+    }
+
+    // Remember what was installed, so later runs can check the binary's integrity
+    if let Some(installed_path) = installed_path.clone().filter(|path| !simulating && path.is_file()) {
+        match integrity::record_install(&installed_path, &latest_version, options.clock.as_ref(), options.fs.as_ref()) {
+            Ok(sha256) => {
+                let provenance = Provenance::new(
+                    &crate_name,
+                    &latest,
+                    &options.active_features,
+                    installed_path,
+                    sha256,
+                    options.clock.as_ref(),
+                );
+                if let Err(e) = provenance::record(&provenance, options.fs.as_ref()) {
+                    warn!(target: log_target::INSTALL, "Failed to record install provenance: {:#}", e);
                 }
             }
-        } else {
-            info!("You chose not to update.");
+            Err(e) => warn!(target: log_target::INSTALL, "Failed to record the installed binary: {:#}", e),
         }
-    } else {
-        info!("You are already using the latest version.");
     }
 
+    // Another copy first in PATH would keep running the old version when the program is typed
+    if let Some(installed_path) = installed_path.filter(|path| !simulating && path.is_file()) {
+        let installed_dir = installed_path.parent().unwrap_or(&installed_path);
+        if !path_hint::in_path(installed_dir) {
+            let shell = path_hint::Shell::detect();
+            let export_line = shell.export_line(installed_dir);
+            options.printer.warning(&format!(
+                "{} is not in PATH, so new shells won't find the program. Add it with this line in {}:\n    {}",
+                installed_dir.display(),
+                shell.profile(),
+                export_line,
+            ));
+            options.events.emit(UpdateEvent::NotInPath { dir: installed_dir.to_path_buf(), shell, export_line });
+        }
+        if let Some(winner) = install::shadowing_binary(&installed_path) {
+            options.printer.warning(&format!(
+                "{} comes first in PATH and shadows the new {}: remove it, or put {} before {} in PATH.",
+                winner.display(),
+                installed_path.display(),
+                installed_dir.display(),
+                winner.parent().unwrap_or(&winner).display(),
+            ));
+            options.events.emit(UpdateEvent::ShadowedInstall { winner, loser: installed_path.clone() });
+            // Relaunching what's first in PATH could start the old version again
+            if options.relaunch_target.is_none() {
+                relaunch = relaunch.map(|_| relaunch_command(&installed_path));
+                relaunch_program = installed_path;
+            }
+        }
+    }
+
+    // This attempt went through, and we won't be back to say so after the relaunch
+    last_error::clear(options.fs.as_ref());
+
+    // Leave the new version for the next start, without interrupting this one
+    if options.relaunch_mode == RelaunchMode::OnNextStart {
+        let recorded = if simulating { Ok(()) } else { restart::record_pending(&latest_version, options.clock.as_ref(), options.fs.as_ref()) };
+        if let Err(e) = recorded {
+            warn!(target: log_target::RELAUNCH, "Failed to record the pending restart: {:#}", e);
+        }
+        options.events.emit(UpdateEvent::RestartPending { version: latest_version.clone() });
+        options.printer.status(&format!(
+            "Installed {}: it runs from the next start.",
+            options.printer.version(&latest_version)
+        ));
+        return Ok(UpdateOutcome::Updated { from: current_version.to_string(), to: latest_version });
+    }
+
+    // After installing, relaunch the program. Release the lock first, so the
+    // new version doesn't wait for it: it finds itself up to date anyway.
+    check_executable(&relaunch_program).map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound => RspawnError::NotOnPath { program: relaunch_program.clone() },
+        _ => RspawnError::RelaunchFailed(e),
+    })?;
+    info!(target: log_target::RELAUNCH, "Relaunching {}", relaunch_program.display());
+    options.events.emit(UpdateEvent::Relaunching);
+    drop(lock_guard);
+    let command = relaunch.unwrap_or_else(|| relaunch_command(&relaunch_program));
+    let started = match options.relaunch_strategy {
+        // Exiting this process is up to the host, once it's done cleaning up
+        RelaunchStrategy::Spawn => options.process_runner.spawn_process(command),
+        // Nothing of this process runs past a successful exec, not even destructors
+        // or `record_run`, so the run is recorded now; a failed exec records it again
+        #[cfg(unix)]
+        RelaunchStrategy::Exec => {
+            diagnose::record(options.diagnosis.clone(), options.fs.as_ref());
+            log::logger().flush();
+            options.process_runner.exec_process(command)
+        }
+    };
+    match started {
+        Ok(pid) => Ok(UpdateOutcome::Relaunched { pid }),
+        Err(e) => Err(RspawnError::RelaunchFailed(anyhow::anyhow!("Failed to relaunch the program: {}", e))),
+    }
+}
+
+// Runs the flow in check-only mode, returning the current and latest versions it found
+#[cfg(any(all(feature = "ipc", unix), all(feature = "dbus", target_os = "linux")))]
+pub(crate) fn check_versions(rspawn: RSpawn) -> Result<(String, String)> {
+    let finished = Arc::new(std::sync::Mutex::new(None));
+    let sink = Arc::clone(&finished);
+    rspawn
+        .check_only(true)
+        .on_event(move |event| {
+            if let UpdateEvent::CheckFinished { current_version, latest_version } = event {
+                *sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                    Some((current_version.clone(), latest_version.clone()));
+            }
+        })
+        .relaunch_program()?;
+
+    let finished = finished.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    finished.ok_or_else(|| anyhow::anyhow!("Update check is disabled"))
+}
+
+// The running executable. On Linux, once cargo install replaced it the kernel
+// reports the old file as "<path> (deleted)", and the path holds the new version.
+#[cfg(feature = "install")]
+pub(crate) fn current_exe_path() -> Result<PathBuf> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    #[cfg(target_os = "linux")]
+    if let Some(path) = exe_path.to_str().and_then(|path| path.strip_suffix(" (deleted)")) {
+        return Ok(PathBuf::from(path));
+    }
+    Ok(exe_path)
+}
+
+// Fails unless `path` is a file we may execute
+#[cfg(feature = "install")]
+pub(crate) fn check_executable(path: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Cannot relaunch {}", path.display()))?;
+    if !metadata.is_file() {
+        return Err(anyhow::anyhow!("Cannot relaunch {}: not a file", path.display()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(anyhow::anyhow!("Cannot relaunch {}: not executable", path.display()));
+        }
+    }
     Ok(())
 }
 
-// Default confirmation function
-fn default_user_confirm(version: &str) -> bool {
-    println!("A new version {} is available. Would you like to install it? (y/n): ", version);
+// Command starting `program` with the arguments we were started with, or the whole
+// invocation captured by `capture_invocation()`. The program never comes from argv[0]:
+// whoever spawned us picked it, and it may be empty or name something else entirely.
+#[cfg(feature = "install")]
+pub(crate) fn relaunch_command(program: &Path) -> Command {
+    let mut command = Command::new(program);
+    match invocation::captured() {
+        Some(invocation) => invocation.apply(&mut command),
+        None => {
+            command.args(env::args_os().skip(1)); // Pass all the arguments to the new process
+        }
+    }
+    command
+}
+
+// Default confirmation function. Downgrades are explained, and declined when
+// nobody is at a terminal to answer.
+#[cfg(feature = "install")]
+fn default_user_confirm(printer: &Printer, plan: &InstallPlan) -> bool {
+    if plan.downgrade {
+        if !io::stdin().is_terminal() {
+            return false;
+        }
+        printer.prompt(&format!(
+            "Version {} is older than the running {}: the release channel you follow is behind it. Would you like to downgrade? (y/n): ",
+            printer.version(&plan.version),
+            plan.current_version
+        ));
+    } else {
+        printer.prompt(&format!(
+            "A new version {} is available. Would you like to install it? (y/n): ",
+            printer.version(&plan.version)
+        ));
+    }
 
     let mut response = String::new();
     io::stdin().read_line(&mut response).unwrap();
@@ -0,0 +1,448 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::{debug, warn, error};
+use crate::log_target::LOCK;
+use crate::clock::Clock;
+use crate::error::RspawnError;
+use crate::filesystem::Fs;
+use crate::state::{current_exe_name, runtime_dir, with_file_lock};
+use crate::unique::unique_token;
+
+/// How long a lock is held before `LockBehavior::default()` takes it over, if its owner is no longer running.
+pub const DEFAULT_LOCK_STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// How long an empty lock is left alone, in case it is still being written in place
+const EMPTY_LOCK_GRACE: Duration = Duration::from_secs(30);
+
+// How often a waiting instance checks whether the lock was released
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do when another instance of the program is already updating it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockBehavior {
+    /// Return an error right away.
+    FailFast,
+    /// Wait for the other instance to finish, for at most this long, then return an error.
+    WaitUpTo(Duration),
+    /// Take over the lock if it was taken longer ago than this and the process
+    /// that took it is no longer running, assuming it died without releasing
+    /// it; return an error otherwise.
+    ///
+    /// A lock whose owner is still running (e.g. in a long `cargo install`)
+    /// is never taken over, however old it is.
+    Steal(Duration),
+}
+
+impl Default for LockBehavior {
+    /// `Steal(DEFAULT_LOCK_STALE_AFTER)`.
+    fn default() -> Self {
+        LockBehavior::Steal(DEFAULT_LOCK_STALE_AFTER)
+    }
+}
+
+// Holds the update lock, releasing it when dropped
+pub(crate) struct LockGuard {
+    path: PathBuf,
+    token: String,
+    fs: Arc<dyn Fs>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        // Leave the lock alone if another instance stole it from us in the meantime
+        match self.fs.read_to_string(&self.path) {
+            Ok(contents) if lock_token(&contents) != Some(self.token.as_str()) => {
+                warn!(target: LOCK, "Lock file {} was taken over by another instance", self.path.display());
+                return;
+            }
+            _ => {}
+        }
+        if let Err(e) = self.fs.remove_file(&self.path) {
+            let error_msg = format!("Failed to remove lock file: {}", e);
+            eprintln!("{error_msg}");
+            error!(target: LOCK, "{error_msg}");
+        }
+    }
+}
+
+// The lock is per program, so different rspawn-powered programs can update concurrently
+fn lock_path(fs: &dyn Fs) -> Result<PathBuf> {
+    Ok(runtime_dir(fs)?.join(format!("{}.lock", current_exe_name()?)))
+}
+
+// Lock files hold "<pid> <unix time taken> <token>"
+fn lock_contents(token: &str, clock: &dyn Clock) -> String {
+    format!("{} {} {}\n", std::process::id(), clock.unix_now(), token)
+}
+
+fn lock_owner(contents: &str) -> Option<u32> {
+    contents.split_whitespace().next()?.parse().ok()
+}
+
+fn lock_taken_at(contents: &str) -> Option<u64> {
+    contents.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn lock_token(contents: &str) -> Option<&str> {
+    contents.split_whitespace().nth(2)
+}
+
+// Creates the lock file, returning false if it already exists. It is written
+// next to it first and linked into place, so other instances never find it
+// empty; without hard links it is written in place.
+fn try_create(fs: &dyn Fs, path: &Path, contents: &str) -> Result<bool> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!("{}.{}.tmp", file_name, unique_token("lock")));
+    if let Err(e) = fs.create_new(&temp).and_then(|()| fs.write(&temp, contents.as_bytes())) {
+        let _ = fs.remove_file(&temp);
+        return Err(e).with_context(|| format!("Failed to write lock file {}", temp.display()));
+    }
+    let linked = fs.hard_link(&temp, path);
+    if let Err(e) = fs.remove_file(&temp) {
+        warn!(target: LOCK, "Failed to remove {}: {}", temp.display(), e);
+    }
+    match linked {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => {
+            debug!(target: LOCK, "Can't link lock file {} into place ({}), writing it in place", path.display(), e);
+            create_in_place(fs, path, contents)
+        }
+    }
+}
+
+// Creates the lock file and writes it, returning false if it already exists
+fn create_in_place(fs: &dyn Fs, path: &Path, contents: &str) -> Result<bool> {
+    match fs.create_new(path) {
+        Ok(()) => {
+            if let Err(e) = fs.write(path, contents.as_bytes()) {
+                // Don't leave an empty lock behind for others to wait on
+                let _ = fs.remove_file(path);
+                return Err(e).with_context(|| format!("Failed to write lock file {}", path.display()));
+            }
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to create lock file {}", path.display())),
+    }
+}
+
+// Whether a process with this pid is running. A pid that can't name a single
+// process (0, or too large for the platform) is never running.
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 only checks that the process exists and may be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // EPERM: it exists, but belongs to another user
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    // From processthreadsapi.h and handleapi.h
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(access: u32, inherit_handle: i32, pid: u32) -> *mut std::ffi::c_void;
+        fn GetExitCodeProcess(process: *mut std::ffi::c_void, exit_code: *mut u32) -> i32;
+        fn CloseHandle(handle: *mut std::ffi::c_void) -> i32;
+    }
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const STILL_ACTIVE: u32 = 259;
+
+    if pid == 0 {
+        return false;
+    }
+    // SAFETY: the handle is checked before use and closed right after
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            // Access denied means it exists
+            return io::Error::last_os_error().kind() == io::ErrorKind::PermissionDenied;
+        }
+        let mut exit_code = 0;
+        let running = GetExitCodeProcess(process, &mut exit_code) != 0 && exit_code == STILL_ACTIVE;
+        CloseHandle(process);
+        running
+    }
+}
+
+// No way to tell: locks are judged by their age alone
+#[cfg(not(any(unix, windows)))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+// Whether `contents`, read from an existing lock last written at `modified`
+// (unix time), were written longer than `stale_after` ago by a process that
+// is no longer running. An empty lock may still be being written in place, so
+// it is only stale once it has stayed empty for `EMPTY_LOCK_GRACE`.
+fn is_stale_lock(contents: &str, modified: Option<u64>, stale_after: Duration, clock: &dyn Clock) -> bool {
+    if contents.trim().is_empty() {
+        return modified.is_some_and(|modified| clock.unix_now().saturating_sub(modified) >= EMPTY_LOCK_GRACE.as_secs());
+    }
+    if lock_owner(contents).is_some_and(is_running) {
+        return false;
+    }
+    match lock_taken_at(contents) {
+        Some(taken_at) => clock.unix_now().saturating_sub(taken_at) >= stale_after.as_secs(),
+        None => true,
+    }
+}
+
+// Whether the existing lock at `path` is stale, see `is_stale_lock`
+fn is_stale(fs: &dyn Fs, path: &Path, stale_after: Duration, clock: &dyn Clock) -> bool {
+    let modified = fs
+        .modified(path)
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs());
+    fs.read_to_string(path)
+        .is_ok_and(|contents| is_stale_lock(&contents, modified, stale_after, clock))
+}
+
+// Replaces the stale lock at `path` with ours, returning false if it turns out
+// not to be stale (anymore). Instances taking over serialize on a file lock next
+// to it, so one that saw the same stale lock as another finds that one's fresh
+// lock instead of removing it.
+fn take_over(fs: &dyn Fs, path: &Path, contents: &str, stale_after: Duration, clock: &dyn Clock) -> Result<bool> {
+    with_file_lock(fs, path, || {
+        if !is_stale(fs, path, stale_after, clock) {
+            return Ok(false);
+        }
+        warn!(target: LOCK, "Taking over stale lock file {}", path.display());
+        match fs.remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove stale lock file {}", path.display())),
+        }
+        // An instance not taking over may have created it in the meantime
+        try_create(fs, path, contents)
+    })
+}
+
+fn already_locked(path: &Path) -> anyhow::Error {
+    RspawnError::AlreadyRunning { lock_path: path.to_path_buf() }.into()
+}
+
+// Takes the update lock for the running program, as directed by `behavior`
+pub(crate) fn acquire(behavior: LockBehavior, fs: Arc<dyn Fs>, clock: &dyn Clock) -> Result<LockGuard> {
+    let path = lock_path(fs.as_ref())?;
+    // For telling our lock apart from one taken over by another instance
+    let token = unique_token("lock");
+    let contents = lock_contents(&token, clock);
+
+    let acquired = match behavior {
+        LockBehavior::FailFast => try_create(fs.as_ref(), &path, &contents)?,
+        LockBehavior::WaitUpTo(timeout) => {
+            // Counting attempts rather than reading the clock keeps this finite with a `MockClock`
+            let attempts = timeout.as_millis() / LOCK_POLL_INTERVAL.as_millis() + 1;
+            let mut acquired = false;
+            for attempt in 0..attempts {
+                if attempt > 0 {
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                if try_create(fs.as_ref(), &path, &contents)? {
+                    acquired = true;
+                    break;
+                }
+                debug!(target: LOCK, "Waiting for lock file {}", path.display());
+            }
+            acquired
+        }
+        LockBehavior::Steal(stale_after) => {
+            if try_create(fs.as_ref(), &path, &contents)? {
+                true
+            } else if is_stale(fs.as_ref(), &path, stale_after, clock) {
+                take_over(fs.as_ref(), &path, &contents, stale_after, clock)?
+            } else {
+                false
+            }
+        }
+    };
+
+    if !acquired {
+        return Err(already_locked(&path));
+    }
+    debug!(target: LOCK, "Took lock file {}", path.display());
+    Ok(LockGuard { path, token, fs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::filesystem::MemoryFs;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn is_already_running(result: Result<LockGuard>) -> bool {
+        matches!(
+            result.err().and_then(|e| e.downcast::<RspawnError>().ok()),
+            Some(RspawnError::AlreadyRunning { .. })
+        )
+    }
+
+    // No platform hands out pids this large
+    const DEAD_PID: u32 = i32::MAX as u32;
+
+    // A lock left behind by an instance that died
+    fn write_dead_lock(fs: &dyn Fs, taken_at: u64, token: &str) -> PathBuf {
+        let path = lock_path(fs).unwrap();
+        fs.write(&path, format!("{DEAD_PID} {taken_at} {token}\n").as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn lock_is_stolen_only_once_stale() {
+        let fs: Arc<MemoryFs> = Arc::new(MemoryFs::new());
+        let clock = MockClock::from_unix(1_700_000_000);
+        write_dead_lock(fs.as_ref(), clock.unix_now(), "dead-token");
+
+        clock.advance(HOUR - Duration::from_secs(60));
+        assert!(is_already_running(acquire(LockBehavior::Steal(HOUR), fs.clone(), &clock)));
+
+        clock.advance(Duration::from_secs(60));
+        let stolen = acquire(LockBehavior::Steal(HOUR), fs.clone(), &clock).unwrap();
+        let contents = fs.read_to_string(&stolen.path).unwrap();
+        assert_eq!(lock_taken_at(&contents), Some(1_700_000_000 + HOUR.as_secs()));
+    }
+
+    #[test]
+    fn lock_of_a_running_owner_is_never_stolen() {
+        let fs: Arc<MemoryFs> = Arc::new(MemoryFs::new());
+        let clock = MockClock::from_unix(1_700_000_000);
+        let held = acquire(LockBehavior::FailFast, fs.clone(), &clock).unwrap();
+
+        clock.advance(HOUR * 24);
+        assert!(is_running(std::process::id()));
+        assert!(is_already_running(acquire(LockBehavior::Steal(HOUR), fs.clone(), &clock)));
+        let contents = fs.read_to_string(&held.path).unwrap();
+        assert_eq!(lock_token(&contents), Some(held.token.as_str()));
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over_once() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = write_dead_lock(&fs, clock.unix_now(), "dead-token");
+        clock.advance(HOUR);
+
+        // Both saw the stale lock; the second one finds the first one's lock in its place
+        assert!(is_stale(&fs, &path, HOUR, &clock));
+        assert!(take_over(&fs, &path, &lock_contents("first", &clock), HOUR, &clock).unwrap());
+        assert!(!take_over(&fs, &path, &lock_contents("second", &clock), HOUR, &clock).unwrap());
+        assert_eq!(lock_token(&fs.read_to_string(&path).unwrap()), Some("first"));
+    }
+
+    #[test]
+    fn empty_lock_is_stale_only_after_the_grace_period() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = lock_path(&fs).unwrap();
+        fs.write(&path, b"").unwrap();
+        fs.set_modified(&path, clock.now());
+
+        assert!(!is_stale(&fs, &path, HOUR, &clock));
+        clock.advance(EMPTY_LOCK_GRACE);
+        assert!(is_stale(&fs, &path, HOUR, &clock));
+    }
+
+    #[test]
+    fn lock_is_created_filled_in() {
+        let fs = MemoryFs::new();
+        let path = lock_path(&fs).unwrap();
+
+        assert!(try_create(&fs, &path, "1 1700000000 lock-token\n").unwrap());
+        assert_eq!(fs.read_to_string(&path).unwrap(), "1 1700000000 lock-token\n");
+        assert_eq!(fs.files(), std::slice::from_ref(&path));
+        assert!(!try_create(&fs, &path, "2 1700000000 other-token\n").unwrap());
+        assert_eq!(fs.read_to_string(&path).unwrap(), "1 1700000000 lock-token\n");
+    }
+
+    #[test]
+    fn unreadable_lock_is_stale() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = lock_path(&fs).unwrap();
+        fs.write(&path, b"not a lock file").unwrap();
+
+        assert!(is_stale(&fs, &path, HOUR, &clock));
+    }
+
+    #[test]
+    fn lock_from_the_future_is_not_stale() {
+        let fs = MemoryFs::new();
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = lock_path(&fs).unwrap();
+        fs.write(&path, b"1 1700003600 lock-token\n").unwrap();
+
+        assert!(!is_stale(&fs, &path, HOUR, &clock));
+    }
+
+    #[test]
+    fn fail_fast_and_wait_up_to_give_up_while_held() {
+        let fs: Arc<MemoryFs> = Arc::new(MemoryFs::new());
+        let clock = MockClock::from_unix(1_700_000_000);
+        let _held = acquire(LockBehavior::FailFast, fs.clone(), &clock).unwrap();
+
+        assert!(is_already_running(acquire(LockBehavior::FailFast, fs.clone(), &clock)));
+        assert!(is_already_running(acquire(LockBehavior::WaitUpTo(LOCK_POLL_INTERVAL), fs.clone(), &clock)));
+    }
+
+    #[test]
+    fn dropping_the_guard_removes_the_lock() {
+        let fs: Arc<MemoryFs> = Arc::new(MemoryFs::new());
+        let clock = MockClock::from_unix(1_700_000_000);
+        let path = lock_path(fs.as_ref()).unwrap();
+
+        drop(acquire(LockBehavior::FailFast, fs.clone(), &clock).unwrap());
+        assert!(!fs.exists(&path));
+        drop(acquire(LockBehavior::FailFast, fs.clone(), &clock).unwrap());
+        assert!(fs.files().is_empty());
+    }
+
+    #[test]
+    fn dropping_a_stolen_guard_leaves_the_new_lock() {
+        let fs: Arc<MemoryFs> = Arc::new(MemoryFs::new());
+        let clock = MockClock::from_unix(1_700_000_000);
+        let stolen = acquire(LockBehavior::FailFast, fs.clone(), &clock).unwrap();
+        // As if its owner had died
+        write_dead_lock(fs.as_ref(), clock.unix_now(), &stolen.token);
+
+        clock.advance(HOUR);
+        let held = acquire(LockBehavior::Steal(HOUR), fs.clone(), &clock).unwrap();
+        drop(stolen);
+        let contents = fs.read_to_string(&held.path).unwrap();
+        assert_eq!(lock_token(&contents), Some(held.token.as_str()));
+
+        drop(held);
+        assert!(fs.files().is_empty());
+    }
+}
@@ -0,0 +1,29 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Log targets for each phase of an update.
+//!
+//! Every log line of a phase goes to the same target whichever module emits it,
+//! so a host can raise verbosity for just that phase with the usual filters,
+//! e.g. `RUST_LOG=warn,rspawn::install=debug`.
+
+/// Finding out whether there is an update: registry, feeds, channels, holds.
+pub const CHECK: &str = "rspawn::check";
+/// Installing it: cargo, downloads, verification, backups and rollback.
+pub const INSTALL: &str = "rspawn::install";
+/// Starting the new version, now or on the next start.
+pub const RELAUNCH: &str = "rspawn::relaunch";
+/// The update lock shared by every instance of a program.
+pub const LOCK: &str = "rspawn::lock";
+/// The control socket and D-Bus service, which start updates from outside.
+pub const CONTROL: &str = "rspawn::control";
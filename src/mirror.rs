@@ -0,0 +1,209 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Offline updates from a local copy of the registry, for machines without
+//! access to crates.io: a local registry made with `cargo local-registry`, or
+//! a directory of vendored sources made with `cargo vendor`.
+use std::fs;
+use std::path::{self, Path, PathBuf};
+use std::process::Command;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use crate::json;
+use crate::log_target::CHECK;
+use crate::registry::{is_on_channel, RegistryVersion};
+use crate::version::compare_versions;
+
+// Name of the source crates.io is replaced with while installing
+const MIRROR_SOURCE: &str = "rspawn-mirror";
+
+// Whether `mirror` is a local registry, with an index, rather than vendored sources
+fn is_local_registry(mirror: &Path) -> bool {
+    mirror.join("index").is_dir()
+}
+
+// Path of `crate_name`'s file in a registry index, e.g. `se/rd/serde`
+fn index_path(crate_name: &str) -> PathBuf {
+    let name = crate_name.to_lowercase();
+    match name.len() {
+        1 => Path::new("1").join(&name),
+        2 => Path::new("2").join(&name),
+        3 => Path::new("3").join(&name[..1]).join(&name),
+        _ => Path::new(&name[..2]).join(&name[2..4]).join(&name),
+    }
+}
+
+// A line of a local registry's index
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    cksum: Option<String>,
+    #[serde(default)]
+    yanked: bool,
+}
+
+// The `.cargo-checksum.json` of a vendored crate
+#[derive(Deserialize)]
+struct VendoredChecksums {
+    package: Option<String>,
+}
+
+// The versions of `crate_name` in the mirror that aren't yanked, with their .crate checksum
+fn mirror_versions(mirror: &Path, crate_name: &str) -> Result<Vec<RegistryVersion>> {
+    if is_local_registry(mirror) {
+        let path = mirror.join("index").join(index_path(crate_name));
+        let index = fs::read_to_string(&path)
+            .with_context(|| format!("{} is not in the local registry at {}", crate_name, mirror.display()))?;
+        // One JSON object per line, one line per published version
+        return Ok(index
+            .lines()
+            .filter_map(|line| json::from_str::<IndexEntry>(line).ok())
+            .filter(|entry| !entry.yanked)
+            .map(|entry| RegistryVersion { num: entry.vers, checksum: entry.cksum })
+            .collect());
+    }
+
+    // Vendored sources: a `<name>` directory, or `<name>-<version>` ones with `--versioned-dirs`
+    let entries = fs::read_dir(mirror).with_context(|| format!("Failed to read the mirror at {}", mirror.display()))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name == crate_name || name.strip_prefix(crate_name).is_some_and(|rest| rest.starts_with('-'))
+        })
+        .filter_map(|entry| {
+            let manifest = fs::read_to_string(entry.path().join("Cargo.toml")).ok()?;
+            if package_field(&manifest, "name")? != crate_name {
+                return None;
+            }
+            let checksums = fs::read_to_string(entry.path().join(".cargo-checksum.json")).ok();
+            Some(RegistryVersion {
+                num: package_field(&manifest, "version")?,
+                checksum: checksums
+                    .and_then(|checksums| json::from_str::<VendoredChecksums>(&checksums).ok())
+                    .and_then(|checksums| checksums.package),
+            })
+        })
+        .collect())
+}
+
+// A string field of the `[package]` table of a manifest, as `cargo vendor` writes them
+fn package_field(manifest: &str, key: &str) -> Option<String> {
+    let mut in_package = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+        } else if in_package {
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            if name.trim() == key {
+                return Some(value.trim().trim_matches('"').to_string());
+            }
+        }
+    }
+    None
+}
+
+// The highest version of `crate_name` in the mirror, on `channel` if given
+pub(crate) fn latest_version(mirror: &Path, crate_name: &str, channel: Option<&str>) -> Result<RegistryVersion> {
+    let latest = mirror_versions(mirror, crate_name)?
+        .into_iter()
+        .filter(|version| channel.is_none_or(|channel| is_on_channel(&version.num, channel)))
+        .max_by(|a, b| compare_versions(&a.num, &b.num))
+        .with_context(|| format!("No version of {} in the mirror at {}", crate_name, mirror.display()))?;
+    debug!(target: CHECK, "Latest version in the mirror: {}", latest.num);
+    Ok(latest)
+}
+
+// Makes `cargo install` take every crate from the mirror, without network access
+pub(crate) fn apply(command: &mut Command, mirror: &Path) -> Result<()> {
+    // Cargo would resolve a relative path against its own working directory
+    let mirror = path::absolute(mirror).with_context(|| format!("Failed to resolve the mirror path {}", mirror.display()))?;
+    let kind = if is_local_registry(&mirror) { "local-registry" } else { "directory" };
+    let path = toml_string(&mirror.to_string_lossy());
+    command
+        .arg("--offline")
+        .arg("--config")
+        .arg(format!("source.crates-io.replace-with=\"{MIRROR_SOURCE}\""))
+        .arg("--config")
+        .arg(format!("source.{MIRROR_SOURCE}.{kind}={path}"));
+    Ok(())
+}
+
+// The mirror `apply` pointed `command` at, if any
+pub(crate) fn configured_mirror(command: &Command) -> Option<PathBuf> {
+    let prefix = format!("source.{MIRROR_SOURCE}.");
+    let mut args = command.get_args().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg != "--config" {
+            continue;
+        }
+        let value = args.next()?;
+        if let Some((_, path)) = value.strip_prefix(&prefix).and_then(|setting| setting.split_once('=')) {
+            return from_toml_string(path).map(PathBuf::from);
+        }
+    }
+    None
+}
+
+// `value` as a TOML basic string, for `--config`
+fn toml_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The value of a TOML basic string made by `toml_string`
+fn from_toml_string(quoted: &str) -> Option<String> {
+    let mut chars = quoted.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'u' => {
+                let code: String = chars.by_ref().take(4).collect();
+                value.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+            }
+            escaped => value.push(escaped),
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_paths_survive_quoting() {
+        for path in [r"/srv/vendor", r"C:\Users\me\vendor", "/tmp/\"quoted\" dir", "/tmp/tab\there"] {
+            let quoted = toml_string(path);
+            assert_eq!(from_toml_string(&quoted).as_deref(), Some(path), "{quoted}");
+        }
+        assert_eq!(toml_string(r#"C:\a "b""#), r#""C:\\a \"b\"""#);
+    }
+}
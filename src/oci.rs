@@ -0,0 +1,241 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Fetching artifacts pushed to an OCI registry (ORAS-style), from
+//! `oci://registry/repository:tag` URLs.
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Read;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use crate::http::{self, ClientConfig, Response, StatusCode};
+use crate::log_target::INSTALL;
+use crate::url_resolver::UrlResolver;
+
+// Manifest types asked for, an image index first so the platform can be picked
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+// Layer annotation ORAS stores the pushed file's name in
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+// An image index or a manifest: only one of the lists is there
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+// `oci://registry/repository:tag` (or `@sha256:...`), split up
+#[derive(Debug, PartialEq, Eq)]
+struct Reference {
+    registry: String,
+    repository: String,
+    reference: String,
+}
+
+impl Reference {
+    fn parse(url: &str) -> Result<Self> {
+        let location = url.split_once("://").map_or(url, |(_, rest)| rest);
+        let Some((registry, rest)) = location.split_once('/') else {
+            bail!("Expected an oci://registry/repository:tag URL, got {}", url);
+        };
+        let (repository, reference) = match rest.split_once('@') {
+            Some((repository, digest)) => (repository, digest),
+            // A colon in the last path segment starts the tag
+            None => match rest.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')) {
+                Some((repository, tag)) => (repository, tag),
+                None => (rest, "latest"),
+            },
+        };
+        if repository.is_empty() || reference.is_empty() {
+            bail!("Expected an oci://registry/repository:tag URL, got {}", url);
+        }
+        let registry = if registry == "docker.io" { "registry-1.docker.io" } else { registry };
+        Ok(Reference { registry: registry.to_string(), repository: repository.to_string(), reference: reference.to_string() })
+    }
+
+    fn url(&self, kind: &str, reference: &str) -> String {
+        format!("https://{}/v2/{}/{}/{}", self.registry, self.repository, kind, reference)
+    }
+}
+
+// The platform of the running program, in OCI's names
+fn current_platform() -> (&'static str, &'static str) {
+    let os = match env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    let architecture = match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    };
+    (os, architecture)
+}
+
+fn sha256_digest(bytes: &[u8]) -> String {
+    let hex: String = Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256:{}", hex)
+}
+
+// A registry session, with the anonymous pull token once the registry asked for one
+struct Session {
+    reference: Reference,
+    token: Option<String>,
+}
+
+impl Session {
+    fn get(&mut self, url: &str, accept: Option<&str>) -> Result<Response> {
+        let request = |token: Option<&str>| -> Result<Response> {
+            let mut request = http::client(ClientConfig::artifacts())?.get(url);
+            if let Some(accept) = accept {
+                request = request.header("Accept", accept);
+            }
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            request.send().with_context(|| format!("Failed to fetch {}", url))
+        };
+        let mut response = request(self.token.as_deref())?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+            let challenge = response.header("www-authenticate").unwrap_or_default().to_string();
+            self.token = Some(pull_token(&challenge, &self.reference)?);
+            response = request(self.token.as_deref())?;
+        }
+        let status = response.status();
+        if !status.is_success() {
+            bail!("Failed to fetch {}: HTTP {}", url, status);
+        }
+        Ok(response)
+    }
+
+    fn get_bytes(&mut self, url: &str, accept: Option<&str>) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.get(url, accept)?.read_to_end(&mut body).with_context(|| format!("Failed to read {}", url))?;
+        Ok(body)
+    }
+
+    fn manifest(&mut self, reference: &str) -> Result<(Manifest, String)> {
+        let url = self.reference.url("manifests", reference);
+        let body = self.get_bytes(&url, Some(MANIFEST_TYPES))?;
+        let digest = sha256_digest(&body);
+        if reference.starts_with("sha256:") && reference != digest {
+            bail!("The manifest at {} has digest {}, expected {}", url, digest, reference);
+        }
+        let manifest = serde_json::from_slice::<Manifest>(&body).with_context(|| format!("Failed to parse the manifest at {}", url))?;
+        Ok((manifest, digest))
+    }
+}
+
+// A token for pulling, from the `Bearer realm=...,service=...,scope=...` challenge
+fn pull_token(challenge: &str, reference: &Reference) -> Result<String> {
+    let Some(params) = challenge.strip_prefix("Bearer ") else {
+        bail!("{} needs credentials rspawn can't provide ({})", reference.registry, challenge);
+    };
+    let params: BTreeMap<&str, &str> = params
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .map(|(key, value)| (key, value.trim_matches('"')))
+        .collect();
+    let realm = params.get("realm").context("The registry's token challenge has no realm")?;
+    let scope = format!("repository:{}:pull", reference.repository);
+    let mut url = format!("{}?scope={}", realm, params.get("scope").copied().unwrap_or(&scope));
+    if let Some(service) = params.get("service") {
+        url.push_str(&format!("&service={}", service));
+    }
+
+    #[derive(Deserialize)]
+    struct Token {
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        access_token: Option<String>,
+    }
+    let response = http::client(ClientConfig::default())?.get(&url).send().with_context(|| format!("Failed to fetch {}", url))?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("Failed to get a pull token from {}: HTTP {}", realm, status);
+    }
+    let token = serde_json::from_str::<Token>(&response.text()?).context("Failed to parse the registry's pull token")?;
+    token.token.or(token.access_token).context("The registry sent no pull token")
+}
+
+// Serves `oci://` URLs: the tag is resolved to a manifest digest, the
+// manifest for this platform is picked from an index, and its layer is
+// downloaded and checked against its digest.
+pub(crate) struct OciResolver;
+
+impl UrlResolver for OciResolver {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let reference = Reference::parse(url)?;
+        let tag = reference.reference.clone();
+        let mut session = Session { reference, token: None };
+        let (os, architecture) = current_platform();
+
+        let (mut manifest, mut digest) = session.manifest(&tag)?;
+        info!(target: INSTALL, "{} resolved to {}", url, digest);
+        if !manifest.manifests.is_empty() {
+            let for_platform = manifest
+                .manifests
+                .iter()
+                .find(|entry| entry.platform.as_ref().is_some_and(|platform| platform.os == os && platform.architecture == architecture))
+                .with_context(|| format!("{} has no artifact for {}/{}", url, os, architecture))?;
+            let platform_digest = for_platform.digest.clone();
+            (manifest, digest) = session.manifest(&platform_digest)?;
+            debug!(target: INSTALL, "Using the {}/{} manifest {}", os, architecture, digest);
+        }
+
+        // ORAS pushes one layer per file, named after it
+        let layer = match &manifest.layers[..] {
+            [] => bail!("{} has no layers", url),
+            [layer] => layer,
+            layers => layers
+                .iter()
+                .find(|layer| {
+                    layer.annotations.get(TITLE_ANNOTATION).is_some_and(|title| title.contains(os) && title.contains(architecture))
+                })
+                .with_context(|| format!("{} has several layers and none is named for {}/{}", url, os, architecture))?,
+        };
+        let blob_url = session.reference.url("blobs", &layer.digest);
+        let blob = session.get_bytes(&blob_url, None)?;
+        let actual = sha256_digest(&blob);
+        if actual != layer.digest {
+            bail!("The layer of {} has digest {}, expected {}", url, actual, layer.digest);
+        }
+        Ok(blob)
+    }
+}
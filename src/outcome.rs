@@ -0,0 +1,66 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! How an update run ended, returned by `relaunch_program`.
+use std::fmt;
+use crate::diagnose::Gate;
+
+/// How a run of `relaunch_program` ended, for the host program to react to.
+///
+/// Errors aren't outcomes: they come back as `Err`, as before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UpdateOutcome {
+    /// Already running the latest version.
+    UpToDate,
+    /// A newer version is out, but only checking for updates.
+    Available {
+        /// The newer version.
+        version: String,
+    },
+    /// The run stopped at this gate without checking or installing, e.g.
+    /// `Gate::Enabled` when updates are disabled, `Gate::ExecutedFromPath` when
+    /// not started from `PATH`, or `Gate::Backoff` after repeated failures.
+    Skipped(Gate),
+    /// The user (or the approval endpoint) didn't want the update.
+    Declined,
+    /// The new version is installed, and runs from the next start (or, for
+    /// `RSpawn::target_binary`, the next time the worker is started).
+    Updated {
+        /// The version that was running.
+        from: String,
+        /// The version installed.
+        to: String,
+    },
+    /// The new version was installed and started. This process keeps running
+    /// until the host program exits, once it has flushed its logs and
+    /// cleaned up.
+    Relaunched {
+        /// The new process, if one was started (not when simulating updates).
+        pid: Option<u32>,
+    },
+}
+
+impl fmt::Display for UpdateOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateOutcome::UpToDate => write!(f, "up to date"),
+            UpdateOutcome::Available { version } => write!(f, "{version} is available"),
+            UpdateOutcome::Skipped(gate) => write!(f, "skipped ({gate})"),
+            UpdateOutcome::Declined => write!(f, "declined"),
+            UpdateOutcome::Updated { from, to } => write!(f, "updated from {from} to {to}"),
+            UpdateOutcome::Relaunched { pid: Some(pid) } => write!(f, "relaunched as process {pid}"),
+            UpdateOutcome::Relaunched { pid: None } => write!(f, "relaunched"),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Experimental peer-assisted downloads: instances on the same LAN serve the
+//! artifacts they already have to each other, so a fleet updating at once
+//! fetches each artifact from the internet only a few times.
+//!
+//! Peers are found by a UDP multicast query, and artifacts are served over
+//! plain HTTP by their SHA-256, in byte ranges. Only artifacts whose hash is
+//! known up front (assets) are shared, and what a peer sends is checked
+//! against that hash like any other download.
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use crate::error::RspawnError;
+use crate::download::{self, DownloadSettings};
+use crate::events::Events;
+use crate::gossip;
+use crate::http::{self, ClientConfig};
+use crate::log_target::INSTALL;
+use crate::state::state_dir;
+
+/// Port peers serve artifacts and answer discovery queries on (TCP and UDP).
+pub const PEER_PORT: u16 = 7649;
+// Multicast group discovery queries are sent to
+pub(crate) const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 73, 80);
+const DISCOVERY_QUERY: &[u8] = b"rspawn-peers?";
+const DISCOVERY_REPLY: &str = "rspawn-peer";
+// How long answers to a discovery query are waited for
+pub(crate) const DISCOVERY_WAIT: Duration = Duration::from_millis(300);
+// How often the serving threads check whether they should stop
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// Most bytes read of a request (request line and headers), so a peer can't make us buffer without end
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+// Most peers served at once; further connections are closed right away
+const MAX_CONNECTIONS: usize = 16;
+// Artifacts kept for peers are removed once they haven't changed for this long
+const PEER_CACHE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Artifacts kept for peers, named by their SHA-256
+fn peer_cache_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("peer-cache"))
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// Keeps `contents` for peers asking for `sha256`
+pub(crate) fn keep(sha256: &str, contents: &[u8]) {
+    let sha256 = sha256.trim().to_lowercase();
+    if !is_sha256(&sha256) {
+        return;
+    }
+    let result = peer_cache_dir().and_then(|dir| {
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(&sha256);
+        fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+        evict_stale(&dir, SystemTime::now());
+        Ok(())
+    });
+    if let Err(e) = result {
+        debug!(target: INSTALL, "Not keeping {} for peers: {:#}", sha256, e);
+    }
+}
+
+// Removes the artifacts in `dir` last written more than `PEER_CACHE_MAX_AGE` before `now`
+fn evict_stale(dir: &Path, now: SystemTime) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let modified = entry.metadata().and_then(|metadata| metadata.modified());
+        let stale = modified.is_ok_and(|modified| now.duration_since(modified).is_ok_and(|age| age > PEER_CACHE_MAX_AGE));
+        if stale {
+            debug!(target: INSTALL, "Evicting {} from the peer cache", entry.path().display());
+            if let Err(e) = fs::remove_file(entry.path()) {
+                debug!(target: INSTALL, "Failed to evict {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+// Counts a connection being served, until dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    // Takes one of `MAX_CONNECTIONS` slots, `None` if all are in use
+    fn take(active: &Arc<AtomicUsize>) -> Option<ConnectionSlot> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < MAX_CONNECTIONS).then_some(count + 1))
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(active)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// The peers answering a discovery query on the LAN
+fn discover() -> io::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    socket.send_to(DISCOVERY_QUERY, (DISCOVERY_GROUP, PEER_PORT))?;
+
+    let mut peers = Vec::new();
+    let mut buf = [0u8; 64];
+    let deadline = Instant::now() + DISCOVERY_WAIT;
+    while Instant::now() < deadline {
+        let Ok((read, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let reply = String::from_utf8_lossy(&buf[..read]);
+        let port = reply.strip_prefix(DISCOVERY_REPLY).and_then(|port| port.trim().parse::<u16>().ok());
+        if let Some(peer) = port.map(|port| SocketAddr::new(from.ip(), port)).filter(|peer| !peers.contains(peer)) {
+            peers.push(peer);
+        }
+    }
+    Ok(peers)
+}
+
+// Fetches the artifact `url`, whose hash is `sha256`, from the peers that have
+// it. `None` when no peer does or their copies don't add up, so it's
+// downloaded from `url` instead.
+pub(crate) fn fetch(url: &str, sha256: &str, settings: &DownloadSettings, events: &mut Events) -> Option<Vec<u8>> {
+    let sha256 = sha256.trim().to_lowercase();
+    if !is_sha256(&sha256) {
+        return None;
+    }
+    let peers = match discover() {
+        Ok(peers) => peers,
+        Err(e) => {
+            debug!(target: INSTALL, "Peer discovery failed: {}", e);
+            return None;
+        }
+    };
+    let client = http::client(ClientConfig::artifacts()).ok()?;
+    let mut total_bytes = None;
+    let sources: Vec<String> = peers
+        .iter()
+        .map(|peer| format!("http://{}/artifacts/{}", peer, sha256))
+        .filter(|source| {
+            let Some(length) = client.head(source).send().ok().filter(|response| response.status().is_success()).and_then(|response| response.content_length()) else {
+                return false;
+            };
+            *total_bytes.get_or_insert(length) == length
+        })
+        .collect();
+    let total_bytes = total_bytes?;
+    if sources.is_empty() {
+        return None;
+    }
+    info!(target: INSTALL, "Fetching {} from {} peer(s)", url, sources.len());
+
+    let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    let contents = match download::download_shared(url, &sources, total_bytes, settings, events) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!(target: INSTALL, "Fetching {} from peers failed, downloading it: {:#}", url, e);
+            return None;
+        }
+    };
+    let actual: String = Sha256::digest(&contents).iter().map(|byte| format!("{:02x}", byte)).collect();
+    if actual != sha256 {
+        warn!(target: INSTALL, "Peers sent {} with hash {}, expected {}: downloading it", url, actual, sha256);
+        return None;
+    }
+    Some(contents)
+}
+
+/// Serves the artifacts this program downloaded to its LAN peers, until dropped.
+///
+/// Returned by `share_with_peers()`.
+#[derive(Debug)]
+pub struct PeerServer {
+    stop: Arc<AtomicBool>,
+    address: SocketAddr,
+}
+
+impl PeerServer {
+    /// The address artifacts are served on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for PeerServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Starts serving the artifacts kept from updates with
+/// `RSpawn::peer_downloads` to peers on the LAN, on `PEER_PORT`, and
+/// answering their `RSpawn::lan_gossip` version queries.
+///
+/// Experimental. Artifacts are served unauthenticated over plain HTTP, which
+/// is fine for their integrity (peers check the hash) but means anyone on the
+/// network can read them. At most 16 peers are served at once, and artifacts
+/// kept for longer than a week are removed. Serving stops when the returned
+/// `PeerServer` is dropped.
+pub fn share_with_peers() -> Result<PeerServer, RspawnError> {
+    let dir = peer_cache_dir()?;
+    evict_stale(&dir, SystemTime::now());
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, PEER_PORT)).with_context(|| format!("Failed to listen on port {}", PEER_PORT))?;
+    listener.set_nonblocking(true).context("Failed to set up the peer listener")?;
+    let address = listener.local_addr().context("Failed to set up the peer listener")?;
+    let discovery = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PEER_PORT)).with_context(|| format!("Failed to bind UDP port {}", PEER_PORT))?;
+    discovery.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED).context("Failed to join the peer discovery group")?;
+    discovery.set_read_timeout(Some(POLL_INTERVAL)).context("Failed to set up peer discovery")?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while !stopped.load(Ordering::Relaxed) {
+            if let Ok((read, from)) = discovery.recv_from(&mut buf) {
+                let reply = match &buf[..read] {
+                    query if query == DISCOVERY_QUERY => Some(format!("{} {}", DISCOVERY_REPLY, PEER_PORT)),
+                    query => gossip::answer(&String::from_utf8_lossy(query)),
+                };
+                if let Some(reply) = reply {
+                    let _ = discovery.send_to(reply.as_bytes(), from);
+                }
+            }
+        }
+    });
+    let stopped = Arc::clone(&stop);
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, from)) => {
+                    // Dropping the connection makes the peer fetch from another source
+                    let Some(slot) = ConnectionSlot::take(&active) else {
+                        debug!(target: INSTALL, "Already serving {} peers, turning {} away", MAX_CONNECTIONS, from);
+                        continue;
+                    };
+                    let dir = dir.clone();
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        if let Err(e) = serve(stream, &dir) {
+                            debug!(target: INSTALL, "Serving peer {} failed: {}", from, e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => debug!(target: INSTALL, "Accepting a peer failed: {}", e),
+            }
+        }
+    });
+    info!(target: INSTALL, "Sharing artifacts with peers on {}", address);
+    Ok(PeerServer { stop, address })
+}
+
+// `bytes=start-end`, inclusive
+fn parse_range(value: &str) -> Option<(u64, u64)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+// Answers one `GET` or `HEAD` for `/artifacts/<sha256>`
+fn serve(mut stream: TcpStream, dir: &Path) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut range = None;
+    let mut complete = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            complete = true;
+            break;
+        }
+        if let Some((_, value)) = line.split_once(':').filter(|(name, _)| name.trim().eq_ignore_ascii_case("range")) {
+            range = parse_range(value.trim());
+        }
+    }
+    // Cut short by the peer or by `MAX_REQUEST_BYTES`
+    if !complete {
+        return stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let file = path
+        .strip_prefix("/artifacts/")
+        .filter(|hash| is_sha256(hash))
+        .filter(|_| method == "GET" || method == "HEAD")
+        .and_then(|hash| File::open(dir.join(hash.to_lowercase())).ok());
+    let Some(mut file) = file else {
+        return stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    };
+    let file_len = file.metadata()?.len();
+    let (status, start, len) = match range {
+        Some((start, end)) if start <= end && start < file_len => ("206 Partial Content", start, end.min(file_len - 1) - start + 1),
+        _ => ("200 OK", 0, file_len),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n", status, len)?;
+    if method == "GET" {
+        // Streamed, so serving a large artifact doesn't hold all of it in memory
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut file.take(len), &mut stream)?;
+    }
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    // A directory of its own for each test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rspawn-p2p-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // What `serve` answers `request` with, serving `dir`
+    fn respond(dir: &Path, request: &[u8]) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let dir = dir.to_path_buf();
+        let server = thread::spawn(move || serve(stream, &dir));
+        client.write_all(request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap().unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_the_requested_range() {
+        let dir = scratch_dir("range");
+        fs::write(dir.join(HASH), b"0123456789").unwrap();
+
+        let request = format!("GET /artifacts/{HASH} HTTP/1.1\r\nRange: bytes=2-5\r\n\r\n");
+        let response = respond(&dir, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{response}");
+        assert!(response.contains("Content-Length: 4\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n2345"), "{response}");
+
+        let request = format!("GET /artifacts/{HASH} HTTP/1.1\r\nRange: bytes=8-100\r\n\r\n");
+        assert!(respond(&dir, request.as_bytes()).ends_with("\r\n\r\n89"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_request_is_rejected() {
+        let dir = scratch_dir("oversized");
+        fs::write(dir.join(HASH), b"0123456789").unwrap();
+
+        let mut request = format!("GET /artifacts/{HASH} HTTP/1.1\r\nX-Padding: ").into_bytes();
+        request.resize(MAX_REQUEST_BYTES as usize, b'a');
+        assert!(respond(&dir, &request).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn connections_are_bounded() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut slots: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::take(&active).unwrap()).collect();
+        assert!(ConnectionSlot::take(&active).is_none());
+
+        slots.pop();
+        assert!(ConnectionSlot::take(&active).is_some());
+    }
+
+    #[test]
+    fn stale_artifacts_are_evicted() {
+        let dir = scratch_dir("evict");
+        let (stale, fresh) = (dir.join(HASH), dir.join("8".repeat(64)));
+        fs::write(&stale, b"old").unwrap();
+        fs::write(&fresh, b"new").unwrap();
+        let now = SystemTime::now();
+        File::options().write(true).open(&stale).unwrap().set_modified(now - PEER_CACHE_MAX_AGE * 2).unwrap();
+
+        evict_stale(&dir, now);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,86 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Telling the user how to put the install directory on their PATH.
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use crate::install;
+
+/// The shell a PATH hint is written for, from `$SHELL` (PowerShell on Windows).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Shell {
+    /// A POSIX shell, also used when `$SHELL` is unknown.
+    Sh,
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl Shell {
+    // The user's login shell
+    pub(crate) fn detect() -> Self {
+        if cfg!(windows) {
+            return Shell::PowerShell;
+        }
+        let shell = env::var_os("SHELL").map(PathBuf::from);
+        match shell.as_deref().and_then(Path::file_name).and_then(|name| name.to_str()) {
+            Some("bash") => Shell::Bash,
+            Some("zsh") => Shell::Zsh,
+            Some("fish") => Shell::Fish,
+            Some("pwsh") | Some("powershell") => Shell::PowerShell,
+            _ => Shell::Sh,
+        }
+    }
+
+    // The line adding `dir` in front of PATH, for the shell's profile
+    pub(crate) fn export_line(self, dir: &Path) -> String {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => format!("export PATH=\"{}:$PATH\"", dir.display()),
+            Shell::Fish => format!("fish_add_path \"{}\"", dir.display()),
+            Shell::PowerShell => format!("$env:Path = \"{};\" + $env:Path", dir.display()),
+        }
+    }
+
+    // Where the export line goes so new sessions pick it up
+    pub(crate) fn profile(self) -> &'static str {
+        match self {
+            Shell::Sh => "~/.profile",
+            Shell::Bash => "~/.bashrc",
+            Shell::Zsh => "~/.zshrc",
+            Shell::Fish => "~/.config/fish/config.fish",
+            Shell::PowerShell => "$PROFILE",
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+            Shell::PowerShell => "PowerShell",
+        })
+    }
+}
+
+// Whether `dir` is one of the directories in PATH
+pub(crate) fn in_path(dir: &Path) -> bool {
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|entry| install::same_path(&entry, dir)))
+}
@@ -0,0 +1,234 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Keeping the cargo-installed plugins of a host program up to date.
+//!
+//! ```ignore
+//! let plugins = [
+//!     rspawn::Plugin::new("mytool-git").requirement(rspawn::VersionReq::parse("^2")?),
+//!     rspawn::Plugin::new("mytool-s3").features(vec!["rustls".to_string()]),
+//! ];
+//! for report in rspawn::update_plugins(&plugins, |updates| ask_user(updates))? {
+//!     println!("{}: {}", report.name, report.outcome);
+//! }
+//! ```
+use std::fmt;
+use std::sync::Arc;
+use anyhow::Result;
+use serde::Serialize;
+use log::{info, warn};
+use crate::error::RspawnError;
+use crate::log_target::INSTALL;
+use crate::clock::SystemClock;
+use crate::credentials::{CredentialStore, EnvCredentialStore, REGISTRY_TOKEN};
+use crate::filesystem::RealFs;
+use crate::hold;
+use crate::install::{self, cargo_bin_dir};
+use crate::lock::{self, LockBehavior};
+use crate::registry::{self, is_outdated};
+use crate::target::{self, TargetBinary};
+use crate::version::VersionReq;
+
+/// A plugin crate of the host program, installed with `cargo install`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plugin {
+    /// The plugin's crate, which installs a binary of the same name.
+    pub name: String,
+    /// Versions of the plugin the host works with; any if `None`.
+    pub requirement: Option<VersionReq>,
+    /// Features to install the plugin with.
+    pub features: Vec<String>,
+}
+
+impl Plugin {
+    /// A plugin accepting any version, installed with its default features.
+    pub fn new(name: &str) -> Self {
+        Plugin {
+            name: name.to_string(),
+            requirement: None,
+            features: Vec::new(),
+        }
+    }
+
+    /// Only installs versions matching `requirement`.
+    pub fn requirement(mut self, requirement: VersionReq) -> Self {
+        self.requirement = Some(requirement);
+        self
+    }
+
+    /// Installs the plugin with `features`.
+    pub fn features(mut self, features: Vec<String>) -> Self {
+        self.features = features;
+        self
+    }
+}
+
+/// A plugin with a version to install, as shown to the confirmation function.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PluginUpdate {
+    /// The plugin's crate.
+    pub name: String,
+    /// The installed version, if the plugin is installed.
+    pub current_version: Option<String>,
+    /// The version to install.
+    pub version: String,
+}
+
+impl fmt::Display for PluginUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.current_version {
+            Some(current_version) => write!(f, "{} {} -> {}", self.name, current_version, self.version),
+            None => write!(f, "{} {} (new)", self.name, self.version),
+        }
+    }
+}
+
+/// What `update_plugins()` did with a plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PluginOutcome {
+    /// The installed version is the one to run.
+    UpToDate { version: String },
+    /// The plugin was installed or updated.
+    Installed { from: Option<String>, to: String },
+    /// An update was available, but not confirmed.
+    Declined { version: String },
+    /// No published version matches the plugin's requirement.
+    NoMatchingVersion,
+    /// Checking or installing the plugin failed.
+    Failed { message: String },
+}
+
+impl fmt::Display for PluginOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginOutcome::UpToDate { version } => write!(f, "up to date ({version})"),
+            PluginOutcome::Installed { from: Some(from), to } => write!(f, "updated {from} -> {to}"),
+            PluginOutcome::Installed { from: None, to } => write!(f, "installed {to}"),
+            PluginOutcome::Declined { version } => write!(f, "{version} available, not installed"),
+            PluginOutcome::NoMatchingVersion => write!(f, "no published version matches"),
+            PluginOutcome::Failed { message } => write!(f, "failed: {message}"),
+        }
+    }
+}
+
+/// What happened to one plugin, see `update_plugins()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginReport {
+    /// The plugin's crate.
+    pub name: String,
+    /// What happened to it.
+    pub outcome: PluginOutcome,
+}
+
+// Looks up the version `plugin` should run, if it differs from the installed one
+fn check_plugin(plugin: &Plugin, token: Option<&str>) -> Result<Result<PluginUpdate, PluginOutcome>> {
+    let target = TargetBinary::new(plugin.name.clone().into())?;
+    let current_version = target.installed_version(target.path(cargo_bin_dir()).as_deref(), &RealFs)?;
+
+    let latest = match &plugin.requirement {
+        Some(requirement) => registry::get_matching_version_from_crates_io(&plugin.name, token, requirement)?,
+        None => Some(registry::get_latest_version_from_crates_io(&plugin.name, token, None)?),
+    };
+    let Some(latest) = latest else {
+        return Ok(Err(PluginOutcome::NoMatchingVersion));
+    };
+    match current_version {
+        Some(current_version) if !is_outdated(&current_version, &latest.num) => {
+            Ok(Err(PluginOutcome::UpToDate { version: current_version }))
+        }
+        current_version => Ok(Ok(PluginUpdate {
+            name: plugin.name.clone(),
+            current_version,
+            version: latest.num,
+        })),
+    }
+}
+
+/// Checks every plugin, asks once whether to install the available updates,
+/// and installs them, reporting what happened to each plugin in order.
+///
+/// `confirm` gets the plugins to install, and is not called if there are none.
+/// A plugin failing to check or install doesn't stop the others; the whole run
+/// only fails when updates are held (see `HeldByOperator`) or another instance
+/// is updating. The registry token, if any, is read from `RSPAWN_REGISTRY_TOKEN`.
+pub fn update_plugins<C>(plugins: &[Plugin], confirm: C) -> Result<Vec<PluginReport>, RspawnError>
+where
+    C: FnOnce(&[PluginUpdate]) -> bool,
+{
+    update_plugins_with(plugins, &EnvCredentialStore, confirm)
+}
+
+/// Same as `update_plugins`, with the registry token read from `credentials`.
+pub fn update_plugins_with<C>(plugins: &[Plugin], credentials: &dyn CredentialStore, confirm: C) -> Result<Vec<PluginReport>, RspawnError>
+where
+    C: FnOnce(&[PluginUpdate]) -> bool,
+{
+    hold::check_hold(&SystemClock, &RealFs)?;
+    let _lock_guard = lock::acquire(LockBehavior::default(), Arc::new(RealFs), &SystemClock)?;
+    let token = credentials.token(REGISTRY_TOKEN)?;
+
+    let outcomes: Vec<Result<PluginUpdate, PluginOutcome>> = plugins
+        .iter()
+        .map(|plugin| {
+            check_plugin(plugin, token.as_deref()).unwrap_or_else(|e| {
+                warn!(target: INSTALL, "Failed to check plugin {}: {:#}", plugin.name, e);
+                Err(PluginOutcome::Failed { message: format!("{:#}", e) })
+            })
+        })
+        .collect();
+
+    let updates: Vec<PluginUpdate> = outcomes.iter().filter_map(|outcome| outcome.as_ref().ok().cloned()).collect();
+    let confirmed = !updates.is_empty() && confirm(&updates);
+
+    let reports = plugins
+        .iter()
+        .zip(outcomes)
+        .map(|(plugin, outcome)| {
+            let outcome = match outcome {
+                Err(outcome) => outcome,
+                Ok(update) if !confirmed => PluginOutcome::Declined { version: update.version },
+                Ok(update) => install_plugin(plugin, update),
+            };
+            PluginReport {
+                name: plugin.name.clone(),
+                outcome,
+            }
+        })
+        .collect();
+    Ok(reports)
+}
+
+// Installs `update`, recording the new version like `RSpawn::target_binary` does
+fn install_plugin(plugin: &Plugin, update: PluginUpdate) -> PluginOutcome {
+    info!(target: INSTALL, "Installing plugin {}", update);
+    let command = install::cargo_install_command(&plugin.name, Some(&update.version), &plugin.features);
+    if let Err(e) = install::run_install_with(command, true, &mut |_| {}, None) {
+        warn!(target: INSTALL, "Failed to install plugin {}: {}", plugin.name, e);
+        return PluginOutcome::Failed { message: e.to_string() };
+    }
+
+    let installed_path = TargetBinary::new(plugin.name.clone().into())
+        .ok()
+        .and_then(|target| target.path(cargo_bin_dir()))
+        .filter(|path| path.is_file());
+    if let Some(installed_path) = installed_path {
+        if let Err(e) = target::record_install(&plugin.name, &installed_path, &update.version, &SystemClock, &RealFs) {
+            warn!(target: INSTALL, "Failed to record the installed plugin: {:#}", e);
+        }
+    }
+    PluginOutcome::Installed {
+        from: update.current_version,
+        to: update.version,
+    }
+}
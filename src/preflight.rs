@@ -0,0 +1,133 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Pre-flight checks that `cargo install` can work in this environment at all.
+//!
+//! Containers and locked-down CI images often have no home directory, or a
+//! read-only `$CARGO_HOME`; cargo would then fail only after the user agreed to
+//! update. Checking first turns that into an `EnvironmentUnsupported` error, or
+//! moves the install to a fallback root set with `RSpawn::fallback_install_root`.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use log::{debug, info};
+use crate::log_target::INSTALL;
+use crate::filesystem::Fs;
+use crate::install::{cargo_bin_dir, cargo_home};
+use crate::unique::unique_token;
+
+/// Error returned when `cargo install` can't work in this environment.
+///
+/// `relaunch_program` returns it as `RspawnError::Environment`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EnvironmentUnsupported {
+    /// Neither `CARGO_HOME` nor a home directory is set.
+    NoHome,
+    /// A directory cargo needs to write to can't be written to.
+    ReadOnly {
+        /// The directory.
+        path: PathBuf,
+        /// Why writing to it failed.
+        reason: String,
+    },
+}
+
+impl fmt::Display for EnvironmentUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvironmentUnsupported::NoHome => {
+                write!(f, "Cannot install updates: neither CARGO_HOME nor a home directory is set")
+            }
+            EnvironmentUnsupported::ReadOnly { path, reason } => {
+                write!(f, "Cannot install updates: {} is not writable ({})", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvironmentUnsupported {}
+
+// Where the update gets installed when the default location can't be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FallbackRoot {
+    root: PathBuf,
+    // Whether cargo's home is unusable too, so its registry cache has to move
+    move_cargo_home: bool,
+}
+
+impl FallbackRoot {
+    // Directory the new binary ends up in
+    pub(crate) fn bin_dir(&self) -> PathBuf {
+        self.root.join("bin")
+    }
+
+    // Points `cargo install` at the fallback root
+    pub(crate) fn apply(&self, command: &mut Command) {
+        command.arg("--root").arg(&self.root);
+        if self.move_cargo_home {
+            command.env("CARGO_HOME", self.root.join("cargo-home"));
+        }
+    }
+}
+
+// Fails unless a file can be created in `dir`, which is created if missing
+pub(crate) fn check_writable(dir: &Path, fs: &dyn Fs) -> Result<(), EnvironmentUnsupported> {
+    let read_only = |e: io::Error| EnvironmentUnsupported::ReadOnly {
+        path: dir.to_path_buf(),
+        reason: e.to_string(),
+    };
+    fs.create_dir_all(dir).map_err(read_only)?;
+    let probe = dir.join(format!(".{}", unique_token("rspawn-probe")));
+    fs.create_new(&probe).map_err(read_only)?;
+    if let Err(e) = fs.remove_file(&probe) {
+        debug!(target: INSTALL, "Failed to remove write probe {}: {}", probe.display(), e);
+    }
+    Ok(())
+}
+
+// Checks that cargo can write its registry cache and the new binary. Returns the
+// fallback root to install into if it can't but `fallback` is usable, `None` if
+// the default location is fine.
+pub(crate) fn check_install_environment(
+    fallback: Option<&Path>,
+    fs: &dyn Fs,
+) -> Result<Option<FallbackRoot>, EnvironmentUnsupported> {
+    let home_check = cargo_home().ok_or(EnvironmentUnsupported::NoHome).and_then(|home| check_writable(&home, fs));
+    let home_usable = home_check.is_ok();
+    let problem = match home_check {
+        Ok(()) => match cargo_bin_dir() {
+            Some(bin_dir) => check_writable(&bin_dir, fs).err(),
+            None => Some(EnvironmentUnsupported::NoHome),
+        },
+        Err(problem) => Some(problem),
+    };
+    let Some(problem) = problem else {
+        return Ok(None);
+    };
+
+    let Some(root) = fallback else {
+        return Err(problem);
+    };
+    let fallback = FallbackRoot {
+        root: root.to_path_buf(),
+        move_cargo_home: !home_usable,
+    };
+    check_writable(&fallback.bin_dir(), fs)?;
+    if fallback.move_cargo_home {
+        check_writable(&root.join("cargo-home"), fs)?;
+    }
+    info!(target: INSTALL, "{}; installing into {} instead", problem, root.display());
+    Ok(Some(fallback))
+}
@@ -0,0 +1,269 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::ffi::OsString;
+#[cfg(feature = "tokio")]
+use std::future::{self, Future};
+use std::io;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::install::{self, InstallError};
+
+/// A `cargo install` run by `ProcessRunner::install_async`.
+#[cfg(feature = "tokio")]
+pub type InstallFuture<'a> = Pin<Box<dyn Future<Output = Result<(), InstallError>> + Send + 'a>>;
+
+/// Runs the processes rspawn starts: `cargo install`, the relaunch of the
+/// updated program, and tools it checks releases with, like `cosign`.
+///
+/// `SystemRunner` is used unless another one is set with `RSpawn::process_runner`;
+/// `RecordingRunner` runs nothing and records what would have been run.
+pub trait ProcessRunner: Send + Sync {
+    /// Runs `command` (a `cargo install`) to completion.
+    ///
+    /// Each line it prints is passed to `on_line`: its status and diagnostics on
+    /// stderr, echoed to ours if `echo`, and its JSON messages on stdout.
+    fn install(&self, command: Command, echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError>;
+
+    /// Like `install`, also calling `on_beat` every `interval` while it runs,
+    /// whether cargo prints anything or not.
+    ///
+    /// Defaults to `install`, without heartbeats.
+    fn install_with_heartbeat(
+        &self,
+        command: Command,
+        echo: bool,
+        on_line: &mut dyn FnMut(&str),
+        interval: Duration,
+        on_beat: &mut dyn FnMut(),
+    ) -> Result<(), InstallError> {
+        let _ = (interval, on_beat);
+        self.install(command, echo, on_line)
+    }
+
+    /// `install_with_heartbeat`, or `install` without a `heartbeat`, as a future
+    /// for `RSpawn::relaunch_async`.
+    ///
+    /// Defaults to running `install_with_heartbeat` or `install` right away, which
+    /// suits runners that don't start real processes; `SystemRunner` runs cargo
+    /// through `tokio::process` instead.
+    #[cfg(feature = "tokio")]
+    fn install_async<'a>(
+        &'a self,
+        command: Command,
+        echo: bool,
+        on_line: &'a mut (dyn FnMut(&str) + Send),
+        heartbeat: Option<(Duration, &'a mut (dyn FnMut() + Send))>,
+    ) -> InstallFuture<'a> {
+        let result = match heartbeat {
+            Some((interval, on_beat)) => self.install_with_heartbeat(command, echo, on_line, interval, on_beat),
+            None => self.install(command, echo, on_line),
+        };
+        Box::pin(future::ready(result))
+    }
+
+    /// Starts `command` (the relaunched program) without waiting for it.
+    fn spawn(&self, command: Command) -> io::Result<()>;
+
+    /// Runs `command` (a tool such as `cosign`) to completion, returning what
+    /// it printed. Defaults to running it for real.
+    fn output(&self, mut command: Command) -> io::Result<Output> {
+        command.output()
+    }
+
+    /// Starts `command` like `spawn`, returning the new process's id if a
+    /// process was started. Defaults to `spawn`, without an id.
+    fn spawn_process(&self, command: Command) -> io::Result<Option<u32>> {
+        self.spawn(command).map(|()| None)
+    }
+
+    /// Replaces the running process with `command`, for `RelaunchStrategy::Exec`:
+    /// returns only if it couldn't.
+    ///
+    /// Defaults to `spawn_process`, so runners that don't start real processes
+    /// never replace the one they run in.
+    #[cfg(unix)]
+    fn exec_process(&self, command: Command) -> io::Result<Option<u32>> {
+        self.spawn_process(command)
+    }
+}
+
+/// Runs processes for real.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl ProcessRunner for SystemRunner {
+    fn install(&self, command: Command, echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        install::run_install_with(command, echo, on_line, None)
+    }
+
+    fn install_with_heartbeat(
+        &self,
+        command: Command,
+        echo: bool,
+        on_line: &mut dyn FnMut(&str),
+        interval: Duration,
+        on_beat: &mut dyn FnMut(),
+    ) -> Result<(), InstallError> {
+        install::run_install_with(command, echo, on_line, Some((interval, on_beat)))
+    }
+
+    #[cfg(feature = "tokio")]
+    fn install_async<'a>(
+        &'a self,
+        command: Command,
+        echo: bool,
+        on_line: &'a mut (dyn FnMut(&str) + Send),
+        heartbeat: Option<(Duration, &'a mut (dyn FnMut() + Send))>,
+    ) -> InstallFuture<'a> {
+        Box::pin(install::run_install_async(command, echo, on_line, heartbeat))
+    }
+
+    fn spawn(&self, mut command: Command) -> io::Result<()> {
+        command.spawn().map(|_| ())
+    }
+
+    fn spawn_process(&self, mut command: Command) -> io::Result<Option<u32>> {
+        command.spawn().map(|child| Some(child.id()))
+    }
+
+    #[cfg(unix)]
+    fn exec_process(&self, mut command: Command) -> io::Result<Option<u32>> {
+        use std::os::unix::process::CommandExt;
+        Err(command.exec())
+    }
+}
+
+/// A command as it would have been run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCommand {
+    /// The program, as passed to `Command::new`.
+    pub program: OsString,
+    /// The arguments, in order.
+    pub args: Vec<OsString>,
+    /// Environment changes: `None` means the variable is removed.
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// Whether it was run as an install (as opposed to spawned, or run for its output).
+    pub install: bool,
+}
+
+impl RecordedCommand {
+    fn new(command: &Command, install: bool) -> Self {
+        RecordedCommand {
+            program: command.get_program().to_os_string(),
+            args: command.get_args().map(|arg| arg.to_os_string()).collect(),
+            env: command
+                .get_envs()
+                .map(|(key, value)| (key.to_os_string(), value.map(|value| value.to_os_string())))
+                .collect(),
+            install,
+        }
+    }
+}
+
+/// Runs nothing: every install succeeds, every spawn starts and every other
+/// command succeeds without output, and each command is recorded for later
+/// inspection.
+///
+/// The relaunch is recorded as spawned, even with `RelaunchStrategy::Exec`, and
+/// `relaunch_program` returns `UpdateOutcome::Relaunched` without a pid.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    commands: Mutex<Vec<RecordedCommand>>,
+}
+
+impl RecordingRunner {
+    /// Creates a runner with nothing recorded.
+    pub fn new() -> Self {
+        RecordingRunner::default()
+    }
+
+    /// Returns the commands recorded so far, in order.
+    pub fn commands(&self) -> Vec<RecordedCommand> {
+        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    fn record(&self, command: &Command, install: bool) {
+        self.commands
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(RecordedCommand::new(command, install));
+    }
+}
+
+impl ProcessRunner for RecordingRunner {
+    fn install(&self, command: Command, _echo: bool, _on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        self.record(&command, true);
+        Ok(())
+    }
+
+    fn spawn(&self, command: Command) -> io::Result<()> {
+        self.record(&command, false);
+        Ok(())
+    }
+
+    fn output(&self, command: Command) -> io::Result<Output> {
+        self.record(&command, false);
+        Ok(Output { status: ExitStatus::default(), stdout: Vec::new(), stderr: Vec::new() })
+    }
+}
+
+// Lets a runner shared with the host program (e.g. an `Arc<RecordingRunner>`
+// kept by a test) be handed to the builder
+impl<T: ProcessRunner + ?Sized> ProcessRunner for std::sync::Arc<T> {
+    fn install(&self, command: Command, echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        (**self).install(command, echo, on_line)
+    }
+
+    fn install_with_heartbeat(
+        &self,
+        command: Command,
+        echo: bool,
+        on_line: &mut dyn FnMut(&str),
+        interval: Duration,
+        on_beat: &mut dyn FnMut(),
+    ) -> Result<(), InstallError> {
+        (**self).install_with_heartbeat(command, echo, on_line, interval, on_beat)
+    }
+
+    #[cfg(feature = "tokio")]
+    fn install_async<'a>(
+        &'a self,
+        command: Command,
+        echo: bool,
+        on_line: &'a mut (dyn FnMut(&str) + Send),
+        heartbeat: Option<(Duration, &'a mut (dyn FnMut() + Send))>,
+    ) -> InstallFuture<'a> {
+        (**self).install_async(command, echo, on_line, heartbeat)
+    }
+
+    fn spawn(&self, command: Command) -> io::Result<()> {
+        (**self).spawn(command)
+    }
+
+    fn output(&self, command: Command) -> io::Result<Output> {
+        (**self).output(command)
+    }
+
+    fn spawn_process(&self, command: Command) -> io::Result<Option<u32>> {
+        (**self).spawn_process(command)
+    }
+
+    #[cfg(unix)]
+    fn exec_process(&self, command: Command) -> io::Result<Option<u32>> {
+        (**self).exec_process(command)
+    }
+}
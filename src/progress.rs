@@ -0,0 +1,124 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::sync::Arc;
+use std::time::Duration;
+use indicatif::{ProgressBar, ProgressStyle};
+use crate::events::UpdateEvent;
+use crate::format::Formatter;
+
+// Sizes and durations go in `{prefix}`, written by the `Formatter` rather than indicatif
+const SPINNER_TEMPLATE: &str = "{spinner:.green} [{prefix}] {msg}";
+const BUILD_TEMPLATE: &str = "{spinner:.green} [{prefix}] [{bar:30}] {pos}/{len} {msg}";
+const DOWNLOAD_TEMPLATE: &str = "{msg} [{bar:30}] {prefix}";
+const DOWNLOAD_UNKNOWN_SIZE_TEMPLATE: &str = "{spinner:.green} {msg} {prefix}";
+
+// Renders `UpdateEvent`s as progress bars: one per download, and a spinner with
+// the elapsed time and cargo's last status line while installing, which becomes
+// a bar once cargo says how many packages it builds
+pub(crate) struct ProgressRenderer {
+    bar: Option<ProgressBar>,
+    formatter: Arc<dyn Formatter>,
+}
+
+impl ProgressRenderer {
+    pub(crate) fn new(formatter: Arc<dyn Formatter>) -> Self {
+        ProgressRenderer { bar: None, formatter }
+    }
+
+    // The time spent so far, e.g. "1m 5s"
+    fn show_elapsed(&self) {
+        if let Some(bar) = &self.bar {
+            bar.set_prefix(self.formatter.duration(Duration::from_secs(bar.elapsed().as_secs())));
+        }
+    }
+
+    fn style(template: &str) -> ProgressStyle {
+        ProgressStyle::with_template(template).unwrap_or_else(|_| ProgressStyle::default_bar())
+    }
+
+    fn finish(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+
+    pub(crate) fn handle(&mut self, event: &UpdateEvent) {
+        match event {
+            UpdateEvent::DownloadStarted { url, total_bytes } => {
+                self.finish();
+                let bar = match total_bytes {
+                    Some(total) => ProgressBar::new(*total)
+                        .with_style(Self::style(DOWNLOAD_TEMPLATE).progress_chars("=> ")),
+                    None => ProgressBar::new_spinner().with_style(Self::style(DOWNLOAD_UNKNOWN_SIZE_TEMPLATE)),
+                };
+                let file_name = url.rsplit('/').next().unwrap_or(url).to_string();
+                bar.set_message(format!("Downloading {file_name}"));
+                self.bar = Some(bar);
+            }
+            UpdateEvent::DownloadProgress { downloaded_bytes, total_bytes, .. } => {
+                if let Some(bar) = &self.bar {
+                    bar.set_position(*downloaded_bytes);
+                    let downloaded = self.formatter.size(*downloaded_bytes);
+                    bar.set_prefix(match total_bytes {
+                        Some(total) => format!(
+                            "{}/{} ({})",
+                            downloaded,
+                            self.formatter.size(*total),
+                            self.formatter.duration(Duration::from_secs(bar.eta().as_secs())),
+                        ),
+                        None => downloaded,
+                    });
+                }
+            }
+            UpdateEvent::DownloadFinished { .. } => self.finish(),
+            UpdateEvent::InstallStarted { version } => {
+                self.finish();
+                let bar = ProgressBar::new_spinner().with_style(Self::style(SPINNER_TEMPLATE));
+                bar.set_message(format!("Installing {version}"));
+                bar.enable_steady_tick(Duration::from_millis(100));
+                self.bar = Some(bar);
+                self.show_elapsed();
+            }
+            UpdateEvent::InstallHeartbeat { .. } => self.show_elapsed(),
+            UpdateEvent::InstallOutput { line } => {
+                if let Some(bar) = &self.bar {
+                    let line = line.trim();
+                    // Keep cargo's diagnostics visible above the spinner
+                    if line.starts_with("error") || line.starts_with("warning") {
+                        bar.println(line);
+                    } else if !line.is_empty() {
+                        bar.set_message(line.to_string());
+                    }
+                }
+            }
+            UpdateEvent::InstallProgress { compiled, total: Some(total) } => {
+                if let Some(bar) = &self.bar {
+                    if bar.length() != Some(*total as u64) {
+                        bar.set_style(Self::style(BUILD_TEMPLATE).progress_chars("=> "));
+                        bar.set_length(*total as u64);
+                    }
+                    bar.set_position(*compiled as u64);
+                }
+            }
+            UpdateEvent::InstallFinished { .. } | UpdateEvent::Relaunching => self.finish(),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for ProgressRenderer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,137 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::{info, debug};
+use crate::error::RspawnError;
+use crate::json;
+use crate::log_target::INSTALL;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::state::{state_dir, state_dir_in, write_atomic};
+use crate::registry::{RegistryVersion, CRATES_IO_URL};
+
+// File holding the provenance of the last install, inside `state_dir()`
+const PROVENANCE_FILE_NAME: &str = "provenance.json";
+
+/// The toolchain a binary was built with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Toolchain {
+    /// Output of `rustc --version`.
+    pub rustc: Option<String>,
+    /// Output of `cargo --version`.
+    pub cargo: Option<String>,
+    /// The host target triple, as reported by `rustc -vV`.
+    pub host: Option<String>,
+}
+
+impl Toolchain {
+    // Queries the local toolchain; anything that can't be determined is left empty
+    fn detect() -> Self {
+        let rustc_verbose = command_output("rustc", &["-vV"]);
+        Toolchain {
+            rustc: rustc_verbose.as_deref().and_then(|out| out.lines().next()).map(str::to_string),
+            cargo: command_output("cargo", &["--version"]),
+            host: rustc_verbose
+                .as_deref()
+                .and_then(|out| out.lines().find_map(|line| line.strip_prefix("host: ")))
+                .map(str::to_string),
+        }
+    }
+}
+
+// Trimmed stdout of a command, if it ran successfully
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        debug!(target: INSTALL, "{} {:?} exited with {}", program, args, output.status);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Provenance metadata recorded for an install.
+///
+/// A small SBOM-like document describing where an installed binary came from
+/// and how it was built, stored as JSON in `state_dir()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Name of the installed crate.
+    pub crate_name: String,
+    /// Installed version.
+    pub version: String,
+    /// Registry the crate was fetched from.
+    pub registry: String,
+    /// SHA-256 of the published .crate file, as reported by the registry.
+    pub crate_checksum: Option<String>,
+    /// Cargo features the crate was built with.
+    pub features: Vec<String>,
+    /// Where the binary was installed.
+    pub binary_path: PathBuf,
+    /// SHA-256 of the installed binary.
+    pub binary_sha256: String,
+    /// The toolchain used for the build.
+    pub toolchain: Toolchain,
+    /// Install time, in seconds since the Unix epoch.
+    pub installed_at: u64,
+}
+
+impl Provenance {
+    pub(crate) fn new(
+        crate_name: &str,
+        version: &RegistryVersion,
+        features: &[String],
+        binary_path: PathBuf,
+        binary_sha256: String,
+        clock: &dyn Clock,
+    ) -> Self {
+        Provenance {
+            crate_name: crate_name.to_string(),
+            version: version.num.clone(),
+            registry: CRATES_IO_URL.to_string(),
+            crate_checksum: version.checksum.clone(),
+            features: features.to_vec(),
+            binary_path,
+            binary_sha256,
+            toolchain: Toolchain::detect(),
+            installed_at: clock.unix_now(),
+        }
+    }
+}
+
+// Writes the provenance document for the last install
+pub(crate) fn record(provenance: &Provenance, fs: &dyn Fs) -> Result<()> {
+    if !json::AVAILABLE {
+        return Ok(());
+    }
+    let path = state_dir_in(fs)?.join(PROVENANCE_FILE_NAME);
+    let contents = json::to_string_pretty(provenance).context("Failed to serialize provenance")?;
+    write_atomic(fs, &path, contents.as_bytes())?;
+    info!(target: INSTALL, "Recorded install provenance in {}", path.display());
+    Ok(())
+}
+
+/// Returns the provenance of the last install performed by rspawn, if any.
+pub fn install_provenance() -> Result<Option<Provenance>, RspawnError> {
+    let path = state_dir()?.join(PROVENANCE_FILE_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let provenance = json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(provenance))
+}
@@ -0,0 +1,85 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Python bindings for the updater core, built with `maturin build --features python`.
+//!
+//! ```python
+//! import sys
+//! import rspawn
+//!
+//! latest = rspawn.check("mytool", "1.2.0")
+//! if latest is not None:
+//!     rspawn.install("mytool", features=["tls"])
+//!     rspawn.relaunch()
+//!     sys.exit(0)
+//! ```
+// pyo3's #[pyfunction] expansion converts PyErr into itself
+#![allow(clippy::useless_conversion)]
+use std::env;
+use std::process::Command;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use crate::clock::SystemClock;
+use crate::filesystem::RealFs;
+use crate::hold;
+use crate::install;
+use crate::registry;
+
+/// Returns the latest version of `crate_name` on crates.io.
+#[pyfunction]
+fn latest_version(py: Python<'_>, crate_name: &str) -> PyResult<String> {
+    py.allow_threads(|| registry::latest_version(crate_name))
+        .map_err(|e| PyRuntimeError::new_err(format!("{:#}", e)))
+}
+
+/// Returns the latest version of `crate_name` if it is newer than `current_version`, else None.
+#[pyfunction]
+fn check(py: Python<'_>, crate_name: &str, current_version: &str) -> PyResult<Option<String>> {
+    let latest = latest_version(py, crate_name)?;
+    Ok(registry::is_outdated(current_version, &latest).then_some(latest))
+}
+
+/// Installs the latest version of `crate_name` with `cargo install`.
+#[pyfunction]
+#[pyo3(name = "install", signature = (crate_name, features = None))]
+fn py_install(py: Python<'_>, crate_name: &str, features: Option<Vec<String>>) -> PyResult<()> {
+    let features = features.unwrap_or_default();
+    hold::check_hold(&SystemClock, &RealFs).map_err(|hold| PyRuntimeError::new_err(hold.to_string()))?;
+    py.allow_threads(|| install::run_install(install::cargo_install_command(crate_name, None, &features)))
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+}
+
+/// Starts a new instance of the running program with the same arguments.
+///
+/// Returns the pid of the new process; exiting the current one is up to the caller.
+#[pyfunction]
+fn relaunch() -> PyResult<u32> {
+    let exe_path = env::current_exe()
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to resolve the current executable: {}", e)))?;
+    let child = Command::new(exe_path)
+        .args(env::args_os().skip(1)) // Pass all the arguments to the new process
+        .spawn()
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to relaunch the program: {}", e)))?;
+    Ok(child.id())
+}
+
+#[pymodule]
+#[pyo3(name = "rspawn")]
+fn rspawn_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", crate::RSPAWN_VERSION)?;
+    m.add_function(wrap_pyfunction!(latest_version, m)?)?;
+    m.add_function(wrap_pyfunction!(check, m)?)?;
+    m.add_function(wrap_pyfunction!(py_install, m)?)?;
+    m.add_function(wrap_pyfunction!(relaunch, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,371 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Version lookup on crates.io.
+//!
+//! Nothing in here touches processes or the filesystem, so this module is also
+//! available in check-only builds (`default-features = false`), including
+//! `wasm32-unknown-unknown` where requests go through the browser's fetch.
+use serde::Deserialize;
+use anyhow::{Result, Context};
+use log::{info, debug, error};
+use crate::log_target::CHECK;
+use crate::credentials::{CredentialStore, EnvCredentialStore, REGISTRY_TOKEN};
+use crate::error::RspawnError;
+use crate::json;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::fixtures;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::http::{self, ClientConfig};
+#[cfg(feature = "install")]
+use crate::version::{compare_versions, VersionReq};
+use crate::version::{is_update, UpdatePolicy};
+use crate::RSPAWN_VERSION;
+
+// Base URL of the registry versions are fetched from
+pub(crate) const CRATES_IO_URL: &str = "https://crates.io";
+
+// A version as published on crates.io
+pub(crate) struct RegistryVersion {
+    pub(crate) num: String,
+    // SHA-256 of the published .crate file
+    #[cfg_attr(not(feature = "install"), allow(dead_code))]
+    pub(crate) checksum: Option<String>,
+}
+
+// The fields of a version on crates.io that rspawn reads
+#[derive(Debug, Deserialize)]
+struct PublishedVersion {
+    num: String,
+    #[serde(default)]
+    yanked: bool,
+    checksum: Option<String>,
+    // RFC 3339
+    created_at: Option<String>,
+}
+
+// A response of the versions endpoint, most recently published first
+#[derive(Debug, Deserialize)]
+struct VersionsResponse {
+    versions: Vec<PublishedVersion>,
+}
+
+// A response of the endpoint for one version
+#[cfg(all(feature = "install", not(target_arch = "wasm32")))]
+#[derive(Debug, Deserialize)]
+struct VersionResponse {
+    version: Option<PublishedVersion>,
+}
+
+impl From<&PublishedVersion> for RegistryVersion {
+    fn from(version: &PublishedVersion) -> Self {
+        RegistryVersion {
+            num: version.num.clone(),
+            checksum: version.checksum.clone(),
+        }
+    }
+}
+
+// User-Agent sent with every request, as required by the crates.io crawler policy
+pub(crate) fn user_agent() -> String {
+    format!("rspawn/{RSPAWN_VERSION} (https://github.com/jgabaut/rspawn)")
+}
+
+// URL of the versions endpoint for `crate_name`
+fn versions_url(crate_name: &str) -> String {
+    format!("{CRATES_IO_URL}/api/v1/crates/{}/versions", crate_name)
+}
+
+// Whether `num` is offered on `channel`. Every channel gets stable releases, plus
+// the prereleases tagged with the channel name (e.g. `1.2.0-beta.1` on "beta").
+pub(crate) fn is_on_channel(num: &str, channel: &str) -> bool {
+    let num = num.split('+').next().unwrap_or(num);
+    match num.split_once('-') {
+        Some((_, prerelease)) => prerelease.starts_with(channel),
+        None => true,
+    }
+}
+
+// Extracts the latest version from a crates.io versions response.
+// Without a channel this is the most recently published version; with one,
+// yanked versions and prereleases from other channels are skipped.
+pub(crate) fn parse_latest_version(body: &str, channel: Option<&str>) -> Result<RegistryVersion> {
+    let response: VersionsResponse = json::from_str(body).context("Failed to parse JSON response")?;
+    debug!(target: CHECK, "Parsed JSON: {:?}", response);
+
+    let latest_version = match channel {
+        None => response.versions.first(),
+        Some(channel) => response.versions.iter().find(|version| !version.yanked && is_on_channel(&version.num, channel)),
+    };
+    latest_version
+        .map(RegistryVersion::from)
+        .ok_or_else(|| anyhow::anyhow!("Failed to get the latest version"))
+}
+
+// Extracts the highest non-yanked version matching `requirement` from a crates.io versions response
+#[cfg(feature = "install")]
+pub(crate) fn parse_matching_version(body: &str, requirement: &VersionReq) -> Result<Option<RegistryVersion>> {
+    let response: VersionsResponse = json::from_str(body).context("Failed to parse JSON response")?;
+    let matching = response
+        .versions
+        .iter()
+        .filter(|version| !version.yanked && requirement.matches(&version.num))
+        .max_by(|a, b| compare_versions(&a.num, &b.num));
+    Ok(matching.map(RegistryVersion::from))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn get_latest_version_from_crates_io(
+    crate_name: &str,
+    token: Option<&str>,
+    channel: Option<&str>,
+) -> Result<RegistryVersion> {
+    let body = fetch_versions(crate_name, token)?;
+    parse_latest_version(&body, channel)
+}
+
+// `get_latest_version_from_crates_io` through reqwest's async client, for `RSpawn::relaunch_async`
+#[cfg(feature = "tokio")]
+pub(crate) async fn get_latest_version_from_crates_io_async(
+    crate_name: &str,
+    token: Option<&str>,
+    channel: Option<&str>,
+) -> Result<RegistryVersion> {
+    let url = versions_url(crate_name);
+
+    info!(target: CHECK, "Fetching latest version for {} from: {}", crate_name, url);
+    let body = match fixtures::replay(&url) {
+        Some(recorded) => recorded?,
+        None => {
+            let mut request = http::async_client()?.get(&url);
+            if let Some(token) = token {
+                request = request.header("Authorization", token);
+            }
+            let response = request.send().await.context("Failed to fetch from crates.io")?;
+
+            let status = response.status();
+            debug!(target: CHECK, "Response status: {}", status);
+
+            if !status.is_success() {
+                let error_msg = format!("Failed to fetch crate info: HTTP {}", status);
+                error!(target: CHECK, "{error_msg}");
+                return Err(anyhow::anyhow!("{error_msg}"));
+            }
+
+            let body = response.text().await.context("Failed to read response body")?;
+            debug!(target: CHECK, "Response body: {}", body);
+            fixtures::record(&url, &body);
+            body
+        }
+    };
+    parse_latest_version(&body, channel)
+}
+
+// Looks up the highest published version of `crate_name` matching `requirement`, if any
+#[cfg(all(feature = "install", not(target_arch = "wasm32")))]
+pub(crate) fn get_matching_version_from_crates_io(
+    crate_name: &str,
+    token: Option<&str>,
+    requirement: &VersionReq,
+) -> Result<Option<RegistryVersion>> {
+    let body = fetch_versions(crate_name, token)?;
+    parse_matching_version(&body, requirement)
+}
+
+// Fetches the body of the versions endpoint for `crate_name`
+#[cfg(not(target_arch = "wasm32"))]
+fn fetch_versions(crate_name: &str, token: Option<&str>) -> Result<String> {
+    let url = versions_url(crate_name);
+
+    info!(target: CHECK, "Fetching latest version for {} from: {}", crate_name, url);
+    if let Some(recorded) = fixtures::replay(&url) {
+        return recorded;
+    }
+
+    let mut request = http::client(ClientConfig::default())?.get(&url);
+    if let Some(token) = token {
+        request = request.header("Authorization", token);
+    }
+    let response = request
+        .send()
+        .context("Failed to fetch from crates.io")?;
+
+    let status = response.status();
+    debug!(target: CHECK, "Response status: {}", status);
+
+    if !status.is_success() {
+        let error_msg = format!("Failed to fetch crate info: HTTP {}", status);
+        error!(target: CHECK, "{error_msg}");
+        return Err(anyhow::anyhow!("{error_msg}"));
+    }
+
+    let body = response.text().context("Failed to read response body")?;
+    debug!(target: CHECK, "Response body: {}", body);
+    fixtures::record(&url, &body);
+    Ok(body)
+}
+
+// Looks up a specific published version of `crate_name`, for its checksum
+#[cfg(all(feature = "install", not(target_arch = "wasm32")))]
+pub(crate) fn get_version_from_crates_io(crate_name: &str, version: &str, token: Option<&str>) -> Result<RegistryVersion> {
+    let url = format!("{CRATES_IO_URL}/api/v1/crates/{}/{}", crate_name, version);
+    debug!(target: CHECK, "Fetching {} {} from: {}", crate_name, version, url);
+    let body = match fixtures::replay(&url) {
+        Some(recorded) => recorded?,
+        None => fetch_version(&url, crate_name, version, token)?,
+    };
+
+    let response: VersionResponse = json::from_str(&body).context("Failed to parse JSON response")?;
+    response
+        .version
+        .as_ref()
+        .map(RegistryVersion::from)
+        .ok_or_else(|| anyhow::anyhow!("crates.io has no version {} of {}", version, crate_name))
+}
+
+// Fetches the body of the endpoint for one version of `crate_name`
+#[cfg(all(feature = "install", not(target_arch = "wasm32")))]
+fn fetch_version(url: &str, crate_name: &str, version: &str, token: Option<&str>) -> Result<String> {
+    let mut request = http::client(ClientConfig::default())?.get(url);
+    if let Some(token) = token {
+        request = request.header("Authorization", token);
+    }
+    let response = request.send().context("Failed to fetch from crates.io")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("Failed to fetch {} {} from crates.io: HTTP {}", crate_name, version, status));
+    }
+
+    let body = response.text().context("Failed to read response body")?;
+    fixtures::record(url, &body);
+    Ok(body)
+}
+
+/// Fetches the latest version of `crate_name` from crates.io.
+///
+/// The registry token, if any, is read from `RSPAWN_REGISTRY_TOKEN`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn latest_version(crate_name: &str) -> Result<String, RspawnError> {
+    latest_version_with(crate_name, &EnvCredentialStore)
+}
+
+/// Same as `latest_version`, with the registry token read from `credentials`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn latest_version_with(crate_name: &str, credentials: &dyn CredentialStore) -> Result<String, RspawnError> {
+    let token = credentials.token(REGISTRY_TOKEN)?;
+    let latest = get_latest_version_from_crates_io(crate_name, token.as_deref(), None)?;
+    Ok(latest.num)
+}
+
+/// Fetches the latest version of `crate_name` from crates.io.
+///
+/// On `wasm32` the request goes through the browser's fetch API, which does
+/// not allow overriding the User-Agent, and handles compression and
+/// connection reuse on its own. The registry token, if any, is read from
+/// `RSPAWN_REGISTRY_TOKEN`.
+#[cfg(target_arch = "wasm32")]
+pub async fn latest_version(crate_name: &str) -> Result<String, RspawnError> {
+    latest_version_with(crate_name, &EnvCredentialStore).await
+}
+
+/// Same as `latest_version`, with the registry token read from `credentials`.
+#[cfg(target_arch = "wasm32")]
+pub async fn latest_version_with(crate_name: &str, credentials: &dyn CredentialStore) -> Result<String, RspawnError> {
+    let url = versions_url(crate_name);
+
+    info!(target: CHECK, "Fetching latest version for {} from: {}", crate_name, url);
+
+    let mut request = reqwest::Client::new().get(&url);
+    if let Some(token) = credentials.token(REGISTRY_TOKEN)? {
+        request = request.header("Authorization", token);
+    }
+    let response = request.send().await.context("Failed to fetch from crates.io")?;
+
+    let status = response.status();
+    debug!(target: CHECK, "Response status: {}", status);
+
+    if !status.is_success() {
+        let error_msg = format!("Failed to fetch crate info: HTTP {}", status);
+        error!(target: CHECK, "{error_msg}");
+        return Err(anyhow::anyhow!("{error_msg}").into());
+    }
+
+    let body = response.text().await.context("Failed to read response body")?;
+    Ok(parse_latest_version(&body, None)?.num)
+}
+
+/// A newer version of a crate, as found by `check_update`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateInfo {
+    /// The version that was checked against.
+    pub current_version: String,
+    /// The most recently published version.
+    pub latest_version: String,
+    /// Whether the latest version was yanked since it was published.
+    pub yanked: bool,
+    /// Whether the current version was yanked, e.g. for a serious bug.
+    pub current_yanked: bool,
+    /// When the latest version was published, as reported by crates.io (RFC 3339).
+    pub published_at: Option<String>,
+}
+
+// Builds the `UpdateInfo` for `current_version` from a crates.io versions response,
+// `None` if the latest version isn't newer
+pub(crate) fn parse_update_info(body: &str, current_version: &str) -> Result<Option<UpdateInfo>> {
+    let response: VersionsResponse = json::from_str(body).context("Failed to parse JSON response")?;
+    let latest = response.versions.first().ok_or_else(|| anyhow::anyhow!("Failed to get the latest version"))?;
+    if !is_update(current_version, &latest.num, UpdatePolicy::Newer) {
+        return Ok(None);
+    }
+
+    let current_yanked = response
+        .versions
+        .iter()
+        .find(|version| version.num == current_version)
+        .is_some_and(|version| version.yanked);
+    Ok(Some(UpdateInfo {
+        current_version: current_version.to_string(),
+        latest_version: latest.num.clone(),
+        yanked: latest.yanked,
+        current_yanked,
+        published_at: latest.created_at.clone(),
+    }))
+}
+
+/// Checks crates.io for a version of `crate_name` newer than `current_version`,
+/// without installing anything.
+///
+/// Returns `None` when `current_version` is the latest. Installing the update
+/// is left to `RSpawn` or `relaunch_program`. The registry token, if any, is
+/// read from `RSPAWN_REGISTRY_TOKEN`; use `check_update_with` for another store.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn check_update(crate_name: &str, current_version: &str) -> Result<Option<UpdateInfo>, RspawnError> {
+    check_update_with(crate_name, current_version, &EnvCredentialStore)
+}
+
+/// Same as `check_update`, with the registry token read from `credentials`,
+/// e.g. a `KeyringCredentialStore`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn check_update_with(crate_name: &str, current_version: &str, credentials: &dyn CredentialStore) -> Result<Option<UpdateInfo>, RspawnError> {
+    let token = credentials.token(REGISTRY_TOKEN)?;
+    let body = fetch_versions(crate_name, token.as_deref())?;
+    Ok(parse_update_info(&body, current_version)?)
+}
+
+/// Returns whether `latest_version` is an update over `current_version`.
+///
+/// Same as `is_update(current_version, latest_version, UpdatePolicy::AnyChange)`.
+pub fn is_outdated(current_version: &str, latest_version: &str) -> bool {
+    is_update(current_version, latest_version, UpdatePolicy::AnyChange)
+}
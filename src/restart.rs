@@ -0,0 +1,82 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! When a freshly installed version takes over from the running one.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use log::debug;
+use crate::log_target::RELAUNCH;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::state::State;
+use crate::version::compare_versions;
+
+/// When the new version takes over after an update, see `RSpawn::relaunch_mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelaunchMode {
+    /// Start the new version with the same arguments as soon as it's installed,
+    /// see `RelaunchStrategy`.
+    #[default]
+    Immediately,
+    /// Keep the running process going, and leave the new version for the next
+    /// time the program is started. The least disruptive mode for interactive tools.
+    OnNextStart,
+}
+
+/// How the new version is started with `RelaunchMode::Immediately`, see
+/// `RSpawn::relaunch_strategy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelaunchStrategy {
+    /// Start it as a separate process, and return `UpdateOutcome::Relaunched`
+    /// with its pid: the host exits once it's done cleaning up.
+    #[default]
+    Spawn,
+    /// Replace the running process with it (`execve`): it keeps the pid, the open
+    /// file descriptors and the terminal, and supervisors never see the program
+    /// exit. Nothing of the running version runs afterwards, not even destructors.
+    #[cfg(unix)]
+    Exec,
+}
+
+/// An update installed while the program kept running, see `RelaunchMode::OnNextStart`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRestart {
+    /// The installed version, which the next start runs.
+    pub version: String,
+    /// When it was installed, in seconds since the Unix epoch.
+    pub installed_at: u64,
+}
+
+// Notes that the next start runs `version`
+pub(crate) fn record_pending(version: &str, clock: &dyn Clock, fs: &dyn Fs) -> Result<()> {
+    let pending = PendingRestart {
+        version: version.to_string(),
+        installed_at: clock.unix_now(),
+    };
+    State::update_in(fs, |state| state.pending_restart = Some(pending))
+}
+
+// The version waiting for the next start, forgotten once a start runs it (or something newer)
+pub(crate) fn pending_version(current_version: &str, fs: &dyn Fs) -> Result<Option<String>> {
+    let Some(pending) = State::load_in(fs)?.pending_restart else {
+        return Ok(None);
+    };
+    if compare_versions(current_version, &pending.version).is_lt() {
+        return Ok(Some(pending.version));
+    }
+    debug!(target: RELAUNCH, "Now running {}, the pending restart into {} is done", current_version, pending.version);
+    State::update_in(fs, |state| state.pending_restart = None)?;
+    Ok(None)
+}
@@ -0,0 +1,211 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Running `cargo install` in a sandbox, so building a release can only write where cargo needs to.
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{bail, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use crate::install;
+use crate::log_target::INSTALL;
+use crate::mirror;
+
+/// Confines `cargo install` while it builds a release, and the build scripts
+/// and proc macros of every crate it compiles.
+///
+/// Sandboxed, the build sees the filesystem read-only, except for cargo's home
+/// (registry cache and installed binaries), the install root, the target
+/// directory and a private `/tmp`. The rest of the home directory is hidden,
+/// so ssh keys and git credentials are out of reach too. The network stays
+/// available, since cargo downloads the dependencies itself.
+///
+/// The sandboxes are Linux programs: anywhere else, or when the one asked for
+/// isn't installed, the update fails instead of building unconfined. There is
+/// no Windows sandbox: a restricted token alone doesn't keep the build from
+/// writing to the user's files, and confining it with a low integrity level
+/// would mean relabeling cargo's directories, so asking for one on Windows
+/// fails the update too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallSandbox {
+    /// Cargo runs with the program's own access.
+    #[default]
+    Off,
+    /// Bubblewrap if it's installed, otherwise firejail.
+    Auto,
+    /// `bwrap`, in its own mount, user, IPC and PID namespaces.
+    Bubblewrap,
+    /// `firejail`, without capabilities or new privileges, under its default seccomp filter.
+    Firejail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Bubblewrap,
+    Firejail,
+}
+
+impl Tool {
+    fn program(self) -> &'static str {
+        match self {
+            Tool::Bubblewrap => "bwrap",
+            Tool::Firejail => "firejail",
+        }
+    }
+}
+
+// The paths the build gets to see, from the install command and our environment
+#[derive(Debug, Default)]
+struct Access {
+    home: Option<PathBuf>,
+    writable: Vec<PathBuf>,
+    read_only: Vec<PathBuf>,
+}
+
+impl Access {
+    fn of(command: &Command) -> Self {
+        let var = |key: &str| install::command_env(command, key);
+        let home = var("HOME");
+        let root = install::command_install_root(command);
+        let cargo_home = var("CARGO_HOME").or_else(install::cargo_home);
+        let rustup_home = var("RUSTUP_HOME").or_else(|| Some(home.as_ref()?.join(".rustup")));
+
+        let writable = [cargo_home, root, var("CARGO_TARGET_DIR")].into_iter().flatten().collect();
+        let read_only = [rustup_home, mirror::configured_mirror(command)].into_iter().flatten().collect();
+        Access { home, writable, read_only }
+    }
+}
+
+// Wraps `command` in `sandbox`. Fails if the sandbox isn't available, since
+// building unconfined after being asked not to is worse than not updating.
+pub(crate) fn wrap(command: Command, sandbox: InstallSandbox) -> Result<Command> {
+    let tool = match sandbox {
+        InstallSandbox::Off => return Ok(command),
+        _ if !cfg!(target_os = "linux") => bail!("The install sandbox is only available on Linux (there is none for Windows), turn it off to update here"),
+        InstallSandbox::Auto => match [Tool::Bubblewrap, Tool::Firejail].into_iter().find(|tool| install::find_in_path(tool.program()).is_some()) {
+            Some(tool) => tool,
+            None => bail!("The install sandbox needs bubblewrap (bwrap) or firejail, and neither was found in PATH"),
+        },
+        InstallSandbox::Bubblewrap => Tool::Bubblewrap,
+        InstallSandbox::Firejail => Tool::Firejail,
+    };
+    if install::find_in_path(tool.program()).is_none() {
+        bail!("The install sandbox needs {}, which was not found in PATH", tool.program());
+    }
+
+    let access = Access::of(&command);
+    // Binding a missing directory fails, and cargo couldn't create it from inside
+    for dir in &access.writable {
+        if let Err(e) = fs::create_dir_all(dir) {
+            debug!(target: INSTALL, "Could not create {} for the sandbox: {}", dir.display(), e);
+        }
+    }
+    let mut sandboxed = Command::new(tool.program());
+    sandboxed.args(match tool {
+        Tool::Bubblewrap => bubblewrap_args(&access),
+        Tool::Firejail => firejail_args(&access),
+    });
+    sandboxed.arg("--").arg(command.get_program()).args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => sandboxed.env(key, value),
+            None => sandboxed.env_remove(key),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        sandboxed.current_dir(dir);
+    }
+    debug!(target: INSTALL, "Sandboxing cargo install with {}, writable: {:?}", tool.program(), access.writable);
+    Ok(sandboxed)
+}
+
+// `--option path path`, for bubblewrap's binds
+fn bind(args: &mut Vec<OsString>, option: &str, path: &Path) {
+    args.extend([option.into(), path.into(), path.into()]);
+}
+
+fn bubblewrap_args(access: &Access) -> Vec<OsString> {
+    let mut args: Vec<OsString> = ["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]
+        .map(OsString::from)
+        .into();
+    // Later mounts go on top, so the directories cargo needs are put back into the hidden home
+    if let Some(home) = &access.home {
+        args.extend(["--tmpfs".into(), home.into()]);
+    }
+    for path in &access.read_only {
+        bind(&mut args, "--ro-bind-try", path);
+    }
+    for path in &access.writable {
+        bind(&mut args, "--bind-try", path);
+    }
+    args.extend(["--unshare-all", "--share-net", "--die-with-parent", "--new-session"].map(OsString::from));
+    args
+}
+
+fn firejail_args(access: &Access) -> Vec<OsString> {
+    let mut args: Vec<OsString> = [
+        "--quiet",
+        "--noprofile",
+        "--caps.drop=all",
+        "--nonewprivs",
+        "--noroot",
+        "--seccomp",
+        "--private-dev",
+        "--private-tmp",
+    ]
+    .map(OsString::from)
+    .into();
+    // Whitelisting anything in the home directory hides the rest of it
+    let in_home = |path: &&PathBuf| access.home.as_ref().is_some_and(|home| path.starts_with(home));
+    let whitelisted: Vec<&PathBuf> = access.writable.iter().chain(&access.read_only).filter(in_home).collect();
+    if whitelisted.is_empty() {
+        args.push("--private".into());
+    }
+    for path in whitelisted {
+        let mut whitelist = OsString::from("--whitelist=");
+        whitelist.push(path);
+        args.push(whitelist);
+    }
+    // Everything is read-only, except the paths the build writes to
+    args.push("--read-only=/".into());
+    for path in access.writable.iter().map(PathBuf::as_path).chain([Path::new("/tmp")]) {
+        let mut read_write = OsString::from("--read-write=");
+        read_write.push(path);
+        args.push(read_write);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firejail_writes_only_where_cargo_needs_to() {
+        let access = Access {
+            home: Some("/home/user".into()),
+            writable: vec!["/home/user/.cargo".into(), "/opt/target".into()],
+            read_only: vec!["/home/user/.rustup".into()],
+        };
+        let args: Vec<String> = firejail_args(&access).into_iter().map(|arg| arg.into_string().unwrap()).collect();
+
+        let read_only = args.iter().position(|arg| arg == "--read-only=/").unwrap();
+        let read_write: Vec<&str> = args[read_only..].iter().filter_map(|arg| arg.strip_prefix("--read-write=")).collect();
+        assert_eq!(read_write, ["/home/user/.cargo", "/opt/target", "/tmp"]);
+        assert!(args.contains(&"--whitelist=/home/user/.rustup".to_string()));
+        assert!(!args.contains(&"--private".to_string()));
+    }
+}
@@ -0,0 +1,191 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Update settings end users change for an installed program with `rspawn
+//! config`, kept in the program's state and applied over the program's own.
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::error::RspawnError;
+use crate::state::State;
+
+/// A setting managed with `rspawn config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Setting {
+    /// The release channel followed, as set with `set_channel`.
+    Channel,
+    /// Minimum time between two update checks, e.g. `6h`.
+    Interval,
+    /// `true` to turn update checks off.
+    Disabled,
+    /// Proxy for update checks, downloads and `cargo install`, e.g. `http://proxy:3128`.
+    Proxy,
+}
+
+impl Setting {
+    /// Every setting, in the order `rspawn config list` shows them.
+    pub const ALL: [Setting; 4] = [Setting::Channel, Setting::Interval, Setting::Disabled, Setting::Proxy];
+
+    /// The setting's name on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::Channel => "channel",
+            Setting::Interval => "interval",
+            Setting::Disabled => "disabled",
+            Setting::Proxy => "proxy",
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Setting {
+    type Err = RspawnError;
+
+    fn from_str(name: &str) -> Result<Self, RspawnError> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.name() == name)
+            .ok_or_else(|| RspawnError::ParseError(anyhow::anyhow!("unknown setting {:?}, expected one of channel, interval, disabled, proxy", name)))
+    }
+}
+
+/// Update settings the user chose for a program, see `Setting`.
+///
+/// The channel is kept in `State::channel`, shared with `set_channel`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserSettings {
+    /// Minimum time between two update checks, in seconds.
+    #[serde(default)]
+    pub check_interval: Option<u64>,
+    /// Whether update checks are turned off.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Proxy URL for update checks, downloads and `cargo install`.
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+/// Parses an interval like `30s`, `15m`, `6h` or `1d`.
+pub fn parse_interval(interval: &str) -> Result<Duration, RspawnError> {
+    let invalid = || RspawnError::ParseError(anyhow::anyhow!("invalid interval {:?}, expected e.g. 30m, 6h or 1d", interval));
+    let split = interval.find(|c: char| !c.is_ascii_digit()).unwrap_or(interval.len());
+    let amount: u64 = interval[..split].parse().map_err(|_| invalid())?;
+    let unit = match &interval[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match amount.checked_mul(unit) {
+        None | Some(0) => Err(invalid()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+    }
+}
+
+// Seconds back as an interval, in the largest unit that keeps it whole
+fn format_interval(secs: u64) -> String {
+    [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")]
+        .into_iter()
+        .find(|(unit, _)| secs % unit == 0)
+        .map_or_else(|| format!("{secs}s"), |(unit, suffix)| format!("{}{}", secs / unit, suffix))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(anyhow::anyhow!("invalid value {:?}, expected true or false", value)),
+    }
+}
+
+// Proxies cargo and both HTTP clients understand
+fn check_proxy(proxy: &str) -> Result<()> {
+    let host = ["http://", "https://", "socks5://", "socks5h://"]
+        .into_iter()
+        .find_map(|scheme| proxy.strip_prefix(scheme))
+        .with_context(|| format!("invalid proxy {:?}, expected an http://, https:// or socks5:// URL", proxy))?;
+    if host.trim_matches('/').is_empty() {
+        anyhow::bail!("invalid proxy {:?}, it has no host", proxy);
+    }
+    Ok(())
+}
+
+fn value_of(state: &State, setting: Setting) -> Option<String> {
+    match setting {
+        Setting::Channel => state.channel.clone(),
+        Setting::Interval => state.settings.check_interval.map(format_interval),
+        Setting::Disabled => Some(state.settings.disabled.to_string()),
+        Setting::Proxy => state.settings.proxy.clone(),
+    }
+}
+
+/// Returns `program`'s value of `setting`, `None` if it isn't set.
+pub fn get_setting(program: &str, setting: Setting) -> Result<Option<String>, RspawnError> {
+    Ok(value_of(&State::load_program(program)?, setting))
+}
+
+/// Returns every setting of `program`, with its value if set.
+pub fn list_settings(program: &str) -> Result<Vec<(Setting, Option<String>)>, RspawnError> {
+    let state = State::load_program(program)?;
+    Ok(Setting::ALL.into_iter().map(|setting| (setting, value_of(&state, setting))).collect())
+}
+
+/// Sets `program`'s `setting` to `value`, failing without changing anything
+/// if the value isn't valid for it.
+pub fn set_setting(program: &str, setting: Setting, value: &str) -> Result<(), RspawnError> {
+    let value = value.trim();
+    match setting {
+        Setting::Channel => {
+            if value.is_empty() {
+                return Err(anyhow::anyhow!("Release channel name can't be empty").into());
+            }
+            State::update_program(program, |state| state.channel = Some(value.to_string()))?;
+        }
+        Setting::Interval => {
+            let interval = parse_interval(value)?;
+            State::update_program(program, |state| state.settings.check_interval = Some(interval.as_secs()))?;
+        }
+        Setting::Disabled => {
+            let disabled = parse_bool(value)?;
+            State::update_program(program, |state| state.settings.disabled = disabled)?;
+        }
+        Setting::Proxy => {
+            check_proxy(value)?;
+            State::update_program(program, |state| state.settings.proxy = Some(value.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes `program`'s `setting`, going back to what the program was built with.
+pub fn unset_setting(program: &str, setting: Setting) -> Result<(), RspawnError> {
+    State::update_program(program, |state| match setting {
+        Setting::Channel => state.channel = None,
+        Setting::Interval => {
+            state.settings.check_interval = None;
+            state.last_check = None;
+        }
+        Setting::Disabled => state.settings.disabled = false,
+        Setting::Proxy => state.settings.proxy = None,
+    })
+    .map_err(RspawnError::from)
+}
@@ -0,0 +1,127 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Releases signed by the crate author.
+//!
+//! The author signs a statement binding crate name, version and the checksum
+//! of the published `.crate` with an ed25519 key, and publishes the signature
+//! (e.g. attached to the GitHub release). The public key is compiled into the
+//! program, so a compromised registry account alone can't push an update: the
+//! checksum cargo verifies while installing must be the one the author signed.
+use anyhow::{Result, Context};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::{info, debug, error};
+use crate::error::RspawnError;
+use crate::log_target::INSTALL;
+use crate::http::{self, ClientConfig};
+use crate::registry::RegistryVersion;
+use crate::url_resolver;
+
+/// Settings for verifying author-signed releases before installing them.
+#[derive(Debug, Clone)]
+pub struct ReleaseSignature {
+    /// The author's ed25519 public key.
+    pub public_key: [u8; 32],
+    /// URL of the hex-encoded signature of a release. `{crate}` and `{version}`
+    /// are replaced with the crate name and the version being installed.
+    pub signature_url: String,
+}
+
+impl ReleaseSignature {
+    /// Creates the settings from a hex-encoded public key.
+    pub fn new(public_key_hex: &str, signature_url: &str) -> Result<Self, RspawnError> {
+        let public_key = decode_hex::<32>(public_key_hex).context("Invalid release signing public key")?;
+        VerifyingKey::from_bytes(&public_key).context("Invalid release signing public key")?;
+        Ok(ReleaseSignature {
+            public_key,
+            signature_url: signature_url.to_string(),
+        })
+    }
+
+    // Signature URL for a specific release
+    fn signature_url_for(&self, crate_name: &str, version: &str) -> String {
+        self.signature_url
+            .replace("{crate}", crate_name)
+            .replace("{version}", version)
+    }
+}
+
+/// Returns the statement signed for a release: `"<crate> <version> <sha256 of the .crate>\n"`.
+pub fn release_statement(crate_name: &str, version: &str, checksum: &str) -> String {
+    format!("{} {} {}\n", crate_name, version, checksum.trim().to_lowercase())
+}
+
+/// Signs a release with the author's hex-encoded ed25519 secret key, returning
+/// the hex-encoded signature to publish at `ReleaseSignature::signature_url`.
+///
+/// `checksum` is the one crates.io reports for the published `.crate`
+/// (`cargo package` prints the same file's path; hash it with `sha256sum`).
+pub fn sign_release(secret_key_hex: &str, crate_name: &str, version: &str, checksum: &str) -> Result<String, RspawnError> {
+    let secret_key = decode_hex::<32>(secret_key_hex).context("Invalid release signing secret key")?;
+    let signature = SigningKey::from_bytes(&secret_key).sign(release_statement(crate_name, version, checksum).as_bytes());
+    Ok(encode_hex(&signature.to_bytes()))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return Err(anyhow::anyhow!("expected {} hex digits, found {:?}", N * 2, hex));
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap_or_default();
+        *byte = u8::from_str_radix(digits, 16).with_context(|| format!("invalid hex digits {:?}", digits))?;
+    }
+    Ok(bytes)
+}
+
+// Fetches the published signature for `version` and checks it against the registry checksum
+pub(crate) fn verify_release(settings: &ReleaseSignature, crate_name: &str, version: &RegistryVersion) -> Result<()> {
+    let checksum = version
+        .checksum
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("crates.io did not report a checksum for {} {}", crate_name, version.num))?;
+
+    let url = settings.signature_url_for(crate_name, &version.num);
+    debug!(target: INSTALL, "Fetching release signature from {}", url);
+    let signature_hex = match url_resolver::fetch_text(&url) {
+        Some(signature_hex) => signature_hex.context("Failed to fetch release signature")?,
+        None => {
+            let response = http::client(ClientConfig::default())?
+                .get(&url)
+                .send()
+                .with_context(|| format!("Failed to fetch release signature {}", url))?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(anyhow::anyhow!("Failed to fetch release signature {}: HTTP {}", url, status));
+            }
+            response.text().context("Failed to read release signature")?
+        }
+    };
+
+    let signature = Signature::from_bytes(&decode_hex::<64>(&signature_hex).context("Malformed release signature")?);
+    let public_key = VerifyingKey::from_bytes(&settings.public_key).context("Invalid release signing public key")?;
+    let statement = release_statement(crate_name, &version.num, checksum);
+    if let Err(e) = public_key.verify_strict(statement.as_bytes(), &signature) {
+        let error_msg = format!("{} {} is not signed by the expected key: {}", crate_name, version.num, e);
+        error!(target: INSTALL, "{error_msg}");
+        return Err(anyhow::anyhow!("{error_msg}"));
+    }
+
+    info!(target: INSTALL, "Verified the author's signature of {} {}", crate_name, version.num);
+    Ok(())
+}
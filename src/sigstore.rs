@@ -0,0 +1,145 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Verification of published crates against sigstore/cosign signatures.
+//!
+//! The `.crate` file published on crates.io is downloaded, checked against the
+//! registry checksum (the same one cargo checks when installing), and then
+//! verified with `cosign verify-blob` against a cosign bundle published by the
+//! author, e.g. attached to the GitHub release.
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use log::{info, error};
+use crate::log_target::INSTALL;
+use crate::download::{download, download_checked, DownloadSettings};
+use crate::events::Events;
+use crate::filesystem::{Fs, RealFs};
+use crate::process::ProcessRunner;
+use crate::registry::{RegistryVersion, CRATES_IO_URL};
+use crate::state::runtime_dir;
+use crate::unique::unique_token;
+
+/// How the signer of a release is identified.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CosignIdentity {
+    /// Key-based signing: path to the author's cosign public key.
+    Key(PathBuf),
+    /// Keyless signing: the certificate identity and OIDC issuer expected in the
+    /// Fulcio certificate (e.g. a GitHub workflow URL and
+    /// `https://token.actions.githubusercontent.com`).
+    Keyless {
+        /// The certificate identity, e.g.
+        /// `https://github.com/owner/tool/.github/workflows/release.yml@refs/tags/v1.2.0`.
+        identity: String,
+        /// The OIDC issuer that vouched for the identity.
+        issuer: String,
+    },
+}
+
+/// Settings for verifying a release with cosign before installing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigstoreVerification {
+    /// URL of the cosign bundle for a release. `{crate}` and `{version}` are
+    /// replaced with the crate name and the version being installed.
+    pub bundle_url: String,
+    /// Who the release is expected to be signed by.
+    pub identity: CosignIdentity,
+}
+
+impl SigstoreVerification {
+    /// Verifies releases against the cosign bundle at `bundle_url` (see the
+    /// field of the same name), expecting them to be signed by `identity`.
+    pub fn new(bundle_url: &str, identity: CosignIdentity) -> Self {
+        SigstoreVerification {
+            bundle_url: bundle_url.to_string(),
+            identity,
+        }
+    }
+
+    // Bundle URL for a specific release
+    fn bundle_url_for(&self, crate_name: &str, version: &str) -> String {
+        self.bundle_url
+            .replace("{crate}", crate_name)
+            .replace("{version}", version)
+    }
+}
+
+// Verifies the published .crate for `version` with cosign
+pub(crate) fn verify_release(
+    verification: &SigstoreVerification,
+    crate_name: &str,
+    version: &RegistryVersion,
+    downloads: &DownloadSettings,
+    events: &mut Events,
+    runner: &dyn ProcessRunner,
+) -> Result<()> {
+    let checksum = version
+        .checksum
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("crates.io did not report a checksum for {} {}", crate_name, version.num))?;
+
+    let crate_url = format!("{CRATES_IO_URL}/api/v1/crates/{}/{}/download", crate_name, version.num);
+    let crate_bytes = download_checked(&crate_url, downloads, events, |crate_bytes| {
+        let actual: String = Sha256::digest(crate_bytes).iter().map(|byte| format!("{:02x}", byte)).collect();
+        if !actual.eq_ignore_ascii_case(checksum) {
+            return Err(anyhow::anyhow!(
+                "Downloaded {} {} does not match the registry checksum (expected {}, found {})",
+                crate_name, version.num, checksum, actual
+            ));
+        }
+        Ok(())
+    })?;
+
+    let bundle = download(&verification.bundle_url_for(crate_name, &version.num), downloads, events)?;
+
+    // cosign works on files, so stage both next to each other
+    let work_dir = runtime_dir(&RealFs)?.join(unique_token("sigstore"));
+    RealFs.create_private_dir(&work_dir).context("Failed to create sigstore work dir")?;
+    let crate_path = work_dir.join(format!("{}-{}.crate", crate_name, version.num));
+    let bundle_path = work_dir.join("cosign.bundle");
+    fs::write(&crate_path, &crate_bytes).context("Failed to stage downloaded crate")?;
+    fs::write(&bundle_path, &bundle).context("Failed to stage cosign bundle")?;
+
+    let mut cmd = Command::new("cosign");
+    cmd.arg("verify-blob").arg("--bundle").arg(&bundle_path);
+    match &verification.identity {
+        CosignIdentity::Key(key) => {
+            cmd.arg("--key").arg(key);
+        }
+        CosignIdentity::Keyless { identity, issuer } => {
+            cmd.args(["--certificate-identity", identity, "--certificate-oidc-issuer", issuer]);
+        }
+    }
+    cmd.arg(&crate_path);
+
+    let output = runner.output(cmd);
+    let _ = fs::remove_dir_all(&work_dir);
+    let output = output.context("Failed to run cosign; is it installed and on PATH?")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(target: INSTALL, "cosign verification failed: {}", stderr.trim());
+        return Err(anyhow::anyhow!(
+            "Signature verification failed for {} {}: {}",
+            crate_name, version.num, stderr.trim()
+        ));
+    }
+
+    info!(target: INSTALL, "Verified signature of {} {} with cosign", crate_name, version.num);
+    Ok(())
+}
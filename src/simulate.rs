@@ -0,0 +1,116 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Simulated updates, for testing a program's update prompts, event hooks and
+//! migrations end to end without publishing releases.
+//!
+//! With the `simulate` feature, these environment variables take over:
+//!
+//! - `RSPAWN_SIMULATE_LATEST=9.9.9` pretends `9.9.9` is the latest version,
+//!   without asking the registry.
+//! - `RSPAWN_SIMULATE_INSTALL=ok|fail` fakes `cargo install`, succeeding
+//!   (the default) or failing like a compile error would.
+//!
+//! While simulating, nothing is installed or relaunched: the update flow
+//! returns where it would have relaunched, and the running binary is not
+//! recorded as the new version.
+use std::env;
+use std::io;
+use std::process::{Command, ExitStatus};
+use log::{info, warn};
+use crate::install::{InstallError, InstallFailureKind};
+use crate::log_target::{INSTALL, RELAUNCH};
+use crate::process::ProcessRunner;
+
+/// Environment variable pretending a version is the latest.
+pub const SIMULATE_LATEST_ENV: &str = "RSPAWN_SIMULATE_LATEST";
+/// Environment variable faking the install's outcome: `ok` or `fail`.
+pub const SIMULATE_INSTALL_ENV: &str = "RSPAWN_SIMULATE_INSTALL";
+
+// Stderr of a simulated failed install, classified as a compile error
+const SIMULATED_FAILURE: &str = "error: could not compile `rspawn-simulation` (simulated by RSPAWN_SIMULATE_INSTALL=fail)";
+
+// The simulation requested through the environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Simulation {
+    pub(crate) latest: Option<String>,
+    install_succeeds: bool,
+}
+
+impl Simulation {
+    // Reads the simulation from the environment, `None` if none is requested
+    pub(crate) fn from_env() -> Option<Self> {
+        let latest = env::var(SIMULATE_LATEST_ENV).ok().filter(|latest| !latest.trim().is_empty());
+        let install = env::var(SIMULATE_INSTALL_ENV).ok().filter(|install| !install.trim().is_empty());
+        if latest.is_none() && install.is_none() {
+            return None;
+        }
+        let install_succeeds = match install.as_deref().map(str::trim) {
+            None | Some("ok") => true,
+            Some("fail") => false,
+            Some(other) => {
+                warn!(target: INSTALL, "Unknown {} value {:?}, simulating a successful install", SIMULATE_INSTALL_ENV, other);
+                true
+            }
+        };
+        Some(Simulation {
+            latest: latest.map(|latest| latest.trim().to_string()),
+            install_succeeds,
+        })
+    }
+
+    // Runs nothing, faking the install's outcome
+    pub(crate) fn runner(&self) -> SimulatedRunner {
+        SimulatedRunner {
+            install_succeeds: self.install_succeeds,
+        }
+    }
+}
+
+// Fakes `cargo install` and the relaunch
+pub(crate) struct SimulatedRunner {
+    install_succeeds: bool,
+}
+
+impl ProcessRunner for SimulatedRunner {
+    fn install(&self, command: Command, _echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        info!(target: INSTALL, "Simulating {:?}", command);
+        if self.install_succeeds {
+            return Ok(());
+        }
+        on_line(SIMULATED_FAILURE);
+        Err(InstallError::InstallFailed {
+            status: failed_status(),
+            kind: InstallFailureKind::classify(failed_status(), SIMULATED_FAILURE),
+            stderr: SIMULATED_FAILURE.to_string(),
+        })
+    }
+
+    fn spawn(&self, command: Command) -> io::Result<()> {
+        info!(target: RELAUNCH, "Simulating relaunch with {:?}", command);
+        Ok(())
+    }
+}
+
+// Exit status of a process that exited with 101, as cargo does on build errors
+#[cfg(unix)]
+fn failed_status() -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(101 << 8)
+}
+
+#[cfg(windows)]
+fn failed_status() -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(101)
+}
@@ -0,0 +1,321 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use log::debug;
+use crate::error::RspawnError;
+use crate::json;
+use crate::log_target::CHECK;
+use crate::backoff::FailedInstalls;
+use crate::filesystem::{Fs, RealFs};
+use crate::diagnose::Diagnosis;
+use crate::history::HistoryEntry;
+use crate::last_error::LastError;
+use crate::restart::PendingRestart;
+use crate::settings::UserSettings;
+use crate::unique::unique_token;
+
+/// Environment variable overriding the root of rspawn's state directory.
+pub const STATE_DIR_ENV: &str = "RSPAWN_STATE_DIR";
+
+// Root directory shared by every rspawn-powered program on this machine
+pub(crate) fn state_root() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(STATE_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    if cfg!(windows) {
+        let local_app_data = env::var_os("LOCALAPPDATA")
+            .context("Neither RSPAWN_STATE_DIR nor LOCALAPPDATA are set")?;
+        return Ok(PathBuf::from(local_app_data).join("rspawn"));
+    }
+
+    if let Some(xdg_state_home) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(xdg_state_home).join("rspawn"));
+    }
+
+    let home = env::var_os("HOME").context("Neither RSPAWN_STATE_DIR nor HOME are set")?;
+    Ok(PathBuf::from(home).join(".local").join("state").join("rspawn"))
+}
+
+// Per-user directory for lock and staging files: `$XDG_RUNTIME_DIR/rspawn`, or a
+// private `rspawn-<uid>` directory in the temp dir, so other users can't squat them
+pub(crate) fn runtime_dir(fs: &dyn Fs) -> Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("rspawn"),
+        None => env::temp_dir().join(format!("rspawn-{}", current_user_id())),
+    };
+    fs.create_private_dir(&dir)
+        .with_context(|| format!("Failed to create runtime directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+#[cfg(unix)]
+fn current_user_id() -> String {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }.to_string()
+}
+
+// The temp dir is already per user on Windows
+#[cfg(not(unix))]
+fn current_user_id() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
+// Name of the running executable, used to keep each program's state apart
+pub(crate) fn current_exe_name() -> Result<String> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    let exe_name = exe_path
+        .file_stem()
+        .context("Current executable has no file name")?;
+    Ok(exe_name.to_string_lossy().into_owned())
+}
+
+// File name of the running executable, including any extension
+pub(crate) fn current_exe_file_name() -> Result<PathBuf> {
+    let exe_path = env::current_exe().context("Failed to resolve the current executable")?;
+    exe_path
+        .file_name()
+        .map(PathBuf::from)
+        .context("Current executable has no file name")
+}
+
+/// Returns the state directory of the running program, creating it if needed.
+///
+/// This is `<root>/<program name>`, where `<root>` is `$RSPAWN_STATE_DIR` if set,
+/// otherwise `$XDG_STATE_HOME/rspawn` (falling back to `~/.local/state/rspawn`),
+/// or `%LOCALAPPDATA%\rspawn` on Windows.
+pub fn state_dir() -> Result<PathBuf, RspawnError> {
+    state_dir_in(&RealFs).map_err(RspawnError::from)
+}
+
+// `state_dir()`, created through `fs`
+pub(crate) fn state_dir_in(fs: &dyn Fs) -> Result<PathBuf> {
+    let dir = state_root()?.join(current_exe_name()?);
+    fs.create_dir_all(&dir)
+        .with_context(|| format!("Failed to create state directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+// File holding the persisted `State`, inside `state_dir()`
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Record of a binary installed by rspawn.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledBinary {
+    /// The installed version.
+    pub version: String,
+    /// Where the binary was installed.
+    pub path: PathBuf,
+    /// Hex-encoded SHA-256 of the binary right after it was installed.
+    pub sha256: String,
+    /// Install time, in seconds since the Unix epoch.
+    pub installed_at: u64,
+}
+
+/// State persisted across runs in `state_dir()`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The last binary installed by rspawn, if any.
+    #[serde(default)]
+    pub installed: Option<InstalledBinary>,
+    /// Random identifier of this install, used to place it in staged rollouts.
+    #[serde(default)]
+    pub install_id: Option<String>,
+    /// Release channel chosen with `set_channel`.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// The last failed update attempt, cleared once one succeeds.
+    #[serde(default)]
+    pub last_error: Option<LastError>,
+    /// Consecutive failed installs of the latest version of each crate, while
+    /// backing off from it, by `<crate>` or `<crate>@<channel>`.
+    #[serde(default)]
+    pub backoff: BTreeMap<String, FailedInstalls>,
+    /// An update installed without relaunching, until the program runs it.
+    #[serde(default)]
+    pub pending_restart: Option<PendingRestart>,
+    /// Binaries updated with `RSpawn::target_binary`, by name.
+    #[serde(default)]
+    pub targets: BTreeMap<String, InstalledBinary>,
+    /// What the last update run checked, see `diagnose()`.
+    #[serde(default)]
+    pub last_run: Option<Diagnosis>,
+    /// Settings changed by the user with `rspawn config`.
+    #[serde(default)]
+    pub settings: UserSettings,
+    /// When the latest version was last looked up, in seconds since the Unix
+    /// epoch. Only kept while a check interval is set.
+    #[serde(default)]
+    pub last_check: Option<u64>,
+    /// Update attempts, oldest first, see `update_history`.
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+impl State {
+    // Reads the state file, returning the default state if there is none yet.
+    // Without the `json` feature there never is one.
+    fn load_from(path: &Path, fs: &dyn Fs) -> Result<State> {
+        if !json::AVAILABLE {
+            return Ok(State::default());
+        }
+        if !fs.exists(path) {
+            debug!(target: CHECK, "No state file at {}, using defaults", path.display());
+            return Ok(State::default());
+        }
+        let contents = fs.read_to_string(path)
+            .with_context(|| format!("Failed to read state file {}", path.display()))?;
+        json::from_str(&contents)
+            .with_context(|| format!("Failed to parse state file {}", path.display()))
+    }
+
+    fn save_to(&self, path: &Path, fs: &dyn Fs) -> Result<()> {
+        if !json::AVAILABLE {
+            debug!(target: CHECK, "Built without the `json` feature, not keeping state in {}", path.display());
+            return Ok(());
+        }
+        let contents = json::to_string_pretty(self).context("Failed to serialize state")?;
+        write_atomic(fs, path, contents.as_bytes())
+    }
+
+    /// Loads the running program's state.
+    pub fn load() -> Result<State, RspawnError> {
+        State::load_in(&RealFs).map_err(RspawnError::from)
+    }
+
+    /// Persists the running program's state.
+    ///
+    /// This overwrites changes other instances made since it was loaded; use
+    /// `State::update` to change only part of it.
+    pub fn save(&self) -> Result<(), RspawnError> {
+        self.save_in(&RealFs).map_err(RspawnError::from)
+    }
+
+    /// Loads, changes and saves the running program's state while holding a
+    /// lock on it, so concurrent instances never lose each other's changes.
+    pub fn update<R>(change: impl FnOnce(&mut State) -> R) -> Result<R, RspawnError> {
+        State::update_in(&RealFs, change).map_err(RspawnError::from)
+    }
+
+    // Loads the state of another program, by executable name
+    pub(crate) fn load_program(program: &str) -> Result<State> {
+        State::load_from(&state_root()?.join(program).join(STATE_FILE_NAME), &RealFs)
+    }
+
+    // Changes the state of another program, by executable name, see `update()`
+    pub(crate) fn update_program<R>(program: &str, change: impl FnOnce(&mut State) -> R) -> Result<R> {
+        let dir = state_root()?.join(program);
+        RealFs.create_dir_all(&dir)
+            .with_context(|| format!("Failed to create state directory {}", dir.display()))?;
+        let path = dir.join(STATE_FILE_NAME);
+        with_file_lock(&RealFs, &path, || {
+            let mut state = State::load_from(&path, &RealFs)?;
+            let result = change(&mut state);
+            state.save_to(&path, &RealFs)?;
+            Ok(result)
+        })
+    }
+
+    // `load()` through `fs`
+    pub(crate) fn load_in(fs: &dyn Fs) -> Result<State> {
+        State::load_from(&state_dir_in(fs)?.join(STATE_FILE_NAME), fs)
+    }
+
+    // `save()` through `fs`
+    pub(crate) fn save_in(&self, fs: &dyn Fs) -> Result<()> {
+        self.save_to(&state_dir_in(fs)?.join(STATE_FILE_NAME), fs)
+    }
+
+    // `update()` through `fs`
+    pub(crate) fn update_in<R>(fs: &dyn Fs, change: impl FnOnce(&mut State) -> R) -> Result<R> {
+        let path = state_dir_in(fs)?.join(STATE_FILE_NAME);
+        with_file_lock(fs, &path, || {
+            let mut state = State::load_from(&path, fs)?;
+            let result = change(&mut state);
+            state.save_to(&path, fs)?;
+            Ok(result)
+        })
+    }
+}
+
+// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+// Replaces `path` with `contents` in one step, by writing a temporary file unique
+// to this call and renaming it in place: readers see the old or the new
+// contents, never a mix
+pub(crate) fn write_atomic(fs: &dyn Fs, path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = sibling(path, &format!(".{}.tmp", unique_token("write")));
+    fs.write(&tmp_path, contents)
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs.rename(&tmp_path, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+// Runs `f` while holding an advisory lock on `<path>.lock`, to serialize
+// read-modify-write cycles on `path` across processes
+pub(crate) fn with_file_lock<R>(fs: &dyn Fs, path: &Path, f: impl FnOnce() -> Result<R>) -> Result<R> {
+    let lock_path = sibling(path, ".lock");
+    let _lock = fs.lock_file(&lock_path)
+        .with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    fn state_path(fs: &dyn Fs) -> PathBuf {
+        state_dir_in(fs).unwrap().join(STATE_FILE_NAME)
+    }
+
+    #[test]
+    fn missing_state_file_loads_defaults() {
+        let fs = MemoryFs::new();
+        let state = State::load_in(&fs).unwrap();
+        assert!(state.last_error.is_none());
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn corrupted_state_file_is_reported_and_left_alone() {
+        let fs = MemoryFs::new();
+        let path = state_path(&fs);
+        fs.write(&path, b"{\"history\": [").unwrap();
+
+        let error = State::load_in(&fs).unwrap_err();
+        assert!(format!("{error:#}").contains("Failed to parse state file"));
+        assert!(State::update_in(&fs, |state| state.last_check = Some(1)).is_err());
+        assert_eq!(fs.read(&path).unwrap(), b"{\"history\": [");
+    }
+
+    #[test]
+    fn update_leaves_no_temporary_files() {
+        let fs = MemoryFs::new();
+        State::update_in(&fs, |state| state.last_check = Some(1)).unwrap();
+        State::update_in(&fs, |state| state.last_check = Some(2)).unwrap();
+
+        assert_eq!(fs.files(), vec![state_path(&fs)]);
+        assert_eq!(State::load_in(&fs).unwrap().last_check, Some(2));
+    }
+}
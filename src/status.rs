@@ -0,0 +1,63 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! What rspawn knows about an installed program, for `rspawn status`.
+use std::collections::BTreeMap;
+use anyhow::Result;
+use crate::error::RspawnError;
+use crate::backoff::FailedInstalls;
+use crate::clock::SystemClock;
+use crate::diagnose::Diagnosis;
+use crate::filesystem::RealFs;
+use crate::hold::{self, HeldByOperator};
+use crate::last_error::LastError;
+use crate::restart::PendingRestart;
+use crate::settings::UserSettings;
+use crate::state::{InstalledBinary, State};
+
+/// The update state of a program, see `program_status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramStatus {
+    /// The last binary rspawn installed, if any.
+    pub installed: Option<InstalledBinary>,
+    /// The last update run, with the versions it found.
+    pub last_run: Option<Diagnosis>,
+    /// An update installed and waiting for the program to be restarted.
+    pub pending_restart: Option<PendingRestart>,
+    /// The operator's hold, if updates are frozen.
+    pub held: Option<HeldByOperator>,
+    /// Versions being backed off from after failed installs.
+    pub backoff: BTreeMap<String, FailedInstalls>,
+    /// Settings changed with `rspawn config`.
+    pub settings: UserSettings,
+    /// The channel chosen with `set_channel` or `rspawn config`.
+    pub channel: Option<String>,
+    /// The last failed update attempt, if the last attempt failed.
+    pub last_error: Option<LastError>,
+}
+
+/// Returns what rspawn knows about `program` (by executable name), from its
+/// state and the operator's hold file. Doesn't check for a newer version.
+pub fn program_status(program: &str) -> Result<ProgramStatus, RspawnError> {
+    let state = State::load_program(program)?;
+    Ok(ProgramStatus {
+        installed: state.installed,
+        last_run: state.last_run,
+        pending_restart: state.pending_restart,
+        held: hold::check_hold(&SystemClock, &RealFs).err(),
+        backoff: state.backoff,
+        settings: state.settings,
+        channel: state.channel,
+        last_error: state.last_error,
+    })
+}
@@ -0,0 +1,120 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::env;
+use std::io::{self, IsTerminal};
+use serde::{Deserialize, Serialize};
+
+const BOLD_GREEN: &str = "\x1b[1;32m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether rspawn's own output is colored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Style {
+    /// Color when writing to a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always color, even when not writing to a terminal.
+    Always,
+    /// Never color.
+    Never,
+}
+
+/// How much rspawn prints on its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verbosity {
+    /// Only print confirmation prompts.
+    Quiet,
+    /// Also print status lines and warnings.
+    #[default]
+    Normal,
+}
+
+// See https://no-color.org: any non-empty value disables color
+fn no_color_requested() -> bool {
+    env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+// Prints rspawn's prompts, status lines and warnings according to `Style` and `Verbosity`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Printer {
+    stdout_color: bool,
+    stderr_color: bool,
+    verbosity: Verbosity,
+}
+
+impl Printer {
+    pub(crate) fn new(style: Style, verbosity: Verbosity) -> Self {
+        let (stdout_color, stderr_color) = match style {
+            Style::Always => (true, true),
+            Style::Never => (false, false),
+            Style::Auto => {
+                let allowed = !no_color_requested();
+                (allowed && io::stdout().is_terminal(), allowed && io::stderr().is_terminal())
+            }
+        };
+        Printer {
+            stdout_color,
+            stderr_color,
+            verbosity,
+        }
+    }
+
+    fn paint(color: bool, code: &str, text: &str) -> String {
+        if color {
+            format!("{code}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    // Highlights a version number in stdout output
+    pub(crate) fn version(&self, version: &str) -> String {
+        Printer::paint(self.stdout_color, BOLD_GREEN, version)
+    }
+
+    // Prints a question, whatever the verbosity
+    pub(crate) fn prompt(&self, message: &str) {
+        println!("{message}");
+    }
+
+
+    // Prints a status line, unless quiet
+    pub(crate) fn status(&self, message: &str) {
+        if self.verbosity != Verbosity::Quiet {
+            println!("{message}");
+        }
+    }
+
+    // Prints a warning to stderr, unless quiet
+    pub(crate) fn warning(&self, message: &str) {
+        if self.verbosity != Verbosity::Quiet {
+            eprintln!("{} {}", Printer::paint(self.stderr_color, BOLD_YELLOW, "warning:"), message);
+        }
+    }
+
+    // Whether progress bars should be drawn: never when quiet or when stderr isn't a terminal
+    #[cfg(feature = "progress")]
+    pub(crate) fn show_progress(&self) -> bool {
+        self.verbosity != Verbosity::Quiet && io::stderr().is_terminal()
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer::new(Style::default(), Verbosity::default())
+    }
+}
@@ -0,0 +1,112 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Updating another binary than the running one, for "launcher updates worker"
+//! setups: the launcher keeps running, and only restarts its worker.
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use crate::log_target::INSTALL;
+use crate::clock::Clock;
+use crate::filesystem::Fs;
+use crate::integrity::sha256_file;
+use crate::state::{InstalledBinary, State};
+
+// A binary updated instead of the running one, see `RSpawn::target_binary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TargetBinary {
+    // The binary's name, also the name of the crate it is installed from
+    name: String,
+    // Where it lives, when given as a path rather than a name
+    path: Option<PathBuf>,
+}
+
+impl TargetBinary {
+    pub(crate) fn new(name_or_path: PathBuf) -> Result<Self> {
+        let name = name_or_path
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !name.is_empty())
+            .with_context(|| format!("Target binary {} has no name", name_or_path.display()))?;
+        let is_path = name_or_path.components().count() > 1;
+        Ok(TargetBinary {
+            name,
+            path: is_path.then_some(name_or_path),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Where the binary is, or is about to be installed: the given path, or `bin_dir`
+    pub(crate) fn path(&self, bin_dir: Option<PathBuf>) -> Option<PathBuf> {
+        match &self.path {
+            Some(path) => Some(path.clone()),
+            None => Some(bin_dir?.join(format!("{}{}", self.name, env::consts::EXE_SUFFIX))),
+        }
+    }
+
+    // `--root` putting the binary at its given path, which has to be `<root>/bin/<name>`
+    pub(crate) fn install_root(&self) -> Result<Option<PathBuf>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let bin_dir = path.parent().filter(|dir| dir.file_name().is_some_and(|name| name == "bin"));
+        bin_dir
+            .and_then(Path::parent)
+            .map(|root| Some(root.to_path_buf()))
+            .with_context(|| format!("Target binary {} is not in a `bin` directory, where cargo installs binaries", path.display()))
+    }
+
+    // The installed version, from `<binary> --version` or else the last one rspawn installed
+    pub(crate) fn installed_version(&self, path: Option<&Path>, fs: &dyn Fs) -> Result<Option<String>> {
+        if let Some(version) = path.and_then(reported_version) {
+            return Ok(Some(version));
+        }
+        let recorded = State::load_in(fs)?.targets.get(&self.name).map(|installed| installed.version.clone());
+        debug!(target: INSTALL, "{} did not report its version, last installed: {:?}", self.name, recorded);
+        Ok(recorded)
+    }
+}
+
+// Version printed by `<binary> --version`, e.g. "worker 1.4.2"
+fn reported_version(path: &Path) -> Option<String> {
+    let output = Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.lines().next()?.split_whitespace().last()?.trim_start_matches('v');
+    version.starts_with(|c: char| c.is_ascii_digit()).then(|| version.to_string())
+}
+
+// Remembers the installed version of a target, apart from the running program's own
+pub(crate) fn record_install(name: &str, path: &Path, version: &str, clock: &dyn Clock, fs: &dyn Fs) -> Result<()> {
+    let sha256 = sha256_file(path)?;
+    info!(target: INSTALL, "Installed {} {} (sha256 {})", path.display(), version, sha256);
+    let installed = InstalledBinary {
+        version: version.to_string(),
+        path: path.to_path_buf(),
+        sha256,
+        installed_at: clock.unix_now(),
+    };
+    State::update_in(fs, |state| {
+        state.targets.insert(name.to_string(), installed);
+    })
+}
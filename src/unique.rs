@@ -0,0 +1,37 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Tokens made by this process so far, so two made in the same instant still differ
+static TOKENS_MADE: AtomicU64 = AtomicU64::new(0);
+
+// A name unique across processes and calls, for lock tokens, staging files and
+// install ids: `<label>-<pid>-<nanos>-<count>-<random>`. The random part comes
+// from std's per-process hash keys, which are seeded by the OS, and keeps
+// tokens apart across machines and pid reuse.
+pub(crate) fn unique_token(label: &str) -> String {
+    let pid = process::id();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_nanos())
+        .unwrap_or_default();
+    let count = TOKENS_MADE.fetch_add(1, Ordering::Relaxed);
+
+    let random = RandomState::new().hash_one((pid, nanos, count, label));
+    format!("{label}-{pid}-{nanos:x}-{count}-{random:016x}")
+}
@@ -0,0 +1,105 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Fetching manifests and artifacts from `file://` URLs and from schemes the
+//! program teaches rspawn, like an internal `artifactory://`.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock, RwLock};
+use anyhow::{Context, Result};
+use log::debug;
+use crate::log_target::CHECK;
+
+/// Fetches the resources behind the URLs of a custom scheme.
+///
+/// Registered with `register_url_resolver()`, it serves every URL rspawn
+/// fetches with that scheme: the channel manifest, the release feed, release
+/// signatures and artifacts. `http://` and `https://` URLs always go to the
+/// built-in client.
+pub trait UrlResolver: Send + Sync {
+    /// Returns the bytes at `url`, which starts with the resolver's scheme.
+    fn fetch(&self, url: &str) -> Result<Vec<u8>>;
+}
+
+impl<F> UrlResolver for F
+where
+    F: Fn(&str) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        self(url)
+    }
+}
+
+fn resolvers() -> &'static RwLock<BTreeMap<String, Arc<dyn UrlResolver>>> {
+    static RESOLVERS: OnceLock<RwLock<BTreeMap<String, Arc<dyn UrlResolver>>>> = OnceLock::new();
+    RESOLVERS.get_or_init(Default::default)
+}
+
+/// Serves URLs starting with `scheme://` with `resolver`, for the whole process.
+///
+/// Registering a scheme again replaces its resolver. `file://` works without
+/// one, and `http`/`https` can't be taken over.
+pub fn register_url_resolver(scheme: &str, resolver: impl UrlResolver + 'static) {
+    let scheme = scheme.trim_end_matches("://").to_ascii_lowercase();
+    if scheme == "http" || scheme == "https" {
+        debug!(target: CHECK, "Not registering a resolver for {}://, it is built in", scheme);
+        return;
+    }
+    resolvers().write().unwrap_or_else(|e| e.into_inner()).insert(scheme, Arc::new(resolver));
+}
+
+// The scheme of `url`, lowercased
+fn scheme(url: &str) -> Option<String> {
+    url.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase())
+}
+
+// The local path of a `file://` URL
+fn file_path(url: &str) -> PathBuf {
+    let path = &url["file://".len()..];
+    // `file:///C:/dir` on Windows
+    let path = match path.strip_prefix('/') {
+        Some(rest) if cfg!(windows) && rest.get(1..2) == Some(":") => rest,
+        _ => path,
+    };
+    PathBuf::from(path.replace("%20", " "))
+}
+
+// Fetches `url` if it's a `file://` URL or has a registered resolver, `None`
+// if it's for the HTTP client
+pub(crate) fn fetch(url: &str) -> Option<Result<Vec<u8>>> {
+    let scheme = scheme(url)?;
+    if scheme == "file" {
+        let path = file_path(url);
+        debug!(target: CHECK, "Reading {}", path.display());
+        return Some(fs::read(&path).with_context(|| format!("Failed to read {}", path.display())));
+    }
+    let resolver = resolvers().read().unwrap_or_else(|e| e.into_inner()).get(&scheme).cloned();
+    #[cfg(feature = "s3")]
+    let resolver = resolver.or_else(|| (scheme == "s3").then(|| Arc::new(crate::s3::S3Resolver::new()) as Arc<dyn UrlResolver>));
+    #[cfg(feature = "oci")]
+    let resolver = resolver.or_else(|| (scheme == "oci").then(|| Arc::new(crate::oci::OciResolver) as Arc<dyn UrlResolver>));
+    match resolver {
+        Some(resolver) => {
+            debug!(target: CHECK, "Fetching {} with the {}:// resolver", url, scheme);
+            Some(resolver.fetch(url).with_context(|| format!("Failed to fetch {}", url)))
+        }
+        None if scheme == "http" || scheme == "https" => None,
+        None => Some(Err(anyhow::anyhow!("No resolver registered for {}:// URLs like {}", scheme, url))),
+    }
+}
+
+// `fetch`, for text resources
+pub(crate) fn fetch_text(url: &str) -> Option<Result<String>> {
+    Some(fetch(url)?.and_then(|bytes| String::from_utf8(bytes).with_context(|| format!("{} is not UTF-8 text", url))))
+}
@@ -0,0 +1,409 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{bail, Context, Result};
+use crate::error::RspawnError;
+
+// Compares one dot-separated prerelease identifier, following semver:
+// numeric ones compare as numbers and sort before alphanumeric ones
+fn compare_identifier(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+// Splits "1.2.3-beta.1+build" into ([1, 2, 3], Some("beta.1")), ignoring build metadata.
+// Missing or unparseable components count as 0.
+fn split(version: &str) -> ([u64; 3], Option<&str>) {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split('+').next().unwrap_or(version);
+    let (release, prerelease) = match version.split_once('-') {
+        Some((release, prerelease)) => (release, Some(prerelease)),
+        None => (version, None),
+    };
+
+    let mut numbers = [0; 3];
+    for (number, part) in numbers.iter_mut().zip(release.split('.')) {
+        *number = part.parse().unwrap_or(0);
+    }
+    (numbers, prerelease)
+}
+
+/// Orders two versions by semver precedence, as rspawn does.
+///
+/// A leading `v` and build metadata are ignored, missing or unparseable
+/// components count as 0, and a prerelease comes before its release
+/// (`1.2.0-beta.2 < 1.2.0-rc.1 < 1.2.0`).
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_release, a_prerelease) = split(a);
+    let (b_release, b_prerelease) = split(b);
+
+    a_release.cmp(&b_release).then_with(|| match (a_prerelease, b_prerelease) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => {
+            let mut a = a.split('.');
+            let mut b = b.split('.');
+            loop {
+                match (a.next(), b.next()) {
+                    (None, None) => return Ordering::Equal,
+                    (None, Some(_)) => return Ordering::Less,
+                    (Some(_), None) => return Ordering::Greater,
+                    (Some(a), Some(b)) => match compare_identifier(a, b) {
+                        Ordering::Equal => continue,
+                        other => return other,
+                    },
+                }
+            }
+        }
+    })
+}
+
+// Whether `version` is a well-formed semver version, like the registry publishes:
+// three numeric components without leading zeros, then an optional prerelease
+// and build metadata made of non-empty alphanumeric identifiers
+#[cfg_attr(not(feature = "p2p"), allow(dead_code))]
+pub(crate) fn is_valid(version: &str) -> bool {
+    let (version, build) = match version.split_once('+') {
+        Some((version, build)) => (version, Some(build)),
+        None => (version, None),
+    };
+    let (release, prerelease) = match version.split_once('-') {
+        Some((release, prerelease)) => (release, Some(prerelease)),
+        None => (version, None),
+    };
+    let identifiers_valid = |identifiers: &str| {
+        identifiers
+            .split('.')
+            .all(|identifier| !identifier.is_empty() && identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+    };
+    let numeric = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()) && (part == "0" || !part.starts_with('0'));
+    let parts: Vec<&str> = release.split('.').collect();
+    parts.len() == 3
+        && parts.iter().all(|part| numeric(part))
+        && prerelease.is_none_or(|prerelease| {
+            identifiers_valid(prerelease)
+                && prerelease
+                    .split('.')
+                    .all(|identifier| !identifier.chars().all(|c| c.is_ascii_digit()) || numeric(identifier))
+        })
+        && build.is_none_or(identifiers_valid)
+}
+
+/// Returns whether `version` is a prerelease, e.g. `1.2.0-beta.1`.
+pub fn is_prerelease(version: &str) -> bool {
+    split(version).1.is_some()
+}
+
+/// Which versions count as an update over the current one, see `is_update`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UpdatePolicy {
+    /// Any other version, including an older one: the registry or release
+    /// channel decides what to run, as in rspawn's own update flow.
+    #[default]
+    AnyChange,
+    /// Only versions with a higher precedence, prereleases included.
+    Newer,
+    /// Only stable releases with a higher precedence.
+    NewerStable,
+}
+
+/// Returns whether moving from `current` to `candidate` is an update under `policy`.
+///
+/// # Example
+/// ```
+/// use rspawn::{is_update, UpdatePolicy};
+///
+/// assert!(is_update("1.2.0", "1.3.0-beta.1", UpdatePolicy::Newer));
+/// assert!(!is_update("1.2.0", "1.3.0-beta.1", UpdatePolicy::NewerStable));
+/// assert!(is_update("1.3.0-beta.1", "1.2.0", UpdatePolicy::AnyChange));
+/// ```
+pub fn is_update(current: &str, candidate: &str, policy: UpdatePolicy) -> bool {
+    match policy {
+        UpdatePolicy::AnyChange => candidate != current,
+        UpdatePolicy::Newer => compare_versions(candidate, current) == Ordering::Greater,
+        UpdatePolicy::NewerStable => !is_prerelease(candidate) && compare_versions(candidate, current) == Ordering::Greater,
+    }
+}
+
+// How a comparator relates a version to the one it was written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+}
+
+// One end of the range a comparator allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bound {
+    version: String,
+    inclusive: bool,
+}
+
+// A single comparator, lowered to the range of versions it allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparator {
+    min: Option<Bound>,
+    max: Option<Bound>,
+    // Release a prerelease comparator was written for: only its prereleases may match
+    prerelease_of: Option<[u64; 3]>,
+}
+
+impl Comparator {
+    fn parse(text: &str) -> Result<Comparator> {
+        let text = text.trim();
+        let (op, rest) = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("~", Op::Tilde),
+            ("^", Op::Caret),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest.trim())))
+        .unwrap_or((Op::Caret, text));
+
+        let rest = rest.split('+').next().unwrap_or(rest);
+        let (release, prerelease) = match rest.split_once('-') {
+            Some((release, prerelease)) => (release, Some(prerelease)),
+            None => (rest, None),
+        };
+
+        // Leading numeric parts; a wildcard ends them and makes a bare comparator exact
+        let mut numbers = [0; 3];
+        let mut given = 0;
+        let mut wildcard = false;
+        for part in release.split('.') {
+            if matches!(part, "*" | "x" | "X") {
+                wildcard = true;
+                break;
+            }
+            if given == 3 {
+                bail!("Invalid version requirement {:?}: too many version components", text);
+            }
+            numbers[given] = part
+                .parse::<u64>()
+                .with_context(|| format!("Invalid version requirement {:?}", text))?;
+            given += 1;
+        }
+        if prerelease.is_some() && given < 3 {
+            bail!("Invalid version requirement {:?}: a prerelease needs a full version", text);
+        }
+        let op = if wildcard && text.starts_with(|c: char| c.is_ascii_digit() || c == '*' || c == 'x' || c == 'X') {
+            Op::Exact
+        } else {
+            op
+        };
+
+        let [major, minor, patch] = numbers;
+        let written = match prerelease {
+            Some(prerelease) => format!("{major}.{minor}.{patch}-{prerelease}"),
+            None => format!("{major}.{minor}.{patch}"),
+        };
+        let at_least = |version: String| Some(Bound { version, inclusive: true });
+        let below = |version: String| Some(Bound { version, inclusive: false });
+        // The first version past everything matching the given components
+        let next = match given {
+            0 => None,
+            1 => below(format!("{}.0.0", major + 1)),
+            2 => below(format!("{major}.{}.0", minor + 1)),
+            _ => None,
+        };
+
+        let (min, max) = match op {
+            Op::Exact if given == 3 => (at_least(written.clone()), Some(Bound { version: written, inclusive: true })),
+            Op::Exact => (at_least(written), next),
+            Op::Greater if given == 3 => (Some(Bound { version: written, inclusive: false }), None),
+            Op::Greater => (next.map(|bound| Bound { inclusive: true, ..bound }), None),
+            Op::GreaterEq => (at_least(written), None),
+            Op::Less => (None, below(written)),
+            Op::LessEq if given == 3 => (None, Some(Bound { version: written, inclusive: true })),
+            Op::LessEq => (None, next),
+            Op::Tilde if given == 1 => (at_least(written), below(format!("{}.0.0", major + 1))),
+            Op::Tilde => (at_least(written), below(format!("{major}.{}.0", minor + 1))),
+            Op::Caret => {
+                let max = if major > 0 || given == 1 {
+                    format!("{}.0.0", major + 1)
+                } else if minor > 0 || given == 2 {
+                    format!("0.{}.0", minor + 1)
+                } else {
+                    format!("0.0.{}", patch + 1)
+                };
+                (at_least(written), below(max))
+            }
+        };
+        let (min, max) = if given == 0 { (None, None) } else { (min, max) };
+
+        Ok(Comparator {
+            min,
+            max,
+            prerelease_of: prerelease.map(|_| numbers),
+        })
+    }
+
+    fn matches(&self, version: &str) -> bool {
+        let above_min = self.min.as_ref().is_none_or(|min| match compare_versions(version, &min.version) {
+            Ordering::Greater => true,
+            Ordering::Equal => min.inclusive,
+            Ordering::Less => false,
+        });
+        let below_max = self.max.as_ref().is_none_or(|max| match compare_versions(version, &max.version) {
+            Ordering::Less => true,
+            Ordering::Equal => max.inclusive,
+            Ordering::Greater => false,
+        });
+        above_min && below_max
+    }
+}
+
+/// A version requirement in Cargo's syntax: comma-separated comparators such as
+/// `>=1.2, <2`, `^1.4`, `~1.4.2`, `=1.4.2`, `1.4.*` or `*`. A bare version like
+/// `1.4` means `^1.4`.
+///
+/// As with Cargo, prereleases only match a comparator written with a prerelease
+/// of the same version, e.g. `>=2.0.0-beta.1` matches `2.0.0-beta.3` but not `2.1.0-beta.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    text: String,
+    comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    /// Parses a requirement, e.g. as declared by a server the program talks to.
+    pub fn parse(text: &str) -> Result<VersionReq, RspawnError> {
+        let comparators = text
+            .split(',')
+            .map(Comparator::parse)
+            .collect::<Result<Vec<_>>>()
+            .map_err(RspawnError::ParseError)?;
+        Ok(VersionReq {
+            text: text.trim().to_string(),
+            comparators,
+        })
+    }
+
+    /// Returns whether `version` satisfies every comparator.
+    pub fn matches(&self, version: &str) -> bool {
+        let (release, prerelease) = split(version);
+        if prerelease.is_some() && !self.comparators.iter().any(|comparator| comparator.prerelease_of == Some(release)) {
+            return false;
+        }
+        self.comparators.iter().all(|comparator| comparator.matches(version))
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = RspawnError;
+
+    fn from_str(text: &str) -> Result<VersionReq, RspawnError> {
+        VersionReq::parse(text)
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requirements_match_as_cargo_does() {
+        for (requirement, matching, not_matching) in [
+            // Caret, written or implied by a bare version
+            ("^1.2.3", &["1.2.3", "1.9.0", "1.2.4"][..], &["1.2.2", "2.0.0", "0.9.9"][..]),
+            ("1.2.3", &["1.2.3", "1.99.0"], &["2.0.0", "1.2.0"]),
+            ("^1.2", &["1.2.0", "1.3.1"], &["1.1.9", "2.0.0"]),
+            ("^1", &["1.0.0", "1.9.9"], &["0.9.0", "2.0.0"]),
+            ("^0.2.3", &["0.2.3", "0.2.9"], &["0.3.0", "0.2.2"]),
+            ("^0.0.3", &["0.0.3"], &["0.0.4", "0.0.2"]),
+            ("^0.0", &["0.0.0", "0.0.9"], &["0.1.0"]),
+            ("0", &["0.0.0", "0.9.9"], &["1.0.0"]),
+            // Tilde
+            ("~1.2.3", &["1.2.3", "1.2.9"], &["1.3.0", "1.2.2"]),
+            ("~1.2", &["1.2.0", "1.2.9"], &["1.3.0"]),
+            ("~1", &["1.0.0", "1.9.0"], &["2.0.0"]),
+            // Wildcards
+            ("*", &["0.0.1", "1.0.0", "99.0.0"], &[]),
+            ("1.*", &["1.0.0", "1.9.9"], &["2.0.0", "0.9.0"]),
+            ("1.2.x", &["1.2.0", "1.2.9"], &["1.3.0"]),
+            ("1.X", &["1.4.0"], &["2.0.0"]),
+            // Exact and comparison operators
+            ("=1.2.3", &["1.2.3", "1.2.3+build"], &["1.2.4", "1.2.2"]),
+            ("=1.2", &["1.2.0", "1.2.9"], &["1.3.0"]),
+            (">1.2.3", &["1.2.4", "2.0.0"], &["1.2.3"]),
+            (">1.2", &["1.3.0"], &["1.2.9"]),
+            ("<=1.2", &["1.2.9", "0.1.0"], &["1.3.0"]),
+            ("<1.2.3", &["1.2.2"], &["1.2.3"]),
+            // Ranges
+            (">=1.2, <2", &["1.2.0", "1.99.99"], &["1.1.9", "2.0.0"]),
+            (" >= 1.2.0 ,< 1.5.0 ", &["1.4.9"], &["1.5.0"]),
+            (">=0.1.0, <0.1.0", &[], &["0.1.0"]),
+            // Prereleases only match comparators written with one, for the same release
+            (">=2.0.0-beta.1", &["2.0.0-beta.1", "2.0.0-beta.3", "2.0.0", "2.1.0"], &["2.1.0-beta.1", "2.0.0-alpha.9"]),
+            ("^1.2.3", &[], &["1.2.4-beta.1", "1.3.0-rc.1"]),
+            ("*", &[], &["1.0.0-alpha"]),
+            (">=1.0.0-rc.1, <2", &["1.0.0-rc.2", "1.5.0"], &["1.5.0-rc.1"]),
+            ("=1.2.3-rc.1", &["1.2.3-rc.1"], &["1.2.3-rc.2", "1.2.3"]),
+        ] {
+            let parsed = VersionReq::parse(requirement).unwrap_or_else(|e| panic!("{requirement:?}: {e:#}"));
+            for version in matching {
+                assert!(parsed.matches(version), "{requirement:?} should match {version}");
+            }
+            for version in not_matching {
+                assert!(!parsed.matches(version), "{requirement:?} should not match {version}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_requirements_are_rejected() {
+        for requirement in ["", "abc", "^", ">=1.2,", "1.2.3.4", "1.2-beta", "^1.x.3-rc", ">= one", "1..2", "~1.-2"] {
+            assert!(matches!(VersionReq::parse(requirement), Err(RspawnError::ParseError(_))), "{requirement:?}");
+        }
+    }
+
+    #[test]
+    fn requirement_displays_as_written() {
+        let requirement: VersionReq = " >=1.2, <2 ".parse().unwrap();
+        assert_eq!(requirement.to_string(), ">=1.2, <2");
+    }
+
+    #[test]
+    fn only_semver_versions_are_valid() {
+        for version in ["0.0.0", "1.2.3", "10.20.30", "1.2.3-beta.1", "1.2.3-rc-1.0", "1.2.3+build.5", "1.2.3-alpha+001"] {
+            assert!(is_valid(version), "{version}");
+        }
+        for version in ["", "1", "1.2", "1.2.3.4", "v1.2.3", "01.2.3", "1.2.3-", "1.2.3-beta..1", "1.2.3-01", "1.2.3+", "1.2.x", "1.2.3 ", "1.2.3-b@d"] {
+            assert!(!is_valid(version), "{version}");
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![cfg(all(feature = "install", feature = "json"))]
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex, Once};
+use rspawn::{
+    ConfigProblem, ContainerPolicy, Gate, InstallError, InstallFailureKind, MemoryFs, MockClock, ProcessRunner, RSpawn, RecordedCommand,
+    RecordingRunner, RspawnError, UpdateOutcome, VersionSource,
+};
+
+const CRATE_NAME: &str = "rspawn-flow-test";
+
+// Points cargo and crates.io at a scratch directory, where the registry has
+// CRATE_NAME 2.0.0; everything an update keeps is in each test's `MemoryFs`
+fn scratch_environment() {
+    static SETUP: Once = Once::new();
+    SETUP.call_once(|| {
+        let dir = env::temp_dir().join(format!("rspawn-process-runner-{}", std::process::id()));
+        let versions = dir.join("registry").join(CRATE_NAME).join("versions.json");
+        fs::create_dir_all(versions.parent().unwrap()).unwrap();
+        fs::write(&versions, r#"{"versions":[{"num":"2.0.0","checksum":null,"yanked":false}]}"#).unwrap();
+        env::set_var(rspawn::REPLAY_REGISTRY_ENV, dir.join("registry"));
+        env::set_var("CARGO_HOME", dir.join("cargo"));
+    });
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+// Fails every install with `error`, counting installs and spawns
+struct FailingRunner {
+    error: fn() -> InstallError,
+    installs: Mutex<usize>,
+    spawns: Mutex<Vec<PathBuf>>,
+}
+
+impl FailingRunner {
+    fn new(error: fn() -> InstallError) -> Arc<Self> {
+        Arc::new(FailingRunner {
+            error,
+            installs: Mutex::new(0),
+            spawns: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl ProcessRunner for FailingRunner {
+    fn install(&self, _command: Command, _echo: bool, on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        *self.installs.lock().unwrap() += 1;
+        on_line("error[E0425]: cannot find value `x` in this scope");
+        Err((self.error)())
+    }
+
+    fn spawn(&self, command: Command) -> io::Result<()> {
+        self.spawns.lock().unwrap().push(PathBuf::from(command.get_program()));
+        Ok(())
+    }
+}
+
+fn compile_error() -> InstallError {
+    InstallError::InstallFailed {
+        status: exit_status(101),
+        kind: InstallFailureKind::Compile,
+        stderr: "error: could not compile `rspawn-flow-test`".to_string(),
+    }
+}
+
+fn cargo_missing() -> InstallError {
+    InstallError::Spawn(io::Error::from(io::ErrorKind::NotFound))
+}
+
+fn updater<R: ProcessRunner + 'static>(runner: &Arc<R>, fs: &Arc<MemoryFs>, clock: &Arc<MockClock>) -> RSpawn {
+    RSpawn::new()
+        .crate_name(CRATE_NAME)
+        .current_version("1.0.0")
+        .check_if_executed_from_PATH(false)
+        .container_policy(ContainerPolicy::Proceed)
+        .keep_backups(0)
+        .assume_yes(true)
+        .fs(Arc::clone(fs))
+        .clock(Arc::clone(clock))
+        .process_runner(Arc::clone(runner))
+}
+
+#[test]
+fn failed_install_is_reported_and_never_relaunched() {
+    scratch_environment();
+    let runner = FailingRunner::new(compile_error);
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+
+    let result = updater(&runner, &fs, &clock).relaunch_program();
+
+    match result {
+        Err(RspawnError::InstallFailed { status, kind, .. }) => {
+            assert_eq!(status.code(), Some(101));
+            assert_eq!(kind, InstallFailureKind::Compile);
+        }
+        other => panic!("expected InstallFailed, got {:?}", other),
+    }
+    assert_eq!(*runner.installs.lock().unwrap(), 1);
+    assert!(runner.spawns.lock().unwrap().is_empty(), "relaunched after a failed install");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn failed_install_is_reported_from_a_spawned_task() {
+    scratch_environment();
+    let runner = FailingRunner::new(compile_error);
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let update = updater(&runner, &fs, &clock).relaunch_async();
+    let result = runtime.block_on(async { tokio::spawn(update).await.unwrap() });
+
+    assert!(matches!(result, Err(RspawnError::InstallFailed { .. })), "{:?}", result);
+    assert_eq!(*runner.installs.lock().unwrap(), 1);
+    assert!(runner.spawns.lock().unwrap().is_empty(), "relaunched after a failed install");
+}
+
+#[test]
+fn missing_cargo_is_reported_and_never_relaunched() {
+    scratch_environment();
+    let runner = FailingRunner::new(cargo_missing);
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+
+    let result = updater(&runner, &fs, &clock).relaunch_program();
+
+    assert!(matches!(result, Err(RspawnError::NotOnPath { ref program }) if program == &PathBuf::from("cargo")), "{:?}", result);
+    assert!(runner.spawns.lock().unwrap().is_empty(), "relaunched without cargo");
+}
+
+#[test]
+fn failed_install_backs_off_the_next_run() {
+    scratch_environment();
+    let runner = FailingRunner::new(compile_error);
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+
+    assert!(updater(&runner, &fs, &clock).relaunch_program().is_err());
+    let retried = updater(&runner, &fs, &clock).relaunch_program();
+
+    assert!(matches!(retried, Ok(UpdateOutcome::Skipped(Gate::Backoff))), "{:?}", retried);
+    assert_eq!(*runner.installs.lock().unwrap(), 1);
+    assert!(runner.spawns.lock().unwrap().is_empty());
+}
+
+#[cfg(all(unix, feature = "ipc"))]
+#[test]
+fn control_update_answers_once_after_the_relaunch() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use rspawn::ControlServer;
+
+    scratch_environment();
+    let socket = env::temp_dir().join(format!("rspawn-control-{}.sock", std::process::id()));
+    let runner = Arc::new(RecordingRunner::new());
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+    let server_runner = Arc::clone(&runner);
+    let server = ControlServer::bind(&socket).unwrap().spawn(move || {
+        RSpawn::new()
+            .crate_name(CRATE_NAME)
+            .current_version("1.0.0")
+            .check_if_executed_from_PATH(false)
+            .container_policy(ContainerPolicy::Proceed)
+            .keep_backups(0)
+            .fs(Arc::clone(&fs))
+            .clock(Arc::clone(&clock))
+            .process_runner(Arc::clone(&server_runner))
+    });
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    writeln!(stream, r#"{{"jsonrpc":"2.0","id":7,"method":"update"}}"#).unwrap();
+    let responses: Vec<String> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+
+    assert_eq!(responses.len(), 1, "{:?}", responses);
+    let response: serde_json::Value = serde_json::from_str(&responses[0]).unwrap();
+    assert_eq!(response["id"], 7);
+    assert_eq!(response["result"]["updated"], true);
+    assert_eq!(response["result"]["version"], "2.0.0");
+    assert!(matches!(server.join().unwrap(), Ok(UpdateOutcome::Relaunched { pid: None })));
+    let spawned = runner.commands().into_iter().filter(|command| !command.install).count();
+    assert_eq!(spawned, 1);
+    let _ = fs::remove_file(&socket);
+}
+
+// Records installs, and what the state held when asked to exec
+#[cfg(unix)]
+struct ExecRunner {
+    fs: Arc<MemoryFs>,
+    state_at_exec: Mutex<Option<serde_json::Value>>,
+}
+
+#[cfg(unix)]
+impl ProcessRunner for ExecRunner {
+    fn install(&self, _command: Command, _echo: bool, _on_line: &mut dyn FnMut(&str)) -> Result<(), InstallError> {
+        Ok(())
+    }
+
+    fn spawn(&self, _command: Command) -> io::Result<()> {
+        panic!("spawned instead of exec");
+    }
+
+    fn exec_process(&self, _command: Command) -> io::Result<Option<u32>> {
+        let state = self.fs.files().into_iter().find(|path| path.ends_with("state.json")).expect("no state recorded");
+        let state = serde_json::from_slice(&rspawn::Fs::read(self.fs.as_ref(), &state).unwrap()).unwrap();
+        *self.state_at_exec.lock().unwrap() = Some(state);
+        Ok(None)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn exec_relaunch_records_the_run_before_replacing_the_process() {
+    scratch_environment();
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+    let runner = Arc::new(ExecRunner { fs: Arc::clone(&fs), state_at_exec: Mutex::new(None) });
+
+    let result = updater(&runner, &fs, &clock).relaunch_strategy(rspawn::RelaunchStrategy::Exec).relaunch_program();
+
+    assert!(matches!(result, Ok(UpdateOutcome::Relaunched { pid: None })), "{:?}", result);
+    let state = runner.state_at_exec.lock().unwrap().take().unwrap();
+    assert_eq!(state["last_run"]["latest_version"], "2.0.0");
+    assert_eq!(state["last_run"]["error"], serde_json::Value::Null);
+}
+
+// The arguments of the one install `runner` recorded
+fn install_args(runner: &RecordingRunner) -> Vec<String> {
+    let installs: Vec<RecordedCommand> = runner.commands().into_iter().filter(|command| command.install).collect();
+    assert_eq!(installs.len(), 1, "{:?}", installs);
+    assert_eq!(installs[0].program, "cargo");
+    installs[0].args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+}
+
+#[test]
+fn install_runs_the_exact_cargo_command() {
+    scratch_environment();
+    let runner = Arc::new(RecordingRunner::new());
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+
+    let result = updater(&runner, &fs, &clock)
+        .active_features(vec!["tls".to_string(), "cli".to_string()])
+        .relaunch_program();
+
+    assert!(matches!(result, Ok(UpdateOutcome::Relaunched { pid: None })), "{:?}", result);
+    assert_eq!(
+        install_args(&runner),
+        [
+            "install", CRATE_NAME,
+            "--version", "2.0.0",
+            "--locked",
+            "--features", "tls",
+            "--features", "cli",
+            "--message-format", "json-render-diagnostics",
+        ],
+    );
+}
+
+#[test]
+fn mirror_install_takes_every_crate_from_the_mirror() {
+    scratch_environment();
+    let mirror = env::temp_dir().join(format!("rspawn-process-runner-{}", std::process::id())).join("vendor");
+    fs::create_dir_all(mirror.join(CRATE_NAME)).unwrap();
+    fs::write(
+        mirror.join(CRATE_NAME).join("Cargo.toml"),
+        format!("[package]\nname = \"{CRATE_NAME}\"\nversion = \"2.1.0\"\n"),
+    )
+    .unwrap();
+    let runner = Arc::new(RecordingRunner::new());
+    let (fs, clock) = (Arc::new(MemoryFs::new()), Arc::new(MockClock::from_unix(1_700_000_000)));
+
+    let result = updater(&runner, &fs, &clock)
+        .version_source(VersionSource::Mirror(mirror.clone()))
+        .relaunch_program();
+
+    assert!(matches!(result, Ok(UpdateOutcome::Relaunched { pid: None })), "{:?}", result);
+    assert_eq!(
+        install_args(&runner),
+        [
+            "install", CRATE_NAME,
+            "--version", "2.1.0",
+            "--locked",
+            "--offline",
+            "--config", "source.crates-io.replace-with=\"rspawn-mirror\"",
+            "--config", &format!("source.rspawn-mirror.directory={:?}", mirror.to_string_lossy()),
+            "--message-format", "json-render-diagnostics",
+        ],
+    );
+}
+
+#[test]
+fn missing_package_is_a_config_error() {
+    let runner = Arc::new(RecordingRunner::new());
+
+    let result = RSpawn::new().current_version("1.0.0").process_runner(Arc::clone(&runner)).relaunch_program();
+
+    match result {
+        Err(RspawnError::Config(error)) => assert_eq!(error.problems, [ConfigProblem::Missing { option: "crate_name" }]),
+        other => panic!("expected a config error, got {:?}", other),
+    }
+    assert!(runner.commands().is_empty());
+    let problems = RSpawn::new().build().err().map(|error| error.problems);
+    assert_eq!(
+        problems,
+        Some(vec![ConfigProblem::Missing { option: "crate_name" }, ConfigProblem::Missing { option: "current_version" }]),
+    );
+}
@@ -0,0 +1,234 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "json")]
+use std::cmp::Ordering;
+use proptest::prelude::*;
+use rspawn::fuzzing::{is_on_channel, parse_latest_version};
+use rspawn::{compare_versions, is_prerelease, is_update, UpdatePolicy, VersionReq};
+use serde_json::json;
+
+// Semver-looking versions, some of them prereleases on one of a few channels
+fn version() -> impl Strategy<Value = String> {
+    (0u32..20, 0u32..20, 0u32..20, prop::option::of(("alpha|beta|rc", 0u32..5))).prop_map(
+        |(major, minor, patch, prerelease)| match prerelease {
+            Some((channel, n)) => format!("{major}.{minor}.{patch}-{channel}.{n}"),
+            None => format!("{major}.{minor}.{patch}"),
+        },
+    )
+}
+
+// Versions as they show up in the wild: any prerelease identifiers, numeric ones
+// with leading zeros, a leading `v`, build metadata
+fn loose_version() -> impl Strategy<Value = String> {
+    (
+        "v?",
+        version(),
+        prop::option::of(prop::collection::vec("[0-9]{1,3}|[a-z]{1,5}|0[0-9]", 1..4)),
+        prop::option::of("[a-z0-9]{1,6}"),
+    )
+        .prop_map(|(prefix, version, prerelease, build)| {
+            let mut version = format!("{prefix}{version}");
+            if let Some(prerelease) = prerelease {
+                version = format!("{}-{}", version.split('-').next().unwrap_or(&version), prerelease.join("."));
+            }
+            if let Some(build) = build {
+                version = format!("{version}+{build}");
+            }
+            version
+        })
+}
+
+// A crates.io versions response listing `versions` (number, yanked) in order
+fn versions_response(versions: &[(String, bool)]) -> String {
+    let versions: Vec<_> = versions
+        .iter()
+        .map(|(num, yanked)| json!({ "num": num, "yanked": yanked, "checksum": "00" }))
+        .collect();
+    json!({ "versions": versions }).to_string()
+}
+
+proptest! {
+    #[test]
+    fn parser_never_panics(body in any::<String>(), channel in prop::option::of("[a-z]{0,8}")) {
+        let _ = parse_latest_version(&body, channel.as_deref());
+    }
+
+    #[test]
+    fn parser_never_panics_on_json(body in r#"\{"versions":\[(\{"num":.{0,12},"yanked":.{0,6}\},?){0,4}\]\}"#) {
+        let _ = parse_latest_version(&body, None);
+        let _ = parse_latest_version(&body, Some("beta"));
+    }
+
+    #[test]
+    fn without_channel_the_first_version_wins(versions in prop::collection::vec((version(), any::<bool>()), 1..16)) {
+        let body = versions_response(&versions);
+        prop_assert_eq!(parse_latest_version(&body, None).ok(), Some(versions[0].0.clone()));
+    }
+
+    #[test]
+    fn channel_skips_yanked_and_other_channels(
+        versions in prop::collection::vec((version(), any::<bool>()), 0..16),
+        channel in "alpha|beta|rc",
+    ) {
+        let body = versions_response(&versions);
+        let expected = versions
+            .iter()
+            .find(|(num, yanked)| !yanked && is_on_channel(num, &channel))
+            .map(|(num, _)| num.clone());
+        prop_assert_eq!(parse_latest_version(&body, Some(channel.as_str())).ok(), expected);
+    }
+
+    #[test]
+    fn stable_versions_are_on_every_channel(major in 0u32..100, minor in 0u32..100, patch in 0u32..100, channel in "[a-z]{1,8}") {
+        // prop_assert! turns its condition into a format string, so no braces in there
+        let version = format!("{}.{}.{}", major, minor, patch);
+        let with_build = format!("{}+build.1", version);
+        prop_assert!(is_on_channel(&version, &channel));
+        prop_assert!(is_on_channel(&with_build, &channel));
+    }
+
+    #[test]
+    fn comparison_is_antisymmetric(a in loose_version(), b in loose_version()) {
+        prop_assert_eq!(compare_versions(&a, &b), compare_versions(&b, &a).reverse());
+    }
+
+    #[test]
+    fn comparison_is_transitive(a in loose_version(), b in loose_version(), c in loose_version()) {
+        let mut sorted = [a, b, c];
+        sorted.sort_by(|a, b| compare_versions(a, b));
+        let [low, middle, high] = &sorted;
+        prop_assert_ne!(compare_versions(low, middle), Ordering::Greater);
+        prop_assert_ne!(compare_versions(middle, high), Ordering::Greater);
+        prop_assert_ne!(compare_versions(low, high), Ordering::Greater);
+    }
+
+    #[test]
+    fn leading_v_and_build_metadata_are_ignored(version in version(), build in "[a-z0-9]{1,6}") {
+        let decorated = format!("v{}+{}", version, build);
+        prop_assert_eq!(compare_versions(&version, &decorated), Ordering::Equal);
+    }
+
+    #[test]
+    fn prerelease_comes_before_its_release(major in 0u32..20, minor in 0u32..20, patch in 0u32..20, pre in "[a-z0-9]{1,6}") {
+        let release = format!("{}.{}.{}", major, minor, patch);
+        let prerelease = format!("{}-{}", release, pre);
+        prop_assert_eq!(compare_versions(&prerelease, &release), Ordering::Less);
+    }
+
+    #[test]
+    fn update_policies_follow_the_ordering(current in loose_version(), candidate in loose_version()) {
+        let newer = is_update(&current, &candidate, UpdatePolicy::Newer);
+        prop_assert_eq!(newer, compare_versions(&candidate, &current) == Ordering::Greater);
+        prop_assert_eq!(is_update(&current, &candidate, UpdatePolicy::NewerStable), newer && !is_prerelease(&candidate));
+        prop_assert!(!newer || is_update(&current, &candidate, UpdatePolicy::AnyChange));
+        prop_assert!(!is_update(&current, &current, UpdatePolicy::Newer));
+        prop_assert!(!is_update(&current, &current, UpdatePolicy::AnyChange));
+    }
+
+    #[test]
+    fn requirements_agree_with_the_ordering(bound in version(), version in version()) {
+        let at_least = VersionReq::parse(&format!(">={}", bound)).unwrap();
+        let below = VersionReq::parse(&format!("<{}", bound)).unwrap();
+        let exact = VersionReq::parse(&format!("={}", bound)).unwrap();
+        prop_assert!(at_least.matches(&bound) && exact.matches(&bound) && !below.matches(&bound));
+        // Prereleases only match comparators written for their own release
+        if !is_prerelease(&version) {
+            let ordering = compare_versions(&version, &bound);
+            prop_assert_eq!(at_least.matches(&version), ordering != Ordering::Less);
+            prop_assert_eq!(below.matches(&version), ordering == Ordering::Less);
+            prop_assert_eq!(exact.matches(&version), ordering == Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn requirement_parser_never_panics(requirement in any::<String>(), version in loose_version()) {
+        if let Ok(parsed) = VersionReq::parse(&requirement) {
+            let reparsed = VersionReq::parse(&parsed.to_string()).unwrap();
+            prop_assert_eq!(reparsed.matches(&version), parsed.matches(&version));
+        }
+    }
+
+    #[test]
+    fn requirement_parser_never_panics_on_comparators(requirement in r"([<>=~^]{0,2}v?[0-9*xX]{0,3}(\.[0-9*xX]{0,3}){0,3}(-[a-z0-9.]{0,6})?,? ?){1,3}") {
+        if let Ok(parsed) = VersionReq::parse(&requirement) {
+            let _ = parsed.matches("1.2.3");
+            let _ = parsed.matches("1.2.3-beta.1");
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+mod channel_manifest {
+    use std::collections::BTreeMap;
+    use proptest::prelude::*;
+    use rspawn::{ChannelManifest, HostInfo};
+    use serde_json::json;
+    use super::version;
+
+    // A manifest with a few channels and host targets, as JSON
+    fn manifest() -> impl Strategy<Value = String> {
+        (
+            prop::collection::btree_map("stable|beta|nightly", (version(), prop::option::of(0u8..=100)), 1..4),
+            prop::collection::vec((prop::collection::vec("[a-z*?-]{0,8}", 0..3), version()), 0..3),
+        )
+            .prop_map(|(channels, targets)| {
+                let default = channels.keys().next().cloned().unwrap_or_default();
+                let channels: BTreeMap<_, _> = channels
+                    .into_iter()
+                    .map(|(name, (version, rollout_percent))| match rollout_percent {
+                        Some(percent) => (name, json!({ "version": version, "rollout_percent": percent })),
+                        None => (name, json!({ "version": version })),
+                    })
+                    .collect();
+                let targets: Vec<_> = targets
+                    .into_iter()
+                    .map(|(hosts, version)| json!({ "hosts": hosts, "version": version }))
+                    .collect();
+                json!({ "default": default, "channels": channels, "targets": targets }).to_string()
+            })
+    }
+
+    fn host() -> HostInfo {
+        HostInfo { hostname: Some("web-01".to_string()), labels: BTreeMap::new() }
+    }
+
+    proptest! {
+        #[test]
+        fn parser_never_panics(json in any::<String>()) {
+            let _ = ChannelManifest::parse(&json);
+        }
+
+        #[test]
+        fn manifest_survives_a_round_trip(json in manifest()) {
+            let parsed = ChannelManifest::parse(&json).unwrap();
+            let serialized = serde_json::to_string(&parsed).unwrap();
+            prop_assert_eq!(ChannelManifest::parse(&serialized).unwrap(), parsed);
+        }
+
+        #[test]
+        fn resolved_version_is_a_channel_or_target_version(json in manifest(), install_id in "[a-f0-9]{16}") {
+            let manifest = ChannelManifest::parse(&json).unwrap();
+            for name in manifest.channels.keys() {
+                let resolved = manifest.target_version("mytool", Some(name), &install_id, &host()).unwrap();
+                if let Some(resolved) = resolved {
+                    let listed = manifest.channels[name].version == resolved
+                        || manifest.targets.iter().any(|target| target.version == resolved);
+                    prop_assert!(listed);
+                } else {
+                    prop_assert!(manifest.channels[name].rollout_percent < 100);
+                }
+            }
+        }
+    }
+}
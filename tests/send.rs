@@ -0,0 +1,31 @@
+//  SPDX-License-Identifier: GPL-3.0-only
+/*
+ *  Copyright (C) 2024  jgabaut
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3 of the License.
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "tokio")]
+use rspawn::RSpawn;
+
+fn assert_send<T: Send>(_: &T) {}
+
+// The update futures can be handed to `tokio::spawn`, and so run on any worker thread
+#[test]
+fn update_futures_are_send() {
+    assert_send(&RSpawn::new().relaunch_async());
+    assert_send(&rspawn::self_update!().user_confirm(|_: &str| true).build().unwrap().relaunch_async());
+    assert_send(&rspawn::relaunch_program_async(
+        "rspawn",
+        "0.0.1",
+        None,
+        Some(|version: &str| version.starts_with('1')),
+        false,
+    ));
+}